@@ -0,0 +1,206 @@
+use std::time::{Duration, Instant};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tsdistances_gpu::{
+    Float,
+    context::GpuContext,
+    kernels::{
+        MSM_C, adtw_distance::cpu::ADTWImpl, dtw_distance::cpu::DTWImpl,
+        erp_distance::cpu::ERPImpl, kernel_trait::GpuKernelImpl, lcss_distance::cpu::LCSSImpl,
+        msm_distance::cpu::MSMImpl, twe_distance::cpu::TWEImpl, wdtw_distance::cpu::WDTWImpl,
+    },
+    utils::{DeviceHandles, RunOptions, generate_random_batch, get_device, logistic_weights},
+    warps::GpuSeries,
+};
+
+const LENGTHS: [usize; 3] = [64, 256, 1024];
+const BATCH_SIZES: [usize; 2] = [16, 64];
+
+/// Measures `metric` over every batch shape twice: until the distances are back on the host,
+/// and until the work is submitted only. The data is uploaded once per shape, outside the
+/// measurements.
+fn bench_metric<G: GpuKernelImpl>(
+    c: &mut Criterion,
+    handles: &DeviceHandles,
+    metric: &str,
+    init_val: Float,
+    params: impl Fn(usize) -> G,
+) {
+    let options = RunOptions::default();
+    let mut group = c.benchmark_group(metric);
+    for len in LENGTHS {
+        for count in BATCH_SIZES {
+            let a = generate_random_batch(count, len, 1);
+            let b = generate_random_batch(count, len, 2);
+            let (device, queue, sba, dsa, sa) = handles.clone();
+            let ctx = GpuContext::new(device, queue, sba, dsa, sa, &options, &a, &b).unwrap();
+            let shape = format!("{}x{}", count, len);
+
+            group.throughput(Throughput::Elements((count * count) as u64));
+            group.bench_function(BenchmarkId::new("gpu", &shape), |bench| {
+                bench.iter(|| ctx.distance_flat(params(len), init_val).unwrap())
+            });
+            group.bench_function(BenchmarkId::new("dispatch", &shape), |bench| {
+                bench.iter_custom(|iters| {
+                    let mut submitted = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        let pending = ctx.distance_async(params(len), init_val).unwrap();
+                        submitted += start.elapsed();
+                        pending.await_flat().unwrap();
+                    }
+                    submitted
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn distances(c: &mut Criterion) {
+    // A single device for every sample, so that its creation is not measured.
+    let handles = get_device().unwrap();
+
+    bench_metric(c, &handles, "dtw", Float::INFINITY, |_| DTWImpl {
+        window: u64::MAX,
+        itakura: 0.0,
+        cutoff: Float::INFINITY,
+    });
+    bench_metric(c, &handles, "erp", Float::INFINITY, |_| ERPImpl {
+        gap: vec![0.0],
+    });
+    bench_metric(c, &handles, "msm", Float::INFINITY, |_| MSMImpl {
+        split_cost: MSM_C,
+        merge_cost: MSM_C,
+        weighted: 0,
+        weights: vec![1.0],
+    });
+    bench_metric(c, &handles, "twe", Float::INFINITY, |_| TWEImpl {
+        nu: 0.001,
+        lambda: 1.0,
+    });
+    bench_metric(c, &handles, "adtw", Float::INFINITY, |_| ADTWImpl {
+        w: 0.1,
+    });
+    bench_metric(c, &handles, "lcss", 0.0, |_| LCSSImpl {
+        epsilon: 1.0,
+        delta: u64::MAX,
+    });
+    bench_metric(c, &handles, "wdtw", Float::INFINITY, |len| WDTWImpl {
+        weights: logistic_weights(len, 0.05),
+    });
+}
+
+/// Compares a large DTW batch split into chunks submitted to a single queue with the same
+/// batch spread over every compute queue of the device, with small chunks and with chunks
+/// as large as the storage range allows.
+fn queues(c: &mut Criterion) {
+    let (device, queue, sba, dsa, sa) = get_device().unwrap();
+    let a = generate_random_batch(256, 512, 1);
+    let b = generate_random_batch(256, 512, 2);
+
+    let mut group = c.benchmark_group("queues");
+    group.throughput(Throughput::Elements((a.len() * b.len()) as u64));
+    for (name, max_queues, max_storage_range) in [
+        // Small chunks, so that there are many of them to overlap.
+        ("single", 1, Some(1 << 22)),
+        ("all", usize::MAX, Some(1 << 22)),
+        ("single_full_range", 1, None),
+        ("all_full_range", usize::MAX, None),
+    ] {
+        let sa = match max_storage_range {
+            Some(bytes) => sa.clone().with_max_storage_range(bytes),
+            None => sa.clone(),
+        };
+        let options = RunOptions::default().with_max_queues(max_queues);
+        let ctx = GpuContext::new(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            dsa.clone(),
+            sa,
+            &options,
+            &a,
+            &b,
+        )
+        .unwrap();
+        group.bench_function(name, |bench| {
+            bench.iter(|| {
+                let params = DTWImpl {
+                    window: u64::MAX,
+                    itakura: 0.0,
+                    cutoff: Float::INFINITY,
+                };
+                ctx.distance_flat(params, Float::INFINITY).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Compares the diamonds synchronized with workgroup barriers and with subgroup barriers.
+/// Both run the workgroup path unless built with `--features subgroup-sync`, and on devices
+/// where `describe_device` reports no subgroup sync.
+fn tile_sync(c: &mut Criterion) {
+    let (device, queue, sba, dsa, sa) = get_device().unwrap();
+    let a = generate_random_batch(64, 1024, 1);
+    let b = generate_random_batch(64, 1024, 2);
+
+    let options = RunOptions::default();
+    let mut group = c.benchmark_group("tile_sync");
+    group.throughput(Throughput::Elements((a.len() * b.len()) as u64));
+    for (name, subgroup_sync) in [("workgroup", false), ("subgroup", true)] {
+        let sa = sa.clone().with_subgroup_sync(subgroup_sync);
+        let ctx = GpuContext::new(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            dsa.clone(),
+            sa,
+            &options,
+            &a,
+            &b,
+        )
+        .unwrap();
+        group.bench_function(name, |bench| {
+            bench.iter(|| {
+                let params = DTWImpl {
+                    window: u64::MAX,
+                    itakura: 0.0,
+                    cutoff: Float::INFINITY,
+                };
+                ctx.distance_flat(params, Float::INFINITY).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn upload(c: &mut Criterion) {
+    let (device, queue, sba, _, sa) = get_device().unwrap();
+    // Host side preparation dominates here, compare runs with and without `--features rayon`.
+    let batch = generate_random_batch(4096, 2048, 3);
+    let options = RunOptions::default();
+
+    let mut group = c.benchmark_group("upload");
+    group.throughput(Throughput::Elements((batch.len() * batch[0].len()) as u64));
+    group.bench_function("batch", |bench| {
+        bench.iter(|| {
+            let series = GpuSeries::upload(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                sa.clone(),
+                &options,
+                &batch,
+            )
+            .unwrap();
+            sa.clear();
+            series
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, distances, queues, tile_sync, upload);
+criterion_main!(benches);
@@ -6,11 +6,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
-    SpirvBuilder::new(".", "spirv-unknown-spv1.5")
+    let mut builder = SpirvBuilder::new(".", "spirv-unknown-spv1.5")
         .print_metadata(MetadataPrintout::Full)
         .spirv_metadata(SpirvMetadata::NameVariables)
         .capability(Capability::Int8)
-        .capability(Capability::Int64)
-        .build()?;
+        .capability(Capability::Int64);
+    let mut features = Vec::new();
+    if env::var_os("CARGO_FEATURE_F64").is_some() {
+        builder = builder.capability(Capability::Float64);
+        features.push("f64".to_string());
+    }
+    if env::var_os("CARGO_FEATURE_SUBGROUP_SYNC").is_some() {
+        // Subgroup scoped barriers, see `kernels::tile_barrier`.
+        builder = builder.capability(Capability::GroupNonUniform);
+        features.push("subgroup-sync".to_string());
+    }
+    builder.shader_crate_features(features).build()?;
     Ok(())
 }
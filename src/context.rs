@@ -0,0 +1,1366 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use vulkano::{
+    buffer::Subbuffer,
+    command_buffer::allocator::StandardCommandBufferAllocator,
+    descriptor_set::allocator::StandardDescriptorSetAllocator,
+    device::{Device, Queue},
+};
+
+use crate::Float;
+use crate::error::TsDistanceError;
+use crate::kernels::BoundaryCondition;
+use crate::kernels::LocalCost;
+use crate::kernels::MSM_C;
+use crate::kernels::StepPattern;
+use crate::kernels::adtw_distance::cpu::ADTWImpl;
+use crate::kernels::adtw_scaled_distance::cpu::ADTWScaledImpl;
+use crate::kernels::dtw_cosine_distance::cpu::DTWCosineImpl;
+use crate::kernels::dtw_cost_distance::cpu::DTWCostImpl;
+use crate::kernels::dtw_distance::cpu::DTWImpl;
+use crate::kernels::dtw_masked_distance::cpu::DTWMaskedImpl;
+use crate::kernels::dtw_p1_distance::cpu::DTWP1Impl;
+use crate::kernels::edr_distance::cpu::EDRImpl;
+use crate::kernels::erp_distance::cpu::ERPImpl;
+use crate::kernels::erp_masked_distance::cpu::ERPMaskedImpl;
+use crate::kernels::gak_distance::cpu::GAKImpl;
+use crate::kernels::kernel_trait::{GpuKernelImpl, WithBoundary};
+use crate::kernels::knn::cpu::knn_select_gpu;
+use crate::kernels::lcss_distance::cpu::LCSSImpl;
+use crate::kernels::msm_compensated_distance::cpu::MSMCompensatedImpl;
+use crate::kernels::msm_distance::cpu::MSMImpl;
+use crate::kernels::twe_compensated_distance::cpu::TWECompensatedImpl;
+use crate::kernels::twe_distance::cpu::TWEImpl;
+use crate::kernels::wdtw_distance::cpu::{KernelParams as WDTWParams, WDTWImpl};
+use crate::matrix::DistanceMatrix;
+use crate::utils::{
+    DeviceInfo, RunOptions, SubBuffersAllocator, check_batch, check_exact_counts, describe_device,
+    logistic_weights,
+};
+use crate::warps::{
+    DiamondPartitioning, DistanceIter, GpuSeries, MultiMetricBatch, PendingDistances,
+    build_kernel_params, diamond_partitioning_async, diamond_partitioning_device,
+    diamond_partitioning_ends, diamond_partitioning_flat, diamond_partitioning_for_each,
+    diamond_partitioning_iter, diamond_partitioning_prepared, diamond_partitioning_reusing,
+    diamond_partitioning_self, diamond_partitioning_with_progress, diamond_partitioning_zipped,
+    unflatten,
+};
+
+/// Distance measure selected at runtime, e.g. for `GpuContext::knn`.
+#[derive(Clone, Debug)]
+pub enum Metric {
+    Dtw {
+        window: Option<usize>,
+    },
+    Ddtw,
+    Wdtw {
+        weights: Vec<Float>,
+    },
+    Erp {
+        gap_penalty: Float,
+    },
+    Lcss {
+        epsilon: Float,
+        delta: Option<usize>,
+    },
+    Msm,
+    Twe {
+        nu: Float,
+        lambda: Float,
+    },
+    Adtw {
+        w: Float,
+    },
+}
+
+impl Metric {
+    /// The variant and the bits of its parameters, which `Eq` and `Hash` compare so that a
+    /// metric can key the results of `GpuContext::matrices`.
+    fn key(&self) -> (u8, Vec<u64>) {
+        let bits = |values: &[Float]| values.iter().map(|x| x.to_bits() as u64).collect();
+        match self {
+            Metric::Dtw { window } => (0, window.iter().map(|&w| w as u64).collect()),
+            Metric::Ddtw => (1, Vec::new()),
+            Metric::Wdtw { weights } => (2, bits(weights)),
+            Metric::Erp { gap_penalty } => (3, bits(&[*gap_penalty])),
+            Metric::Lcss { epsilon, delta } => {
+                let mut key: Vec<u64> = bits(&[*epsilon]);
+                key.extend(delta.map(|d| d as u64));
+                (4, key)
+            }
+            Metric::Msm => (5, Vec::new()),
+            Metric::Twe { nu, lambda } => (6, bits(&[*nu, *lambda])),
+            Metric::Adtw { w } => (7, bits(&[*w])),
+        }
+    }
+}
+
+impl PartialEq for Metric {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Metric {}
+
+impl Hash for Metric {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+fn dtw_params(window: Option<usize>, itakura: Option<Float>) -> DTWImpl {
+    DTWImpl {
+        window: window.map_or(u64::MAX, |w| w as u64),
+        itakura: itakura.unwrap_or(0.0),
+        cutoff: Float::INFINITY,
+    }
+}
+
+// Checks of the parameters shared with the `reference` implementations, so that both
+// reject the same input with the same error.
+
+/// WDTW and WMSM look up a weight for every offset `|i - j|` up to the `longest` series.
+pub(crate) fn check_weights(
+    name: &str,
+    longest: usize,
+    weights: &[Float],
+) -> Result<(), TsDistanceError> {
+    if weights.len() < longest {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "{} needs at least {} weights, got {}",
+            name,
+            longest,
+            weights.len()
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_gap(dim: usize, gap: &[Float]) -> Result<(), TsDistanceError> {
+    if gap.len() != dim {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "erp needs a gap value per channel ({}), got {}",
+            dim,
+            gap.len()
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_local_cost(cost: LocalCost) -> Result<(), TsDistanceError> {
+    if let LocalCost::Power(p) = cost {
+        if !(p.is_finite() && p > 0.0) {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "the local cost exponent must be positive, got {}",
+                p
+            )));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn check_itakura(max_slope: Float) -> Result<(), TsDistanceError> {
+    if max_slope.is_nan() || max_slope < 1.0 {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "the Itakura slope must be at least 1, got {}",
+            max_slope
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_gak_sigma(sigma: Float) -> Result<(), TsDistanceError> {
+    if sigma.is_nan() || sigma <= 0.0 {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "the GAK bandwidth must be positive, got {}",
+            sigma
+        )));
+    }
+    Ok(())
+}
+
+/// LCSS matching values at most `delta` indices apart, any distance apart with `None`.
+fn lcss_params(epsilon: Float, delta: Option<usize>) -> LCSSImpl {
+    LCSSImpl {
+        epsilon,
+        delta: delta.map_or(u64::MAX, |d| d as u64),
+    }
+}
+
+/// MSM with the given split and merge costs, scaled by `weights[|i - j|]` if any.
+fn msm_params(split_cost: Float, merge_cost: Float, weights: Option<&[Float]>) -> MSMImpl {
+    MSMImpl {
+        split_cost,
+        merge_cost,
+        weighted: weights.is_some() as u64,
+        weights: weights.map_or_else(|| vec![1.0], <[Float]>::to_vec),
+    }
+}
+
+/// Exact counts, as `check_exact_counts` makes sure of.
+fn to_counts(matrix: Vec<Vec<Float>>) -> Vec<Vec<u32>> {
+    matrix
+        .into_iter()
+        .map(|row| row.into_iter().map(|count| count as u32).collect())
+        .collect()
+}
+
+/// Two batches of series uploaded once and kept on the device, so that several distances
+/// can be computed over the same data without paying the host upload again.
+pub struct GpuContext {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    sba: Arc<StandardCommandBufferAllocator>,
+    dsa: Arc<StandardDescriptorSetAllocator>,
+    sa: SubBuffersAllocator,
+    options: RunOptions,
+    a: GpuSeries,
+    b: GpuSeries,
+    shared: bool,
+    /// The WDTW weights of the last `wdtw` call and their device buffer.
+    wdtw_weights: Mutex<Option<(Vec<Float>, WDTWParams)>>,
+}
+
+impl GpuContext {
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Self, TsDistanceError> {
+        Self::new_multivariate(device, queue, sba, dsa, sa, options, a, b, 1)
+    }
+
+    /// A context over multivariate series, each stored as `dim` interleaved channels per
+    /// timestep. Only `dtw` and `erp` support more than one channel.
+    pub fn new_multivariate(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        dim: usize,
+    ) -> Result<Self, TsDistanceError> {
+        check_batch("a", a)?;
+        check_batch("b", b)?;
+        let sa = sa.start_run(0);
+        // `&data, &data` is a self-distance, uploaded once as in `new_self`.
+        let shared = std::ptr::eq(a, b);
+        let a_series = GpuSeries::upload_multivariate(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sa.clone(),
+            options,
+            a,
+            dim,
+        )?;
+        let b_series = if shared {
+            a_series.clone()
+        } else {
+            GpuSeries::upload_multivariate(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                sa.clone(),
+                options,
+                b,
+                dim,
+            )?
+        };
+        Ok(Self {
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            options: options.clone(),
+            a: a_series,
+            b: b_series,
+            shared,
+            wdtw_weights: Mutex::new(None),
+        })
+    }
+
+    /// A context over series already resident on the device, such as those of
+    /// `GpuSeries::upload_flat`.
+    pub fn from_series(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: GpuSeries,
+        b: GpuSeries,
+    ) -> Self {
+        Self {
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            options: options.clone(),
+            a,
+            b,
+            shared: false,
+            wdtw_weights: Mutex::new(None),
+        }
+    }
+
+    /// A context comparing a batch against itself, uploaded only once.
+    pub fn new_self(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        data: &Vec<Vec<Float>>,
+    ) -> Result<Self, TsDistanceError> {
+        check_batch("data", data)?;
+        let sa = sa.start_run(0);
+        let a = GpuSeries::upload(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sa.clone(),
+            options,
+            data,
+        )?;
+        Ok(Self {
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            options: options.clone(),
+            b: a.clone(),
+            a,
+            shared: true,
+            wdtw_weights: Mutex::new(None),
+        })
+    }
+
+    /// Z-normalizes the resident series in place, so that every following distance is
+    /// computed on normalized data.
+    pub fn znormalize(&self) -> Result<(), TsDistanceError> {
+        self.a.znormalize(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.options.timeout(),
+        )?;
+        // A self-distance context shares the same buffers between a and b.
+        if !self.shared {
+            self.b.znormalize(
+                self.device.clone(),
+                self.queue.clone(),
+                self.sba.clone(),
+                self.dsa.clone(),
+                self.options.timeout(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Device memory allocated by the last computation, the resident series included.
+    pub fn last_run_memory_bytes(&self) -> u64 {
+        self.sa.last_run_memory_bytes()
+    }
+
+    pub fn a(&self) -> &GpuSeries {
+        &self.a
+    }
+
+    pub fn b(&self) -> &GpuSeries {
+        &self.b
+    }
+
+    /// Runs any diamond partitioning kernel over the resident series, returning the
+    /// distances row-major in a single buffer along with the number of rows and columns.
+    pub fn distance_flat<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+    ) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+        diamond_partitioning_flat(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            &self.b,
+            init_val,
+        )
+    }
+
+    /// Uploads the vector parameters of `params`, such as the WDTW weights, once for several
+    /// `distance_prepared` calls.
+    pub fn prepare<G: GpuKernelImpl>(
+        &self,
+        params: &G,
+    ) -> Result<G::KernelParams, TsDistanceError> {
+        build_kernel_params(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+        )
+    }
+
+    /// Same as `distance_flat`, with the vector parameters prepared by `prepare`.
+    pub fn distance_prepared<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        kernel_params: &G::KernelParams,
+        init_val: Float,
+    ) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+        diamond_partitioning_prepared(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            kernel_params,
+            &self.a,
+            &self.b,
+            init_val,
+        )
+    }
+
+    /// Same as `distance_flat`, handing each distance to `each(i, j, distance)` as its chunk
+    /// is read back instead of collecting them, see `diamond_partitioning_for_each`.
+    pub fn distance_for_each<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+        each: impl FnMut(usize, usize, Float),
+    ) -> Result<(), TsDistanceError> {
+        diamond_partitioning_for_each(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            &self.b,
+            init_val,
+            each,
+        )
+    }
+
+    /// Same as `distance_for_each`, as an iterator that computes each chunk on demand.
+    pub fn distance_iter<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+    ) -> Result<DistanceIter<G>, TsDistanceError> {
+        diamond_partitioning_iter(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            self.a.clone(),
+            self.b.clone(),
+            init_val,
+        )
+    }
+
+    /// Runs any diamond partitioning kernel over every pair of `a` and `b`. The series of `a`
+    /// always index the rows of each cost matrix and those of `b` the columns, they are never
+    /// swapped to shorten the padded axis, so asymmetric kernels see them in the given roles.
+    pub fn distance<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (flat, _, cols) = self.distance_flat(params, init_val)?;
+        Ok(unflatten(flat, cols))
+    }
+
+    /// Same as `distance`, reporting the fraction of pairs done to `progress` after every
+    /// chunk.
+    pub fn distance_with_progress<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+        progress: &dyn Fn(f32),
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (flat, _, cols) = diamond_partitioning_with_progress(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            &self.b,
+            init_val,
+            progress,
+        )?;
+        Ok(unflatten(flat, cols))
+    }
+
+    /// Same as `distance_flat`, on the buffers of a previous call when they fit, see
+    /// `diamond_partitioning_reusing`.
+    pub fn distance_reusing<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+        buffers: Option<DiamondPartitioning<G>>,
+    ) -> Result<((Vec<Float>, usize, usize), DiamondPartitioning<G>), TsDistanceError> {
+        diamond_partitioning_reusing(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            &self.b,
+            init_val,
+            buffers,
+        )
+    }
+
+    /// Same as `distance`, returning as soon as the work is submitted. Several batches can be
+    /// enqueued before waiting on any of them.
+    pub fn distance_async<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+    ) -> Result<PendingDistances, TsDistanceError> {
+        diamond_partitioning_async(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            &self.b,
+            init_val,
+        )
+    }
+
+    /// Runs any diamond partitioning kernel over the resident series, leaving the
+    /// `rows x cols` row-major distance matrix on the device for other kernels to consume.
+    pub fn distance_device<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+    ) -> Result<(Subbuffer<[Float]>, usize, usize), TsDistanceError> {
+        let matrix = diamond_partitioning_device(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            &self.b,
+            init_val,
+            1.0,
+            0.0,
+            false,
+        )?;
+        Ok((matrix, self.a.count(), self.b.count()))
+    }
+
+    /// Distances of a single new series against every resident `b` series, as a row of
+    /// `distance`. Only `query` is uploaded, the resident series are used as they are.
+    pub fn query_distance<G: GpuKernelImpl>(
+        &self,
+        query: &[Float],
+        params: G,
+        init_val: Float,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        let query = GpuSeries::upload_multivariate(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.sa.clone(),
+            &self.options,
+            &vec![query.to_vec()],
+            self.b.dim(),
+        )?;
+        let (distances, _, _) = diamond_partitioning_flat(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &query,
+            &self.b,
+            init_val,
+        )?;
+        Ok(distances)
+    }
+
+    /// DTW of `query` against every resident `b` series, the inference-time counterpart of
+    /// `dtw`.
+    pub fn query_dtw(
+        &self,
+        query: &[Float],
+        window: Option<usize>,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        self.query_distance(query, dtw_params(window, None), Float::INFINITY)
+    }
+
+    /// The device the series live on, see `describe_device`.
+    pub fn device_info(&self) -> DeviceInfo {
+        describe_device(&self.device)
+    }
+
+    pub fn is_hardware_accelerated(&self) -> bool {
+        self.device_info().is_hardware_accelerated()
+    }
+
+    /// A batch recording several kernels over the resident series into one submission,
+    /// see `MultiMetricBatch`.
+    pub fn multi_metric(&self) -> Result<MultiMetricBatch<'_>, TsDistanceError> {
+        MultiMetricBatch::new(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            &self.a,
+            &self.b,
+        )
+    }
+
+    /// Distance matrices of every metric in `metrics` over the resident series, recorded
+    /// into a single submission with `multi_metric`. `Metric::Ddtw` expects the context to
+    /// hold derivatives already, as built by `cpu::compute_matrices`.
+    pub fn matrices(
+        &self,
+        metrics: &[Metric],
+    ) -> Result<HashMap<Metric, DistanceMatrix>, TsDistanceError> {
+        let mut batch = self.multi_metric()?;
+        let mut recorded: Vec<Metric> = Vec::new();
+        for metric in metrics {
+            if recorded.contains(metric) {
+                continue;
+            }
+            if !matches!(metric, Metric::Dtw { .. } | Metric::Erp { .. }) {
+                self.univariate("matrices with this metric")?;
+            }
+            match metric {
+                Metric::Dtw { window } => batch.add(dtw_params(*window, None), Float::INFINITY)?,
+                Metric::Ddtw => batch.add(dtw_params(None, None), Float::INFINITY)?,
+                Metric::Wdtw { weights } => {
+                    self.check_weights("wdtw", weights)?;
+                    batch.add(
+                        WDTWImpl {
+                            weights: weights.clone(),
+                        },
+                        Float::INFINITY,
+                    )?
+                }
+                Metric::Erp { gap_penalty } => batch.add(
+                    ERPImpl {
+                        gap: vec![*gap_penalty; self.a.dim()],
+                    },
+                    Float::INFINITY,
+                )?,
+                Metric::Lcss { epsilon, delta } => batch.add(lcss_params(*epsilon, *delta), 0.0)?,
+                Metric::Msm => batch.add(msm_params(MSM_C, MSM_C, None), Float::INFINITY)?,
+                Metric::Twe { nu, lambda } => batch.add(
+                    TWEImpl {
+                        nu: *nu,
+                        lambda: *lambda,
+                    },
+                    Float::INFINITY,
+                )?,
+                Metric::Adtw { w } => batch.add(ADTWImpl { w: *w }, Float::INFINITY)?,
+            };
+            recorded.push(metric.clone());
+        }
+
+        let results = batch.submit_flat()?;
+        Ok(recorded
+            .into_iter()
+            .zip(results)
+            .map(|(metric, (mut data, rows, cols))| {
+                if let Metric::Lcss { .. } = metric {
+                    // Same rescaling of the similarity as `lcss`.
+                    for (i, &a_len) in self.a.lengths().iter().enumerate() {
+                        for (j, &b_len) in self.b.lengths().iter().enumerate() {
+                            let s = data[i * cols + j];
+                            data[i * cols + j] = 1.0 - s / min(a_len, b_len) as Float;
+                        }
+                    }
+                }
+                (metric, DistanceMatrix { rows, cols, data })
+            })
+            .collect())
+    }
+
+    /// Runs any diamond partitioning kernel over the pairs `(a[k], b[k])` only, for batches
+    /// holding as many series, see `diamond_partitioning_zipped`.
+    pub fn distance_zipped<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        diamond_partitioning_zipped(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            &self.b,
+            init_val,
+        )
+    }
+
+    /// Runs any diamond partitioning kernel over the pairs `i < j` of the `a` series only,
+    /// packed row-major as returned by `diamond_partitioning_self`.
+    pub fn distance_self<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        diamond_partitioning_self(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            init_val,
+        )
+    }
+
+    fn univariate(&self, metric: &str) -> Result<(), TsDistanceError> {
+        if self.a.dim() != 1 {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "{} only supports univariate series",
+                metric
+            )));
+        }
+        Ok(())
+    }
+
+    /// Length of the longest series of either batch.
+    fn longest(&self) -> usize {
+        let longest = self.a.lengths().iter().chain(self.b.lengths()).max();
+        longest.map_or(0, |&len| len as usize)
+    }
+
+    /// Warns when the counts of `metric` may be rounded, which only the integer counts
+    /// refuse, see `check_exact_counts`.
+    fn warn_inexact_counts(&self, metric: &str) {
+        if let Err(err) = check_exact_counts(metric, self.longest()) {
+            log::warn!("{}, its results may be rounded", err);
+        }
+    }
+
+    fn check_weights(&self, name: &str, weights: &[Float]) -> Result<(), TsDistanceError> {
+        let longest = self.a.lengths().iter().chain(self.b.lengths()).max();
+        check_weights(name, longest.map_or(0, |&len| len as usize), weights)
+    }
+
+    /// ERP with `gap_penalty` as the reference point of every channel.
+    pub fn erp(&self, gap_penalty: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.erp_gap(&vec![gap_penalty; self.a.dim()])
+    }
+
+    /// ERP with a reference point per channel, summing the costs over the channels.
+    pub fn erp_gap(&self, gap: &[Float]) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        check_gap(self.a.dim(), gap)?;
+        self.distance(ERPImpl { gap: gap.to_vec() }, Float::INFINITY)
+    }
+
+    /// ERP skipping the local cost of missing values, the NaNs kept by `NanPolicy::Mask`.
+    pub fn erp_masked(&self, gap_penalty: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.distance(
+            ERPMaskedImpl {
+                gap: vec![gap_penalty; self.a.dim()],
+            },
+            Float::INFINITY,
+        )
+    }
+
+    /// LCSS distance `1 - s / min(n, m)`, normalized by the lengths of each pair.
+    pub fn lcss(
+        &self,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let similarity = self.lcss_length(epsilon, delta)?;
+        Ok(similarity
+            .iter()
+            .zip(self.a.lengths())
+            .map(|(row, &a_len)| {
+                row.iter()
+                    .zip(self.b.lengths())
+                    .map(|(&s, &b_len)| 1.0 - s / min(a_len, b_len) as Float)
+                    .collect::<Vec<Float>>()
+            })
+            .collect::<Vec<Vec<Float>>>())
+    }
+
+    /// Length `s` of the longest common subsequence under `epsilon`, before the
+    /// normalization of `lcss`. Values only match when their indices are at most `delta`
+    /// apart, any distance apart with `None`. Past `MAX_EXACT_COUNT`, the lengths may be
+    /// rounded, which is only warned about.
+    pub fn lcss_length(
+        &self,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("lcss")?;
+        self.warn_inexact_counts("lcss");
+        self.distance(lcss_params(epsilon, delta), 0.0)
+    }
+
+    /// `lcss_length` as integers, refusing series longer than `MAX_EXACT_COUNT`.
+    pub fn lcss_count(
+        &self,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<u32>>, TsDistanceError> {
+        check_exact_counts("lcss", self.longest())?;
+        Ok(to_counts(self.lcss_length(epsilon, delta)?))
+    }
+
+    /// Edit Distance on Real sequences, the number of edits turning `a` into `b` where two
+    /// values within `epsilon` match. The count is left unnormalized, and may be rounded past
+    /// `MAX_EXACT_COUNT`, which is only warned about.
+    pub fn edr(&self, epsilon: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("edr")?;
+        self.warn_inexact_counts("edr");
+        self.distance(EDRImpl { epsilon }, Float::INFINITY)
+    }
+
+    /// `edr` as integers, refusing series longer than `MAX_EXACT_COUNT`.
+    pub fn edr_count(&self, epsilon: Float) -> Result<Vec<Vec<u32>>, TsDistanceError> {
+        check_exact_counts("edr", self.longest())?;
+        Ok(to_counts(self.edr(epsilon)?))
+    }
+
+    pub fn dtw(&self, window: Option<usize>) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (flat, _, cols) = self.dtw_flat(window)?;
+        Ok(unflatten(flat, cols))
+    }
+
+    /// DTW skipping the local cost of missing values, the NaNs kept by `NanPolicy::Mask`.
+    pub fn dtw_masked(&self, window: Option<usize>) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.distance(
+            DTWMaskedImpl {
+                window: window.map_or(u64::MAX, |w| w as u64),
+            },
+            Float::INFINITY,
+        )
+    }
+
+    pub fn dtw_for_each(
+        &self,
+        window: Option<usize>,
+        each: impl FnMut(usize, usize, Float),
+    ) -> Result<(), TsDistanceError> {
+        self.distance_for_each(dtw_params(window, None), Float::INFINITY, each)
+    }
+
+    pub fn dtw_iter(
+        &self,
+        window: Option<usize>,
+    ) -> Result<DistanceIter<DTWImpl>, TsDistanceError> {
+        self.distance_iter(dtw_params(window, None), Float::INFINITY)
+    }
+
+    pub fn dtw_flat(
+        &self,
+        window: Option<usize>,
+    ) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+        self.distance_flat(dtw_params(window, None), Float::INFINITY)
+    }
+
+    /// Same as `dtw_flat`, with the distances left on the device, see `distance_device`.
+    pub fn dtw_device(
+        &self,
+        window: Option<usize>,
+    ) -> Result<(Subbuffer<[Float]>, usize, usize), TsDistanceError> {
+        self.distance_device(dtw_params(window, None), Float::INFINITY)
+    }
+
+    /// DTW divided by `a.len() + b.len()`, an upper bound on the length of the warping path,
+    /// so that pairs of different lengths can be compared. This is the usual approximation
+    /// of the division by the path length itself, see `cpu::dtw_normalized_exact`.
+    pub fn dtw_normalized(
+        &self,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let distances = self.dtw(window)?;
+        Ok(distances
+            .iter()
+            .zip(self.a.lengths())
+            .map(|(row, &a_len)| {
+                row.iter()
+                    .zip(self.b.lengths())
+                    .map(|(&d, &b_len)| d / (a_len + b_len) as Float)
+                    .collect::<Vec<Float>>()
+            })
+            .collect::<Vec<Vec<Float>>>())
+    }
+
+    /// DTW with `cost` as the local cost of every cell in place of the squared difference.
+    pub fn dtw_with_cost(
+        &self,
+        window: Option<usize>,
+        cost: LocalCost,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("dtw with a local cost")?;
+        check_local_cost(cost)?;
+        let (cost, power) = cost.code();
+        self.distance(
+            DTWCostImpl {
+                window: window.map_or(u64::MAX, |w| w as u64),
+                cost,
+                power,
+            },
+            Float::INFINITY,
+        )
+    }
+
+    /// DTW whose local cost is the cosine distance between the channels of two timesteps,
+    /// for multivariate series whose direction matters more than their magnitude.
+    pub fn dtw_cosine(&self, window: Option<usize>) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.distance(
+            DTWCosineImpl {
+                window: window.map_or(u64::MAX, |w| w as u64),
+            },
+            Float::INFINITY,
+        )
+    }
+
+    /// DTW whose warping path follows `pattern`. `SymmetricP1` keeps a second value for every
+    /// cell, so it needs twice the device memory of `dtw`.
+    pub fn dtw_step(
+        &self,
+        window: Option<usize>,
+        pattern: StepPattern,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        match pattern {
+            StepPattern::SymmetricP0 => self.dtw(window),
+            StepPattern::SymmetricP1 => {
+                self.univariate("dtw")?;
+                self.distance(
+                    DTWP1Impl {
+                        window: window.map_or(u64::MAX, |w| w as u64),
+                    },
+                    Float::INFINITY,
+                )
+            }
+        }
+    }
+
+    /// DTW restricted to the Itakura parallelogram, where the warping path may deviate from
+    /// the main diagonal by at most `max_slope` (at least 1) and its inverse.
+    pub fn dtw_itakura(&self, max_slope: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        check_itakura(max_slope)?;
+        self.distance(dtw_params(None, Some(max_slope)), Float::INFINITY)
+    }
+
+    /// DTW of each `a` series against the subsequence of each `b` series it matches best,
+    /// the alignment being free to start and end anywhere along `b`.
+    pub fn subsequence_dtw(&self) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.distance(
+            WithBoundary {
+                kernel: dtw_params(None, None),
+                boundary: BoundaryCondition::OpenBoth,
+            },
+            Float::INFINITY,
+        )
+    }
+
+    /// DTW of each `a` series against the prefix of each `b` series it matches best, with the
+    /// index in `b` where that prefix ends. The alignment starts at the beginning of both
+    /// series but may leave `b` anywhere along its last row.
+    pub fn dtw_partial(
+        &self,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<(Float, usize)>>, TsDistanceError> {
+        let (distances, ends, _, cols) = diamond_partitioning_ends(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            WithBoundary {
+                kernel: dtw_params(window, None),
+                boundary: BoundaryCondition::OpenEnd,
+            },
+            &self.a,
+            &self.b,
+            Float::INFINITY,
+        )?;
+        let mut pairs = distances.into_iter().zip(ends);
+        Ok(self
+            .a
+            .lengths()
+            .iter()
+            .map(|_| pairs.by_ref().take(cols).collect())
+            .collect())
+    }
+
+    pub fn dtw_with_progress(
+        &self,
+        window: Option<usize>,
+        progress: &dyn Fn(f32),
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.distance_with_progress(dtw_params(window, None), Float::INFINITY, progress)
+    }
+
+    pub fn dtw_async(&self, window: Option<usize>) -> Result<PendingDistances, TsDistanceError> {
+        self.distance_async(dtw_params(window, None), Float::INFINITY)
+    }
+
+    pub fn dtw_self(&self, window: Option<usize>) -> Result<Vec<Float>, TsDistanceError> {
+        self.distance_self(dtw_params(window, None), Float::INFINITY)
+    }
+
+    pub fn dtw_zipped(&self, window: Option<usize>) -> Result<Vec<Float>, TsDistanceError> {
+        self.distance_zipped(dtw_params(window, None), Float::INFINITY)
+    }
+
+    pub fn wdtw(&self, weights: &[Float]) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("wdtw")?;
+        self.check_weights("wdtw", weights)?;
+        let params = WDTWImpl {
+            weights: weights.to_vec(),
+        };
+        // The weights stay on the device for as long as the same ones are asked for.
+        let kernel_params = {
+            let mut cached = self.wdtw_weights.lock().unwrap();
+            match &*cached {
+                Some((cached_weights, kernel_params)) if cached_weights.as_slice() == weights => {
+                    kernel_params.clone()
+                }
+                _ => {
+                    let kernel_params = self.prepare(&params)?;
+                    *cached = Some((weights.to_vec(), kernel_params.clone()));
+                    kernel_params
+                }
+            }
+        };
+        let (flat, _, cols) = self.distance_prepared(params, &kernel_params, Float::INFINITY)?;
+        Ok(unflatten(flat, cols))
+    }
+
+    /// WDTW with the modified logistic weights of `logistic_weights`, built over the length
+    /// of the longest series.
+    pub fn wdtw_logistic(&self, g: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let longest = self.a.lengths().iter().chain(self.b.lengths()).max();
+        self.wdtw(&logistic_weights(longest.map_or(0, |&len| len as usize), g))
+    }
+
+    pub fn msm(&self) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.msm_c(MSM_C)
+    }
+
+    /// MSM with `c` as the cost of a split or merge.
+    pub fn msm_c(&self, c: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("msm")?;
+        self.distance(msm_params(c, c, None), Float::INFINITY)
+    }
+
+    /// `msm_c` accumulated with `kernels::compensated_add`.
+    pub fn msm_compensated(&self, c: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("msm_compensated")?;
+        self.distance(MSMCompensatedImpl { c }, Float::INFINITY)
+    }
+
+    /// MSM with the split and merge costs of each cell scaled by `weights[|i - j|]`, as WDTW
+    /// scales the squared differences. All weights equal to 1 give `msm_c`.
+    pub fn wmsm(&self, c: Float, weights: &[Float]) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("wmsm")?;
+        self.check_weights("wmsm", weights)?;
+        self.distance(msm_params(c, c, Some(weights)), Float::INFINITY)
+    }
+
+    /// MSM with separate costs for splitting a value of `a` into several of `b` and for
+    /// merging several values of `a` into one of `b`.
+    pub fn msm_gen(
+        &self,
+        split_cost: Float,
+        merge_cost: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("msm")?;
+        self.distance(msm_params(split_cost, merge_cost, None), Float::INFINITY)
+    }
+
+    pub fn twe(&self, nu: Float, lambda: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("twe")?;
+        self.distance(TWEImpl { nu, lambda }, Float::INFINITY)
+    }
+
+    /// `twe` accumulated with `kernels::compensated_add`.
+    pub fn twe_compensated(
+        &self,
+        nu: Float,
+        lambda: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("twe_compensated")?;
+        self.distance(TWECompensatedImpl { nu, lambda }, Float::INFINITY)
+    }
+
+    pub fn adtw(&self, w: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("adtw")?;
+        self.distance(ADTWImpl { w }, Float::INFINITY)
+    }
+
+    /// ADTW whose penalty for a warping step into cell `(i, j)` is `w * |i - j|` instead of a
+    /// flat `w`: warping near the main diagonal is almost free, while drifting far from it
+    /// costs increasingly more.
+    pub fn adtw_scaled(&self, w: Float) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("adtw_scaled")?;
+        self.distance(ADTWScaledImpl { w }, Float::INFINITY)
+    }
+
+    /// Logarithm of the Global Alignment Kernel, a similarity summing the Gaussian local
+    /// kernel of bandwidth `sigma` over every alignment. A `triangular` order above 0 only
+    /// lets `a[i]` meet `b[j]` when `|i - j| < triangular`.
+    pub fn gak(&self, sigma: Float, triangular: usize) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        self.univariate("gak")?;
+        check_gak_sigma(sigma)?;
+        self.distance(
+            GAKImpl {
+                sigma,
+                triangular: triangular as u64,
+            },
+            Float::NEG_INFINITY,
+        )
+    }
+
+    /// Predicts a label for each `a` series by majority vote among its `k` nearest `b`
+    /// series, labelled by `b_labels`. Distances and the top-k selection both stay on the
+    /// device, only the predictions are read back. `Metric::Ddtw` expects the context to
+    /// hold derivatives already, as built by `cpu::knn`.
+    pub fn knn(
+        &self,
+        b_labels: &[usize],
+        k: usize,
+        metric: &Metric,
+    ) -> Result<Vec<usize>, TsDistanceError> {
+        if !matches!(metric, Metric::Dtw { .. }) {
+            self.univariate("knn with this metric")?;
+        }
+        match metric {
+            Metric::Dtw { window } => self.knn_with(
+                dtw_params(*window, None),
+                Float::INFINITY,
+                1.0,
+                0.0,
+                false,
+                b_labels,
+                k,
+            ),
+            Metric::Ddtw => self.knn_with(
+                dtw_params(None, None),
+                Float::INFINITY,
+                1.0,
+                0.0,
+                false,
+                b_labels,
+                k,
+            ),
+            Metric::Wdtw { weights } => {
+                self.check_weights("wdtw", weights)?;
+                self.knn_with(
+                    WDTWImpl {
+                        weights: weights.clone(),
+                    },
+                    Float::INFINITY,
+                    1.0,
+                    0.0,
+                    false,
+                    b_labels,
+                    k,
+                )
+            }
+            Metric::Erp { gap_penalty } => self.knn_with(
+                ERPImpl {
+                    gap: vec![*gap_penalty; self.a.dim()],
+                },
+                Float::INFINITY,
+                1.0,
+                0.0,
+                false,
+                b_labels,
+                k,
+            ),
+            Metric::Lcss { epsilon, delta } => {
+                // Same rescaling of the similarity as `lcss`.
+                self.knn_with(
+                    lcss_params(*epsilon, *delta),
+                    0.0,
+                    -1.0,
+                    1.0,
+                    true,
+                    b_labels,
+                    k,
+                )
+            }
+            Metric::Msm => self.knn_with(
+                msm_params(MSM_C, MSM_C, None),
+                Float::INFINITY,
+                1.0,
+                0.0,
+                false,
+                b_labels,
+                k,
+            ),
+            Metric::Twe { nu, lambda } => self.knn_with(
+                TWEImpl {
+                    nu: *nu,
+                    lambda: *lambda,
+                },
+                Float::INFINITY,
+                1.0,
+                0.0,
+                false,
+                b_labels,
+                k,
+            ),
+            Metric::Adtw { w } => self.knn_with(
+                ADTWImpl { w: *w },
+                Float::INFINITY,
+                1.0,
+                0.0,
+                false,
+                b_labels,
+                k,
+            ),
+        }
+    }
+
+    /// Distance of `a[k]` to `b[k]` for every `k`, the diagonal of the matrix `metric` would
+    /// give, without computing the other pairs. Both batches must hold as many series.
+    /// `Metric::Ddtw` expects the context to hold derivatives already, as built by
+    /// `cpu::pairwise`.
+    pub fn pairwise(&self, metric: &Metric) -> Result<Vec<Float>, TsDistanceError> {
+        if !matches!(metric, Metric::Dtw { .. } | Metric::Erp { .. }) {
+            self.univariate("pairwise with this metric")?;
+        }
+        match metric {
+            Metric::Dtw { window } => self.dtw_zipped(*window),
+            Metric::Ddtw => self.distance_zipped(dtw_params(None, None), Float::INFINITY),
+            Metric::Wdtw { weights } => {
+                self.check_weights("wdtw", weights)?;
+                self.distance_zipped(
+                    WDTWImpl {
+                        weights: weights.clone(),
+                    },
+                    Float::INFINITY,
+                )
+            }
+            Metric::Erp { gap_penalty } => self.distance_zipped(
+                ERPImpl {
+                    gap: vec![*gap_penalty; self.a.dim()],
+                },
+                Float::INFINITY,
+            ),
+            Metric::Lcss { epsilon, delta } => {
+                // Same rescaling of the similarity as `lcss`.
+                let similarity = self.distance_zipped(lcss_params(*epsilon, *delta), 0.0)?;
+                Ok(similarity
+                    .iter()
+                    .zip(self.a.lengths().iter().zip(self.b.lengths()))
+                    .map(|(&s, (&a_len, &b_len))| 1.0 - s / min(a_len, b_len) as Float)
+                    .collect())
+            }
+            Metric::Msm => self.distance_zipped(msm_params(MSM_C, MSM_C, None), Float::INFINITY),
+            Metric::Twe { nu, lambda } => self.distance_zipped(
+                TWEImpl {
+                    nu: *nu,
+                    lambda: *lambda,
+                },
+                Float::INFINITY,
+            ),
+            Metric::Adtw { w } => self.distance_zipped(ADTWImpl { w: *w }, Float::INFINITY),
+        }
+    }
+
+    fn knn_with<G: GpuKernelImpl>(
+        &self,
+        params: G,
+        init_val: Float,
+        scale: Float,
+        offset: Float,
+        per_pair_min_len: bool,
+        b_labels: &[usize],
+        k: usize,
+    ) -> Result<Vec<usize>, TsDistanceError> {
+        let distances = diamond_partitioning_device(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            params,
+            &self.a,
+            &self.b,
+            init_val,
+            scale,
+            offset,
+            per_pair_min_len,
+        )?;
+        let labels = b_labels.iter().map(|&l| l as u32).collect::<Vec<_>>();
+        let predictions = knn_select_gpu(
+            self.device.clone(),
+            self.queue.clone(),
+            self.sba.clone(),
+            self.dsa.clone(),
+            self.sa.clone(),
+            &self.options,
+            distances,
+            self.a.count(),
+            self.b.count(),
+            &labels,
+            k,
+        )?;
+        Ok(predictions.into_iter().map(|l| l as usize).collect())
+    }
+}
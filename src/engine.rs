@@ -0,0 +1,927 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use vulkano::buffer::Subbuffer;
+
+use crate::Float;
+use crate::context::{GpuContext, Metric};
+use crate::cpu;
+use crate::error::TsDistanceError;
+use crate::kernels::dtw_distance::cpu::DTWImpl;
+use crate::kernels::dtw_stream::cpu::StreamingDtw;
+use crate::kernels::{LocalCost, StepPattern};
+use crate::matrix::DistanceMatrix;
+use crate::utils::{
+    CancellationToken, DeviceHandles, DeviceInfo, DevicePreference, DispatchTrace, NanPolicy,
+    RunOptions, compute_envelopes, describe_device, get_device, get_device_with,
+};
+use crate::warps::{DistanceIter, PendingDistances};
+
+/// The device handles captured once, with one method per distance. Each method is the
+/// `cpu` function of the same name without the five leading device arguments.
+#[derive(Clone)]
+pub struct DistanceEngine {
+    handles: DeviceHandles,
+    options: RunOptions,
+}
+
+impl DistanceEngine {
+    /// An engine on the default device of `get_device`.
+    pub fn new() -> Result<Self, TsDistanceError> {
+        Ok(Self::from_handles(get_device()?))
+    }
+
+    /// An engine on the device matching `preference`, see `get_device_with`.
+    pub fn with_device(preference: DevicePreference) -> Result<Self, TsDistanceError> {
+        Ok(Self::from_handles(get_device_with(preference)?))
+    }
+
+    pub fn from_handles(handles: DeviceHandles) -> Self {
+        Self {
+            handles,
+            options: RunOptions::default(),
+        }
+    }
+
+    /// Caps the size of a single storage buffer, see
+    /// `SubBuffersAllocator::with_max_storage_range`.
+    pub fn with_max_storage_range(mut self, bytes: usize) -> Self {
+        self.handles.4 = self.handles.4.with_max_storage_range(bytes);
+        self
+    }
+
+    /// Overrides the diamond tile size, see `RunOptions::with_tile_size`.
+    pub fn with_tile_size(mut self, tile_size: usize) -> Self {
+        self.options = self.options.with_tile_size(tile_size);
+        self
+    }
+
+    /// Gives up on the device after `timeout`, see `RunOptions::with_timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.options = self.options.with_timeout(timeout);
+        self
+    }
+
+    /// Stops batches once `token` is cancelled, see `RunOptions::with_cancellation`.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.options = self.options.with_cancellation(token);
+        self
+    }
+
+    /// Bounds the device memory of every call, see `SubBuffersAllocator::with_memory_budget`.
+    pub fn with_memory_budget(mut self, bytes: u64) -> Self {
+        self.handles.4 = self.handles.4.with_memory_budget(bytes);
+        self
+    }
+
+    /// Device memory allocated by the last call, see
+    /// `SubBuffersAllocator::last_run_memory_bytes`.
+    pub fn last_run_memory_bytes(&self) -> u64 {
+        self.handles.4.last_run_memory_bytes()
+    }
+
+    /// Square roots the DTW family distances, see `RunOptions::with_sqrt_output`.
+    pub fn with_sqrt_output(mut self, sqrt_output: bool) -> Self {
+        self.options = self.options.with_sqrt_output(sqrt_output);
+        self
+    }
+
+    /// Caps the compute queues chunks are spread over, see `RunOptions::with_max_queues`.
+    pub fn with_max_queues(mut self, max_queues: usize) -> Self {
+        self.options = self.options.with_max_queues(max_queues);
+        self
+    }
+
+    /// Records every diamond dispatch into `trace`, see
+    /// `SubBuffersAllocator::with_dispatch_trace`.
+    pub fn with_dispatch_trace(mut self, trace: DispatchTrace) -> Self {
+        self.handles.4 = self.handles.4.with_dispatch_trace(trace);
+        self
+    }
+
+    /// Allows subgroup barriers inside the diamonds, see
+    /// `SubBuffersAllocator::with_subgroup_sync`.
+    pub fn with_subgroup_sync(mut self, enabled: bool) -> Self {
+        self.handles.4 = self.handles.4.with_subgroup_sync(enabled);
+        self
+    }
+
+    /// Ignores the subgroup size the device reports, see
+    /// `RunOptions::with_subgroup_size_query`.
+    pub fn with_subgroup_size_query(mut self, enabled: bool) -> Self {
+        self.options = self.options.with_subgroup_size_query(enabled);
+        self
+    }
+
+    /// Caps the push constants size, see `SubBuffersAllocator::with_max_push_constants_size`.
+    pub fn with_max_push_constants_size(mut self, bytes: usize) -> Self {
+        self.handles.4 = self.handles.4.with_max_push_constants_size(bytes);
+        self
+    }
+
+    /// Sets how non-finite input values are treated, see `NanPolicy`.
+    pub fn with_nan_policy(mut self, policy: NanPolicy) -> Self {
+        self.options = self.options.with_nan_policy(policy);
+        self
+    }
+
+    pub fn handles(&self) -> DeviceHandles {
+        self.handles.clone()
+    }
+
+    /// The options passed to every call, for use with the `cpu` functions directly.
+    pub fn options(&self) -> RunOptions {
+        self.options.clone()
+    }
+
+    /// The device the engine runs on, see `describe_device`.
+    pub fn device_info(&self) -> DeviceInfo {
+        describe_device(&self.handles.0)
+    }
+
+    /// False on CPU and software Vulkan devices, where a host implementation may be faster.
+    pub fn is_hardware_accelerated(&self) -> bool {
+        self.device_info().is_hardware_accelerated()
+    }
+
+    /// Uploads `a` and `b` once for several distances over the same data.
+    pub fn context(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<GpuContext, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        GpuContext::new(device, queue, sba, dsa, sa, &self.options, a, b)
+    }
+
+    /// Uploads `data` once for distances between its own series.
+    pub fn context_self(&self, data: &Vec<Vec<Float>>) -> Result<GpuContext, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        GpuContext::new_self(device, queue, sba, dsa, sa, &self.options, data)
+    }
+
+    pub fn erp(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        gap_penalty: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::erp(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            gap_penalty,
+        )
+    }
+
+    pub fn erp_multivariate(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        dim: usize,
+        gap: &[Float],
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::erp_multivariate(device, queue, sba, dsa, sa, &self.options, a, b, dim, gap)
+    }
+
+    pub fn euclidean(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::euclidean(device, queue, sba, dsa, sa, &self.options, a, b)
+    }
+
+    pub fn sbd(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::sbd(device, queue, sba, dsa, sa, &self.options, a, b)
+    }
+
+    /// Keogh envelopes of every series of `data`, see `utils::compute_envelopes`.
+    pub fn compute_envelopes(
+        &self,
+        data: &Vec<Vec<Float>>,
+        window: usize,
+    ) -> Result<(Vec<Vec<Float>>, Vec<Vec<Float>>), TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        compute_envelopes(device, queue, sba, dsa, sa, &self.options, data, window)
+    }
+
+    pub fn lb_keogh(
+        &self,
+        query: &[Float],
+        candidates: &Vec<Vec<Float>>,
+        window: usize,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::lb_keogh(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            query,
+            candidates,
+            window,
+        )
+    }
+
+    pub fn lcss(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::lcss(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            epsilon,
+            delta,
+        )
+    }
+
+    pub fn lcss_length(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::lcss_length(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            epsilon,
+            delta,
+        )
+    }
+
+    pub fn lcss_count(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<u32>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::lcss_count(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            epsilon,
+            delta,
+        )
+    }
+
+    pub fn edr(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::edr(device, queue, sba, dsa, sa, &self.options, a, b, epsilon)
+    }
+
+    pub fn edr_count(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+    ) -> Result<Vec<Vec<u32>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::edr_count(device, queue, sba, dsa, sa, &self.options, a, b, epsilon)
+    }
+
+    pub fn dtw(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_with_progress(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+        progress: &dyn Fn(f32),
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_with_progress(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            window,
+            progress,
+        )
+    }
+
+    pub fn dtw_with_cost(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+        cost: LocalCost,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_with_cost(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            window,
+            cost,
+        )
+    }
+
+    pub fn dtw_step(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+        pattern: StepPattern,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_step(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            window,
+            pattern,
+        )
+    }
+
+    pub fn dtw_itakura(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        max_slope: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_itakura(device, queue, sba, dsa, sa, &self.options, a, b, max_slope)
+    }
+
+    pub fn subsequence_dtw(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::subsequence_dtw(device, queue, sba, dsa, sa, &self.options, a, b)
+    }
+
+    pub fn dtw_partial(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<(Float, usize)>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_partial(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn cid_dtw(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::cid_dtw(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_multivariate(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        dim: usize,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_multivariate(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            dim,
+            window,
+        )
+    }
+
+    pub fn dtw_cosine(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        dim: usize,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_cosine(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            dim,
+            window,
+        )
+    }
+
+    pub fn dtw_masked(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_masked(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn erp_masked(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        gap_penalty: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::erp_masked(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            gap_penalty,
+        )
+    }
+
+    pub fn dtw_flat(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_flat(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_iter(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<DistanceIter<DTWImpl>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_iter(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_for_each(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+        each: impl FnMut(usize, usize, Float),
+    ) -> Result<(), TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_for_each(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            window,
+            each,
+        )
+    }
+
+    pub fn dtw_gpu_resident(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<(Subbuffer<[Float]>, usize, usize), TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_gpu_resident(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_async(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<PendingDistances, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_async(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_self(
+        &self,
+        data: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_self(device, queue, sba, dsa, sa, &self.options, data, window)
+    }
+
+    pub fn dtw_reversed(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_reversed(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_chunked(
+        &self,
+        train: &Vec<Vec<Float>>,
+        chunks: impl IntoIterator<Item = std::io::Result<Vec<Vec<Float>>>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_chunked(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            train,
+            chunks,
+            window,
+        )
+    }
+
+    pub fn dtw_auto(
+        &self,
+        a: &[Vec<Float>],
+        b: &[Vec<Float>],
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_auto(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_many_singles(
+        &self,
+        pairs: &[(&[Float], &[Float])],
+        window: Option<usize>,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_many_singles(device, queue, sba, dsa, sa, &self.options, pairs, window)
+    }
+
+    pub fn dtw_nn_search(
+        &self,
+        query: &[Float],
+        candidates: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Option<(usize, Float)>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_nn_search(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            query,
+            candidates,
+            window,
+        )
+    }
+
+    pub fn dtw_stream(&self, template: &[Float]) -> Result<StreamingDtw, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_stream(device, queue, sba, dsa, sa, &self.options, template)
+    }
+
+    pub fn dtw_with_path(
+        &self,
+        a: &[Float],
+        b: &[Float],
+        window: Option<usize>,
+    ) -> Result<(Float, Vec<(usize, usize)>), TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_with_path(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_tiled(
+        &self,
+        a: &[Float],
+        b: &[Float],
+        band_rows: usize,
+    ) -> Result<Float, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_tiled(device, queue, sba, dsa, sa, &self.options, a, b, band_rows)
+    }
+
+    /// See `cpu::dtw_full_matrix`, which keeps every cell of the pair on the device.
+    pub fn dtw_full_matrix(
+        &self,
+        a: &[Float],
+        b: &[Float],
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_full_matrix(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_normalized(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_normalized(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn dtw_normalized_exact(
+        &self,
+        a: &[Float],
+        b: &[Float],
+        window: Option<usize>,
+    ) -> Result<Float, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::dtw_normalized_exact(device, queue, sba, dsa, sa, &self.options, a, b, window)
+    }
+
+    pub fn ddtw(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::ddtw(device, queue, sba, dsa, sa, &self.options, a, b)
+    }
+
+    pub fn wdtw(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        weights: &[Float],
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::wdtw(device, queue, sba, dsa, sa, &self.options, a, b, weights)
+    }
+
+    pub fn wdtw_logistic(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        g: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::wdtw_logistic(device, queue, sba, dsa, sa, &self.options, a, b, g)
+    }
+
+    pub fn msm(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::msm(device, queue, sba, dsa, sa, &self.options, a, b)
+    }
+
+    pub fn msm_c(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        c: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::msm_c(device, queue, sba, dsa, sa, &self.options, a, b, c)
+    }
+
+    pub fn msm_compensated(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        c: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::msm_compensated(device, queue, sba, dsa, sa, &self.options, a, b, c)
+    }
+
+    pub fn wmsm(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        c: Float,
+        weights: &[Float],
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::wmsm(device, queue, sba, dsa, sa, &self.options, a, b, c, weights)
+    }
+
+    pub fn msm_gen(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        split_cost: Float,
+        merge_cost: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::msm_gen(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            split_cost,
+            merge_cost,
+        )
+    }
+
+    pub fn twe(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        nu: Float,
+        lambda: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::twe(device, queue, sba, dsa, sa, &self.options, a, b, nu, lambda)
+    }
+
+    pub fn twe_compensated(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        nu: Float,
+        lambda: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::twe_compensated(device, queue, sba, dsa, sa, &self.options, a, b, nu, lambda)
+    }
+
+    pub fn adtw(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        w: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::adtw(device, queue, sba, dsa, sa, &self.options, a, b, w)
+    }
+
+    pub fn adtw_scaled(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        w: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::adtw_scaled(device, queue, sba, dsa, sa, &self.options, a, b, w)
+    }
+
+    pub fn gak(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        sigma: Float,
+        triangular: usize,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::gak(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            a,
+            b,
+            sigma,
+            triangular,
+        )
+    }
+
+    pub fn compute_matrices(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        metrics: &[Metric],
+    ) -> Result<HashMap<Metric, DistanceMatrix>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::compute_matrices(device, queue, sba, dsa, sa, &self.options, a, b, metrics)
+    }
+
+    pub fn ensemble(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        metrics: &[(Metric, Float)],
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::ensemble(device, queue, sba, dsa, sa, &self.options, a, b, metrics)
+    }
+
+    pub fn knn(
+        &self,
+        train: &Vec<Vec<Float>>,
+        train_labels: &[usize],
+        test: &Vec<Vec<Float>>,
+        k: usize,
+        metric: Metric,
+    ) -> Result<Vec<usize>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::knn(
+            device,
+            queue,
+            sba,
+            dsa,
+            sa,
+            &self.options,
+            train,
+            train_labels,
+            test,
+            k,
+            metric,
+        )
+    }
+
+    pub fn pairwise(
+        &self,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        metric: Metric,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        let (device, queue, sba, dsa, sa) = self.handles();
+        cpu::pairwise(device, queue, sba, dsa, sa, &self.options, a, b, metric)
+    }
+}
+
+impl Default for DistanceEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::time::Duration;
+
+use vulkano::{
+    LoadingError, Validated, ValidationError, VulkanError, command_buffer::CommandBufferExecError,
+    memory::allocator::MemoryAllocatorError, pipeline::layout::IntoPipelineLayoutCreateInfoError,
+    sync::HostAccessError,
+};
+
+#[derive(Debug)]
+pub enum TsDistanceError {
+    /// The Vulkan library could not be loaded.
+    Loading(LoadingError),
+    /// A Vulkan call failed, either at runtime or during vulkano's validation.
+    Vulkan(Validated<VulkanError>),
+    /// A command was recorded with invalid arguments.
+    Validation(Box<ValidationError>),
+    /// A command buffer could not be submitted to the queue.
+    Execution(CommandBufferExecError),
+    /// A host or device buffer could not be allocated.
+    Allocation(String),
+    /// A mapped buffer could not be accessed from the host.
+    HostAccess(HostAccessError),
+    /// The shader module or its pipeline could not be built.
+    Shader(String),
+    /// The input series are not valid for the requested distance.
+    InvalidInput(String),
+    /// An input series holds a `NaN` or infinite value, see `NanPolicy`.
+    NonFiniteInput(String),
+    /// A series of `len` values needs a rolling diagonal larger than a single storage buffer
+    /// on this device, which supports series of up to `max` values.
+    SeriesTooLong { len: usize, max: usize },
+    /// The device did not finish within the timeout set with
+    /// `RunOptions::with_timeout`.
+    Timeout(Duration),
+    /// The computation was stopped through its `CancellationToken`.
+    Cancelled,
+    /// A run needs `required` bytes of device memory, over the budget set with
+    /// `SubBuffersAllocator::with_memory_budget`.
+    MemoryBudgetExceeded { required: u64, budget: u64 },
+    /// Series could not be read from a file, see `utils::read_ts_chunked`.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for TsDistanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsDistanceError::Loading(err) => write!(f, "loading error: {}", err),
+            TsDistanceError::Vulkan(err) => write!(f, "vulkan error: {}", err),
+            TsDistanceError::Validation(err) => write!(f, "validation error: {}", err),
+            TsDistanceError::Execution(err) => write!(f, "execution error: {}", err),
+            TsDistanceError::Allocation(msg) => write!(f, "allocation error: {}", msg),
+            TsDistanceError::HostAccess(err) => write!(f, "host access error: {}", err),
+            TsDistanceError::Shader(msg) => write!(f, "shader error: {}", msg),
+            TsDistanceError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            TsDistanceError::NonFiniteInput(msg) => write!(f, "non-finite input: {}", msg),
+            TsDistanceError::SeriesTooLong { len, max } => write!(
+                f,
+                "series of {} values is too long, this device supports up to {}",
+                len, max
+            ),
+            TsDistanceError::Timeout(timeout) => {
+                write!(f, "the device did not finish within {:?}", timeout)
+            }
+            TsDistanceError::Cancelled => write!(f, "the computation was cancelled"),
+            TsDistanceError::MemoryBudgetExceeded { required, budget } => write!(
+                f,
+                "{} bytes of device memory are needed, over the budget of {}",
+                required, budget
+            ),
+            TsDistanceError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TsDistanceError {}
+
+impl From<LoadingError> for TsDistanceError {
+    fn from(err: LoadingError) -> Self {
+        TsDistanceError::Loading(err)
+    }
+}
+
+impl From<Validated<VulkanError>> for TsDistanceError {
+    fn from(err: Validated<VulkanError>) -> Self {
+        TsDistanceError::Vulkan(err)
+    }
+}
+
+impl From<VulkanError> for TsDistanceError {
+    fn from(err: VulkanError) -> Self {
+        TsDistanceError::Vulkan(Validated::Error(err))
+    }
+}
+
+impl From<Box<ValidationError>> for TsDistanceError {
+    fn from(err: Box<ValidationError>) -> Self {
+        TsDistanceError::Validation(err)
+    }
+}
+
+impl From<CommandBufferExecError> for TsDistanceError {
+    fn from(err: CommandBufferExecError) -> Self {
+        TsDistanceError::Execution(err)
+    }
+}
+
+impl From<MemoryAllocatorError> for TsDistanceError {
+    fn from(err: MemoryAllocatorError) -> Self {
+        TsDistanceError::Allocation(err.to_string())
+    }
+}
+
+impl From<HostAccessError> for TsDistanceError {
+    fn from(err: HostAccessError) -> Self {
+        TsDistanceError::HostAccess(err)
+    }
+}
+
+impl From<std::io::Error> for TsDistanceError {
+    fn from(err: std::io::Error) -> Self {
+        TsDistanceError::Io(err)
+    }
+}
+
+impl From<IntoPipelineLayoutCreateInfoError> for TsDistanceError {
+    fn from(err: IntoPipelineLayoutCreateInfoError) -> Self {
+        TsDistanceError::Shader(err.to_string())
+    }
+}
@@ -1,26 +1,260 @@
+use crate::Float;
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float as _;
+
+/// Panics unless the diagonal buffer splits into `pair_count` rolling diagonals of
+/// `diag_len` values, a power of two as `GpuMatrix` masks offsets with it. Any other length
+/// would silently alias cells of different offsets.
+#[cfg(not(target_arch = "spirv"))]
+pub(crate) fn check_diag_len(diag_len: u64, pair_count: u64, buffer_len: u64) {
+    assert!(
+        diag_len.is_power_of_two() && diag_len * pair_count == buffer_len,
+        "a diagonal buffer of {} values does not hold {} rolling diagonals of a power of two",
+        buffer_len,
+        pair_count
+    );
+}
+
+/// Waits for the other threads of a diamond tile and makes their writes to the diagonal
+/// visible. When the host found every tile to lie within a single subgroup, a subgroup
+/// barrier is enough, otherwise the whole workgroup is synchronized. Without the
+/// `subgroup-sync` feature the shaders lack the capability and always take the latter.
+#[cfg(target_arch = "spirv")]
+#[inline(always)]
+fn tile_barrier(subgroup_sync: bool) {
+    #[cfg(feature = "subgroup-sync")]
+    if subgroup_sync {
+        use spirv_std::memory::{Scope, Semantics};
+        unsafe {
+            spirv_std::arch::control_barrier::<
+                { Scope::Subgroup as u32 },
+                { Scope::Subgroup as u32 },
+                {
+                    Semantics::ACQUIRE_RELEASE.bits()
+                        | Semantics::UNIFORM_MEMORY.bits()
+                        | Semantics::WORKGROUP_MEMORY.bits()
+                },
+            >()
+        };
+        return;
+    }
+    #[cfg(not(feature = "subgroup-sync"))]
+    let _ = subgroup_sync;
+    unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+}
+
 pub struct GpuMatrix<'a> {
-    diagonal: &'a mut [f32],
+    diagonal: &'a mut [Float],
     diagonal_offset: usize,
     mask: usize,
+    cells: &'a mut [Float],
+    cells_offset: usize,
+    cells_stride: usize,
 }
 
-impl GpuMatrix<'_> {
+impl<'a> GpuMatrix<'a> {
+    /// The rolling diagonal is addressed through `diagonal_len - 1` as a mask, which only
+    /// keeps every offset apart for a power of two.
     #[inline(always)]
-    fn get_diagonal_cell(&self, _diag_row: usize, diag_offset: isize) -> f32 {
+    fn new(
+        diagonal: &'a mut [Float],
+        diagonal_offset: usize,
+        diagonal_len: usize,
+        cells: &'a mut [Float],
+        cells_offset: usize,
+        cells_stride: usize,
+    ) -> Self {
+        debug_assert!(diagonal_len.is_power_of_two());
+        Self {
+            diagonal,
+            diagonal_offset,
+            mask: diagonal_len - 1,
+            cells,
+            cells_offset,
+            cells_stride,
+        }
+    }
+
+    #[inline(always)]
+    fn get_diagonal_cell(&self, _diag_row: usize, diag_offset: isize) -> Float {
         self.diagonal[self.diagonal_offset + (diag_offset as usize & self.mask)]
     }
 
     #[inline(always)]
-    fn set_diagonal_cell(&mut self, _diag_row: usize, diag_offset: isize, value: f32) {
+    fn set_diagonal_cell(&mut self, _diag_row: usize, diag_offset: isize, value: Float) {
         self.diagonal[self.diagonal_offset + (diag_offset as usize & self.mask)] = value;
     }
+
+    // Only called by the `batch_call_cells` entry points, the others bind a single
+    // placeholder element.
+    #[inline(always)]
+    fn set_matrix_cell(&mut self, i: usize, j: usize, value: Float) {
+        self.cells[self.cells_offset + i * self.cells_stride + j] = value;
+    }
+}
+
+/// What the body of a `warp_kernel_spec!` kernel evaluates to: the value of the cell, or the
+/// value together with the second state of the kernels declared with `state`.
+pub trait CellValue {
+    fn split(self) -> (Float, Float);
+}
+
+impl CellValue for Float {
+    #[inline(always)]
+    fn split(self) -> (Float, Float) {
+        (self, self)
+    }
+}
+
+impl CellValue for (Float, Float) {
+    #[inline(always)]
+    fn split(self) -> (Float, Float) {
+        self
+    }
+}
+
+/// Which pairs of the `a` and `b` series a dispatch computes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PairLayout {
+    /// Every `(i, j)`, row-major.
+    All,
+    /// The pairs `i < j` of a single batch, see `upper_triangle_pair`.
+    UpperTriangle,
+    /// `a[k]` against `b[k]` only, both batches holding as many series.
+    Zipped,
+}
+
+impl PairLayout {
+    pub fn pair_count(self, a_count: usize, b_count: usize) -> usize {
+        match self {
+            PairLayout::All => a_count * b_count,
+            PairLayout::UpperTriangle => a_count * a_count.saturating_sub(1) / 2,
+            PairLayout::Zipped => a_count,
+        }
+    }
+}
+
+/// Transitions a DTW warping path may take, after Sakoe and Chiba.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StepPattern {
+    /// Horizontal, vertical and diagonal steps of unit weight, the usual DTW.
+    #[default]
+    SymmetricP0,
+    /// Every horizontal or vertical step follows a diagonal one, whose local cost counts
+    /// twice, so the slope of the path stays between 1/2 and 2.
+    SymmetricP1,
+}
+
+/// Local cost of aligning two values `x` and `y` in `cpu::dtw_with_cost`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LocalCost {
+    /// `(x - y)^2`, as `cpu::dtw`.
+    #[default]
+    SquaredEuclidean,
+    /// `|x - y|`, less dominated by outliers than the squared difference.
+    Absolute,
+    /// `|x - y|^p`, for a positive `p`.
+    Power(Float),
+}
+
+impl LocalCost {
+    /// The discriminant and exponent the `dtw_cost_distance` kernel branches on.
+    pub(crate) fn code(self) -> (u64, Float) {
+        match self {
+            LocalCost::SquaredEuclidean => (0, 2.0),
+            LocalCost::Absolute => (1, 1.0),
+            LocalCost::Power(p) => (2, p),
+        }
+    }
+
+    /// The cost of aligning `x` with `y`, as the kernel computes it.
+    pub fn apply(self, x: Float, y: Float) -> Float {
+        match self {
+            LocalCost::SquaredEuclidean => (x - y) * (x - y),
+            LocalCost::Absolute => (x - y).abs(),
+            LocalCost::Power(p) => (x - y).abs().powf(p),
+        }
+    }
+}
+
+/// How the accumulated cost matrix of a pair is seeded and read. `Closed` aligns the whole of
+/// `a` with the whole of `b`; the open variants let the alignment start, end, or both, anywhere
+/// along `b`, which with DTW matches `a` against the best subsequence of `b`. They only make
+/// sense for kernels minimizing a cost.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BoundaryCondition {
+    #[default]
+    Closed,
+    /// The row above the first one is 0, so the alignment may skip a prefix of `b`.
+    OpenBegin,
+    /// The distance is the smallest of the last row, so the alignment may skip a suffix of `b`.
+    OpenEnd,
+    OpenBoth,
+}
+
+impl BoundaryCondition {
+    pub fn open_begin(self) -> bool {
+        matches!(
+            self,
+            BoundaryCondition::OpenBegin | BoundaryCondition::OpenBoth
+        )
+    }
+
+    pub fn open_end(self) -> bool {
+        matches!(
+            self,
+            BoundaryCondition::OpenEnd | BoundaryCondition::OpenBoth
+        )
+    }
+}
+
+/// Maps the linear index of a pair with `i < j` among `n` series, in row-major order,
+/// back to `(i, j)`.
+#[inline(always)]
+pub fn upper_triangle_pair(pair_index: u64, n: u64) -> (u64, u64) {
+    let total = n * (n - 1) / 2;
+    let q = total - 1 - pair_index;
+    let k = (isqrt(8 * q + 1) - 1) / 2;
+    let i = n - 2 - k;
+    let row_start = i * (2 * n - i - 1) / 2;
+    (i, pair_index - row_start + i + 1)
+}
+
+#[inline(always)]
+fn isqrt(x: u64) -> u64 {
+    if x < 2 {
+        return x;
+    }
+    let mut r = x;
+    let mut y = (r + 1) / 2;
+    while y < r {
+        r = y;
+        y = (r + x / r) / 2;
+    }
+    r
+}
+
+// Binds the second state of a neighbour for `warp_kernel_spec!`, unless the kernel names it
+// `_`, which skips the read.
+macro_rules! read_state {
+    (_ = $read:expr) => {};
+    ($name:ident = $read:expr) => {
+        let $name = $read;
+    };
+}
+
+// Expands to the statement for kernels declaring a state, given any of its names.
+macro_rules! with_state {
+    ($state:tt => $statement:expr) => {
+        $statement;
+    };
 }
 
 macro_rules! warp_kernel_spec {
     ($(
-        fn $name:ident[$impl_struct:ident](
-            $a:ident[$a_offset:ident],
-            $b:ident[$b_offset:ident],
+        fn $name:ident[$impl_struct:ident $(; band = $band:ident)? $(; squared = $squared:literal)? $(; squared_when = $squared_when:tt)? $(; state = ($sx:tt, $sy:tt, $sz:tt))?](
+            $a:ident[$a_offset:ident $(; $dim:ident)? $(, $a_n:ident)?],
+            $b:ident[$b_offset:ident $(; $dim_b:ident)? $(, $b_n:ident)?],
             $i:ident,
             $j:ident,
             $x:ident,
@@ -35,15 +269,19 @@ macro_rules! warp_kernel_spec {
     )*) => {
         $(
             pub mod $name {
+                #[allow(unused_imports)]
+                use crate::Float;
+
                 #[cfg(not(target_arch = "spirv"))]
                 pub mod cpu {
+                    use crate::Float;
                     use std::sync::Arc;
                     use vulkano::buffer::Subbuffer;
                     use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
                     use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
                     use vulkano::descriptor_set::{DescriptorSet, WriteDescriptorSet};
                     use vulkano::device::Device;
-                    use crate::{kernels::kernel_trait::{GpuKernelImpl}, utils::SubBuffersAllocator};
+                    use crate::{error::TsDistanceError, kernels::kernel_trait::{GpuKernelImpl}, utils::SubBuffersAllocator};
                     use vulkano::pipeline::{Pipeline, PipelineBindPoint};
 
                     pub struct $impl_struct {
@@ -54,26 +292,61 @@ macro_rules! warp_kernel_spec {
                         $(pub $vec5:  Vec<$ty5>,)?
                     }
 
+                    #[derive(Clone)]
                     pub struct KernelParams {
-                        $(pub $vec5:  Subbuffer<[$ty5]>)?
+                        $(pub $vec5:  Subbuffer<[$ty5]>,)?
+                        allocator: SubBuffersAllocator,
                     }
 
                     impl GpuKernelImpl for $impl_struct {
 
                         type KernelParams = KernelParams;
 
+                        $(
+                            fn band(&self) -> Option<u64> {
+                                Some(self.$band)
+                            }
+                        )?
+
+                        $(
+                            fn squared_cost(&self) -> bool {
+                                $squared
+                            }
+                        )?
+
+                        $(
+                            fn squared_cost(&self) -> bool {
+                                let squared_when: fn(&Self) -> bool = $squared_when;
+                                squared_when(self)
+                            }
+                        )?
+
+                        $(
+                            fn kernel_params_bytes(&self) -> u64 {
+                                (self.$vec5.len() * std::mem::size_of::<$ty5>()) as u64
+                            }
+                        )?
+
+                        $(
+                            #[doc = concat!("The second state is read as `", stringify!($sx), "`, `", stringify!($sy), "` and `", stringify!($sz), "`.")]
+                            fn diagonal_states(&self) -> usize {
+                                2
+                            }
+                        )?
+
                         fn build_kernel_params(
                             &self,
-                            _allocator: SubBuffersAllocator,
+                            allocator: SubBuffersAllocator,
                             _builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
-                        ) -> Self::KernelParams {
+                        ) -> Result<Self::KernelParams, TsDistanceError> {
                             $(
                                 use crate::utils::SubBufferPair;
-                                let buffers = SubBufferPair::new(&_allocator, self.$vec5.len() as u64);
+                                let buffers = SubBufferPair::new(&allocator, self.$vec5.len() as u64)?;
                             )?
-                            KernelParams {
-                                $($vec5: buffers.move_gpu(&self.$vec5, _builder))?
-                            }
+                            Ok(KernelParams {
+                                $($vec5: buffers.move_gpu(&self.$vec5, _builder)?,)?
+                                allocator,
+                            })
                         }
 
                         fn dispatch(
@@ -89,35 +362,61 @@ macro_rules! warp_kernel_spec {
                             a_len: u64,
                             b_len: u64,
                             max_subgroup_threads: u64,
-                            a: &Subbuffer<[f32]>,
-                            b: &Subbuffer<[f32]>,
-                            diagonal: &mut Subbuffer<[f32]>,
-                            _kernel_params: &Self::KernelParams,
-                        ) {
+                            pair_layout: crate::kernels::PairLayout,
+                            a: &Subbuffer<[Float]>,
+                            b: &Subbuffer<[Float]>,
+                            a_lengths: &Subbuffer<[u32]>,
+                            b_lengths: &Subbuffer<[u32]>,
+                            diagonal: &mut Subbuffer<[Float]>,
+                            cells: &mut Subbuffer<[Float]>,
+                            kernel_params: &Self::KernelParams,
+                        ) -> Result<(), TsDistanceError> {
 
-                            let shader_name = concat!("kernels::", stringify!($name), "::batch_call");
-                            let a_count = a.len() as u64 / a_len;
-                            let b_count = b.len() as u64 / b_len;
-                            let threads_count = (a_count * b_count * tile_count * max_subgroup_threads) as u32;
-                            let diag_len = diagonal.len() as u64 / (a_count * b_count);
+                            // Constants larger than the device allows to push are read from a
+                            // uniform buffer by a second entry point.
+                            let uniform = size_of::<super::KernelConstants>()
+                                > kernel_params.allocator.max_push_constants_size(&device);
+                            // The cost matrix is only written by its own entry points, so that the
+                            // distance kernels don't branch on it for every cell. Without it
+                            // `cells` is a single placeholder element.
+                            let store_cells = cells.len() > 1;
+                            let shader_name = match (store_cells, uniform) {
+                                (false, false) => concat!("kernels::", stringify!($name), "::batch_call"),
+                                (false, true) => concat!("kernels::", stringify!($name), "::batch_call_uniform"),
+                                (true, false) => concat!("kernels::", stringify!($name), "::batch_call_cells"),
+                                (true, true) => concat!("kernels::", stringify!($name), "::batch_call_cells_uniform"),
+                            };
+                            let a_count = a_lengths.len();
+                            let b_count = b_lengths.len();
+                            // Each timestep holds `dim` interleaved channels.
+                            let dim = a.len() / (a_count * a_len);
+                            let pair_count = pair_layout.pair_count(a_count as usize, b_count as usize) as u64;
+                            let threads_count = (pair_count * tile_count * max_subgroup_threads) as u32;
+                            let diag_len = diagonal.len() as u64 / pair_count;
+                            crate::kernels::check_diag_len(diag_len, pair_count, diagonal.len());
+                            let subgroup_sync = kernel_params
+                                .allocator
+                                .subgroup_sync(&device, max_subgroup_threads as usize);
 
+                            log::trace!(
+                                kernel_name = shader_name,
+                                row,
+                                tile_count,
+                                a_len,
+                                b_len,
+                                a_count,
+                                b_count,
+                                pair_layout = pair_layout as u64,
+                                dim,
+                                diag_len,
+                                subgroup_sync,
+                                threads_count;
+                                "Dispatching kernel"
+                            );
 
-                            let pipeline = crate::shader_load::get_shader_entry_pipeline(device.clone(), shader_name);
+                            let pipeline = crate::shader_load::get_shader_entry_pipeline(device.clone(), shader_name)?;
                             let layout = &pipeline.layout().set_layouts()[0];
 
-                            let set = DescriptorSet::new(
-                                dsa.clone(),
-                                layout.clone(),
-                                [
-                                    WriteDescriptorSet::buffer(0, diagonal.clone()),
-                                    WriteDescriptorSet::buffer(1, a.clone()),
-                                    WriteDescriptorSet::buffer(2, b.clone()),
-                                    $(WriteDescriptorSet::buffer(3, _kernel_params.$vec5.clone()),)?
-                                ],
-                                [],
-                            )
-                            .unwrap();
-
                             let kernel_constants = super::KernelConstants {
                                     first_coord,
                                     row,
@@ -130,6 +429,9 @@ macro_rules! warp_kernel_spec {
                                     b_count,
                                     diag_len,
                                     max_subgroup_threads,
+                                    pair_layout: pair_layout as u64,
+                                    dim,
+                                    subgroup_sync: subgroup_sync as u64,
                                     $(param1: self.$param1,)?
                                     $(param2: self.$param2,)?
                                     $(param3: self.$param3,)?
@@ -137,29 +439,61 @@ macro_rules! warp_kernel_spec {
                                     _padding: 0,
                             };
 
+                            let mut writes = vec![
+                                WriteDescriptorSet::buffer(0, diagonal.clone()),
+                                WriteDescriptorSet::buffer(1, a.clone()),
+                                WriteDescriptorSet::buffer(2, b.clone()),
+                                $(WriteDescriptorSet::buffer(3, kernel_params.$vec5.clone()),)?
+                                WriteDescriptorSet::buffer(4, a_lengths.clone()),
+                                WriteDescriptorSet::buffer(5, b_lengths.clone()),
+                                WriteDescriptorSet::buffer(6, cells.clone()),
+                            ];
+                            if uniform {
+                                let constants = kernel_params.allocator.uniform(kernel_constants)?;
+                                writes.push(WriteDescriptorSet::buffer(7, constants));
+                            }
+                            let set = DescriptorSet::new(dsa.clone(), layout.clone(), writes, [])?;
+
                             builder
-                                .bind_pipeline_compute(pipeline.clone())
-                                .unwrap()
+                                .bind_pipeline_compute(pipeline.clone())?
                                 .bind_descriptor_sets(
                                     PipelineBindPoint::Compute,
                                     pipeline.layout().clone(),
                                     0,
                                     set,
-                                )
-                                .unwrap()
-                                .push_constants(
-                                    pipeline.layout().clone(),
-                                    0,
-                                    kernel_constants
-                                )
-                                .unwrap();
+                                )?;
+                            if !uniform {
+                                builder.push_constants(pipeline.layout().clone(), 0, kernel_constants)?;
+                            }
 
                             let max_threads_x = device
                                 .physical_device()
                                 .properties()
                                 .max_compute_work_group_size[0];
+                            let workgroup_count = threads_count.div_ceil(max_threads_x);
+
+                            kernel_params.allocator.trace_dispatch(|| crate::utils::DispatchRecord {
+                                kernel_name: shader_name,
+                                first_coord,
+                                row,
+                                tile_count,
+                                a_start,
+                                b_start,
+                                a_len,
+                                b_len,
+                                a_count,
+                                b_count,
+                                diag_len,
+                                max_subgroup_threads,
+                                pair_layout: pair_layout as u64,
+                                dim,
+                                subgroup_sync,
+                                threads_count,
+                                workgroup_count,
+                            });
 
-                            unsafe { builder.dispatch([threads_count.div_ceil(max_threads_x), 1u32, 1u32]) }.unwrap();
+                            unsafe { builder.dispatch([workgroup_count, 1u32, 1u32]) }?;
+                            Ok(())
                         }
                     }
                 }
@@ -179,6 +513,9 @@ macro_rules! warp_kernel_spec {
                     b_count: u64,
                     diag_len: u64,
                     max_subgroup_threads: u64,
+                    pair_layout: u64,
+                    dim: u64,
+                    subgroup_sync: u64,
                     $(param1: $ty1,)?
                     $(param2: $ty2,)?
                     $(param3: $ty3,)?
@@ -187,11 +524,11 @@ macro_rules! warp_kernel_spec {
                 }
 
                 #[cfg(target_arch = "spirv")]
-                use spirv_std::{glam::UVec3, spirv, num_traits::Float};
+                use spirv_std::{glam::UVec3, spirv, num_traits::Float as _};
 
                 #[cfg(target_arch = "spirv")]
                 #[inline(always)]
-                fn warp_kernel_inner(
+                fn warp_kernel_inner<const STORE_CELLS: bool>(
                     mut matrix: super::GpuMatrix,
                     d_offset: u64,
                     a_start: u64,
@@ -200,8 +537,12 @@ macro_rules! warp_kernel_spec {
                     diag_count: u64,
                     warp: u64,
                     max_subgroup_threads: u64,
-                    $a: &[f32],
-                    $b: &[f32],
+                    a_series_len: u64,
+                    b_series_len: u64,
+                    _dim: u64,
+                    subgroup_sync: bool,
+                    $a: &[Float],
+                    $b: &[Float],
                     $a_offset: usize,
                     $b_offset: usize,
                     $($param1: $ty1,)?
@@ -210,6 +551,10 @@ macro_rules! warp_kernel_spec {
                     $($param4: $ty4,)?
                     $($vec5: &[$ty5],)?
                 ) {
+                    $(let $dim = _dim as usize;)?
+                    $(let $dim_b = _dim as usize;)?
+                    $(let $a_n = a_series_len;)?
+                    $(let $b_n = b_series_len;)?
                     let mut i = a_start;
                     let mut j = b_start;
                     let mut s = diag_mid;
@@ -224,16 +569,35 @@ macro_rules! warp_kernel_spec {
                             let $x = matrix.get_diagonal_cell((d_offset + d - 1) as usize, (k - 1) as isize);
                             let $y = matrix.get_diagonal_cell((d_offset + d - 2) as usize, k as isize);
                             let $z = matrix.get_diagonal_cell((d_offset + d - 1) as usize, (k + 1) as isize);
+                            // The second state of a cell lies half a diagonal past its value.
+                            $(
+                                let half = (matrix.mask as isize + 1) / 2;
+                                read_state!($sx = matrix.get_diagonal_cell((d_offset + d - 1) as usize, k - 1 + half));
+                                read_state!($sy = matrix.get_diagonal_cell((d_offset + d - 2) as usize, k + half));
+                                read_state!($sz = matrix.get_diagonal_cell((d_offset + d - 1) as usize, k + 1 + half));
+                            )?
 
-
-                            let value = {
-                                $body
+                            // Cells past the end of a shorter series copy the value of the
+                            // last real cell, so the padded corner holds the real distance.
+                            let (value, _state) = if $i >= a_series_len {
+                                let value = if $j >= b_series_len { $y } else { $z };
+                                (value, value)
+                            } else if $j >= b_series_len {
+                                ($x, $x)
+                            } else {
+                                super::CellValue::split($body)
                             };
 
                             matrix.set_diagonal_cell((d_offset + d) as usize, k as isize, value);
+                            $(
+                                with_state!($sx => matrix.set_diagonal_cell((d_offset + d) as usize, k + half, _state));
+                            )?
+                            if STORE_CELLS && $i < a_series_len && $j < b_series_len {
+                                matrix.set_matrix_cell($i as usize, $j as usize, value);
+                            }
                         }
                         // Warp synchronize
-                        unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+                        super::tile_barrier(subgroup_sync);
 
                         if d <= max_subgroup_threads {
                             i += 1;
@@ -249,7 +613,7 @@ macro_rules! warp_kernel_spec {
 
                 #[cfg(target_arch = "spirv")]
                 #[inline(always)]
-                fn warp_kernel(
+                fn warp_kernel<const STORE_CELLS: bool>(
                     global_id: u64,
                     first_coord: i64,
                     row: u64,
@@ -259,11 +623,17 @@ macro_rules! warp_kernel_spec {
                     a_len: u64,
                     b_len: u64,
                     max_subgroup_threads: u64,
-                    diagonal: &mut [f32],
+                    a_series_len: u64,
+                    b_series_len: u64,
+                    diagonal: &mut [Float],
                     diagonal_offset: u64,
                     diagonal_len: u64,
-                    $a: &[f32],
-                    $b: &[f32],
+                    cells: &mut [Float],
+                    cells_offset: u64,
+                    dim: u64,
+                    subgroup_sync: bool,
+                    $a: &[Float],
+                    $b: &[Float],
                     $a_offset: usize,
                     $b_offset: usize,
                     $($param1: $ty1,)?
@@ -286,13 +656,16 @@ macro_rules! warp_kernel_spec {
                     let alen = a_len - d_a_start;
                     let blen = b_len - d_b_start;
 
-                    let matrix = super::GpuMatrix {
+                    let matrix = super::GpuMatrix::new(
                         diagonal,
-                        diagonal_offset: diagonal_offset as usize,
-                        mask: diagonal_len as usize - 1,
-                    };
+                        diagonal_offset as usize,
+                        diagonal_len as usize,
+                        cells,
+                        cells_offset as usize,
+                        b_len as usize,
+                    );
 
-                    warp_kernel_inner(
+                    warp_kernel_inner::<STORE_CELLS>(
                         matrix,
                         row * max_subgroup_threads,
                         d_a_start,
@@ -301,6 +674,10 @@ macro_rules! warp_kernel_spec {
                         (max_subgroup_threads * 2 + 1).min(alen + blen + 1),
                         warp_id,
                         max_subgroup_threads,
+                        a_series_len,
+                        b_series_len,
+                        dim,
+                        subgroup_sync,
                         $a,
                         $b,
                         $a_offset,
@@ -318,17 +695,86 @@ macro_rules! warp_kernel_spec {
                 pub fn batch_call(
                     #[spirv(global_invocation_id)] global_id: UVec3,
                     #[spirv(push_constant)] constants: &KernelConstants,
-                    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] diagonal: &mut [f32],
-                    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] $a: &[f32],
-                    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] $b: &[f32],
-                    $(#[spirv(storage_buffer, descriptor_set = 0, binding = 3)] vec5: &[$ty5],)?
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] diagonal: &mut [Float],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] $a: &[Float],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] $b: &[Float],
+                    $(#[spirv(storage_buffer, descriptor_set = 0, binding = 3)] $vec5: &[$ty5],)?
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] a_lengths: &[u32],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] b_lengths: &[u32],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] cells: &mut [Float],
+                ) {
+                    batch_call_inner::<false>(global_id, constants, diagonal, $a, $b, $($vec5,)? a_lengths, b_lengths, cells);
+                }
+
+                // Same as `batch_call`, for devices whose push constants can't hold `KernelConstants`.
+                #[cfg(target_arch = "spirv")]
+                #[spirv(compute(threads(1)))]
+                pub fn batch_call_uniform(
+                    #[spirv(global_invocation_id)] global_id: UVec3,
+                    #[spirv(uniform, descriptor_set = 0, binding = 7)] constants: &KernelConstants,
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] diagonal: &mut [Float],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] $a: &[Float],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] $b: &[Float],
+                    $(#[spirv(storage_buffer, descriptor_set = 0, binding = 3)] $vec5: &[$ty5],)?
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] a_lengths: &[u32],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] b_lengths: &[u32],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] cells: &mut [Float],
+                ) {
+                    batch_call_inner::<false>(global_id, constants, diagonal, $a, $b, $($vec5,)? a_lengths, b_lengths, cells);
+                }
+
+                // Same as `batch_call`, also writing every cell to the cost matrix at binding 6.
+                #[cfg(target_arch = "spirv")]
+                #[spirv(compute(threads(1)))]
+                pub fn batch_call_cells(
+                    #[spirv(global_invocation_id)] global_id: UVec3,
+                    #[spirv(push_constant)] constants: &KernelConstants,
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] diagonal: &mut [Float],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] $a: &[Float],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] $b: &[Float],
+                    $(#[spirv(storage_buffer, descriptor_set = 0, binding = 3)] $vec5: &[$ty5],)?
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] a_lengths: &[u32],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] b_lengths: &[u32],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] cells: &mut [Float],
+                ) {
+                    batch_call_inner::<true>(global_id, constants, diagonal, $a, $b, $($vec5,)? a_lengths, b_lengths, cells);
+                }
+
+                // Same as `batch_call_cells`, for devices whose push constants can't hold `KernelConstants`.
+                #[cfg(target_arch = "spirv")]
+                #[spirv(compute(threads(1)))]
+                pub fn batch_call_cells_uniform(
+                    #[spirv(global_invocation_id)] global_id: UVec3,
+                    #[spirv(uniform, descriptor_set = 0, binding = 7)] constants: &KernelConstants,
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] diagonal: &mut [Float],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] $a: &[Float],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] $b: &[Float],
+                    $(#[spirv(storage_buffer, descriptor_set = 0, binding = 3)] $vec5: &[$ty5],)?
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] a_lengths: &[u32],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] b_lengths: &[u32],
+                    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] cells: &mut [Float],
+                ) {
+                    batch_call_inner::<true>(global_id, constants, diagonal, $a, $b, $($vec5,)? a_lengths, b_lengths, cells);
+                }
+
+                #[cfg(target_arch = "spirv")]
+                #[inline(always)]
+                fn batch_call_inner<const STORE_CELLS: bool>(
+                    global_id: UVec3,
+                    constants: &KernelConstants,
+                    diagonal: &mut [Float],
+                    $a: &[Float],
+                    $b: &[Float],
+                    $($vec5: &[$ty5],)?
+                    a_lengths: &[u32],
+                    b_lengths: &[u32],
+                    cells: &mut [Float],
                 ) {
 
                     $(let $param1 = constants.param1;)?
                     $(let $param2 = constants.param2;)?
                     $(let $param3 = constants.param3;)?
                     $(let $param4 = constants.param4;)?
-                    $(let $vec5 = vec5;)?
 
 
                     let global_id = global_id.x as u64;
@@ -337,15 +783,26 @@ macro_rules! warp_kernel_spec {
                     let pair_index = global_id / threads_stride;
                     let instance_id = global_id % threads_stride;
 
-                    let a_index = pair_index / constants.b_count as u64;
-                    let b_index = pair_index % constants.b_count as u64;
+                    // In the upper triangle layout a and b hold the same series and only the
+                    // pairs above the main diagonal are computed.
+                    let (a_index, b_index) = if constants.pair_layout == super::PairLayout::UpperTriangle as u64 {
+                        super::upper_triangle_pair(pair_index, constants.a_count)
+                    } else if constants.pair_layout == super::PairLayout::Zipped as u64 {
+                        (pair_index, pair_index)
+                    } else {
+                        (pair_index / constants.b_count, pair_index % constants.b_count)
+                    };
 
                     let diagonal_offset = pair_index * constants.diag_len;
+                    let cells_offset = pair_index * constants.a_len * constants.b_len;
+
+                    let $a_offset = (a_index * constants.a_len * constants.dim) as usize;
+                    let $b_offset = (b_index * constants.b_len * constants.dim) as usize;
 
-                    let $a_offset = a_index as usize * constants.a_len as usize;
-                    let $b_offset = b_index as usize * constants.b_len as usize;
+                    let a_series_len = a_lengths[a_index as usize] as u64;
+                    let b_series_len = b_lengths[b_index as usize] as u64;
 
-                    warp_kernel(
+                    warp_kernel::<STORE_CELLS>(
                         instance_id,
                         constants.first_coord,
                         constants.row,
@@ -355,9 +812,15 @@ macro_rules! warp_kernel_spec {
                         constants.a_len,
                         constants.b_len,
                         constants.max_subgroup_threads,
+                        a_series_len,
+                        b_series_len,
                         diagonal,
                         diagonal_offset,
                         constants.diag_len,
+                        cells,
+                        cells_offset,
+                        constants.dim,
+                        constants.subgroup_sync != 0,
                         $a,
                         $b,
                         $a_offset,
@@ -376,6 +839,9 @@ macro_rules! warp_kernel_spec {
 
 #[cfg(not(target_arch = "spirv"))]
 pub mod kernel_trait {
+    use crate::Float;
+    use crate::error::TsDistanceError;
+    use crate::kernels::BoundaryCondition;
     use crate::utils::SubBuffersAllocator;
     use std::sync::Arc;
     use vulkano::buffer::Subbuffer;
@@ -384,13 +850,104 @@ pub mod kernel_trait {
     use vulkano::device::Device;
 
     pub trait GpuKernelImpl {
-        type KernelParams;
+        /// Device buffers of the kernel's vector parameters, cheap to clone so that they can
+        /// be built once and shared by several runs, see `warps::build_kernel_params`.
+        type KernelParams: Clone;
+
+        /// Largest `|i - j|` of the cells the kernel computes, every other one being
+        /// `init_val`. Whole diamonds outside the band are then never dispatched.
+        fn band(&self) -> Option<u64> {
+            None
+        }
+
+        /// Whether the kernel accumulates squared differences, so that its distances are
+        /// square rooted when `RunOptions::with_sqrt_output` asks for it.
+        fn squared_cost(&self) -> bool {
+            false
+        }
+
+        fn boundary(&self) -> BoundaryCondition {
+            BoundaryCondition::Closed
+        }
+
+        /// Values kept for every offset of the rolling diagonal, 2 for the kernels declared
+        /// with `state`, whose second state takes the upper half of each pair's diagonal.
+        /// Must be a power of two, as the diagonal length it multiplies.
+        fn diagonal_states(&self) -> usize {
+            1
+        }
+
+        /// Bytes of device memory taken by the buffers of `build_kernel_params`.
+        fn kernel_params_bytes(&self) -> u64 {
+            0
+        }
+
+        fn build_kernel_params(
+            &self,
+            allocator: SubBuffersAllocator,
+            builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        ) -> Result<Self::KernelParams, TsDistanceError>;
+
+        fn dispatch(
+            &self,
+            device: Arc<Device>,
+            stsa: Arc<StandardDescriptorSetAllocator>,
+            builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+            first_coord: i64,
+            row: u64,
+            tile_count: u64,
+            a_start: u64,
+            b_start: u64,
+            a_len: u64,
+            b_len: u64,
+            max_subgroup_threads: u64,
+            pair_layout: crate::kernels::PairLayout,
+            a: &Subbuffer<[Float]>,
+            b: &Subbuffer<[Float]>,
+            a_lengths: &Subbuffer<[u32]>,
+            b_lengths: &Subbuffer<[u32]>,
+            diagonal: &mut Subbuffer<[Float]>,
+            cells: &mut Subbuffer<[Float]>,
+            kernel_params: &Self::KernelParams,
+        ) -> Result<(), TsDistanceError>;
+    }
+
+    /// Runs `kernel` under `boundary` instead of the closed boundary conditions.
+    pub struct WithBoundary<G> {
+        pub kernel: G,
+        pub boundary: BoundaryCondition,
+    }
+
+    impl<G: GpuKernelImpl> GpuKernelImpl for WithBoundary<G> {
+        type KernelParams = G::KernelParams;
+
+        fn band(&self) -> Option<u64> {
+            self.kernel.band()
+        }
+
+        fn squared_cost(&self) -> bool {
+            self.kernel.squared_cost()
+        }
+
+        fn kernel_params_bytes(&self) -> u64 {
+            self.kernel.kernel_params_bytes()
+        }
+
+        fn boundary(&self) -> BoundaryCondition {
+            self.boundary
+        }
+
+        fn diagonal_states(&self) -> usize {
+            self.kernel.diagonal_states()
+        }
 
         fn build_kernel_params(
             &self,
             allocator: SubBuffersAllocator,
             builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
-        ) -> Self::KernelParams;
+        ) -> Result<Self::KernelParams, TsDistanceError> {
+            self.kernel.build_kernel_params(allocator, builder)
+        }
 
         fn dispatch(
             &self,
@@ -405,79 +962,2146 @@ pub mod kernel_trait {
             a_len: u64,
             b_len: u64,
             max_subgroup_threads: u64,
-            a: &Subbuffer<[f32]>,
-            b: &Subbuffer<[f32]>,
-            diagonal: &mut Subbuffer<[f32]>,
+            pair_layout: crate::kernels::PairLayout,
+            a: &Subbuffer<[Float]>,
+            b: &Subbuffer<[Float]>,
+            a_lengths: &Subbuffer<[u32]>,
+            b_lengths: &Subbuffer<[u32]>,
+            diagonal: &mut Subbuffer<[Float]>,
+            cells: &mut Subbuffer<[Float]>,
             kernel_params: &Self::KernelParams,
-        );
+        ) -> Result<(), TsDistanceError> {
+            self.kernel.dispatch(
+                device,
+                stsa,
+                builder,
+                first_coord,
+                row,
+                tile_count,
+                a_start,
+                b_start,
+                a_len,
+                b_len,
+                max_subgroup_threads,
+                pair_layout,
+                a,
+                b,
+                a_lengths,
+                b_lengths,
+                diagonal,
+                cells,
+                kernel_params,
+            )
+        }
+    }
+}
+
+#[cfg(not(target_arch = "spirv"))]
+pub mod custom {
+    //! Diamond partitioning over a kernel compiled outside this crate, registered with
+    //! `register_custom_kernel`. The entry point is dispatched exactly as the kernels of
+    //! `warp_kernel_spec!`, so it has to follow their interface:
+    //!
+    //! - storage buffers in descriptor set 0: the diagonals at binding 0, the series of `a`
+    //!   and `b` at 1 and 2, the vector parameter at 3, the lengths of the `a` and `b` series
+    //!   (`u32`) at 4 and 5, and the cost matrix cells at 6. Unused bindings may be left out.
+    //! - push constants: the 112 bytes of `CustomKernelConstants`, up to `subgroup_sync`,
+    //!   directly followed by `CustomKernel::params`, within the `max_push_constants_size` of
+    //!   the device (often 128 bytes).
+    //! - workgroup size: the `LocalSize` declared by the shader is ignored, loading the module
+    //!   overwrites it with the `max_compute_work_group_size[0]` of the device.
+    //!
+    //! The simplest way to get there is to copy a kernel of `warp_kernel_spec!` into a
+    //! rust-gpu crate and change its body.
+
+    use crate::Float;
+    use crate::error::TsDistanceError;
+    use crate::kernels::kernel_trait::GpuKernelImpl;
+    use crate::utils::{SubBufferPair, SubBuffersAllocator};
+    use std::sync::Arc;
+    use vulkano::buffer::{BufferContents, Subbuffer};
+    use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
+    use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+    use vulkano::descriptor_set::{DescriptorSet, WriteDescriptorSet};
+    use vulkano::device::Device;
+    use vulkano::pipeline::{Pipeline, PipelineBindPoint};
+
+    pub use crate::shader_load::register_custom_kernel;
+
+    /// The push constants every diamond partitioning kernel starts with.
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct CustomKernelConstants {
+        pub first_coord: i64,
+        pub row: u64,
+        pub tile_count: u64,
+        pub a_start: u64,
+        pub b_start: u64,
+        pub a_len: u64,
+        pub b_len: u64,
+        pub a_count: u64,
+        pub b_count: u64,
+        pub diag_len: u64,
+        pub max_subgroup_threads: u64,
+        pub pair_layout: u64,
+        pub dim: u64,
+        /// Non-zero when tiles may synchronize with subgroup barriers instead of workgroup ones.
+        pub subgroup_sync: u64,
+    }
+
+    /// The vector parameter of a `CustomKernel` on the device, with the allocator of its run.
+    #[derive(Clone)]
+    pub struct CustomKernelParams {
+        pub vector: Subbuffer<[Float]>,
+        allocator: SubBuffersAllocator,
+    }
+
+    /// A registered entry point with its scalar parameters `params`, pushed right after
+    /// `CustomKernelConstants` and laid out as the shader expects them, padding included, and
+    /// its vector parameter `vector`.
+    pub struct CustomKernel<P> {
+        pub entry: &'static str,
+        pub params: P,
+        pub vector: Vec<Float>,
+    }
+
+    impl<P: BufferContents + Copy> GpuKernelImpl for CustomKernel<P> {
+        type KernelParams = CustomKernelParams;
+
+        fn kernel_params_bytes(&self) -> u64 {
+            (self.vector.len().max(1) * std::mem::size_of::<Float>()) as u64
+        }
+
+        fn build_kernel_params(
+            &self,
+            allocator: SubBuffersAllocator,
+            builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        ) -> Result<Self::KernelParams, TsDistanceError> {
+            // A buffer cannot be empty, even when the kernel has no vector parameter.
+            let vector = if self.vector.is_empty() {
+                vec![0.0]
+            } else {
+                self.vector.clone()
+            };
+            let vector =
+                SubBufferPair::new(&allocator, vector.len() as u64)?.move_gpu(&vector, builder)?;
+            Ok(CustomKernelParams { vector, allocator })
+        }
+
+        fn dispatch(
+            &self,
+            device: Arc<Device>,
+            dsa: Arc<StandardDescriptorSetAllocator>,
+            builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+            first_coord: i64,
+            row: u64,
+            tile_count: u64,
+            a_start: u64,
+            b_start: u64,
+            a_len: u64,
+            b_len: u64,
+            max_subgroup_threads: u64,
+            pair_layout: crate::kernels::PairLayout,
+            a: &Subbuffer<[Float]>,
+            b: &Subbuffer<[Float]>,
+            a_lengths: &Subbuffer<[u32]>,
+            b_lengths: &Subbuffer<[u32]>,
+            diagonal: &mut Subbuffer<[Float]>,
+            cells: &mut Subbuffer<[Float]>,
+            kernel_params: &Self::KernelParams,
+        ) -> Result<(), TsDistanceError> {
+            let a_count = a_lengths.len();
+            let b_count = b_lengths.len();
+            let dim = a.len() / (a_count * a_len);
+            let pair_count = pair_layout.pair_count(a_count as usize, b_count as usize) as u64;
+            let threads_count = (pair_count * tile_count * max_subgroup_threads) as u32;
+            let diag_len = diagonal.len() as u64 / pair_count;
+            crate::kernels::check_diag_len(diag_len, pair_count, diagonal.len());
+            let subgroup_sync = kernel_params
+                .allocator
+                .subgroup_sync(&device, max_subgroup_threads as usize);
+
+            log::trace!(
+                kernel_name = self.entry,
+                row,
+                tile_count,
+                a_len,
+                b_len,
+                a_count,
+                b_count,
+                pair_layout = pair_layout as u64,
+                dim,
+                diag_len,
+                subgroup_sync,
+                threads_count;
+                "Dispatching kernel"
+            );
+
+            // The shader was compiled elsewhere, so its constants can't be moved to a buffer.
+            let push_size = size_of::<CustomKernelConstants>() + size_of::<P>();
+            let max_push_size = kernel_params.allocator.max_push_constants_size(&device);
+            if push_size > max_push_size {
+                return Err(TsDistanceError::Shader(format!(
+                    "the push constants of {} take {} bytes, the device allows {}",
+                    self.entry, push_size, max_push_size
+                )));
+            }
+
+            let pipeline =
+                crate::shader_load::get_shader_entry_pipeline(device.clone(), self.entry)?;
+            let layout = &pipeline.layout().set_layouts()[0];
+            let writes = [
+                WriteDescriptorSet::buffer(0, diagonal.clone()),
+                WriteDescriptorSet::buffer(1, a.clone()),
+                WriteDescriptorSet::buffer(2, b.clone()),
+                WriteDescriptorSet::buffer(3, kernel_params.vector.clone()),
+                WriteDescriptorSet::buffer(4, a_lengths.clone()),
+                WriteDescriptorSet::buffer(5, b_lengths.clone()),
+                WriteDescriptorSet::buffer(6, cells.clone()),
+            ]
+            .into_iter()
+            .filter(|write| layout.bindings().contains_key(&write.binding()));
+            let set = DescriptorSet::new(dsa, layout.clone(), writes, [])?;
+
+            let constants = CustomKernelConstants {
+                first_coord,
+                row,
+                tile_count,
+                a_start,
+                b_start,
+                a_len,
+                b_len,
+                a_count,
+                b_count,
+                diag_len,
+                max_subgroup_threads,
+                pair_layout: pair_layout as u64,
+                dim,
+                subgroup_sync: subgroup_sync as u64,
+            };
+
+            builder
+                .bind_pipeline_compute(pipeline.clone())?
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Compute,
+                    pipeline.layout().clone(),
+                    0,
+                    set,
+                )?
+                .push_constants(pipeline.layout().clone(), 0, constants)?
+                .push_constants(
+                    pipeline.layout().clone(),
+                    size_of::<CustomKernelConstants>() as u32,
+                    self.params,
+                )?;
+
+            let max_threads_x = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_size[0];
+            let workgroup_count = threads_count.div_ceil(max_threads_x);
+
+            kernel_params
+                .allocator
+                .trace_dispatch(|| crate::utils::DispatchRecord {
+                    kernel_name: self.entry,
+                    first_coord,
+                    row,
+                    tile_count,
+                    a_start,
+                    b_start,
+                    a_len,
+                    b_len,
+                    a_count,
+                    b_count,
+                    diag_len,
+                    max_subgroup_threads,
+                    pair_layout: pair_layout as u64,
+                    dim,
+                    subgroup_sync,
+                    threads_count,
+                    workgroup_count,
+                });
+
+            unsafe { builder.dispatch([workgroup_count, 1u32, 1u32]) }?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_arch = "spirv"))]
+pub mod standalone {
+    use crate::error::TsDistanceError;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use vulkano::buffer::BufferContents;
+    use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
+    use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+    use vulkano::descriptor_set::{DescriptorSet, WriteDescriptorSet};
+    use vulkano::device::{Device, Queue};
+    use vulkano::pipeline::{Pipeline, PipelineBindPoint};
+    use vulkano::sync::GpuFuture;
+    use vulkano::sync::future::FenceSignalFuture;
+    use vulkano::{Validated, VulkanError};
+
+    /// Records a dispatch of an entry point that does not go through diamond partitioning.
+    /// `buffers` are bound to descriptor set 0 and `constants` are pushed as is.
+    pub fn dispatch<C: BufferContents>(
+        device: Arc<Device>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        shader_name: &'static str,
+        buffers: impl IntoIterator<Item = WriteDescriptorSet>,
+        constants: C,
+        group_count: u32,
+    ) -> Result<(), TsDistanceError> {
+        log::trace!(kernel_name = shader_name, group_count; "Dispatching kernel");
+
+        let pipeline = crate::shader_load::get_shader_entry_pipeline(device, shader_name)?;
+        let layout = &pipeline.layout().set_layouts()[0];
+        let set = DescriptorSet::new(dsa, layout.clone(), buffers, [])?;
+
+        builder
+            .bind_pipeline_compute(pipeline.clone())?
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                pipeline.layout().clone(),
+                0,
+                set,
+            )?
+            .push_constants(pipeline.layout().clone(), 0, constants)?;
+
+        unsafe { builder.dispatch([group_count, 1u32, 1u32]) }?;
+        Ok(())
+    }
+
+    pub fn submit_and_wait(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        builder: AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        timeout: Option<Duration>,
+    ) -> Result<(), TsDistanceError> {
+        let command_buffer = builder.build()?;
+        let future = vulkano::sync::now(device)
+            .then_execute(queue, command_buffer)?
+            .then_signal_fence_and_flush()?;
+        wait(future, timeout)
+    }
+
+    /// Same as `submit_and_wait`, with `transfer` first submitted to `transfer_queue`. The
+    /// compute queue waits for it on a semaphore, so only `builder` is waited for on the host.
+    pub fn submit_after_transfer(
+        device: Arc<Device>,
+        transfer_queue: Arc<Queue>,
+        transfer: AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        queue: Arc<Queue>,
+        builder: AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        timeout: Option<Duration>,
+    ) -> Result<(), TsDistanceError> {
+        let future = vulkano::sync::now(device)
+            .then_execute(transfer_queue, transfer.build()?)?
+            .then_signal_semaphore_and_flush()?
+            .then_execute(queue, builder.build()?)?
+            .then_signal_fence_and_flush()?;
+        wait(future, timeout)
+    }
+
+    /// A future that timed out, kept until the device is done with it.
+    trait TimedOut: Send {
+        /// Whether the device finished within `timeout`, so that dropping no longer blocks.
+        fn finished(&self, timeout: Option<Duration>) -> bool;
+    }
+
+    impl<F: GpuFuture + Send> TimedOut for FenceSignalFuture<F> {
+        fn finished(&self, timeout: Option<Duration>) -> bool {
+            !matches!(
+                FenceSignalFuture::wait(self, timeout),
+                Err(Validated::Error(VulkanError::Timeout))
+            )
+        }
+    }
+
+    static TIMED_OUT: Mutex<Vec<Box<dyn TimedOut>>> = Mutex::new(Vec::new());
+
+    /// Waits for `future` for at most `timeout`. A future that timed out is parked rather
+    /// than dropped, since dropping it blocks until the device is done, which a hung device
+    /// never is. The parked futures the device has finished since are released on every
+    /// call, see `release_timed_out`.
+    pub fn wait<F: GpuFuture + Send + 'static>(
+        future: FenceSignalFuture<F>,
+        timeout: Option<Duration>,
+    ) -> Result<(), TsDistanceError> {
+        release_timed_out(Some(Duration::ZERO));
+        match future.wait(timeout) {
+            Err(Validated::Error(VulkanError::Timeout)) => {
+                TIMED_OUT.lock().unwrap().push(Box::new(future));
+                Err(TsDistanceError::Timeout(timeout.unwrap_or_default()))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Gives every future parked by a timed out `wait` up to `timeout` more to finish and
+    /// releases the finished ones, returning how many are still pending.
+    pub fn release_timed_out(timeout: Option<Duration>) -> usize {
+        let mut parked = TIMED_OUT.lock().unwrap();
+        parked.retain(|future| !future.finished(timeout));
+        parked.len()
+    }
+
+    /// Number of threads taking part in a workgroup reduction: the workgroup size the
+    /// shaders are patched with, clamped to the shared memory array they reduce into.
+    pub fn reduction_size(device: &Device) -> u64 {
+        let max_threads_x = device
+            .physical_device()
+            .properties()
+            .max_compute_work_group_size[0] as usize;
+        let size = max_threads_x.min(super::REDUCTION_SIZE);
+        // Round down to a power of two so the reduction tree halves cleanly.
+        (1usize << (usize::BITS - 1 - size.leading_zeros())) as u64
+    }
+}
+
+/// Size of the workgroup shared arrays used by the reduction kernels.
+pub const REDUCTION_SIZE: usize = 1024;
+
+/// Sum of `value` over the first `reduction_size` lanes, available to every lane.
+///
+/// Lane `l` first accumulates its own strided terms in order, then the partials are folded
+/// pairwise, `partial[l] += partial[l + stride]` for `stride = reduction_size / 2, ..., 1`.
+/// The tree only depends on `reduction_size`, which is fixed per device, so every sum
+/// reduction is bit-reproducible across runs on the same device.
+#[cfg(target_arch = "spirv")]
+#[inline(always)]
+fn workgroup_sum(
+    partial: &mut [Float; REDUCTION_SIZE],
+    lane: u64,
+    reduction_size: u64,
+    value: Float,
+) -> Float {
+    if lane < reduction_size {
+        partial[lane as usize] = value;
+    }
+    unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+
+    let mut stride = reduction_size / 2;
+    while stride > 0 {
+        if lane < stride {
+            partial[lane as usize] += partial[(lane + stride) as usize];
+        }
+        unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+        stride /= 2;
+    }
+
+    let sum = partial[0];
+    // Nobody may overwrite the partials before every lane has read the sum.
+    unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+    sum
+}
+
+pub mod euclidean_distance {
+    #[cfg(not(target_arch = "spirv"))]
+    pub mod cpu {
+        use crate::Float;
+        use crate::error::TsDistanceError;
+        use crate::kernels::standalone;
+        use crate::utils::{RunOptions, SubBufferPair, SubBuffersAllocator, check_batch};
+        use std::sync::Arc;
+        use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+        use vulkano::descriptor_set::WriteDescriptorSet;
+        use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+        use vulkano::device::{Device, Queue};
+
+        pub fn euclidean_gpu(
+            device: Arc<Device>,
+            queue: Arc<Queue>,
+            command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+            descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+            subbuffer_allocator: SubBuffersAllocator,
+            options: &RunOptions,
+            a: &Vec<Vec<Float>>,
+            b: &Vec<Vec<Float>>,
+        ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+            check_batch("a", a)?;
+            check_batch("b", b)?;
+            let a = options.nan_policy().apply("a", a)?;
+            let b = options.nan_policy().apply("b", b)?;
+            let len = a[0].len();
+            if a.iter().chain(b.iter()).any(|ts| ts.len() != len) {
+                return Err(TsDistanceError::InvalidInput(
+                    "euclidean distance requires all series to have the same length".to_string(),
+                ));
+            }
+
+            let a_count = a.len();
+            let b_count = b.len();
+            let pairs_count = (a_count * b_count) as u64;
+            let max_groups = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_count[0] as u64;
+
+            let a_flat = a.concat();
+            let b_flat = b.concat();
+
+            let a_buffer = SubBufferPair::new(&subbuffer_allocator, a_flat.len() as u64)?;
+            let b_buffer = SubBufferPair::new(&subbuffer_allocator, b_flat.len() as u64)?;
+            let result_buffer = SubBufferPair::<Float>::new(&subbuffer_allocator, pairs_count)?;
+
+            let mut builder = AutoCommandBufferBuilder::primary(
+                command_buffer_allocator,
+                queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )?;
+
+            let a_gpu = a_buffer.move_gpu(&a_flat, &mut builder)?;
+            let b_gpu = b_buffer.move_gpu(&b_flat, &mut builder)?;
+            let result_gpu = result_buffer.gpu();
+
+            // One workgroup per pair, split in several dispatches if there are more pairs
+            // than the device allows workgroups in a single one.
+            for pair_offset in (0..pairs_count).step_by(max_groups as usize) {
+                let group_count = (pairs_count - pair_offset).min(max_groups) as u32;
+                standalone::dispatch(
+                    device.clone(),
+                    descriptor_set_allocator.clone(),
+                    &mut builder,
+                    "kernels::euclidean_distance::batch_call",
+                    [
+                        WriteDescriptorSet::buffer(0, result_gpu.clone()),
+                        WriteDescriptorSet::buffer(1, a_gpu.clone()),
+                        WriteDescriptorSet::buffer(2, b_gpu.clone()),
+                    ],
+                    super::EuclideanConstants {
+                        a_count: a_count as u64,
+                        b_count: b_count as u64,
+                        len: len as u64,
+                        pair_offset,
+                        reduction_size: standalone::reduction_size(&device),
+                    },
+                    group_count,
+                )?;
+            }
+
+            let result = result_buffer.move_cpu(&mut builder)?;
+            standalone::submit_and_wait(device, queue, builder, options.timeout())?;
+
+            let result = result.read()?;
+            let dist_matrix = (0..a_count)
+                .map(|i| result[i * b_count..(i + 1) * b_count].to_vec())
+                .collect();
+
+            subbuffer_allocator.clear();
+
+            Ok(dist_matrix)
+        }
+    }
+
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct EuclideanConstants {
+        a_count: u64,
+        b_count: u64,
+        len: u64,
+        pair_offset: u64,
+        reduction_size: u64,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use {
+        crate::Float,
+        spirv_std::{glam::UVec3, num_traits::Float as _, spirv},
+    };
+
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(workgroup_id)] group_id: UVec3,
+        #[spirv(local_invocation_id)] local_id: UVec3,
+        #[spirv(push_constant)] constants: &EuclideanConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] result: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] a: &[Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] b: &[Float],
+        #[spirv(workgroup)] partial: &mut [Float; super::REDUCTION_SIZE],
+    ) {
+        let pair_index = constants.pair_offset + group_id.x as u64;
+        let pairs_count = constants.a_count * constants.b_count;
+        let lane = local_id.x as u64;
+
+        let mut sum = 0.0;
+        if pair_index < pairs_count && lane < constants.reduction_size {
+            let a_offset = (pair_index / constants.b_count * constants.len) as usize;
+            let b_offset = (pair_index % constants.b_count * constants.len) as usize;
+            let mut t = lane;
+            while t < constants.len {
+                let diff = a[a_offset + t as usize] - b[b_offset + t as usize];
+                sum += diff * diff;
+                t += constants.reduction_size;
+            }
+        }
+        let sum = super::workgroup_sum(partial, lane, constants.reduction_size, sum);
+
+        if lane == 0 && pair_index < pairs_count {
+            result[pair_index as usize] = sum.sqrt();
+        }
+    }
+}
+
+pub mod lb_keogh {
+    #[cfg(not(target_arch = "spirv"))]
+    pub mod cpu {
+        use crate::Float;
+        use crate::error::TsDistanceError;
+        use crate::kernels::standalone;
+        use crate::utils::{RunOptions, SubBufferPair, SubBuffersAllocator, check_batch};
+        use std::sync::Arc;
+        use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+        use vulkano::descriptor_set::WriteDescriptorSet;
+        use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+        use vulkano::device::{Device, Queue};
+
+        pub fn lb_keogh_gpu(
+            device: Arc<Device>,
+            queue: Arc<Queue>,
+            command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+            descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+            subbuffer_allocator: SubBuffersAllocator,
+            options: &RunOptions,
+            lower: &[Float],
+            upper: &[Float],
+            candidates: &Vec<Vec<Float>>,
+        ) -> Result<Vec<Float>, TsDistanceError> {
+            if lower.is_empty() {
+                return Err(TsDistanceError::InvalidInput(
+                    "LB_Keogh query is empty".to_string(),
+                ));
+            }
+            check_batch("candidates", candidates)?;
+            let candidates = options.nan_policy().apply("candidates", candidates)?;
+            let len = lower.len();
+            if candidates.iter().any(|ts| ts.len() != len) {
+                return Err(TsDistanceError::InvalidInput(
+                    "LB_Keogh requires candidates of the same length as the query".to_string(),
+                ));
+            }
+
+            let count = candidates.len() as u64;
+            let max_groups = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_count[0] as u64;
+
+            let candidates_flat = candidates.concat();
+
+            let candidates_buffer =
+                SubBufferPair::new(&subbuffer_allocator, candidates_flat.len() as u64)?;
+            let lower_buffer = SubBufferPair::new(&subbuffer_allocator, len as u64)?;
+            let upper_buffer = SubBufferPair::new(&subbuffer_allocator, len as u64)?;
+            let result_buffer = SubBufferPair::<Float>::new(&subbuffer_allocator, count)?;
+
+            let mut builder = AutoCommandBufferBuilder::primary(
+                command_buffer_allocator,
+                queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )?;
+
+            let candidates_gpu = candidates_buffer.move_gpu(&candidates_flat, &mut builder)?;
+            let lower_gpu = lower_buffer.move_gpu(lower, &mut builder)?;
+            let upper_gpu = upper_buffer.move_gpu(upper, &mut builder)?;
+            let result_gpu = result_buffer.gpu();
+
+            for candidate_offset in (0..count).step_by(max_groups as usize) {
+                let group_count = (count - candidate_offset).min(max_groups) as u32;
+                standalone::dispatch(
+                    device.clone(),
+                    descriptor_set_allocator.clone(),
+                    &mut builder,
+                    "kernels::lb_keogh::batch_call",
+                    [
+                        WriteDescriptorSet::buffer(0, result_gpu.clone()),
+                        WriteDescriptorSet::buffer(1, candidates_gpu.clone()),
+                        WriteDescriptorSet::buffer(2, lower_gpu.clone()),
+                        WriteDescriptorSet::buffer(3, upper_gpu.clone()),
+                    ],
+                    super::LBKeoghConstants {
+                        count,
+                        len: len as u64,
+                        candidate_offset,
+                        reduction_size: standalone::reduction_size(&device),
+                    },
+                    group_count,
+                )?;
+            }
+
+            let result = result_buffer.move_cpu(&mut builder)?;
+            standalone::submit_and_wait(device, queue, builder, options.timeout())?;
+
+            let bounds = result.read()?.to_vec();
+
+            subbuffer_allocator.clear();
+
+            Ok(bounds)
+        }
+    }
+
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct LBKeoghConstants {
+        count: u64,
+        len: u64,
+        candidate_offset: u64,
+        reduction_size: u64,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use {
+        crate::Float,
+        spirv_std::{glam::UVec3, spirv},
+    };
+
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(workgroup_id)] group_id: UVec3,
+        #[spirv(local_invocation_id)] local_id: UVec3,
+        #[spirv(push_constant)] constants: &LBKeoghConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] result: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] candidates: &[Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] lower: &[Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] upper: &[Float],
+        #[spirv(workgroup)] partial: &mut [Float; super::REDUCTION_SIZE],
+    ) {
+        let candidate_index = constants.candidate_offset + group_id.x as u64;
+        let lane = local_id.x as u64;
+
+        let mut sum = 0.0;
+        if candidate_index < constants.count && lane < constants.reduction_size {
+            let offset = (candidate_index * constants.len) as usize;
+            let mut t = lane;
+            while t < constants.len {
+                let value = candidates[offset + t as usize];
+                let l = lower[t as usize];
+                let u = upper[t as usize];
+                if value > u {
+                    sum += (value - u) * (value - u);
+                } else if value < l {
+                    sum += (value - l) * (value - l);
+                }
+                t += constants.reduction_size;
+            }
+        }
+        let sum = super::workgroup_sum(partial, lane, constants.reduction_size, sum);
+
+        if lane == 0 && candidate_index < constants.count {
+            result[candidate_index as usize] = sum;
+        }
+    }
+}
+
+pub mod envelopes {
+    #[cfg(not(target_arch = "spirv"))]
+    pub mod cpu {
+        use crate::Float;
+        use crate::error::TsDistanceError;
+        use crate::kernels::standalone;
+        use crate::utils::{RunOptions, SubBufferPair, SubBuffersAllocator, check_batch};
+        use std::sync::Arc;
+        use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+        use vulkano::descriptor_set::WriteDescriptorSet;
+        use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+        use vulkano::device::{Device, Queue};
+
+        /// Keogh lower and upper envelopes of every series of `data`, as `utils::envelopes`
+        /// computes for one, with a thread per point. Series may differ in length.
+        pub fn envelopes_gpu(
+            device: Arc<Device>,
+            queue: Arc<Queue>,
+            command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+            descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+            subbuffer_allocator: SubBuffersAllocator,
+            options: &RunOptions,
+            data: &Vec<Vec<Float>>,
+            window: usize,
+        ) -> Result<(Vec<Vec<Float>>, Vec<Vec<Float>>), TsDistanceError> {
+            check_batch("data", data)?;
+            let data = options.nan_policy().apply("data", data)?;
+
+            let count = data.len();
+            let max_len = data.iter().map(|ts| ts.len()).max().unwrap_or(0);
+            let lengths = data.iter().map(|ts| ts.len() as u32).collect::<Vec<_>>();
+            let mut flat = vec![0.0; count * max_len];
+            for (i, ts) in data.iter().enumerate() {
+                flat[i * max_len..i * max_len + ts.len()].copy_from_slice(ts);
+            }
+
+            let points = (count * max_len) as u64;
+            let max_threads_x = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_size[0] as u64;
+            let max_groups = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_count[0] as u64;
+            let points_per_dispatch = max_groups * max_threads_x;
+
+            let data_buffer = SubBufferPair::new(&subbuffer_allocator, points)?;
+            let lengths_buffer = SubBufferPair::new(&subbuffer_allocator, count as u64)?;
+            let lower_buffer = SubBufferPair::<Float>::new(&subbuffer_allocator, points)?;
+            let upper_buffer = SubBufferPair::<Float>::new(&subbuffer_allocator, points)?;
+
+            let mut builder = AutoCommandBufferBuilder::primary(
+                command_buffer_allocator,
+                queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )?;
+
+            let data_gpu = data_buffer.move_gpu(&flat, &mut builder)?;
+            let lengths_gpu = lengths_buffer.move_gpu(&lengths, &mut builder)?;
+
+            for point_offset in (0..points).step_by(points_per_dispatch as usize) {
+                let group_count = (points - point_offset)
+                    .min(points_per_dispatch)
+                    .div_ceil(max_threads_x) as u32;
+                standalone::dispatch(
+                    device.clone(),
+                    descriptor_set_allocator.clone(),
+                    &mut builder,
+                    "kernels::envelopes::batch_call",
+                    [
+                        WriteDescriptorSet::buffer(0, lower_buffer.gpu()),
+                        WriteDescriptorSet::buffer(1, upper_buffer.gpu()),
+                        WriteDescriptorSet::buffer(2, data_gpu.clone()),
+                        WriteDescriptorSet::buffer(3, lengths_gpu.clone()),
+                    ],
+                    super::EnvelopeConstants {
+                        points,
+                        max_len: max_len as u64,
+                        // Wider windows cover every series whole.
+                        window: window.min(max_len) as u64,
+                        point_offset,
+                    },
+                    group_count,
+                )?;
+            }
+
+            let lower = lower_buffer.move_cpu(&mut builder)?;
+            let upper = upper_buffer.move_cpu(&mut builder)?;
+            standalone::submit_and_wait(device, queue, builder, options.timeout())?;
+
+            let unpad = |flat: &[Float]| {
+                data.iter()
+                    .enumerate()
+                    .map(|(i, ts)| flat[i * max_len..i * max_len + ts.len()].to_vec())
+                    .collect::<Vec<_>>()
+            };
+            let envelopes = (unpad(&lower.read()?), unpad(&upper.read()?));
+
+            subbuffer_allocator.clear();
+
+            Ok(envelopes)
+        }
+    }
+
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct EnvelopeConstants {
+        points: u64,
+        max_len: u64,
+        window: u64,
+        point_offset: u64,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use {
+        crate::Float,
+        spirv_std::{glam::UVec3, spirv},
+    };
+
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(global_invocation_id)] global_id: UVec3,
+        #[spirv(push_constant)] constants: &EnvelopeConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] lower: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] upper: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] data: &[Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] lengths: &[u32],
+    ) {
+        let point = constants.point_offset + global_id.x as u64;
+        if point >= constants.points {
+            return;
+        }
+        let series = point / constants.max_len;
+        let t = point % constants.max_len;
+        let len = lengths[series as usize] as u64;
+        if t >= len {
+            return;
+        }
+
+        let offset = series * constants.max_len;
+        let first = if t > constants.window {
+            t - constants.window
+        } else {
+            0
+        };
+        let last = (t + constants.window + 1).min(len);
+        let mut min = Float::INFINITY;
+        let mut max = Float::NEG_INFINITY;
+        let mut k = first;
+        while k < last {
+            let value = data[(offset + k) as usize];
+            min = min.min(value);
+            max = max.max(value);
+            k += 1;
+        }
+        lower[point as usize] = min;
+        upper[point as usize] = max;
+    }
+}
+
+pub mod sbd {
+    #[cfg(not(target_arch = "spirv"))]
+    pub mod cpu {
+        use crate::Float;
+        use crate::error::TsDistanceError;
+        use crate::kernels::standalone;
+        use crate::utils::{
+            RunOptions, SubBufferPair, SubBuffersAllocator, check_batch, znormalize,
+        };
+        use std::sync::Arc;
+        use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+        use vulkano::descriptor_set::WriteDescriptorSet;
+        use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+        use vulkano::device::{Device, Queue};
+
+        /// Shape-based distance of k-Shape, `1 - max_w NCC_w(a, b)` over the z-normalized
+        /// series. The cross-correlation at every shift is summed directly, one workgroup per
+        /// pair, which for the series lengths the diamond kernels handle is cheap enough not
+        /// to need an FFT.
+        pub fn sbd_gpu(
+            device: Arc<Device>,
+            queue: Arc<Queue>,
+            command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+            descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+            subbuffer_allocator: SubBuffersAllocator,
+            options: &RunOptions,
+            a: &Vec<Vec<Float>>,
+            b: &Vec<Vec<Float>>,
+        ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+            check_batch("a", a)?;
+            check_batch("b", b)?;
+            let a = options.nan_policy().apply("a", a)?;
+            let b = options.nan_policy().apply("b", b)?;
+            let len = a[0].len();
+            if a.iter().chain(b.iter()).any(|ts| ts.len() != len) {
+                return Err(TsDistanceError::InvalidInput(
+                    "SBD requires all series to have the same length".to_string(),
+                ));
+            }
+
+            let a = a.iter().map(|ts| znormalize(ts)).collect::<Vec<_>>();
+            let b = b.iter().map(|ts| znormalize(ts)).collect::<Vec<_>>();
+            let norm = |ts: &Vec<Float>| ts.iter().map(|x| x * x).sum::<Float>().sqrt();
+            let a_norms = a.iter().map(norm).collect::<Vec<_>>();
+            let b_norms = b.iter().map(norm).collect::<Vec<_>>();
+
+            let a_count = a.len();
+            let b_count = b.len();
+            let pairs_count = (a_count * b_count) as u64;
+            let max_groups = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_count[0] as u64;
+
+            let a_flat = a.concat();
+            let b_flat = b.concat();
+
+            let a_buffer = SubBufferPair::new(&subbuffer_allocator, a_flat.len() as u64)?;
+            let b_buffer = SubBufferPair::new(&subbuffer_allocator, b_flat.len() as u64)?;
+            let result_buffer = SubBufferPair::<Float>::new(&subbuffer_allocator, pairs_count)?;
+
+            let mut builder = AutoCommandBufferBuilder::primary(
+                command_buffer_allocator,
+                queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )?;
+
+            let a_gpu = a_buffer.move_gpu(&a_flat, &mut builder)?;
+            let b_gpu = b_buffer.move_gpu(&b_flat, &mut builder)?;
+            let result_gpu = result_buffer.gpu();
+
+            for pair_offset in (0..pairs_count).step_by(max_groups as usize) {
+                let group_count = (pairs_count - pair_offset).min(max_groups) as u32;
+                standalone::dispatch(
+                    device.clone(),
+                    descriptor_set_allocator.clone(),
+                    &mut builder,
+                    "kernels::sbd::batch_call",
+                    [
+                        WriteDescriptorSet::buffer(0, result_gpu.clone()),
+                        WriteDescriptorSet::buffer(1, a_gpu.clone()),
+                        WriteDescriptorSet::buffer(2, b_gpu.clone()),
+                    ],
+                    super::SBDConstants {
+                        a_count: a_count as u64,
+                        b_count: b_count as u64,
+                        len: len as u64,
+                        pair_offset,
+                        reduction_size: standalone::reduction_size(&device),
+                    },
+                    group_count,
+                )?;
+            }
+
+            let result = result_buffer.move_cpu(&mut builder)?;
+            standalone::submit_and_wait(device, queue, builder, options.timeout())?;
+
+            // The device returns the peak of the raw cross-correlation, normalized here. A
+            // series of zeros correlates with nothing.
+            let result = result.read()?;
+            let dist_matrix = (0..a_count)
+                .map(|i| {
+                    (0..b_count)
+                        .map(|j| {
+                            let scale = a_norms[i] * b_norms[j];
+                            let ncc = if scale > 0.0 {
+                                result[i * b_count + j] / scale
+                            } else {
+                                0.0
+                            };
+                            1.0 - ncc
+                        })
+                        .collect()
+                })
+                .collect();
+
+            subbuffer_allocator.clear();
+
+            Ok(dist_matrix)
+        }
+    }
+
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct SBDConstants {
+        a_count: u64,
+        b_count: u64,
+        len: u64,
+        pair_offset: u64,
+        reduction_size: u64,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use {
+        crate::Float,
+        spirv_std::{glam::UVec3, spirv},
+    };
+
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(workgroup_id)] group_id: UVec3,
+        #[spirv(local_invocation_id)] local_id: UVec3,
+        #[spirv(push_constant)] constants: &SBDConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] result: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] a: &[Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] b: &[Float],
+        #[spirv(workgroup)] partial: &mut [Float; super::REDUCTION_SIZE],
+    ) {
+        let pair_index = constants.pair_offset + group_id.x as u64;
+        let pairs_count = constants.a_count * constants.b_count;
+        let lane = local_id.x as u64;
+        let len = constants.len;
+
+        // Each lane takes a strided set of the 2 * len - 1 shifts, where shift s pairs
+        // a[t + s - (len - 1)] with b[t].
+        let mut peak = Float::NEG_INFINITY;
+        if pair_index < pairs_count && lane < constants.reduction_size {
+            let a_offset = (pair_index / constants.b_count * len) as usize;
+            let b_offset = (pair_index % constants.b_count * len) as usize;
+            let mut s = lane;
+            while s < 2 * len - 1 {
+                let (a_start, b_start) = if s < len {
+                    (0, len - 1 - s)
+                } else {
+                    (s - (len - 1), 0)
+                };
+                let mut sum = 0.0;
+                let mut t = 0;
+                while a_start + t < len && b_start + t < len {
+                    sum +=
+                        a[a_offset + (a_start + t) as usize] * b[b_offset + (b_start + t) as usize];
+                    t += 1;
+                }
+                if sum > peak {
+                    peak = sum;
+                }
+                s += constants.reduction_size;
+            }
+        }
+        if lane < constants.reduction_size {
+            partial[lane as usize] = peak;
+        }
+        unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+
+        // A max is exact whatever the order, but it folds with the same tree as the sums.
+        let mut stride = constants.reduction_size / 2;
+        while stride > 0 {
+            if lane < stride && partial[(lane + stride) as usize] > partial[lane as usize] {
+                partial[lane as usize] = partial[(lane + stride) as usize];
+            }
+            unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+            stride /= 2;
+        }
+
+        if lane == 0 && pair_index < pairs_count {
+            result[pair_index as usize] = partial[0];
+        }
+    }
+}
+
+pub mod dtw_stream {
+    #[cfg(not(target_arch = "spirv"))]
+    pub mod cpu {
+        use crate::Float;
+        use crate::error::TsDistanceError;
+        use crate::kernels::standalone;
+        use crate::utils::{RunOptions, SubBufferPair, SubBuffersAllocator};
+        use std::sync::Arc;
+        use vulkano::buffer::Subbuffer;
+        use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+        use vulkano::descriptor_set::WriteDescriptorSet;
+        use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+        use vulkano::device::{Device, Queue};
+
+        /// DTW between a fixed template and a query that grows one sample at a time. The last
+        /// row of the cost matrix stays on the device, so each push only computes the rows of
+        /// the new samples instead of the whole matrix. The template length is fixed when the
+        /// stream is created, the query may grow without bound.
+        pub struct StreamingDtw {
+            device: Arc<Device>,
+            queue: Arc<Queue>,
+            sba: Arc<StandardCommandBufferAllocator>,
+            dsa: Arc<StandardDescriptorSetAllocator>,
+            sa: SubBuffersAllocator,
+            options: RunOptions,
+            /// Rows of the samples of a push below the last row of the previous ones, each
+            /// `len + 1` wide with the boundary column first.
+            rows: Subbuffer<[Float]>,
+            last: SubBufferPair<Float>,
+            distance: Float,
+            template: Subbuffer<[Float]>,
+            len: usize,
+            /// Samples whose rows fit in a single dispatch, one lane each.
+            lanes: usize,
+            pushed: usize,
+        }
+
+        impl StreamingDtw {
+            pub fn new(
+                device: Arc<Device>,
+                queue: Arc<Queue>,
+                sba: Arc<StandardCommandBufferAllocator>,
+                dsa: Arc<StandardDescriptorSetAllocator>,
+                sa: SubBuffersAllocator,
+                options: &RunOptions,
+                template: &[Float],
+            ) -> Result<Self, TsDistanceError> {
+                if template.is_empty() {
+                    return Err(TsDistanceError::InvalidInput(
+                        "the template is empty".to_string(),
+                    ));
+                }
+                let template = options
+                    .nan_policy()
+                    .apply("template", &vec![template.to_vec()])?
+                    .concat();
+                let len = template.len();
+                // As many rows per dispatch as there are lanes, unless they outgrow a buffer.
+                let max_rows = sa.max_storage_floats(&device) / (len + 1);
+                if max_rows < 2 {
+                    return Err(TsDistanceError::InvalidInput(format!(
+                        "a template of {} values does not fit in a storage buffer",
+                        len
+                    )));
+                }
+                let lanes = (device
+                    .physical_device()
+                    .properties()
+                    .max_compute_work_group_size[0] as usize)
+                    .min(max_rows - 1);
+
+                // The row above the first sample: only the origin is reachable.
+                let mut first_row = vec![Float::INFINITY; (lanes + 1) * (len + 1)];
+                first_row[0] = 0.0;
+                let rows = SubBufferPair::new(&sa, first_row.len() as u64)?;
+                let template_buffer = SubBufferPair::new(&sa, len as u64)?;
+
+                let mut builder = AutoCommandBufferBuilder::primary(
+                    sba.clone(),
+                    queue.queue_family_index(),
+                    CommandBufferUsage::OneTimeSubmit,
+                )?;
+                let rows = rows.move_gpu(&first_row, &mut builder)?;
+                let template = template_buffer.move_gpu(&template, &mut builder)?;
+                standalone::submit_and_wait(
+                    device.clone(),
+                    queue.clone(),
+                    builder,
+                    options.timeout(),
+                )?;
+
+                Ok(Self {
+                    last: SubBufferPair::new(&sa, 1)?,
+                    distance: Float::INFINITY,
+                    device,
+                    queue,
+                    sba,
+                    dsa,
+                    sa,
+                    options: options.clone(),
+                    rows,
+                    template,
+                    len,
+                    lanes,
+                    pushed: 0,
+                })
+            }
+
+            pub fn push(&mut self, sample: Float) -> Result<(), TsDistanceError> {
+                self.extend(&[sample])
+            }
+
+            /// Appends several samples to the query in a single submission.
+            pub fn extend(&mut self, samples: &[Float]) -> Result<(), TsDistanceError> {
+                if samples.is_empty() {
+                    return Ok(());
+                }
+                let samples = self
+                    .options
+                    .nan_policy()
+                    .apply("samples", &vec![samples.to_vec()])?
+                    .concat();
+                let samples_buffer = SubBufferPair::new(&self.sa, samples.len() as u64)?;
+
+                let mut builder = AutoCommandBufferBuilder::primary(
+                    self.sba.clone(),
+                    self.queue.queue_family_index(),
+                    CommandBufferUsage::OneTimeSubmit,
+                )?;
+                let samples_gpu = samples_buffer.move_gpu(&samples, &mut builder)?;
+                for start in (0..samples.len()).step_by(self.lanes) {
+                    let count = (samples.len() - start).min(self.lanes);
+                    standalone::dispatch(
+                        self.device.clone(),
+                        self.dsa.clone(),
+                        &mut builder,
+                        "kernels::dtw_stream::batch_call",
+                        [
+                            WriteDescriptorSet::buffer(0, self.rows.clone()),
+                            WriteDescriptorSet::buffer(1, self.template.clone()),
+                            WriteDescriptorSet::buffer(
+                                2,
+                                samples_gpu
+                                    .clone()
+                                    .slice(start as u64..(start + count) as u64),
+                            ),
+                        ],
+                        super::StreamConstants {
+                            len: self.len as u64,
+                            count: count as u64,
+                            lanes: self.lanes as u64,
+                        },
+                        1,
+                    )?;
+                }
+                let corner = self
+                    .rows
+                    .clone()
+                    .slice(self.len as u64..self.len as u64 + 1);
+                self.last.copy_gpu(corner, &mut builder)?;
+                let last = self.last.move_cpu(&mut builder)?;
+                standalone::submit_and_wait(
+                    self.device.clone(),
+                    self.queue.clone(),
+                    builder,
+                    self.options.timeout(),
+                )?;
+                self.distance = last.read()?[0];
+                self.pushed += samples.len();
+                Ok(())
+            }
+
+            /// Number of samples of the query so far.
+            pub fn query_len(&self) -> usize {
+                self.pushed
+            }
+
+            /// DTW distance between the query so far and the template, infinite before the
+            /// first sample.
+            pub fn distance(&self) -> Float {
+                if self.options.sqrt_output() {
+                    self.distance.sqrt()
+                } else {
+                    self.distance
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct StreamConstants {
+        len: u64,
+        count: u64,
+        lanes: u64,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use {
+        crate::Float,
+        spirv_std::{glam::UVec3, spirv},
+    };
+
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(local_invocation_id)] local_id: UVec3,
+        #[spirv(push_constant)] constants: &StreamConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] rows: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] template: &[Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] samples: &[Float],
+    ) {
+        let lane = local_id.x as u64;
+        let width = constants.len + 1;
+        let count = constants.count;
+
+        // Lane r fills row r + 1 from the one above, a column behind the lane above it, so
+        // that the rows advance together along the anti-diagonals.
+        if lane < count {
+            rows[((lane + 1) * width) as usize] = Float::INFINITY;
+        }
+        unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+
+        let mut step = 0;
+        while step < constants.len + count - 1 {
+            if lane < count && step >= lane && step - lane < constants.len {
+                let j = step - lane + 1;
+                let above = (lane * width + j) as usize;
+                let cell = ((lane + 1) * width + j) as usize;
+                let cost = (samples[lane as usize] - template[(j - 1) as usize]).powi(2);
+                rows[cell] = cost + rows[cell - 1].min(rows[above - 1]).min(rows[above]);
+            }
+            unsafe { spirv_std::arch::workgroup_memory_barrier_with_group_sync() };
+            step += 1;
+        }
+
+        // The last new row becomes the one above the next push.
+        let mut j = lane;
+        while j < width {
+            rows[j as usize] = rows[(count * width + j) as usize];
+            j += constants.lanes;
+        }
+    }
+}
+
+pub mod znormalize {
+    #[cfg(not(target_arch = "spirv"))]
+    pub mod cpu {
+        use crate::Float;
+        use crate::error::TsDistanceError;
+        use crate::kernels::standalone;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use vulkano::buffer::Subbuffer;
+        use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+        use vulkano::descriptor_set::WriteDescriptorSet;
+        use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+        use vulkano::device::{Device, Queue};
+
+        /// Z-normalizes in place each channel of `count` padded series laid out as in
+        /// `GpuSeries`. Series with a zero standard deviation are left unchanged.
+        pub fn znormalize_gpu(
+            device: Arc<Device>,
+            queue: Arc<Queue>,
+            command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+            descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+            data: Subbuffer<[Float]>,
+            lengths: Subbuffer<[u32]>,
+            count: usize,
+            padded_len: usize,
+            dim: usize,
+            timeout: Option<Duration>,
+        ) -> Result<(), TsDistanceError> {
+            let count = count as u64;
+            let max_groups = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_count[0] as u64;
+
+            let mut builder = AutoCommandBufferBuilder::primary(
+                command_buffer_allocator,
+                queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )?;
+
+            // One workgroup per series.
+            for series_offset in (0..count).step_by(max_groups as usize) {
+                let group_count = (count - series_offset).min(max_groups) as u32;
+                standalone::dispatch(
+                    device.clone(),
+                    descriptor_set_allocator.clone(),
+                    &mut builder,
+                    "kernels::znormalize::batch_call",
+                    [
+                        WriteDescriptorSet::buffer(0, data.clone()),
+                        WriteDescriptorSet::buffer(1, lengths.clone()),
+                    ],
+                    super::ZNormalizeConstants {
+                        count,
+                        len: padded_len as u64,
+                        dim: dim as u64,
+                        series_offset,
+                        reduction_size: standalone::reduction_size(&device),
+                    },
+                    group_count,
+                )?;
+            }
+
+            standalone::submit_and_wait(device, queue, builder, timeout)
+        }
+    }
+
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct ZNormalizeConstants {
+        count: u64,
+        len: u64,
+        dim: u64,
+        series_offset: u64,
+        reduction_size: u64,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use {
+        crate::Float,
+        spirv_std::{glam::UVec3, num_traits::Float as _, spirv},
+    };
+
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(workgroup_id)] group_id: UVec3,
+        #[spirv(local_invocation_id)] local_id: UVec3,
+        #[spirv(push_constant)] constants: &ZNormalizeConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] data: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] lengths: &[u32],
+        #[spirv(workgroup)] partial: &mut [Float; super::REDUCTION_SIZE],
+    ) {
+        let series = constants.series_offset + group_id.x as u64;
+        let lane = local_id.x as u64;
+        let rs = constants.reduction_size;
+        let dim = constants.dim;
+
+        // Every lane has to reach the barriers, so out of range groups just see an empty series.
+        let len = if series < constants.count {
+            lengths[series as usize] as u64
+        } else {
+            0
+        };
+        let base = series * constants.len * dim;
+
+        for c in 0..dim {
+            let mut sum = 0.0;
+            let mut t = lane;
+            while lane < rs && t < len {
+                sum += data[(base + t * dim + c) as usize];
+                t += rs;
+            }
+            let mean = super::workgroup_sum(partial, lane, rs, sum) / len as Float;
+
+            let mut squares = 0.0;
+            let mut t = lane;
+            while lane < rs && t < len {
+                let diff = data[(base + t * dim + c) as usize] - mean;
+                squares += diff * diff;
+                t += rs;
+            }
+            let std = (super::workgroup_sum(partial, lane, rs, squares) / len as Float).sqrt();
+
+            if std > Float::EPSILON {
+                let mut t = lane;
+                while lane < rs && t < len {
+                    let index = (base + t * dim + c) as usize;
+                    data[index] = (data[index] - mean) / std;
+                    t += rs;
+                }
+            }
+        }
+    }
+}
+
+pub mod widen_f16 {
+    #[cfg(not(target_arch = "spirv"))]
+    pub mod cpu {
+        use crate::Float;
+        use crate::error::TsDistanceError;
+        use crate::kernels::standalone;
+        use std::sync::Arc;
+        use vulkano::buffer::Subbuffer;
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
+        use vulkano::descriptor_set::WriteDescriptorSet;
+        use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+        use vulkano::device::Device;
+
+        /// Records the expansion of `count` half precision values, packed two per word with
+        /// the first one in the low bits, into `output`.
+        pub fn widen_f16_gpu(
+            device: Arc<Device>,
+            descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+            builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+            words: Subbuffer<[u32]>,
+            output: Subbuffer<[Float]>,
+            count: usize,
+        ) -> Result<(), TsDistanceError> {
+            let properties = device.physical_device().properties();
+            let threads = properties.max_compute_work_group_size[0] as u64;
+            let max_groups = properties.max_compute_work_group_count[0] as u64;
+
+            // One thread per word, split in several dispatches past the workgroup count limit.
+            let words_count = count.div_ceil(2) as u64;
+            for word_offset in (0..words_count).step_by((threads * max_groups) as usize) {
+                let group_count = (words_count - word_offset)
+                    .div_ceil(threads)
+                    .min(max_groups);
+                standalone::dispatch(
+                    device.clone(),
+                    descriptor_set_allocator.clone(),
+                    builder,
+                    "kernels::widen_f16::batch_call",
+                    [
+                        WriteDescriptorSet::buffer(0, output.clone()),
+                        WriteDescriptorSet::buffer(1, words.clone()),
+                    ],
+                    super::WidenConstants {
+                        count: count as u64,
+                        word_offset,
+                    },
+                    group_count as u32,
+                )?;
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct WidenConstants {
+        count: u64,
+        word_offset: u64,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use {
+        crate::Float,
+        spirv_std::{
+            glam::{UVec3, Vec2},
+            spirv,
+        },
+    };
+
+    /// Unpacks through `UnpackHalf2x16`, so the device needs no 16-bit storage support.
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(global_invocation_id)] global_id: UVec3,
+        #[spirv(push_constant)] constants: &WidenConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] output: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] words: &[u32],
+    ) {
+        let word = constants.word_offset + global_id.x as u64;
+        let first = 2 * word;
+        if first >= constants.count {
+            return;
+        }
+        let pair: Vec2 = spirv_std::float::f16x2_to_vec2(words[word as usize]);
+        output[first as usize] = pair.x as Float;
+        if first + 1 < constants.count {
+            output[first as usize + 1] = pair.y as Float;
+        }
+    }
+}
+
+pub mod gather_distances {
+    use crate::Float;
+
+    /// Where the distance of each pair of a chunk lands in a device-resident matrix, as
+    /// `offset + scale * value` at `(row_offset + i) * row_stride + (col_offset + j) * col_stride`.
+    /// When `per_pair_min_len` is set, `scale` is further divided by the shorter length of the pair.
+    /// When `sqrt` is set, `value` is square rooted first. `value` is the smallest of the
+    /// `end_cells` diagonal cells up to `cell`, the last row of the pair for an open end.
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct GatherConstants {
+        pub pair_count: u64,
+        pub b_count: u64,
+        pub diag_len: u64,
+        pub cell: u64,
+        pub row_offset: u64,
+        pub col_offset: u64,
+        pub row_stride: u64,
+        pub col_stride: u64,
+        pub per_pair_min_len: u64,
+        pub sqrt: u64,
+        pub end_cells: u64,
+        pub scale: Float,
+        pub offset: Float,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use spirv_std::{glam::UVec3, num_traits::Float as _, spirv};
+
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(global_invocation_id)] global_id: UVec3,
+        #[spirv(push_constant)] constants: &GatherConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] diagonal: &[Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] matrix: &mut [Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] a_lengths: &[u32],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] b_lengths: &[u32],
+    ) {
+        let pair = global_id.x as u64;
+        if pair >= constants.pair_count {
+            return;
+        }
+        let i = pair / constants.b_count;
+        let j = pair % constants.b_count;
+        let mut value = diagonal[(pair * constants.diag_len + constants.cell) as usize];
+        let mut t = 1;
+        while t < constants.end_cells {
+            let slot = (constants.cell + constants.diag_len - t) & (constants.diag_len - 1);
+            value = value.min(diagonal[(pair * constants.diag_len + slot) as usize]);
+            t += 1;
+        }
+        if constants.sqrt != 0 {
+            value = value.sqrt();
+        }
+        let mut scale = constants.scale;
+        if constants.per_pair_min_len != 0 {
+            scale /= a_lengths[i as usize].min(b_lengths[j as usize]) as Float;
+        }
+        let index = (constants.row_offset + i) * constants.row_stride
+            + (constants.col_offset + j) * constants.col_stride;
+        matrix[index as usize] = constants.offset + scale * value;
+    }
+}
+
+/// Largest number of neighbours `knn` can vote with, each thread keeps them in registers.
+pub const MAX_K: usize = 32;
+
+pub mod knn {
+    #[cfg(not(target_arch = "spirv"))]
+    pub mod cpu {
+        use crate::Float;
+        use crate::error::TsDistanceError;
+        use crate::kernels::standalone;
+        use crate::utils::{RunOptions, SubBufferPair, SubBuffersAllocator};
+        use std::sync::Arc;
+        use vulkano::buffer::Subbuffer;
+        use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+        use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage};
+        use vulkano::descriptor_set::WriteDescriptorSet;
+        use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+        use vulkano::device::{Device, Queue};
+
+        /// Majority vote among the `k` smallest entries of each row of a device-resident
+        /// `rows x cols` distance matrix, ties going to the label of the nearest neighbour.
+        /// Only the predicted labels are read back.
+        pub fn knn_select_gpu(
+            device: Arc<Device>,
+            queue: Arc<Queue>,
+            command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+            descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+            subbuffer_allocator: SubBuffersAllocator,
+            options: &RunOptions,
+            distances: Subbuffer<[Float]>,
+            rows: usize,
+            cols: usize,
+            labels: &[u32],
+            k: usize,
+        ) -> Result<Vec<u32>, TsDistanceError> {
+            if k == 0 || k > crate::kernels::MAX_K || k > cols {
+                return Err(TsDistanceError::InvalidInput(format!(
+                    "k must be between 1 and {}, got {}",
+                    crate::kernels::MAX_K.min(cols),
+                    k
+                )));
+            }
+            if labels.len() != cols {
+                return Err(TsDistanceError::InvalidInput(format!(
+                    "expected {} labels, got {}",
+                    cols,
+                    labels.len()
+                )));
+            }
+
+            let max_threads_x = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_size[0];
+
+            let labels_buffer = SubBufferPair::new(&subbuffer_allocator, cols as u64)?;
+            let predictions_buffer = SubBufferPair::<u32>::new(&subbuffer_allocator, rows as u64)?;
+
+            let mut builder = AutoCommandBufferBuilder::primary(
+                command_buffer_allocator,
+                queue.queue_family_index(),
+                CommandBufferUsage::OneTimeSubmit,
+            )?;
+
+            let labels_gpu = labels_buffer.move_gpu(labels, &mut builder)?;
+
+            standalone::dispatch(
+                device.clone(),
+                descriptor_set_allocator,
+                &mut builder,
+                "kernels::knn::batch_call",
+                [
+                    WriteDescriptorSet::buffer(0, predictions_buffer.gpu()),
+                    WriteDescriptorSet::buffer(1, distances),
+                    WriteDescriptorSet::buffer(2, labels_gpu),
+                ],
+                super::KnnConstants {
+                    rows: rows as u64,
+                    cols: cols as u64,
+                    k: k as u64,
+                },
+                (rows as u32).div_ceil(max_threads_x),
+            )?;
+
+            let predictions = predictions_buffer.move_cpu(&mut builder)?;
+            standalone::submit_and_wait(device, queue, builder, options.timeout())?;
+
+            let predictions = predictions.read()?[..rows].to_vec();
+
+            subbuffer_allocator.clear();
+
+            Ok(predictions)
+        }
+    }
+
+    #[derive(Clone, Copy, bytemuck::AnyBitPattern)]
+    #[repr(C)]
+    pub struct KnnConstants {
+        rows: u64,
+        cols: u64,
+        k: u64,
+    }
+
+    #[cfg(target_arch = "spirv")]
+    use {
+        crate::Float,
+        spirv_std::{glam::UVec3, spirv},
+    };
+
+    #[cfg(target_arch = "spirv")]
+    #[spirv(compute(threads(1)))]
+    pub fn batch_call(
+        #[spirv(global_invocation_id)] global_id: UVec3,
+        #[spirv(push_constant)] constants: &KnnConstants,
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] predictions: &mut [u32],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] distances: &[Float],
+        #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] labels: &[u32],
+    ) {
+        let row = global_id.x as u64;
+        if row >= constants.rows {
+            return;
+        }
+        let k = constants.k as usize;
+
+        // Sorted by increasing distance.
+        let mut best_dist = [Float::INFINITY; super::MAX_K];
+        let mut best_label = [0u32; super::MAX_K];
+        let offset = (row * constants.cols) as usize;
+        for c in 0..constants.cols as usize {
+            let d = distances[offset + c];
+            if d < best_dist[k - 1] {
+                let mut p = k - 1;
+                while p > 0 && best_dist[p - 1] > d {
+                    best_dist[p] = best_dist[p - 1];
+                    best_label[p] = best_label[p - 1];
+                    p -= 1;
+                }
+                best_dist[p] = d;
+                best_label[p] = labels[c];
+            }
+        }
+
+        // Neighbours are visited nearest first, so a strict comparison breaks ties in
+        // favour of the closest one.
+        let mut winner = best_label[0];
+        let mut winner_votes = 0;
+        for p in 0..k {
+            let mut votes = 0;
+            for q in 0..k {
+                if best_label[q] == best_label[p] {
+                    votes += 1;
+                }
+            }
+            if votes > winner_votes {
+                winner_votes = votes;
+                winner = best_label[p];
+            }
+        }
+        predictions[row as usize] = winner;
     }
 }
 
 #[inline(always)]
-fn min(a: f32, b: f32) -> f32 {
+fn min(a: Float, b: Float) -> Float {
     if a < b { a } else { b }
 }
 #[inline(always)]
-fn max(a: f32, b: f32) -> f32 {
+fn max(a: Float, b: Float) -> Float {
     if a > b { a } else { b }
 }
 
-const MSM_C: f32 = 1.0;
+/// Adds `cost` to a cell of value `hi + lo`, `lo` holding the low order bits `hi` lost, and
+/// returns the sum in the same form. The seeds carry no such bits, their second state being
+/// the initial value. Accumulating this way keeps `f32` running costs accurate on series long
+/// enough for a plain sum to drift, which the `f64` build has no need for.
+#[inline(always)]
+pub fn compensated_add(hi: Float, lo: Float, cost: Float) -> (Float, Float) {
+    let lo = if lo.is_finite() { lo } else { 0.0 };
+    let sum = hi + cost;
+    if !sum.is_finite() {
+        return (sum, 0.0);
+    }
+    // Knuth's two-sum: `error` is exactly what `sum` rounded away.
+    let cost_part = sum - hi;
+    let error = (hi - (sum - cost_part)) + (cost - cost_part);
+    let lo = lo + error;
+    let total = sum + lo;
+    (total, lo - (total - sum))
+}
+
+/// The smaller of two compensated cells.
+#[inline(always)]
+pub fn compensated_min(a: (Float, Float), b: (Float, Float)) -> (Float, Float) {
+    if a.0 < b.0 || (a.0 == b.0 && a.1 <= b.1) {
+        a
+    } else {
+        b
+    }
+}
+
+/// Default MSM cost of a split or merge.
+pub const MSM_C: Float = 1.0;
+/// Cost of a split or merge inserting `x` next to `y`, against `z` in the other series: `c`
+/// when `x` lies between them, plus its distance to the nearest of the two otherwise.
+#[inline(always)]
+pub fn msm_cost_function(x: Float, y: Float, z: Float, c: Float) -> Float {
+    c + max(max(min(y, z) - x, x - max(y, z)), 0.0)
+}
+
+/// Costs of the move, merge and split steps of MSM into the cell of `a_i` and `b_j`, the
+/// previous values being 0 at the start of a series.
+#[inline(always)]
+pub fn msm_step_costs(
+    a_i: Float,
+    a_prev: Float,
+    b_j: Float,
+    b_prev: Float,
+    merge_cost: Float,
+    split_cost: Float,
+) -> (Float, Float, Float) {
+    (
+        (a_i - b_j).abs(),
+        msm_cost_function(a_i, a_prev, b_j, merge_cost),
+        msm_cost_function(b_j, a_i, b_prev, split_cost),
+    )
+}
+
+/// Costs of deleting `a_i`, deleting `b_j` and matching them in TWE, with time stamps equal
+/// to the indices `i` and `j`.
+#[inline(always)]
+pub fn twe_step_costs(
+    a_i: Float,
+    a_prev: Float,
+    b_j: Float,
+    b_prev: Float,
+    i: u64,
+    j: u64,
+    nu: Float,
+    lambda: Float,
+) -> (Float, Float, Float) {
+    let delete_addition = lambda + nu;
+    (
+        (a_prev - a_i).abs() + delete_addition,
+        (b_prev - b_j).abs() + delete_addition,
+        (a_i - b_j).abs()
+            + (a_prev - b_prev).abs()
+            + nu * (2.0 * (i as isize - j as isize).abs() as Float),
+    )
+}
+
+/// Cosine distance `1 - dot / (|a| |b|)` between two vectors, from their dot product and
+/// squared norms. A zero vector is as far from any other as an orthogonal one, and matches
+/// another zero vector exactly.
+#[inline(always)]
+pub fn cosine_distance(dot: Float, a_norm_sq: Float, b_norm_sq: Float) -> Float {
+    if a_norm_sq == 0.0 && b_norm_sq == 0.0 {
+        0.0
+    } else if a_norm_sq == 0.0 || b_norm_sq == 0.0 {
+        1.0
+    } else {
+        1.0 - dot / (a_norm_sq.sqrt() * b_norm_sq.sqrt())
+    }
+}
+
+/// `cost`, or nothing when it involves a missing value, which `NanPolicy::Mask` keeps as a
+/// NaN.
 #[inline(always)]
-pub fn msm_cost_function(x: f32, y: f32, z: f32) -> f32 {
-    MSM_C + max(max(min(y, z) - x, x - max(z, x)), 0.0)
+pub fn unless_missing(cost: Float) -> Float {
+    if cost.is_nan() { 0.0 } else { cost }
+}
+
+/// Whether `(i, j)` lies inside the Itakura parallelogram of an `n x m` cost matrix, whose
+/// sides have slopes `max_slope` and `1 / max_slope` relative to the main diagonal.
+/// The test always runs with the shorter series as `n`, so `(i, j, n, m)` and its transpose
+/// `(j, i, m, n)` give the same answer and the result does not depend on which side is `a`.
+#[inline(always)]
+pub fn itakura_allowed(i: u64, j: u64, n: u64, m: u64, max_slope: Float) -> bool {
+    if n < 2 || m < 2 {
+        return true;
+    }
+    let (i, j, n, m) = if n > m { (j, i, m, n) } else { (i, j, n, m) };
+    let ratio = (m - 1) as Float / (n - 1) as Float;
+    let (lower, upper) = (ratio / max_slope, ratio * max_slope);
+    let (i, j) = (i as Float, j as Float);
+    let (i_rev, j_rev) = ((n - 1) as Float - i, (m - 1) as Float - j);
+    j >= lower * i && j <= upper * i && j_rev >= lower * i_rev && j_rev <= upper * i_rev
+}
+
+/// `ln(e^x + e^y + e^z)` without overflowing, `-inf` when all three are `-inf`.
+#[inline(always)]
+pub fn log_sum_exp(x: Float, y: Float, z: Float) -> Float {
+    let top = max(x, max(y, z));
+    if top == Float::NEG_INFINITY {
+        return top;
+    }
+    top + ((x - top).exp() + (y - top).exp() + (z - top).exp()).ln()
+}
+
+/// Log of the GAK local kernel, a Gaussian on `dist` (the squared difference) weighted by
+/// `(t - |i - j|) / t` under a triangular constraint of order `t`, no constraint when `t` is 0.
+#[inline(always)]
+pub fn gak_log_local_kernel(dist: Float, i: u64, j: u64, sigma: Float, triangular: u64) -> Float {
+    let offset = if i > j { i - j } else { j - i };
+    if triangular == 0 {
+        -dist / (2.0 * sigma * sigma)
+    } else if offset >= triangular {
+        Float::NEG_INFINITY
+    } else {
+        -dist / (2.0 * sigma * sigma) + ((triangular - offset) as Float / triangular as Float).ln()
+    }
 }
 
 warp_kernel_spec! {
-    fn erp_distance[ERPImpl](a[a_offset], b[b_offset], i, j, x, y, z, [gap_penalty: f32], [], [], [], []) {
-        (y + (a[a_offset + i as usize] - b[b_offset + j as usize]).abs())
-        .min((z + (a[a_offset + i as usize] - gap_penalty).abs()).min(x + (b[b_offset + j as usize] - gap_penalty).abs()))
+    // `gap` holds the reference point of each channel.
+    fn erp_distance[ERPImpl](a[a_offset; dim], b[b_offset; dim], i, j, x, y, z, [], [], [], [], [gap: Float]) {
+        let mut matched = 0.0;
+        let mut a_gap = 0.0;
+        let mut b_gap = 0.0;
+        for c in 0..dim {
+            let a_value = a[a_offset + i as usize * dim + c];
+            let b_value = b[b_offset + j as usize * dim + c];
+            matched += (a_value - b_value).abs();
+            a_gap += (a_value - gap[c]).abs();
+            b_gap += (b_value - gap[c]).abs();
+        }
+        (y + matched).min((z + a_gap).min(x + b_gap))
     }
-    fn lcss_distance[LCSSImpl](a[a_offset], b[b_offset], i, j, x, y, z, [epsilon: f32], [], [], [], []) {
+    // Two values match when they are within `epsilon` and their indices within `delta`, as
+    // in Vlachos et al. (2002).
+    fn lcss_distance[LCSSImpl](a[a_offset], b[b_offset], i, j, x, y, z, [epsilon: Float], [delta: u64], [], [], []) {
         let dist = (a[a_offset + i as usize] - b[b_offset + j as usize]).abs();
-        (dist <= epsilon) as i32 as f32 * (y + 1.0) + (dist > epsilon) as i32 as f32 * x.max(z)
+        let matched = dist <= epsilon && (i as i64 - j as i64).abs() as u64 <= delta;
+        matched as i32 as Float * (y + 1.0) + !matched as i32 as Float * x.max(z)
+    }
+    // Edits are counted from the empty prefixes, so the neighbours on the boundary are the
+    // index of the cell rather than `init_val`.
+    fn edr_distance[EDRImpl](a[a_offset], b[b_offset], i, j, x, y, z, [epsilon: Float], [], [], [], []) {
+        let x = if j == 0 { (i + 1) as Float } else { x };
+        let z = if i == 0 { (j + 1) as Float } else { z };
+        let y = if i == 0 { j as Float } else if j == 0 { i as Float } else { y };
+        let dist = (a[a_offset + i as usize] - b[b_offset + j as usize]).abs();
+        (y + (dist > epsilon) as i32 as Float).min((x + 1.0).min(z + 1.0))
+    }
+    // Cells whose cost exceeds `cutoff` are abandoned: as every local cost is non-negative, no
+    // path through them can end below it, so they are set to infinity and the cells they feed
+    // skip the local cost.
+    fn dtw_distance[DTWImpl; band = window; squared = true](a[a_offset; dim, n], b[b_offset; dim, m], i, j, x, y, z, [window: u64], [itakura: Float], [cutoff: Float], [], []) {
+        let best = z.min(x.min(y));
+        if (i as i64 - j as i64).abs() as u64 > window
+            || (itakura > 0.0 && !super::itakura_allowed(i, j, n, m, itakura))
+            || best > cutoff
+        {
+            Float::INFINITY
+        } else {
+            let mut dist = 0.0;
+            for c in 0..dim {
+                dist += (a[a_offset + i as usize * dim + c] - b[b_offset + j as usize * dim + c]).powi(2);
+            }
+            let cost = dist + best;
+            if cost > cutoff { Float::INFINITY } else { cost }
+        }
     }
-    fn dtw_distance[DTWImpl](a[a_offset], b[b_offset], i, j, x, y, z, [], [], [], [], []) {
+    // Symmetric P1 step pattern of Sakoe and Chiba: every horizontal or vertical step follows a
+    // diagonal one, which weighs twice. The second state is the cost of reaching the cell
+    // through a diagonal step, the only one a horizontal or vertical step may start from.
+    fn dtw_p1_distance[DTWP1Impl; band = window; squared = true; state = (diagonal_x, _, diagonal_z)](a[a_offset], b[b_offset], i, j, x, y, z, [window: u64], [], [], [], []) {
         let dist = (a[a_offset + i as usize] - b[b_offset + j as usize]).powi(2);
-        dist + z.min(x.min(y))
+        if (i as i64 - j as i64).abs() as u64 > window {
+            (Float::INFINITY, Float::INFINITY)
+        } else if i == 0 && j == 0 {
+            (dist, Float::INFINITY)
+        } else {
+            let diagonal = y + 2.0 * dist;
+            (diagonal.min(diagonal_x.min(diagonal_z) + dist), diagonal)
+        }
     }
-    fn wdtw_distance[WDTWImpl](a[a_offset], b[b_offset], i, j, x, y, z, [], [], [], [], [weights: f32]) {
+    // DTW whose local cost is picked at runtime, `cost` being the discriminant of `LocalCost`.
+    // Only the squared costs are square rooted on request.
+    fn dtw_cost_distance[DTWCostImpl; band = window; squared_when = (|params| params.cost == 0 || (params.cost == 2 && params.power == 2.0))](a[a_offset], b[b_offset], i, j, x, y, z, [window: u64], [cost: u64], [power: Float], [], []) {
+        if (i as i64 - j as i64).abs() as u64 > window {
+            Float::INFINITY
+        } else {
+            let diff = (a[a_offset + i as usize] - b[b_offset + j as usize]).abs();
+            let dist = match cost {
+                0 => diff * diff,
+                1 => diff,
+                _ => diff.powf(power),
+            };
+            dist + z.min(x.min(y))
+        }
+    }
+    // DTW over multivariate series whose local cost is the cosine distance between the `dim`
+    // channels of two timesteps.
+    fn dtw_cosine_distance[DTWCosineImpl; band = window](a[a_offset; dim], b[b_offset; dim], i, j, x, y, z, [window: u64], [], [], [], []) {
+        if (i as i64 - j as i64).abs() as u64 > window {
+            Float::INFINITY
+        } else {
+            let mut dot = 0.0;
+            let mut a_norm_sq = 0.0;
+            let mut b_norm_sq = 0.0;
+            for c in 0..dim {
+                let a_value = a[a_offset + i as usize * dim + c];
+                let b_value = b[b_offset + j as usize * dim + c];
+                dot += a_value * b_value;
+                a_norm_sq += a_value * a_value;
+                b_norm_sq += b_value * b_value;
+            }
+            super::cosine_distance(dot, a_norm_sq, b_norm_sq) + z.min(x.min(y))
+        }
+    }
+    // DTW over series with missing values: a channel missing on either side aligns for free.
+    fn dtw_masked_distance[DTWMaskedImpl; band = window; squared = true](a[a_offset; dim], b[b_offset; dim], i, j, x, y, z, [window: u64], [], [], [], []) {
+        if (i as i64 - j as i64).abs() as u64 > window {
+            Float::INFINITY
+        } else {
+            let mut dist = 0.0;
+            for c in 0..dim {
+                dist += super::unless_missing((a[a_offset + i as usize * dim + c] - b[b_offset + j as usize * dim + c]).powi(2));
+            }
+            dist + z.min(x.min(y))
+        }
+    }
+    // ERP over series with missing values: a missing channel matches anything and is skipped
+    // for free.
+    fn erp_masked_distance[ERPMaskedImpl](a[a_offset; dim], b[b_offset; dim], i, j, x, y, z, [], [], [], [], [gap: Float]) {
+        let mut matched = 0.0;
+        let mut a_gap = 0.0;
+        let mut b_gap = 0.0;
+        for c in 0..dim {
+            let a_value = a[a_offset + i as usize * dim + c];
+            let b_value = b[b_offset + j as usize * dim + c];
+            matched += super::unless_missing((a_value - b_value).abs());
+            a_gap += super::unless_missing((a_value - gap[c]).abs());
+            b_gap += super::unless_missing((b_value - gap[c]).abs());
+        }
+        (y + matched).min((z + a_gap).min(x + b_gap))
+    }
+    fn wdtw_distance[WDTWImpl; squared = true](a[a_offset], b[b_offset], i, j, x, y, z, [], [], [], [], [weights: Float]) {
         let dist = (a[a_offset + i as usize] - b[b_offset + j as usize]).powi(2) * weights[(i as i32 - j as i32).abs() as usize];
         dist + x.min(y.min(z))
     }
-    fn msm_distance[MSMImpl](a[a_offset], b[b_offset], i, j, x, y, z, [], [], [], [], []) {
-        (y + (a[a_offset + i as usize] - b[b_offset + j as usize]).abs())
-        .min(
-            z + super::msm_cost_function(a[a_offset + i as usize], if i == 0 {0.0} else {a[a_offset + i as usize - 1]}, b[b_offset + j as usize]),
-        )
-        .min(
-            x + super::msm_cost_function(b[b_offset + j as usize], a[a_offset + i as usize], if j == 0 {0.0} else {b[b_offset + j as usize - 1]}),
-        )
+    // MSM with separate costs for merging `a[i]` into `a[i - 1]` and for splitting `b[j]` off
+    // `a[i]`, both scaled by the weight of the offset `|i - j|` when `weighted` is set. Unweighted
+    // runs bind a single unused weight.
+    fn msm_distance[MSMImpl](a[a_offset], b[b_offset], i, j, x, y, z, [split_cost: Float], [merge_cost: Float], [weighted: u64], [], [weights: Float]) {
+        let weight = if weighted != 0 { weights[(i as i32 - j as i32).abs() as usize] } else { 1.0 };
+        let a_prev = if i == 0 {0.0} else {a[a_offset + i as usize - 1]};
+        let b_prev = if j == 0 {0.0} else {b[b_offset + j as usize - 1]};
+        let (moved, merged, split) = super::msm_step_costs(a[a_offset + i as usize], a_prev, b[b_offset + j as usize], b_prev, merge_cost, split_cost);
+        (y + moved).min(z + weight * merged).min(x + weight * split)
     }
-    fn twe_distance[TWEImpl](a[a_offset], b[b_offset], i, j, x, y, z, [stiffness: f32], [penalty: f32], [], [], []) {
-        let delete_addition = penalty + stiffness;
-        // deletion in a
-        let del_a =
-        z + (if i == 0 {0.0} else {a[a_offset + i as usize - 1]} - a[a_offset + i as usize]).abs() + delete_addition;
-
-        // deletion in b
-        let del_b =
-            x + (if j == 0 {0.0} else {b[b_offset + j as usize - 1]} - b[b_offset + j as usize]).abs() + delete_addition;
-
-        // match
-        let match_current = (a[a_offset + i as usize] - b[b_offset + j as usize]).abs();
-        let match_previous = (if i == 0 {0.0} else {a[a_offset + i as usize - 1]}
-            - if j == 0 {0.0} else {b[b_offset + j as usize - 1]})
-        .abs();
-        let match_a_b = y
-            + match_current
-            + match_previous
-            + stiffness * (2.0 * (i as isize - j as isize).abs() as f32);
-
-        del_a.min(del_b.min(match_a_b))
-    }
-    fn adtw_distance[ADTWImpl](a[a_offset], b[b_offset], i, j, x, y, z, [w: f32], [], [], [], []) {
+    // MSM whose cells keep the low order bits of their running sum as second state, see
+    // `compensated_add`.
+    fn msm_compensated_distance[MSMCompensatedImpl; state = (lo_x, lo_y, lo_z)](a[a_offset], b[b_offset], i, j, x, y, z, [c: Float], [], [], [], []) {
+        let a_i = a[a_offset + i as usize];
+        let b_j = b[b_offset + j as usize];
+        let a_prev = if i == 0 {0.0} else {a[a_offset + i as usize - 1]};
+        let b_prev = if j == 0 {0.0} else {b[b_offset + j as usize - 1]};
+        let (moved, merged, split) = super::msm_step_costs(a_i, a_prev, b_j, b_prev, c, c);
+        let moved = super::compensated_add(y, lo_y, moved);
+        let merged = super::compensated_add(z, lo_z, merged);
+        let split = super::compensated_add(x, lo_x, split);
+        super::compensated_min(moved, super::compensated_min(merged, split))
+    }
+    // Time Warp Edit distance of Marteau (2009), with time stamps equal to the indices and both
+    // series starting from a virtual 0 at time 0: `nu` is the stiffness weighting the time
+    // shift and `lambda` the penalty of each deletion.
+    fn twe_distance[TWEImpl](a[a_offset], b[b_offset], i, j, x, y, z, [nu: Float], [lambda: Float], [], [], []) {
+        let a_prev = if i == 0 {0.0} else {a[a_offset + i as usize - 1]};
+        let b_prev = if j == 0 {0.0} else {b[b_offset + j as usize - 1]};
+        let (del_a, del_b, match_a_b) = super::twe_step_costs(a[a_offset + i as usize], a_prev, b[b_offset + j as usize], b_prev, i, j, nu, lambda);
+        (z + del_a).min((x + del_b).min(y + match_a_b))
+    }
+    // TWE whose cells keep the low order bits of their running sum as second state, see
+    // `compensated_add`.
+    fn twe_compensated_distance[TWECompensatedImpl; state = (lo_x, lo_y, lo_z)](a[a_offset], b[b_offset], i, j, x, y, z, [nu: Float], [lambda: Float], [], [], []) {
+        let a_prev = if i == 0 {0.0} else {a[a_offset + i as usize - 1]};
+        let b_prev = if j == 0 {0.0} else {b[b_offset + j as usize - 1]};
+        let (del_a, del_b, match_a_b) = super::twe_step_costs(a[a_offset + i as usize], a_prev, b[b_offset + j as usize], b_prev, i, j, nu, lambda);
+        let del_a = super::compensated_add(z, lo_z, del_a);
+        let del_b = super::compensated_add(x, lo_x, del_b);
+        let match_a_b = super::compensated_add(y, lo_y, match_a_b);
+        super::compensated_min(del_a, super::compensated_min(del_b, match_a_b))
+    }
+    // Sums over all the alignments instead of taking the best one, in log space so that long
+    // series neither overflow nor underflow.
+    fn gak_distance[GAKImpl](a[a_offset], b[b_offset], i, j, x, y, z, [sigma: Float], [triangular: u64], [], [], []) {
+        let dist = (a[a_offset + i as usize] - b[b_offset + j as usize]).powi(2);
+        super::gak_log_local_kernel(dist, i, j, sigma, triangular) + super::log_sum_exp(x, y, z)
+    }
+    fn adtw_distance[ADTWImpl; squared = true](a[a_offset], b[b_offset], i, j, x, y, z, [w: Float], [], [], [], []) {
         let dist = (a[a_offset + i as usize] - b[b_offset + j as usize]).powi(2);
                 dist + (z + w).min((x + w).min(y))
     }
+    // The penalty of a warping step grows with the distance from the main diagonal, where it
+    // vanishes.
+    fn adtw_scaled_distance[ADTWScaledImpl; squared = true](a[a_offset], b[b_offset], i, j, x, y, z, [w: Float], [], [], [], []) {
+        let dist = (a[a_offset + i as usize] - b[b_offset + j as usize]).powi(2);
+        let penalty = w * (i as i64 - j as i64).abs() as Float;
+        dist + (z + penalty).min((x + penalty).min(y))
+    }
 }
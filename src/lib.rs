@@ -3,6 +3,22 @@
 
 pub mod kernels;
 
+/// Floating point type of the series and distances, `f64` with the `f64` feature.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+#[cfg(not(target_arch = "spirv"))]
+pub mod context;
+#[cfg(not(target_arch = "spirv"))]
+pub mod engine;
+#[cfg(not(target_arch = "spirv"))]
+pub mod error;
+#[cfg(not(target_arch = "spirv"))]
+pub mod matrix;
+#[cfg(not(target_arch = "spirv"))]
+pub mod reference;
 #[cfg(not(target_arch = "spirv"))]
 mod shader_load;
 #[cfg(not(target_arch = "spirv"))]
@@ -12,21 +28,29 @@ pub mod warps;
 
 #[cfg(not(target_arch = "spirv"))]
 pub mod cpu {
-    use crate::kernels::adtw_distance::cpu::ADTWImpl;
+    use crate::Float;
+    use crate::context::{GpuContext, Metric};
+    use crate::error::TsDistanceError;
     use crate::kernels::dtw_distance::cpu::DTWImpl;
-    use crate::kernels::erp_distance::cpu::ERPImpl;
-    use crate::kernels::lcss_distance::cpu::LCSSImpl;
-    use crate::kernels::msm_distance::cpu::MSMImpl;
-    use crate::kernels::twe_distance::cpu::TWEImpl;
-    use crate::kernels::wdtw_distance::cpu::WDTWImpl;
-    use crate::utils::SubBuffersAllocator;
-    use crate::warps::diamond_partitioning_gpu;
-    use std::cmp::min;
+    use crate::kernels::dtw_stream::cpu::StreamingDtw;
+    use crate::kernels::euclidean_distance::cpu::euclidean_gpu;
+    use crate::kernels::lb_keogh::cpu::lb_keogh_gpu;
+    use crate::kernels::sbd::cpu::sbd_gpu;
+    use crate::kernels::{LocalCost, StepPattern};
+    use crate::matrix::DistanceMatrix;
+    use crate::utils::{
+        NanPolicy, RunOptions, SubBuffersAllocator, check_batch, check_exact_counts,
+        complexity_estimate, complexity_factor, derivative, envelopes, warping_path,
+    };
+    use crate::warps::{
+        DistanceIter, GpuSeries, PendingDistances, cost_matrix_gpu, diamond_partitioning_row_bands,
+    };
+    use std::collections::HashMap;
     use std::sync::Arc;
 
     use vulkano::device::Queue;
     use vulkano::{
-        command_buffer::allocator::StandardCommandBufferAllocator,
+        buffer::Subbuffer, command_buffer::allocator::StandardCommandBufferAllocator,
         descriptor_set::allocator::StandardDescriptorSetAllocator, device::Device,
     };
 
@@ -36,126 +60,998 @@ pub mod cpu {
         sba: Arc<StandardCommandBufferAllocator>,
         dsa: Arc<StandardDescriptorSetAllocator>,
         sa: SubBuffersAllocator,
-        a: &Vec<Vec<f32>>,
-        b: &Vec<Vec<f32>>,
-        gap_penalty: f32,
-    ) -> Vec<Vec<f32>> {
-        diamond_partitioning_gpu::<_>(
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        gap_penalty: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.erp(gap_penalty)
+    }
+
+    /// ERP over series of `dim` interleaved channels, with a reference point per channel in
+    /// `gap`.
+    pub fn erp_multivariate(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        dim: usize,
+        gap: &[Float],
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new_multivariate(device, queue, sba, dsa, sa, options, a, b, dim)?.erp_gap(gap)
+    }
+
+    /// Point-wise Euclidean distance between series of equal length, without any warping.
+    pub fn euclidean(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        euclidean_gpu(device, queue, sba, dsa, sa, options, a, b)
+    }
+
+    /// Shape-based distance of k-Shape between series of equal length: one minus the peak
+    /// of the normalized cross-correlation of the z-normalized series, in `[0, 2]`.
+    pub fn sbd(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        sbd_gpu(device, queue, sba, dsa, sa, options, a, b)
+    }
+
+    /// LB_Keogh lower bound of the DTW distance between `query` and each candidate, using the
+    /// query envelopes over a Sakoe-Chiba window. Like `dtw`, the bound is a sum of squared
+    /// differences, so `lb_keogh(q, c, w) <= dtw(q, c, Some(w))`.
+    pub fn lb_keogh(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        query: &[Float],
+        candidates: &Vec<Vec<Float>>,
+        window: usize,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        let query = options.nan_policy().apply("query", &vec![query.to_vec()])?;
+        let (lower, upper) = envelopes(&query[0], window);
+        lb_keogh_gpu(
+            device, queue, sba, dsa, sa, options, &lower, &upper, candidates,
+        )
+    }
+
+    pub fn lcss(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.lcss(epsilon, delta)
+    }
+
+    /// Unnormalized longest common subsequence length, see `GpuContext::lcss_length`.
+    pub fn lcss_length(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.lcss_length(epsilon, delta)
+    }
+
+    /// Longest common subsequence lengths as integers, see `GpuContext::lcss_count`.
+    pub fn lcss_count(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+        delta: Option<usize>,
+    ) -> Result<Vec<Vec<u32>>, TsDistanceError> {
+        // Checked before anything is uploaded.
+        check_exact_counts("lcss", a.iter().chain(b).map(Vec::len).max().unwrap_or(0))?;
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.lcss_count(epsilon, delta)
+    }
+
+    /// Edit Distance on Real sequences, see `GpuContext::edr`.
+    pub fn edr(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.edr(epsilon)
+    }
+
+    /// Edit Distance on Real sequences as integers, see `GpuContext::edr_count`.
+    pub fn edr_count(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        epsilon: Float,
+    ) -> Result<Vec<Vec<u32>>, TsDistanceError> {
+        check_exact_counts("edr", a.iter().chain(b).map(Vec::len).max().unwrap_or(0))?;
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.edr_count(epsilon)
+    }
+
+    pub fn dtw(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw(window)
+    }
+
+    /// Same as `dtw`, calling `progress` with the fraction of pairs done after each chunk
+    /// of a large batch.
+    pub fn dtw_with_progress(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+        progress: &dyn Fn(f32),
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?
+            .dtw_with_progress(window, progress)
+    }
+
+    /// DTW under any `LocalCost`, see `GpuContext::dtw_with_cost`.
+    pub fn dtw_with_cost(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+        cost: LocalCost,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_with_cost(window, cost)
+    }
+
+    /// DTW whose warping path follows `pattern`, see `GpuContext::dtw_step`.
+    pub fn dtw_step(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+        pattern: StepPattern,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_step(window, pattern)
+    }
+
+    /// DTW restricted to the Itakura parallelogram of maximum slope `max_slope`, which must
+    /// be at least 1. The band is narrow at both ends of the series and widest in the middle.
+    pub fn dtw_itakura(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        max_slope: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_itakura(max_slope)
+    }
+
+    /// DTW of each `a` series against its best matching subsequence of each `b` series, e.g.
+    /// to find a short pattern in a longer recording, see `GpuContext::subsequence_dtw`.
+    pub fn subsequence_dtw(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.subsequence_dtw()
+    }
+
+    /// DTW of each `a` series against its best matching prefix of each `b` series, with the
+    /// index in `b` where that prefix ends, see `GpuContext::dtw_partial`.
+    pub fn dtw_partial(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<(Float, usize)>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_partial(window)
+    }
+
+    /// Complexity-invariant DTW, every distance multiplied by
+    /// `max(ce_a, ce_b) / min(ce_a, ce_b)` where `ce` is the `complexity_estimate` of a series.
+    pub fn cid_dtw(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let distances = GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw(window)?;
+        let b_ce = b
+            .iter()
+            .map(|ts| complexity_estimate(ts))
+            .collect::<Vec<_>>();
+        Ok(distances
+            .into_iter()
+            .zip(a)
+            .map(|(row, ts)| {
+                let a_ce = complexity_estimate(ts);
+                row.into_iter()
+                    .zip(&b_ce)
+                    .map(|(d, &b_ce)| d * complexity_factor(a_ce, b_ce))
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// DTW over multivariate series, each stored as `dim` interleaved channels per timestep.
+    /// The local cost is the squared Euclidean distance across channels.
+    pub fn dtw_multivariate(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        dim: usize,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new_multivariate(device, queue, sba, dsa, sa, options, a, b, dim)?.dtw(window)
+    }
+
+    /// DTW over series whose missing values are NaN, which align with anything for free.
+    /// The series are uploaded under `NanPolicy::Mask`, see `utils::apply_mask` to build them
+    /// from validity flags.
+    pub fn dtw_masked(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let options = options.clone().with_nan_policy(NanPolicy::Mask);
+        GpuContext::new(device, queue, sba, dsa, sa, &options, a, b)?.dtw_masked(window)
+    }
+
+    /// ERP over series whose missing values are NaN, which match anything and are skipped
+    /// for free, see `dtw_masked`.
+    pub fn erp_masked(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        gap_penalty: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let options = options.clone().with_nan_policy(NanPolicy::Mask);
+        GpuContext::new(device, queue, sba, dsa, sa, &options, a, b)?.erp_masked(gap_penalty)
+    }
+
+    /// DTW over series of `dim` interleaved channels with the cosine distance between
+    /// timesteps as local cost, see `GpuContext::dtw_cosine`.
+    pub fn dtw_cosine(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        dim: usize,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new_multivariate(device, queue, sba, dsa, sa, options, a, b, dim)?
+            .dtw_cosine(window)
+    }
+
+    /// Same as `dtw`, without blocking on the device. The distances are read back by
+    /// `PendingDistances::await_result`.
+    pub fn dtw_async(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<PendingDistances, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_async(window)
+    }
+
+    /// Same as `dtw`, with the distances in a single row-major buffer of `rows x cols`.
+    pub fn dtw_flat(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_flat(window)
+    }
+
+    /// Same as `dtw`, calling `each(i, j, distance)` for every pair as the chunks are read
+    /// back instead of building the matrix, for cross products too large for host memory.
+    pub fn dtw_for_each(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+        each: impl FnMut(usize, usize, Float),
+    ) -> Result<(), TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_for_each(window, each)
+    }
+
+    /// Distances of every pair as a lazy iterator of `Ok((i, j, distance))`, see
+    /// `GpuContext::distance_iter`.
+    pub fn dtw_iter(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<DistanceIter<DTWImpl>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_iter(window)
+    }
+
+    /// Same as `dtw_flat`, without reading the distances back: the `rows x cols` matrix stays
+    /// in a device-local buffer, to be consumed by other kernels.
+    pub fn dtw_gpu_resident(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<(Subbuffer<[Float]>, usize, usize), TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_device(window)
+    }
+
+    /// DTW distances between every pair `i < j` of `data`, packed row-major so that the
+    /// distance of `(i, j)` is at `i * n - i * (i + 1) / 2 + j - i - 1`.
+    pub fn dtw_self(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        data: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        GpuContext::new_self(device, queue, sba, dsa, sa, options, data)?.dtw_self(window)
+    }
+
+    /// DTW distances between `a` and the time-reversed `b` series, which match those of
+    /// `dtw` when every `b` series reads the same both ways.
+    pub fn dtw_reversed(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        check_batch("a", a)?;
+        check_batch("b", b)?;
+        let sa = sa.start_run(0);
+        let a = GpuSeries::upload(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sa.clone(),
+            options,
+            a,
+        )?;
+        let b = GpuSeries::upload_reversed(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sa.clone(),
+            options,
+            b,
+        )?;
+        GpuContext::from_series(device, queue, sba, dsa, sa, options, a, b).dtw(window)
+    }
+
+    /// DTW distances of every series of `chunks` to every `train` series, one row per test
+    /// series in the order of the chunks. `train` stays resident on the device while the
+    /// chunks are uploaded one after the other, so only one of them, e.g. from
+    /// `utils::read_ts_chunked` or `utils::read_csv_chunked`, is ever held in host memory.
+    pub fn dtw_chunked(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        train: &Vec<Vec<Float>>,
+        chunks: impl IntoIterator<Item = std::io::Result<Vec<Vec<Float>>>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        check_batch("train", train)?;
+        let sa = sa.start_run(0);
+        let train = GpuSeries::upload(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sa.clone(),
+            options,
+            train,
+        )?;
+        let mut rows = Vec::new();
+        for chunk in chunks {
+            let chunk = GpuSeries::upload(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                sa.clone(),
+                options,
+                &chunk?,
+            )?;
+            let ctx = GpuContext::from_series(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                dsa.clone(),
+                sa.clone(),
+                options,
+                chunk,
+                train.clone(),
+            );
+            rows.extend(ctx.dtw(window)?);
+        }
+        Ok(rows)
+    }
+
+    /// DTW distance of many independent pairs, all computed in one submission per chunk
+    /// rather than one per pair, which is where most of the time goes for small pairs.
+    pub fn dtw_many_singles(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        pairs: &[(&[Float], &[Float])],
+        window: Option<usize>,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        let a = pairs.iter().map(|(a, _)| a.to_vec()).collect::<Vec<_>>();
+        let b = pairs.iter().map(|(_, b)| b.to_vec()).collect::<Vec<_>>();
+        GpuContext::new(device, queue, sba, dsa, sa, options, &a, &b)?.dtw_zipped(window)
+    }
+
+    /// DTW distance matrix that takes the single pair path of `dtw_many_singles` when `a`
+    /// and `b` hold one series each, and the batched one of `dtw` otherwise.
+    pub fn dtw_auto(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &[Vec<Float>],
+        b: &[Vec<Float>],
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        match (a, b) {
+            ([a], [b]) => {
+                let pairs = [(a.as_slice(), b.as_slice())];
+                Ok(vec![dtw_many_singles(
+                    device, queue, sba, dsa, sa, options, &pairs, window,
+                )?])
+            }
+            _ => dtw(
+                device,
+                queue,
+                sba,
+                dsa,
+                sa,
+                options,
+                &a.to_vec(),
+                &b.to_vec(),
+                window,
+            ),
+        }
+    }
+
+    /// Candidates compared per DTW batch by `dtw_nn_search`.
+    const NN_SEARCH_BATCH: usize = 64;
+
+    /// Nearest candidate to `query` under DTW, as its index and distance, `None` when every
+    /// distance is infinite, e.g. under a window narrower than the gap between the lengths
+    /// of the query and the candidates. Candidates are
+    /// visited in increasing order of their LB_Keogh bound, a batch at a time, keeping the
+    /// best distance so far: a candidate whose bound already reaches it is abandoned without
+    /// computing its DTW, and the search stops once every remaining one is. The others stop
+    /// accumulating as soon as their cells exceed it, which saves the local costs of the
+    /// abandoned cells though not their dispatch. How much is skipped thus depends on the
+    /// ordering, the sooner a close candidate is met the more are pruned. The bound needs
+    /// candidates as long as the query, otherwise they are all compared in the given order.
+    pub fn dtw_nn_search(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        query: &[Float],
+        candidates: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Option<(usize, Float)>, TsDistanceError> {
+        check_batch("candidates", candidates)?;
+        let bounds = if candidates.iter().all(|ts| ts.len() == query.len()) {
+            lb_keogh(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                dsa.clone(),
+                sa.clone(),
+                options,
+                query,
+                candidates,
+                window.unwrap_or(query.len()),
+            )?
+        } else {
+            vec![0.0; candidates.len()]
+        };
+        let mut order = (0..candidates.len()).collect::<Vec<_>>();
+        order.sort_by(|&x, &y| bounds[x].total_cmp(&bounds[y]));
+
+        // The bounds are on the squared cost, so the search runs on it as well.
+        let sqrt_output = options.sqrt_output();
+        let options = options.clone().with_sqrt_output(false);
+        let query = vec![query.to_vec()];
+        let mut best: Option<(usize, Float)> = None;
+        for batch in order.chunks(NN_SEARCH_BATCH) {
+            let batch = batch
+                .iter()
+                .copied()
+                .filter(|&c| best.is_none_or(|(_, d)| bounds[c] < d))
+                .collect::<Vec<_>>();
+            // The bounds are sorted, so the following batches are pruned as well.
+            if batch.is_empty() {
+                break;
+            }
+            let series = batch
+                .iter()
+                .map(|&c| candidates[c].clone())
+                .collect::<Vec<_>>();
+            // Cells past the best distance so far are abandoned by the kernel, such
+            // candidates come back as infinite.
+            let distances = GpuContext::new(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                dsa.clone(),
+                sa.clone(),
+                &options,
+                &query,
+                &series,
+            )?
+            .distance(
+                DTWImpl {
+                    window: window.map_or(u64::MAX, |w| w as u64),
+                    itakura: 0.0,
+                    cutoff: best.map_or(Float::INFINITY, |(_, d)| d),
+                },
+                Float::INFINITY,
+            )?;
+            for (&c, &d) in batch.iter().zip(&distances[0]) {
+                if d < best.map_or(Float::INFINITY, |(_, best)| best) {
+                    best = Some((c, d));
+                }
+            }
+        }
+        if sqrt_output {
+            best = best.map(|(c, d)| (c, d.sqrt()));
+        }
+        Ok(best)
+    }
+
+    /// DTW against `template` of a query pushed sample by sample, see `StreamingDtw`.
+    pub fn dtw_stream(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        template: &[Float],
+    ) -> Result<StreamingDtw, TsDistanceError> {
+        StreamingDtw::new(device, queue, sba, dsa, sa, options, template)
+    }
+
+    /// DTW distance of a single pair together with its optimal warping path. The whole
+    /// `a.len() x b.len()` cost matrix is kept on the device for the traceback, so prefer
+    /// `dtw` when only the distance is needed.
+    pub fn dtw_with_path(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &[Float],
+        b: &[Float],
+        window: Option<usize>,
+    ) -> Result<(Float, Vec<(usize, usize)>), TsDistanceError> {
+        let sqrt_output = options.sqrt_output();
+        let cost = cost_matrix_gpu::<_>(
             device,
             queue,
             sba,
             dsa,
             sa,
-            ERPImpl {
-                gap_penalty: gap_penalty as f32,
+            options,
+            DTWImpl {
+                window: window.map_or(u64::MAX, |w| w as u64),
+                itakura: 0.0,
+                cutoff: Float::INFINITY,
             },
             a,
             b,
-            f32::INFINITY,
-        )
+            Float::INFINITY,
+        )?;
+        let distance = cost[a.len() - 1][b.len() - 1];
+        let distance = if sqrt_output {
+            distance.sqrt()
+        } else {
+            distance
+        };
+        Ok((distance, warping_path(&cost)))
     }
 
-    pub fn lcss(
+    /// DTW distance of a single pair computed `band_rows` rows of `a` at a time, which
+    /// bounds the diagonals of very long pairs, see `warps::diamond_partitioning_row_bands`.
+    pub fn dtw_tiled(
         device: Arc<Device>,
         queue: Arc<Queue>,
         sba: Arc<StandardCommandBufferAllocator>,
         dsa: Arc<StandardDescriptorSetAllocator>,
         sa: SubBuffersAllocator,
-        a: &Vec<Vec<f32>>,
-        b: &Vec<Vec<f32>>,
-        epsilon: f32,
-    ) -> Vec<Vec<f32>> {
-        let a_len = a.first().unwrap().len();
-        let b_len = b.first().unwrap().len();
-        let similarity = diamond_partitioning_gpu::<_>(
+        options: &RunOptions,
+        a: &[Float],
+        b: &[Float],
+        band_rows: usize,
+    ) -> Result<Float, TsDistanceError> {
+        diamond_partitioning_row_bands(
             device,
             queue,
             sba,
             dsa,
             sa,
-            LCSSImpl { epsilon },
+            options,
+            DTWImpl {
+                window: u64::MAX,
+                itakura: 0.0,
+                cutoff: Float::INFINITY,
+            },
             a,
             b,
-            0.0,
-        );
-        let min_len = min(a_len, b_len) as f32;
-        similarity
-            .iter()
-            .map(|row| row.iter().map(|&s| 1.0 - s / min_len).collect::<Vec<f32>>())
-            .collect::<Vec<Vec<f32>>>()
+            band_rows,
+            Float::INFINITY,
+        )
     }
 
-    pub fn dtw(
+    /// DTW divided by `a.len() + b.len()`, see `GpuContext::dtw_normalized`.
+    pub fn dtw_normalized(
         device: Arc<Device>,
         queue: Arc<Queue>,
         sba: Arc<StandardCommandBufferAllocator>,
         dsa: Arc<StandardDescriptorSetAllocator>,
         sa: SubBuffersAllocator,
-        a: &Vec<Vec<f32>>,
-        b: &Vec<Vec<f32>>,
-    ) -> Vec<Vec<f32>> {
-        diamond_partitioning_gpu::<_>(device, queue, sba, dsa, sa, DTWImpl {}, a, b, f32::INFINITY)
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.dtw_normalized(window)
     }
 
-    pub fn wdtw(
+    /// Whole `a.len() x b.len()` accumulated DTW cost matrix of a single pair, where
+    /// `matrix[i][j]` is the cost of aligning `a[..=i]` with `b[..=j]`, left squared. Every
+    /// cell is written to a dense device buffer of `a.len() * b.len()` values on top of the
+    /// diagonals, so this is meant for inspecting short pairs, not for distances.
+    pub fn dtw_full_matrix(
         device: Arc<Device>,
         queue: Arc<Queue>,
         sba: Arc<StandardCommandBufferAllocator>,
         dsa: Arc<StandardDescriptorSetAllocator>,
         sa: SubBuffersAllocator,
-        a: &Vec<Vec<f32>>,
-        b: &Vec<Vec<f32>>,
-        weights: &[f32],
-    ) -> Vec<Vec<f32>> {
-        diamond_partitioning_gpu::<_>(
+        options: &RunOptions,
+        a: &[Float],
+        b: &[Float],
+        window: Option<usize>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        cost_matrix_gpu(
             device,
             queue,
             sba,
             dsa,
             sa,
-            WDTWImpl {
-                weights: weights.to_vec(),
+            options,
+            DTWImpl {
+                window: window.map_or(u64::MAX, |w| w as u64),
+                itakura: 0.0,
+                cutoff: Float::INFINITY,
             },
             a,
             b,
-            f32::INFINITY,
+            Float::INFINITY,
         )
     }
 
+    /// DTW distance of a single pair divided by the length of its optimal warping path. The
+    /// path comes from `dtw_with_path`, and so does the memory cost of the whole cost matrix.
+    pub fn dtw_normalized_exact(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &[Float],
+        b: &[Float],
+        window: Option<usize>,
+    ) -> Result<Float, TsDistanceError> {
+        let (distance, path) = dtw_with_path(device, queue, sba, dsa, sa, options, a, b, window)?;
+        Ok(distance / path.len() as Float)
+    }
+
+    pub fn ddtw(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let a = a.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+        let b = b.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+        GpuContext::new(device, queue, sba, dsa, sa, options, &a, &b)?.dtw(None)
+    }
+
+    pub fn wdtw(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        weights: &[Float],
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.wdtw(weights)
+    }
+
+    /// WDTW parametrised by the logistic steepness `g` alone, see `GpuContext::wdtw_logistic`.
+    pub fn wdtw_logistic(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        g: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.wdtw_logistic(g)
+    }
+
     pub fn msm(
         device: Arc<Device>,
         queue: Arc<Queue>,
         sba: Arc<StandardCommandBufferAllocator>,
         dsa: Arc<StandardDescriptorSetAllocator>,
         sa: SubBuffersAllocator,
-        a: &Vec<Vec<f32>>,
-        b: &Vec<Vec<f32>>,
-    ) -> Vec<Vec<f32>> {
-        diamond_partitioning_gpu::<_>(device, queue, sba, dsa, sa, MSMImpl {}, a, b, f32::INFINITY)
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.msm()
     }
 
+    /// MSM with `c` as the cost of a split or merge, `msm` uses `MSM_C`.
+    pub fn msm_c(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        c: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.msm_c(c)
+    }
+
+    /// `msm_c` with compensated summation, see `GpuContext::msm_compensated`.
+    pub fn msm_compensated(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        c: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.msm_compensated(c)
+    }
+
+    /// MSM with position dependent split and merge costs, see `GpuContext::wmsm`.
+    pub fn wmsm(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        c: Float,
+        weights: &[Float],
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.wmsm(c, weights)
+    }
+
+    /// MSM with separate split and merge costs, equal to `msm_c` when both are `c`.
+    pub fn msm_gen(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        split_cost: Float,
+        merge_cost: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.msm_gen(split_cost, merge_cost)
+    }
+
+    /// Time Warp Edit distance with stiffness `nu` and deletion penalty `lambda`, following
+    /// Marteau (2009).
     pub fn twe(
         device: Arc<Device>,
         queue: Arc<Queue>,
         sba: Arc<StandardCommandBufferAllocator>,
         dsa: Arc<StandardDescriptorSetAllocator>,
         sa: SubBuffersAllocator,
-        a: &Vec<Vec<f32>>,
-        b: &Vec<Vec<f32>>,
-        stiffness: f32,
-        penalty: f32,
-    ) -> Vec<Vec<f32>> {
-        diamond_partitioning_gpu::<_>(
-            device,
-            queue,
-            sba,
-            dsa,
-            sa,
-            TWEImpl { stiffness, penalty },
-            a,
-            b,
-            f32::INFINITY,
-        )
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        nu: Float,
+        lambda: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.twe(nu, lambda)
+    }
+
+    /// `twe` with compensated summation, see `GpuContext::twe_compensated`.
+    pub fn twe_compensated(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        nu: Float,
+        lambda: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.twe_compensated(nu, lambda)
     }
 
     pub fn adtw(
@@ -164,20 +1060,171 @@ pub mod cpu {
         sba: Arc<StandardCommandBufferAllocator>,
         dsa: Arc<StandardDescriptorSetAllocator>,
         sa: SubBuffersAllocator,
-        a: &Vec<Vec<f32>>,
-        b: &Vec<Vec<f32>>,
-        w: f32,
-    ) -> Vec<Vec<f32>> {
-        diamond_partitioning_gpu::<_>(
-            device,
-            queue,
-            sba,
-            dsa,
-            sa,
-            ADTWImpl { w },
-            a,
-            b,
-            f32::INFINITY,
-        )
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        w: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.adtw(w)
+    }
+
+    /// ADTW with a warping penalty proportional to the distance from the main diagonal, see
+    /// `GpuContext::adtw_scaled`.
+    pub fn adtw_scaled(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        w: Float,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.adtw_scaled(w)
+    }
+
+    /// Logarithm of the Global Alignment Kernel, see `GpuContext::gak`.
+    pub fn gak(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        sigma: Float,
+        triangular: usize,
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?.gak(sigma, triangular)
+    }
+
+    /// Distance matrices of several metrics between `a` and `b`, uploaded once and computed
+    /// in a single submission, see `GpuContext::matrices`.
+    pub fn compute_matrices(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        metrics: &[Metric],
+    ) -> Result<HashMap<Metric, DistanceMatrix>, TsDistanceError> {
+        // DDTW runs over the derivatives, which need a context of their own.
+        let (derivatives, others): (Vec<Metric>, Vec<Metric>) = metrics
+            .iter()
+            .cloned()
+            .partition(|metric| matches!(metric, Metric::Ddtw));
+        let mut matrices = if others.is_empty() {
+            HashMap::new()
+        } else {
+            GpuContext::new(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                dsa.clone(),
+                sa.clone(),
+                options,
+                a,
+                b,
+            )?
+            .matrices(&others)?
+        };
+        if !derivatives.is_empty() {
+            let a = a.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+            let b = b.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+            matrices.extend(
+                GpuContext::new(device, queue, sba, dsa, sa, options, &a, &b)?
+                    .matrices(&derivatives)?,
+            );
+        }
+        Ok(matrices)
+    }
+
+    /// Weighted sum of the distance matrices of several metrics, as the elastic ensemble
+    /// combines them, each computed once by `compute_matrices` however often it is listed.
+    pub fn ensemble(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        metrics: &[(Metric, Float)],
+    ) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        if metrics.is_empty() {
+            return Err(TsDistanceError::InvalidInput(
+                "an ensemble needs at least one metric".to_string(),
+            ));
+        }
+        let distinct = metrics
+            .iter()
+            .map(|(metric, _)| metric.clone())
+            .collect::<Vec<_>>();
+        let matrices = compute_matrices(device, queue, sba, dsa, sa, options, a, b, &distinct)?;
+
+        let mut sum = vec![vec![0.0; b.len()]; a.len()];
+        for (metric, weight) in metrics {
+            let matrix = &matrices[metric];
+            for (i, row) in sum.iter_mut().enumerate() {
+                for (value, distance) in row.iter_mut().zip(matrix.row(i)) {
+                    *value += weight * distance;
+                }
+            }
+        }
+        Ok(sum)
+    }
+
+    /// k-nearest-neighbour classification of `test` against the labelled `train` series.
+    /// The distance matrix never leaves the device, only the predicted labels are read back.
+    pub fn knn(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        train: &Vec<Vec<Float>>,
+        train_labels: &[usize],
+        test: &Vec<Vec<Float>>,
+        k: usize,
+        metric: Metric,
+    ) -> Result<Vec<usize>, TsDistanceError> {
+        let ctx = if let Metric::Ddtw = metric {
+            let train = train.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+            let test = test.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+            GpuContext::new(device, queue, sba, dsa, sa, options, &test, &train)?
+        } else {
+            GpuContext::new(device, queue, sba, dsa, sa, options, test, train)?
+        };
+        ctx.knn(train_labels, k, &metric)
+    }
+
+    /// Distance of `a[k]` to `b[k]` for every `k` under `metric`, e.g. of each series to
+    /// its own reconstruction, see `GpuContext::pairwise`.
+    pub fn pairwise(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        sba: Arc<StandardCommandBufferAllocator>,
+        dsa: Arc<StandardDescriptorSetAllocator>,
+        sa: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &Vec<Vec<Float>>,
+        b: &Vec<Vec<Float>>,
+        metric: Metric,
+    ) -> Result<Vec<Float>, TsDistanceError> {
+        let ctx = if let Metric::Ddtw = metric {
+            let a = a.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+            let b = b.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+            GpuContext::new(device, queue, sba, dsa, sa, options, &a, &b)?
+        } else {
+            GpuContext::new(device, queue, sba, dsa, sa, options, a, b)?
+        };
+        ctx.pairwise(&metric)
     }
 }
@@ -0,0 +1,230 @@
+//! A distance matrix as a single row-major buffer, with the `.npy` and condensed interop that
+//! downstream analysis in Python expects.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::Float;
+use crate::error::TsDistanceError;
+use crate::warps::unflatten;
+
+/// `rows x cols` distances stored row-major in `data`, as returned by the `*_flat` functions.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DistanceMatrix {
+    pub rows: usize,
+    pub cols: usize,
+    pub data: Vec<Float>,
+}
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Little-endian numpy type of `Float`.
+const NPY_DESCR: &str = if std::mem::size_of::<Float>() == 4 {
+    "<f4"
+} else {
+    "<f8"
+};
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl DistanceMatrix {
+    pub fn get(&self, i: usize, j: usize) -> Float {
+        self.data[i * self.cols + j]
+    }
+
+    pub fn row(&self, i: usize) -> &[Float] {
+        &self.data[i * self.cols..(i + 1) * self.cols]
+    }
+
+    pub fn into_rows(self) -> Vec<Vec<Float>> {
+        unflatten(self.data, self.cols)
+    }
+
+    /// Writes the matrix as a version 1.0 `.npy` array of shape `(rows, cols)`.
+    pub fn write_npy(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': ({}, {}), }}",
+            NPY_DESCR, self.rows, self.cols
+        );
+        // The data starts on a 64 byte boundary, the header ending with a newline.
+        let unpadded = NPY_MAGIC.len() + 2 + 2 + header.len() + 1;
+        header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+        header.push('\n');
+
+        writer.write_all(NPY_MAGIC)?;
+        writer.write_all(&[1, 0])?;
+        writer.write_all(&(header.len() as u16).to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+        for value in &self.data {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    pub fn save_npy(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.write_npy(BufWriter::new(File::create(path)?))
+    }
+
+    /// Reads a two dimensional, C-ordered `.npy` array of little-endian `f4` or `f8`
+    /// values, converted to `Float`.
+    pub fn read_npy(mut reader: impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic[..6] != NPY_MAGIC {
+            return Err(invalid_data("not a .npy file".to_string()));
+        }
+        let header_len = match magic[6] {
+            1 => {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_le_bytes(len) as usize
+            }
+            2 | 3 => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len)?;
+                u32::from_le_bytes(len) as usize
+            }
+            version => {
+                return Err(invalid_data(format!(
+                    "unsupported .npy version {}",
+                    version
+                )));
+            }
+        };
+        let mut header = vec![0u8; header_len];
+        reader.read_exact(&mut header)?;
+        let header = String::from_utf8_lossy(&header);
+
+        let field = |key: &str| {
+            let start = header
+                .find(&format!("'{}':", key))
+                .ok_or_else(|| invalid_data(format!(".npy header lacks '{}'", key)))?;
+            Ok::<_, io::Error>(header[start + key.len() + 3..].trim_start())
+        };
+        if !field("fortran_order")?.starts_with("False") {
+            return Err(invalid_data(
+                "Fortran ordered arrays are not supported".to_string(),
+            ));
+        }
+        let descr = field("descr")?;
+        let width = if descr.starts_with("'<f4'") {
+            4
+        } else if descr.starts_with("'<f8'") {
+            8
+        } else {
+            return Err(invalid_data(format!("unsupported .npy type {}", descr)));
+        };
+        let shape = field("shape")?;
+        let shape = &shape[1..shape
+            .find(')')
+            .ok_or_else(|| invalid_data("malformed .npy shape".to_string()))?];
+        let dims = shape
+            .split(',')
+            .map(str::trim)
+            .filter(|dim| !dim.is_empty())
+            .map(|dim| {
+                dim.parse::<usize>()
+                    .map_err(|_| invalid_data(format!("malformed .npy shape ({})", shape)))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let [rows, cols] = dims[..] else {
+            return Err(invalid_data(format!(
+                "expected a two dimensional array, got shape ({})",
+                shape
+            )));
+        };
+
+        let mut bytes = vec![0u8; rows * cols * width];
+        reader.read_exact(&mut bytes)?;
+        let data = bytes
+            .chunks_exact(width)
+            .map(|value| match width {
+                4 => f32::from_le_bytes(value.try_into().unwrap()) as Float,
+                _ => f64::from_le_bytes(value.try_into().unwrap()) as Float,
+            })
+            .collect();
+        Ok(Self { rows, cols, data })
+    }
+
+    pub fn load_npy(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_npy(BufReader::new(File::open(path)?))
+    }
+
+    /// The pairs `i < j` of a square matrix, packed row-major like scipy's condensed
+    /// distance vectors and the output of `dtw_self`.
+    pub fn to_condensed(&self) -> Result<Vec<Float>, TsDistanceError> {
+        if self.rows != self.cols {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "condensed form needs a square matrix, got {}x{}",
+                self.rows, self.cols
+            )));
+        }
+        Ok((0..self.rows)
+            .flat_map(|i| self.row(i)[i + 1..].iter().copied())
+            .collect())
+    }
+
+    /// The symmetric matrix with a zero diagonal whose pairs `i < j` are `condensed`.
+    pub fn from_condensed(condensed: &[Float]) -> Result<Self, TsDistanceError> {
+        // n * (n - 1) / 2 = len
+        let n = (1.0 + (1.0 + 8.0 * condensed.len() as f64).sqrt()) as usize / 2;
+        if n * n.saturating_sub(1) / 2 != condensed.len() {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "{} is not the length of a condensed distance matrix",
+                condensed.len()
+            )));
+        }
+        let mut data = vec![0.0; n * n];
+        let mut values = condensed.iter();
+        for i in 0..n {
+            for j in i + 1..n {
+                let value = *values.next().unwrap();
+                data[i * n + j] = value;
+                data[j * n + i] = value;
+            }
+        }
+        Ok(Self {
+            rows: n,
+            cols: n,
+            data,
+        })
+    }
+}
+
+/// Fails on ragged rows, which have no row-major layout.
+impl TryFrom<Vec<Vec<Float>>> for DistanceMatrix {
+    type Error = TsDistanceError;
+
+    fn try_from(rows: Vec<Vec<Float>>) -> Result<Self, Self::Error> {
+        let cols = rows.first().map_or(0, |row| row.len());
+        if let Some((i, row)) = rows.iter().enumerate().find(|(_, row)| row.len() != cols) {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "row {} has {} columns, row 0 has {}",
+                i,
+                row.len(),
+                cols
+            )));
+        }
+        Ok(Self {
+            rows: rows.len(),
+            cols,
+            data: rows.concat(),
+        })
+    }
+}
+
+impl From<(Vec<Float>, usize, usize)> for DistanceMatrix {
+    fn from((data, rows, cols): (Vec<Float>, usize, usize)) -> Self {
+        Self { rows, cols, data }
+    }
+}
+
+impl From<DistanceMatrix> for Vec<Vec<Float>> {
+    fn from(matrix: DistanceMatrix) -> Self {
+        matrix.into_rows()
+    }
+}
@@ -0,0 +1,601 @@
+//! Host-side implementations of the elastic distances, following the same recurrences as the
+//! kernels in `warp_kernel_spec!`. They need no Vulkan device, so they double as a fallback
+//! on machines without one and as the ground truth the GPU results are checked against.
+//! Each function takes the parameters of the `cpu` function of the same name, without the
+//! device handles, and rejects the same invalid parameters with the same error.
+
+use crate::Float;
+use crate::context::{check_gak_sigma, check_gap, check_itakura, check_local_cost, check_weights};
+use crate::error::TsDistanceError;
+use crate::kernels::{
+    LocalCost, MSM_C, StepPattern, cosine_distance, gak_log_local_kernel, itakura_allowed,
+    log_sum_exp, msm_cost_function, unless_missing,
+};
+use crate::utils::{complexity_estimate, complexity_factor, derivative, znormalize};
+
+/// Fills the accumulated cost matrix of a single pair row by row, where `cell(i, j, x, y, z)`
+/// receives the costs left of, diagonally before and above `(i, j)`, exactly as the kernels.
+/// The origin is 0 and every other boundary cell is `init_val`.
+fn accumulate(
+    a_len: usize,
+    b_len: usize,
+    init_val: Float,
+    cell: impl Fn(usize, usize, Float, Float, Float) -> Float,
+) -> Float {
+    let mut prev = vec![init_val; b_len + 1];
+    let mut curr = vec![init_val; b_len + 1];
+    prev[0] = 0.0;
+    for i in 0..a_len {
+        curr[0] = init_val;
+        for j in 0..b_len {
+            curr[j + 1] = cell(i, j, curr[j], prev[j], prev[j + 1]);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b_len]
+}
+
+/// Same as `accumulate`, keeping every cell: `matrix[i][j]` is the cost of `(i, j)`.
+fn accumulate_matrix(
+    a_len: usize,
+    b_len: usize,
+    init_val: Float,
+    cell: impl Fn(usize, usize, Float, Float, Float) -> Float,
+) -> Vec<Vec<Float>> {
+    let mut matrix = vec![vec![init_val; b_len]; a_len];
+    for i in 0..a_len {
+        for j in 0..b_len {
+            let x = if j > 0 { matrix[i][j - 1] } else { init_val };
+            let y = match (i, j) {
+                (0, 0) => 0.0,
+                (0, _) | (_, 0) => init_val,
+                _ => matrix[i - 1][j - 1],
+            };
+            let z = if i > 0 { matrix[i - 1][j] } else { init_val };
+            matrix[i][j] = cell(i, j, x, y, z);
+        }
+    }
+    matrix
+}
+
+fn longest(a: &Vec<Vec<Float>>, b: &Vec<Vec<Float>>) -> usize {
+    a.iter().chain(b).map(|ts| ts.len()).max().unwrap_or(0)
+}
+
+fn pairwise(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    distance: impl Fn(&[Float], &[Float]) -> Float,
+) -> Vec<Vec<Float>> {
+    a.iter()
+        .map(|x| b.iter().map(|y| distance(x, y)).collect())
+        .collect()
+}
+
+pub fn erp(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    gap_penalty: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    erp_multivariate(a, b, 1, &[gap_penalty])
+}
+
+/// ERP over series of `dim` interleaved channels, see `cpu::erp_multivariate`.
+pub fn erp_multivariate(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    dim: usize,
+    gap: &[Float],
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    check_gap(dim, gap)?;
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(
+            a.len() / dim,
+            b.len() / dim,
+            Float::INFINITY,
+            |i, j, x, y, z| {
+                let (a, b) = (&a[i * dim..(i + 1) * dim], &b[j * dim..(j + 1) * dim]);
+                let matched: Float = (0..dim).map(|c| (a[c] - b[c]).abs()).sum();
+                let a_gap: Float = (0..dim).map(|c| (a[c] - gap[c]).abs()).sum();
+                let b_gap: Float = (0..dim).map(|c| (b[c] - gap[c]).abs()).sum();
+                (y + matched).min((z + a_gap).min(x + b_gap))
+            },
+        )
+    }))
+}
+
+/// ERP skipping the local cost of the NaN values, see `cpu::erp_masked`.
+pub fn erp_masked(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    gap_penalty: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            let matched = unless_missing((a[i] - b[j]).abs());
+            let a_gap = unless_missing((a[i] - gap_penalty).abs());
+            let b_gap = unless_missing((b[j] - gap_penalty).abs());
+            (y + matched).min((z + a_gap).min(x + b_gap))
+        })
+    }))
+}
+
+/// LCSS distance `1 - s / min(a.len(), b.len())`, where `s` is the length of the longest
+/// common subsequence under `epsilon`, matching only indices at most `delta` apart.
+pub fn lcss(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    epsilon: Float,
+    delta: Option<usize>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(pairwise(a, b, |a, b| {
+        let similarity = accumulate(a.len(), b.len(), 0.0, |i, j, x, y, z| {
+            let in_window = delta.is_none_or(|delta| i.abs_diff(j) <= delta);
+            if (a[i] - b[j]).abs() <= epsilon && in_window {
+                y + 1.0
+            } else {
+                x.max(z)
+            }
+        });
+        1.0 - similarity / a.len().min(b.len()) as Float
+    }))
+}
+
+/// Edit Distance on Real sequences, see `cpu::edr`.
+pub fn edr(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    epsilon: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            let x = if j == 0 { (i + 1) as Float } else { x };
+            let z = if i == 0 { (j + 1) as Float } else { z };
+            let y = if i == 0 {
+                j as Float
+            } else if j == 0 {
+                i as Float
+            } else {
+                y
+            };
+            let cost = if (a[i] - b[j]).abs() > epsilon {
+                1.0
+            } else {
+                0.0
+            };
+            (y + cost).min((x + 1.0).min(z + 1.0))
+        })
+    }))
+}
+
+pub fn dtw(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    window: Option<usize>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(dtw_constrained(a, b, 1, window, None))
+}
+
+/// Accumulated DTW cost matrix of a single pair, see `cpu::dtw_full_matrix`.
+pub fn dtw_full_matrix(
+    a: &[Float],
+    b: &[Float],
+    window: Option<usize>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let window = window.unwrap_or(usize::MAX);
+    Ok(accumulate_matrix(
+        a.len(),
+        b.len(),
+        Float::INFINITY,
+        |i, j, x, y, z| {
+            if i.abs_diff(j) > window {
+                return Float::INFINITY;
+            }
+            (a[i] - b[j]).powi(2) + z.min(x.min(y))
+        },
+    ))
+}
+
+/// DTW skipping the local cost of the NaN values, see `cpu::dtw_masked`.
+pub fn dtw_masked(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    window: Option<usize>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let window = window.unwrap_or(usize::MAX);
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            if i.abs_diff(j) > window {
+                return Float::INFINITY;
+            }
+            unless_missing((a[i] - b[j]).powi(2)) + z.min(x.min(y))
+        })
+    }))
+}
+
+/// DTW divided by `a.len() + b.len()`, see `cpu::dtw_normalized`.
+pub fn dtw_normalized(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    window: Option<usize>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let mut distances = dtw(a, b, window)?;
+    for (row, a) in distances.iter_mut().zip(a) {
+        for (d, b) in row.iter_mut().zip(b) {
+            *d /= (a.len() + b.len()) as Float;
+        }
+    }
+    Ok(distances)
+}
+
+/// DTW restricted to the Itakura parallelogram, see `cpu::dtw_itakura`.
+pub fn dtw_itakura(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    max_slope: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    check_itakura(max_slope)?;
+    Ok(dtw_constrained(a, b, 1, None, Some(max_slope)))
+}
+
+/// DTW against the best matching subsequence of `b`, see `cpu::subsequence_dtw`.
+pub fn subsequence_dtw(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(pairwise(a, b, |a, b| {
+        // The row above the first one is free, and so is leaving the last one anywhere.
+        let mut prev = vec![0.0; b.len() + 1];
+        let mut curr = vec![Float::INFINITY; b.len() + 1];
+        for i in 0..a.len() {
+            curr[0] = Float::INFINITY;
+            for j in 0..b.len() {
+                curr[j + 1] = (a[i] - b[j]).powi(2) + curr[j].min(prev[j]).min(prev[j + 1]);
+            }
+            std::mem::swap(&mut prev, &mut curr);
+        }
+        prev[1..].iter().copied().fold(Float::INFINITY, Float::min)
+    }))
+}
+
+/// DTW against the best matching prefix of `b` and where it ends, see `cpu::dtw_partial`.
+pub fn dtw_partial(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    window: Option<usize>,
+) -> Result<Vec<Vec<(Float, usize)>>, TsDistanceError> {
+    Ok(a.iter()
+        .map(|a| {
+            b.iter()
+                .map(|b| {
+                    let window = window.unwrap_or(usize::MAX);
+                    let mut prev = vec![Float::INFINITY; b.len() + 1];
+                    let mut curr = vec![Float::INFINITY; b.len() + 1];
+                    prev[0] = 0.0;
+                    for i in 0..a.len() {
+                        curr[0] = Float::INFINITY;
+                        for j in 0..b.len() {
+                            curr[j + 1] = if i.abs_diff(j) > window {
+                                Float::INFINITY
+                            } else {
+                                (a[i] - b[j]).powi(2) + curr[j].min(prev[j]).min(prev[j + 1])
+                            };
+                        }
+                        std::mem::swap(&mut prev, &mut curr);
+                    }
+                    // The leftmost of equally good ends, as the kernels report it.
+                    prev[1..].iter().copied().enumerate().fold(
+                        (Float::INFINITY, 0),
+                        |best, (j, d)| {
+                            if d < best.0 { (d, j) } else { best }
+                        },
+                    )
+                })
+                .collect()
+        })
+        .collect())
+}
+
+/// Complexity-invariant DTW, see `cpu::cid_dtw`.
+pub fn cid_dtw(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    window: Option<usize>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let distances = dtw(a, b, window)?;
+    Ok(distances
+        .into_iter()
+        .enumerate()
+        .map(|(i, row)| {
+            row.into_iter()
+                .enumerate()
+                .map(|(j, d)| {
+                    d * complexity_factor(complexity_estimate(&a[i]), complexity_estimate(&b[j]))
+                })
+                .collect()
+        })
+        .collect())
+}
+
+/// DTW over series of `dim` interleaved channels, see `cpu::dtw_multivariate`.
+pub fn dtw_multivariate(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    dim: usize,
+    window: Option<usize>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(dtw_constrained(a, b, dim, window, None))
+}
+
+/// DTW with the cosine distance between timesteps as local cost, see `cpu::dtw_cosine`.
+pub fn dtw_cosine(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    dim: usize,
+    window: Option<usize>,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let window = window.unwrap_or(usize::MAX);
+    pairwise(a, b, |a, b| {
+        accumulate(
+            a.len() / dim,
+            b.len() / dim,
+            Float::INFINITY,
+            |i, j, x, y, z| {
+                if i.abs_diff(j) > window {
+                    return Float::INFINITY;
+                }
+                let (a, b) = (&a[i * dim..(i + 1) * dim], &b[j * dim..(j + 1) * dim]);
+                let dot = a.iter().zip(b).map(|(p, q)| p * q).sum();
+                let norm_sq = |v: &[Float]| v.iter().map(|p| p * p).sum();
+                cosine_distance(dot, norm_sq(a), norm_sq(b)) + z.min(x.min(y))
+            },
+        )
+    })
+}
+
+fn dtw_constrained(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    dim: usize,
+    window: Option<usize>,
+    itakura: Option<Float>,
+) -> Vec<Vec<Float>> {
+    let window = window.unwrap_or(usize::MAX);
+    Ok(pairwise(a, b, |a, b| {
+        let (n, m) = (a.len() / dim, b.len() / dim);
+        accumulate(n, m, Float::INFINITY, |i, j, x, y, z| {
+            let outside = itakura.is_some_and(|slope| {
+                !itakura_allowed(i as u64, j as u64, n as u64, m as u64, slope)
+            });
+            if i.abs_diff(j) > window || outside {
+                return Float::INFINITY;
+            }
+            let dist: Float = (0..dim)
+                .map(|c| (a[i * dim + c] - b[j * dim + c]).powi(2))
+                .sum();
+            dist + z.min(x.min(y))
+        })
+    }))
+}
+
+/// DTW under a local cost, see `cpu::dtw_with_cost`.
+pub fn dtw_with_cost(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    window: Option<usize>,
+    cost: LocalCost,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    check_local_cost(cost)?;
+    let window = window.unwrap_or(usize::MAX);
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            if i.abs_diff(j) > window {
+                return Float::INFINITY;
+            }
+            cost.apply(a[i], b[j]) + z.min(x.min(y))
+        })
+    }))
+}
+
+/// DTW under a step pattern, see `cpu::dtw_step`. `SymmetricP1` is computed from its
+/// definition, over the cells a knight's move away, rather than as the kernel does.
+pub fn dtw_step(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    window: Option<usize>,
+    pattern: StepPattern,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    if pattern == StepPattern::SymmetricP0 {
+        return dtw(a, b, window);
+    }
+    let window = window.unwrap_or(usize::MAX);
+    Ok(pairwise(a, b, |a, b| {
+        let (n, m) = (a.len(), b.len());
+        let dist = |i: usize, j: usize| {
+            if i.abs_diff(j) > window {
+                Float::INFINITY
+            } else {
+                (a[i] - b[j]).powi(2)
+            }
+        };
+        let mut g = vec![vec![Float::INFINITY; m]; n];
+        for i in 0..n {
+            for j in 0..m {
+                g[i][j] = if i.abs_diff(j) > window {
+                    Float::INFINITY
+                } else if i == 0 && j == 0 {
+                    dist(0, 0)
+                } else {
+                    let mut best = Float::INFINITY;
+                    if i >= 1 && j >= 1 {
+                        best = best.min(g[i - 1][j - 1] + 2.0 * dist(i, j));
+                    }
+                    if i >= 1 && j >= 2 {
+                        best = best.min(g[i - 1][j - 2] + 2.0 * dist(i, j - 1) + dist(i, j));
+                    }
+                    if i >= 2 && j >= 1 {
+                        best = best.min(g[i - 2][j - 1] + 2.0 * dist(i - 1, j) + dist(i, j));
+                    }
+                    best
+                };
+            }
+        }
+        g[n - 1][m - 1]
+    }))
+}
+
+pub fn ddtw(a: &Vec<Vec<Float>>, b: &Vec<Vec<Float>>) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let a = a.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+    let b = b.iter().map(|ts| derivative(ts)).collect::<Vec<_>>();
+    dtw(&a, &b, None)
+}
+
+pub fn wdtw(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    weights: &[Float],
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    check_weights("wdtw", longest(a, b), weights)?;
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            (a[i] - b[j]).powi(2) * weights[i.abs_diff(j)] + x.min(y.min(z))
+        })
+    }))
+}
+
+pub fn msm(a: &Vec<Vec<Float>>, b: &Vec<Vec<Float>>) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    msm_c(a, b, MSM_C)
+}
+
+pub fn msm_c(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    c: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    msm_gen(a, b, c, c)
+}
+
+pub fn wmsm(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    c: Float,
+    weights: &[Float],
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    check_weights("wmsm", longest(a, b), weights)?;
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            let a_prev = if i == 0 { 0.0 } else { a[i - 1] };
+            let b_prev = if j == 0 { 0.0 } else { b[j - 1] };
+            let weight = weights[i.abs_diff(j)];
+            (y + (a[i] - b[j]).abs())
+                .min(z + weight * msm_cost_function(a[i], a_prev, b[j], c))
+                .min(x + weight * msm_cost_function(b[j], a[i], b_prev, c))
+        })
+    }))
+}
+
+pub fn msm_gen(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    split_cost: Float,
+    merge_cost: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            let a_prev = if i == 0 { 0.0 } else { a[i - 1] };
+            let b_prev = if j == 0 { 0.0 } else { b[j - 1] };
+            (y + (a[i] - b[j]).abs())
+                .min(z + msm_cost_function(a[i], a_prev, b[j], merge_cost))
+                .min(x + msm_cost_function(b[j], a[i], b_prev, split_cost))
+        })
+    }))
+}
+
+pub fn twe(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    nu: Float,
+    lambda: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let delete_addition = lambda + nu;
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            let a_prev = if i == 0 { 0.0 } else { a[i - 1] };
+            let b_prev = if j == 0 { 0.0 } else { b[j - 1] };
+            let del_a = z + (a_prev - a[i]).abs() + delete_addition;
+            let del_b = x + (b_prev - b[j]).abs() + delete_addition;
+            let match_a_b = y
+                + (a[i] - b[j]).abs()
+                + (a_prev - b_prev).abs()
+                + nu * (2.0 * i.abs_diff(j) as Float);
+            del_a.min(del_b.min(match_a_b))
+        })
+    }))
+}
+
+pub fn adtw(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    w: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            (a[i] - b[j]).powi(2) + (z + w).min((x + w).min(y))
+        })
+    }))
+}
+
+/// ADTW with a penalty of `w * |i - j|` per warping step, see `cpu::adtw_scaled`.
+pub fn adtw_scaled(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    w: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::INFINITY, |i, j, x, y, z| {
+            let penalty = w * i.abs_diff(j) as Float;
+            (a[i] - b[j]).powi(2) + (z + penalty).min((x + penalty).min(y))
+        })
+    }))
+}
+
+/// Logarithm of the Global Alignment Kernel, see `cpu::gak`.
+pub fn gak(
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    sigma: Float,
+    triangular: usize,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    check_gak_sigma(sigma)?;
+    Ok(pairwise(a, b, |a, b| {
+        accumulate(a.len(), b.len(), Float::NEG_INFINITY, |i, j, x, y, z| {
+            let dist = (a[i] - b[j]).powi(2);
+            gak_log_local_kernel(dist, i as u64, j as u64, sigma, triangular as u64)
+                + log_sum_exp(x, y, z)
+        })
+    }))
+}
+
+/// Shape-based distance, see `cpu::sbd`.
+pub fn sbd(a: &Vec<Vec<Float>>, b: &Vec<Vec<Float>>) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    Ok(pairwise(a, b, |a, b| {
+        let (a, b) = (znormalize(a), znormalize(b));
+        let norm = |ts: &[Float]| ts.iter().map(|x| x * x).sum::<Float>().sqrt();
+        let scale = norm(&a) * norm(&b);
+        if scale == 0.0 {
+            return 1.0;
+        }
+        let len = a.len();
+        let peak = (1..2 * len)
+            .map(|s| {
+                // Shift `s - len` of a against b.
+                let shift = s as isize - len as isize;
+                (0..len as isize)
+                    .filter(|t| (0..len as isize).contains(&(t + shift)))
+                    .map(|t| a[(t + shift) as usize] * b[t as usize])
+                    .sum::<Float>()
+            })
+            .fold(Float::NEG_INFINITY, Float::max);
+        1.0 - peak / scale
+    }))
+}
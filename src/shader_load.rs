@@ -1,8 +1,7 @@
-use std::sync::{Arc, OnceLock};
+use std::sync::{Arc, OnceLock, Weak};
 
 use dashmap::DashMap;
 use vulkano::{
-    Validated, VulkanError,
     device::Device,
     pipeline::{
         ComputePipeline, PipelineLayout, PipelineShaderStageCreateInfo,
@@ -11,10 +10,41 @@ use vulkano::{
     shader::{ShaderModule, ShaderModuleCreateInfo},
 };
 
-static SHADE_PIPELINES: OnceLock<DashMap<&'static str, Arc<ComputePipeline>>> = OnceLock::new();
+/// The pipelines compiled for a single device, by entry point. The handles of the device
+/// own them, see `device_pipelines`, so that they are dropped together with the device
+/// they keep alive.
+#[derive(Default)]
+pub(crate) struct DevicePipelines {
+    pipelines: DashMap<&'static str, Arc<ComputePipeline>>,
+}
+
+/// The pipeline cache of every live device, by device address. Only weak references are
+/// kept, so a dropped device takes its pipelines with it and its entry is evicted on the
+/// next lookup. An address is only reused once the device, and so its cache, is gone.
+static DEVICE_PIPELINES: OnceLock<DashMap<usize, Weak<DevicePipelines>>> = OnceLock::new();
+
+/// The pipeline cache of `device`, shared by every holder of its handles. A new one is
+/// created when no handles of the device hold it any more.
+pub(crate) fn device_pipelines(device: &Arc<Device>) -> Arc<DevicePipelines> {
+    let registry = DEVICE_PIPELINES.get_or_init(Default::default);
+    registry.retain(|_, cache| cache.strong_count() > 0);
+    let mut entry = registry.entry(Arc::as_ptr(device) as usize).or_default();
+    if let Some(cache) = entry.upgrade() {
+        return cache;
+    }
+    let cache = Arc::new(DevicePipelines::default());
+    *entry = Arc::downgrade(&cache);
+    cache
+}
 
 const SHADER_CODE: &[u8] = include_bytes!(env!("tsdistances_gpu.spv"));
 
+/// SPIR-V modules registered at runtime by `register_custom_kernel`, by entry point. Their
+/// names never clash with the entry points of `SHADER_CODE`.
+static CUSTOM_SHADERS: OnceLock<DashMap<&'static str, Arc<[u8]>>> = OnceLock::new();
+
+use crate::error::TsDistanceError;
+
 use rspirv::binary::Assemble;
 use rspirv::spirv::{ExecutionMode, Op};
 
@@ -22,9 +52,11 @@ fn load(
     entry_point: &str,
     device: Arc<Device>,
     shader: &[u8],
-) -> Result<Arc<ShaderModule>, Validated<VulkanError>> {
+) -> Result<Arc<ShaderModule>, TsDistanceError> {
     // Load the SPIR-V module
-    let mut spirv_module = rspirv::dr::load_bytes(shader).expect("Failed to load SPIR-V module");
+    let mut spirv_module = rspirv::dr::load_bytes(shader).map_err(|err| {
+        TsDistanceError::Shader(format!("failed to load SPIR-V module: {:?}", err))
+    })?;
     // Query the max threads in the x-dimension
     let max_threads_x = device
         .physical_device()
@@ -36,7 +68,7 @@ fn load(
         .entry_points
         .iter()
         .find(|entry| entry.operands[2].unwrap_literal_string() == entry_point)
-        .expect("Entry point not found")
+        .ok_or_else(|| TsDistanceError::Shader(format!("entry point {} not found", entry_point)))?
         .operands[1]
         .unwrap_id_ref(); // Operand[1] is the function ID
 
@@ -66,35 +98,83 @@ fn load(
     let spirv = spirv_module.assemble();
 
     // Create the ShaderModule with the optimized SPIR-V
-    unsafe { ShaderModule::new(device, ShaderModuleCreateInfo::new(&spirv)) }
+    Ok(unsafe { ShaderModule::new(device, ShaderModuleCreateInfo::new(&spirv)) }?)
 }
 
-pub fn get_shader_entry_pipeline(device: Arc<Device>, name: &'static str) -> Arc<ComputePipeline> {
-    let pipelines = SHADE_PIPELINES.get_or_init(Default::default);
+/// Makes the entry point `entry` of an externally compiled SPIR-V module available to
+/// `kernels::custom::CustomKernel`. Only SPIR-V is accepted, GLSL has to be compiled first,
+/// e.g. with `glslc`. Registering an entry point again replaces it, on every device, but the
+/// entry points of the crate itself can't be replaced.
+pub fn register_custom_kernel(spirv: &[u8], entry: &'static str) -> Result<(), TsDistanceError> {
+    if has_entry_point(SHADER_CODE, entry)? {
+        return Err(TsDistanceError::Shader(format!(
+            "entry point {} is a kernel of the crate",
+            entry
+        )));
+    }
+    if !has_entry_point(spirv, entry)? {
+        return Err(TsDistanceError::Shader(format!(
+            "entry point {} not found",
+            entry
+        )));
+    }
+    CUSTOM_SHADERS
+        .get_or_init(Default::default)
+        .insert(entry, spirv.into());
+    if let Some(registry) = DEVICE_PIPELINES.get() {
+        for cache in registry.iter().filter_map(|cache| cache.upgrade()) {
+            cache.pipelines.remove(entry);
+        }
+    }
+    Ok(())
+}
 
-    match pipelines.entry(name) {
-        dashmap::Entry::Occupied(entry) => entry.get().clone(),
+fn has_entry_point(spirv: &[u8], entry: &str) -> Result<bool, TsDistanceError> {
+    let module = rspirv::dr::load_bytes(spirv).map_err(|err| {
+        TsDistanceError::Shader(format!("failed to load SPIR-V module: {:?}", err))
+    })?;
+    Ok(module
+        .entry_points
+        .iter()
+        .any(|inst| inst.operands[2].unwrap_literal_string() == entry))
+}
+
+pub fn get_shader_entry_pipeline(
+    device: Arc<Device>,
+    name: &'static str,
+) -> Result<Arc<ComputePipeline>, TsDistanceError> {
+    let cache = device_pipelines(&device);
+
+    match cache.pipelines.entry(name) {
+        dashmap::Entry::Occupied(entry) => Ok(entry.get().clone()),
         dashmap::Entry::Vacant(vacant_entry) => {
-            let shader_module = load(name, device.clone(), SHADER_CODE).unwrap();
+            let custom = CUSTOM_SHADERS
+                .get()
+                .and_then(|shaders| shaders.get(name).map(|shader| shader.clone()));
+            let shader_module = load(
+                name,
+                device.clone(),
+                custom.as_deref().unwrap_or(SHADER_CODE),
+            )?;
             let Some(entry_point) = shader_module.entry_point(name) else {
-                panic!("Entry point {} not found in shader module", name);
+                return Err(TsDistanceError::Shader(format!(
+                    "entry point {} not found in shader module",
+                    name
+                )));
             };
             let stage = PipelineShaderStageCreateInfo::new(entry_point);
             let layout = PipelineLayout::new(
                 device.clone(),
                 PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
-                    .into_pipeline_layout_create_info(device.clone())
-                    .unwrap(),
-            )
-            .unwrap();
+                    .into_pipeline_layout_create_info(device.clone())?,
+            )?;
             let pipeline = ComputePipeline::new(
                 device.clone(),
                 None,
                 ComputePipelineCreateInfo::stage_layout(stage, layout),
-            )
-            .unwrap();
+            )?;
             vacant_entry.insert(pipeline.clone());
-            pipeline
+            Ok(pipeline)
         }
     }
 }
@@ -1,9 +1,16 @@
-use std::sync::{Arc, LazyLock};
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
 use vulkano::{
     VulkanLibrary,
     buffer::{
-        BufferContents, BufferUsage, Subbuffer,
+        Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer,
         allocator::{SubbufferAllocator, SubbufferAllocatorCreateInfo},
     },
     command_buffer::{
@@ -12,12 +19,24 @@ use vulkano::{
     descriptor_set::allocator::StandardDescriptorSetAllocator,
     device::{
         Device, DeviceCreateInfo, DeviceExtensions, DeviceFeatures, Queue, QueueCreateInfo,
-        QueueFlags, physical::PhysicalDeviceType,
+        QueueFlags,
+        physical::{PhysicalDevice, PhysicalDeviceType, SubgroupFeatures},
     },
     instance::{Instance, InstanceCreateFlags, InstanceCreateInfo},
-    memory::allocator::{MemoryTypeFilter, StandardMemoryAllocator},
+    memory::{
+        MemoryHeapFlags,
+        allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    },
+    shader::ShaderStages,
+    sync::Sharing,
 };
 
+use crate::Float;
+use crate::error::TsDistanceError;
+use crate::kernels::envelopes::cpu::envelopes_gpu;
+use crate::shader_load::{DevicePipelines, device_pipelines};
+use crate::warps::{max_series_len, padded_diag_len};
+
 #[macro_export]
 macro_rules! assert_eq_with_tol {
     ($a:expr, $b:expr, $tol:expr) => {
@@ -33,28 +52,655 @@ macro_rules! assert_eq_with_tol {
     };
 }
 
+/// Panics with a `MismatchReport` unless every entry of the matrix `$got` is within
+/// `$tol * max(|expected|, 1)` of the same entry of `$expected`, see `compare_matrices`.
+#[macro_export]
+macro_rules! assert_matrix_eq_with_tol {
+    ($got:expr, $expected:expr, $tol:expr) => {
+        if let Err(report) = $crate::utils::compare_matrices(&$got, &$expected, $tol) {
+            panic!("matrices differ\n{}", report);
+        }
+    };
+}
+
+/// Largest count of the integer valued measures, LCSS and EDR, that `Float` holds exactly:
+/// every integer up to 2^24 in `f32`, beyond which some are rounded to an even neighbour.
+pub const MAX_EXACT_COUNT: u64 = 1 << Float::MANTISSA_DIGITS;
+
+/// Most offending entries a `MismatchReport` lists.
+pub const MISMATCH_REPORT_LEN: usize = 10;
+
+/// An entry of a matrix that differs from the expected one, see `compare_matrices`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mismatch {
+    pub i: usize,
+    pub j: usize,
+    pub got: Float,
+    pub expected: Float,
+    pub abs_err: Float,
+    /// `abs_err` over `max(|expected|, 1)`, the error the tolerance applies to.
+    pub rel_err: Float,
+}
+
+/// How a matrix differs from the expected one, as returned by `compare_matrices`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MismatchReport {
+    /// Rows and columns of both matrices, when they don't match every row of the smaller one
+    /// is compared.
+    pub got_shape: (usize, usize),
+    pub expected_shape: (usize, usize),
+    /// The worst `MISMATCH_REPORT_LEN` entries out of tolerance, largest relative error
+    /// first.
+    pub worst: Vec<Mismatch>,
+    /// Entries out of tolerance, of which `worst` is a part.
+    pub mismatch_count: usize,
+    /// Largest and mean absolute error over every compared entry.
+    pub max_abs_err: Float,
+    pub mean_abs_err: Float,
+}
+
+impl fmt::Display for MismatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.got_shape != self.expected_shape {
+            writeln!(
+                f,
+                "shape {:?}, expected {:?}",
+                self.got_shape, self.expected_shape
+            )?;
+        }
+        writeln!(
+            f,
+            "{} entries out of tolerance, max abs error {:e}, mean abs error {:e}",
+            self.mismatch_count, self.max_abs_err, self.mean_abs_err
+        )?;
+        for m in &self.worst {
+            writeln!(
+                f,
+                "  [{}][{}]: got {:?}, expected {:?}, abs error {:e}, rel error {:e}",
+                m.i, m.j, m.got, m.expected, m.abs_err, m.rel_err
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MismatchReport {}
+
+/// Compares `got` against `expected` entry by entry, e.g. a kernel against its `reference`
+/// implementation. An entry matches when it is within `tol * max(|expected|, 1)`, relative
+/// for large values and absolute below 1, or when both are the same infinity or both `NaN`.
+/// Matrices of different shapes never match.
+pub fn compare_matrices(
+    got: &[Vec<Float>],
+    expected: &[Vec<Float>],
+    tol: Float,
+) -> Result<(), MismatchReport> {
+    let shape = |m: &[Vec<Float>]| (m.len(), m.first().map_or(0, |row| row.len()));
+    let ragged = |m: &[Vec<Float>]| m.iter().any(|row| row.len() != shape(m).1);
+    let (got_shape, expected_shape) = (shape(got), shape(expected));
+
+    let mut mismatches = Vec::new();
+    let mut max_abs_err: Float = 0.0;
+    let mut sum_abs_err: Float = 0.0;
+    let mut compared = 0;
+    for (i, (got_row, expected_row)) in got.iter().zip(expected).enumerate() {
+        for (j, (&got, &expected)) in got_row.iter().zip(expected_row).enumerate() {
+            let same = got == expected || (got.is_nan() && expected.is_nan());
+            let abs_err = if same {
+                0.0
+            } else if got.is_nan() || expected.is_nan() {
+                Float::INFINITY
+            } else {
+                (got - expected).abs()
+            };
+            let rel_err = if abs_err == 0.0 {
+                0.0
+            } else if expected.is_infinite() {
+                Float::INFINITY
+            } else {
+                abs_err / expected.abs().max(1.0)
+            };
+            max_abs_err = max_abs_err.max(abs_err);
+            sum_abs_err += abs_err;
+            compared += 1;
+            if rel_err > tol {
+                mismatches.push(Mismatch {
+                    i,
+                    j,
+                    got,
+                    expected,
+                    abs_err,
+                    rel_err,
+                });
+            }
+        }
+    }
+
+    if mismatches.is_empty() && got_shape == expected_shape && !ragged(got) && !ragged(expected) {
+        return Ok(());
+    }
+    let mismatch_count = mismatches.len();
+    mismatches.sort_by(|x, y| y.rel_err.total_cmp(&x.rel_err));
+    mismatches.truncate(MISMATCH_REPORT_LEN);
+    Err(MismatchReport {
+        got_shape,
+        expected_shape,
+        worst: mismatches,
+        mismatch_count,
+        max_abs_err,
+        mean_abs_err: if compared == 0 {
+            0.0
+        } else {
+            sum_abs_err / compared as Float
+        },
+    })
+}
+
+/// What to do with `NaN` and infinite input values. The kernels compare costs with
+/// `min`/`max`, whose handling of `NaN` differs between the device and the host, so such
+/// values are rejected unless asked otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NanPolicy {
+    /// Fail with `TsDistanceError::NonFiniteInput`.
+    #[default]
+    Reject,
+    /// Replace every non-finite value by 0 before the upload.
+    TreatAsZero,
+    /// Upload the values as they are, the results are then unspecified.
+    Propagate,
+    /// Keep NaN values as markers of missing timesteps, whose local cost `dtw_masked` and
+    /// `erp_masked` skip. Infinities are still rejected.
+    Mask,
+}
+
+impl NanPolicy {
+    /// Whether `x` is neither rejected nor replaced under the policy, `Propagate` aside.
+    fn keeps(self, x: Float) -> bool {
+        x.is_finite() || (self == NanPolicy::Mask && x.is_nan())
+    }
+
+    /// Checks the values of `series` under the policy, returning them with the non-finite
+    /// ones replaced for `TreatAsZero`.
+    pub(crate) fn apply<'a>(
+        self,
+        name: &str,
+        series: &'a Vec<Vec<Float>>,
+    ) -> Result<Cow<'a, Vec<Vec<Float>>>, TsDistanceError> {
+        let non_finite = series.iter().enumerate().find_map(|(i, ts)| {
+            ts.iter()
+                .position(|&x| !self.keeps(x))
+                .map(|j| (i, j, ts[j]))
+        });
+        match (self, non_finite) {
+            (_, None) | (NanPolicy::Propagate, _) => Ok(Cow::Borrowed(series)),
+            (NanPolicy::Reject | NanPolicy::Mask, Some((i, j, value))) => {
+                Err(TsDistanceError::NonFiniteInput(format!(
+                    "series {} of {} holds {} at {}",
+                    i, name, value, j
+                )))
+            }
+            (NanPolicy::TreatAsZero, Some(_)) => Ok(Cow::Owned(
+                series
+                    .iter()
+                    .map(|ts| {
+                        ts.iter()
+                            .map(|&x| if x.is_finite() { x } else { 0.0 })
+                            .collect()
+                    })
+                    .collect(),
+            )),
+        }
+    }
+
+    /// Same as `apply`, over a single flat buffer of values.
+    pub(crate) fn apply_flat<'a>(
+        self,
+        name: &str,
+        values: &'a [Float],
+    ) -> Result<Cow<'a, [Float]>, TsDistanceError> {
+        let non_finite = values.iter().position(|&x| !self.keeps(x));
+        match (self, non_finite) {
+            (_, None) | (NanPolicy::Propagate, _) => Ok(Cow::Borrowed(values)),
+            (NanPolicy::Reject | NanPolicy::Mask, Some(i)) => Err(TsDistanceError::NonFiniteInput(
+                format!("{} holds {} at {}", name, values[i], i),
+            )),
+            (NanPolicy::TreatAsZero, Some(_)) => Ok(Cow::Owned(
+                values
+                    .iter()
+                    .map(|&x| if x.is_finite() { x } else { 0.0 })
+                    .collect(),
+            )),
+        }
+    }
+}
+
+/// Copy of `ts` with the values whose `valid` flag is false replaced by NaN, which marks them
+/// as missing under `NanPolicy::Mask`.
+pub fn apply_mask(ts: &[Float], valid: &[bool]) -> Result<Vec<Float>, TsDistanceError> {
+    if ts.len() != valid.len() {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "mask of {} flags for a series of {} values",
+            valid.len(),
+            ts.len()
+        )));
+    }
+    Ok(ts
+        .iter()
+        .zip(valid)
+        .map(|(&x, &valid)| if valid { x } else { Float::NAN })
+        .collect())
+}
+
+/// Flag shared with a running batch to stop it, see `RunOptions::with_cancellation`.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks every computation holding the token to stop, from any thread.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How a run treats its input and results and how long it may take, passed to the distance
+/// functions next to the `SubBuffersAllocator` they allocate from.
+#[derive(Clone)]
+pub struct RunOptions {
+    nan_policy: NanPolicy,
+    tile_size: Option<usize>,
+    timeout: Option<Duration>,
+    sqrt_output: bool,
+    cancellation: Option<CancellationToken>,
+    subgroup_size_query: bool,
+    max_queues: Option<usize>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            nan_policy: NanPolicy::default(),
+            tile_size: None,
+            timeout: None,
+            sqrt_output: false,
+            cancellation: None,
+            subgroup_size_query: true,
+            max_queues: None,
+        }
+    }
+}
+
+impl RunOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how the uploaded series treat non-finite values.
+    pub fn with_nan_policy(mut self, policy: NanPolicy) -> Self {
+        self.nan_policy = policy;
+        self
+    }
+
+    pub fn nan_policy(&self) -> NanPolicy {
+        self.nan_policy
+    }
+
+    /// Computes the diamonds in tiles of `tile_size` threads instead of the subgroup size,
+    /// which can improve occupancy on some devices. It must be a power of two no larger than
+    /// the subgroup size.
+    pub fn with_tile_size(mut self, tile_size: usize) -> Self {
+        self.tile_size = Some(tile_size);
+        self
+    }
+
+    /// Gives up on the device after `timeout` instead of waiting forever, failing with
+    /// `TsDistanceError::Timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Checks `token` before every chunk of a batch, which then fails with
+    /// `TsDistanceError::Cancelled` instead of submitting the next one. Chunks already
+    /// submitted still run to completion on the device before the error is returned.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    pub(crate) fn check_cancelled(&self) -> Result<(), TsDistanceError> {
+        match &self.cancellation {
+            Some(token) if token.is_cancelled() => Err(TsDistanceError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the square root of the accumulated cost for the kernels summing squared
+    /// differences (DTW, DDTW, WDTW and ADTW), as tslearn does, instead of the cost itself.
+    /// Cost matrices, as returned by `cost_matrix_gpu`, are left squared.
+    pub fn with_sqrt_output(mut self, sqrt_output: bool) -> Self {
+        self.sqrt_output = sqrt_output;
+        self
+    }
+
+    pub fn sqrt_output(&self) -> bool {
+        self.sqrt_output
+    }
+
+    /// `false` ignores the subgroup size the device reports, as if its driver did not, so
+    /// that the series are padded and tiled with the fallback of `effective_subgroup_size`.
+    pub fn with_subgroup_size_query(mut self, enabled: bool) -> Self {
+        self.subgroup_size_query = enabled;
+        self
+    }
+
+    /// Submits the chunks of a batch to at most `max_queues` of the device's compute queues,
+    /// `1` to keep every chunk on the queue passed to the distance functions.
+    pub fn with_max_queues(mut self, max_queues: usize) -> Self {
+        self.max_queues = Some(max_queues.max(1));
+        self
+    }
+
+    pub fn max_queues(&self) -> Option<usize> {
+        self.max_queues
+    }
+
+    /// The subgroup size the series are padded to, see `effective_subgroup_size`.
+    pub(crate) fn subgroup_size(&self, device: &Device) -> usize {
+        let properties = device.physical_device().properties();
+        effective_subgroup_size(
+            properties
+                .max_subgroup_size
+                .filter(|_| self.subgroup_size_query),
+            properties.max_compute_work_group_invocations,
+        )
+    }
+
+    /// Threads per diamond tile on `device`, the subgroup size unless overridden.
+    pub(crate) fn tile_size(&self, device: &Device) -> Result<usize, TsDistanceError> {
+        let subgroup_size = self.subgroup_size(device);
+        match self.tile_size {
+            None => Ok(subgroup_size),
+            Some(tile_size) if tile_size.is_power_of_two() && tile_size <= subgroup_size => {
+                Ok(tile_size)
+            }
+            Some(tile_size) => Err(TsDistanceError::InvalidInput(format!(
+                "the tile size must be a power of two up to the subgroup size {}, got {}",
+                subgroup_size, tile_size
+            ))),
+        }
+    }
+}
+
+/// Constants and shape of one dispatch of a `warp_kernel_spec!` kernel, see `DispatchTrace`.
+/// The kernel's own parameters are left out.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DispatchRecord {
+    pub kernel_name: &'static str,
+    pub first_coord: i64,
+    pub row: u64,
+    pub tile_count: u64,
+    pub a_start: u64,
+    pub b_start: u64,
+    pub a_len: u64,
+    pub b_len: u64,
+    pub a_count: u64,
+    pub b_count: u64,
+    pub diag_len: u64,
+    pub max_subgroup_threads: u64,
+    pub pair_layout: u64,
+    pub dim: u64,
+    pub subgroup_sync: bool,
+    pub threads_count: u32,
+    pub workgroup_count: u32,
+}
+
+/// Records every diamond dispatch of the runs it is attached to with
+/// `SubBuffersAllocator::with_dispatch_trace`, in the order they are recorded, to inspect how
+/// a batch was partitioned. Off unless attached. With the `serde` feature, the `records`
+/// serialize to any serde format.
+#[derive(Clone, Default)]
+pub struct DispatchTrace(Arc<Mutex<Vec<DispatchRecord>>>);
+
+impl DispatchTrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> Vec<DispatchRecord> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
 #[derive(Clone)]
 pub struct SubBuffersAllocator {
     gpu: Arc<SubbufferAllocator>,
     cpu: Arc<SubbufferAllocator>,
+    max_storage_range: Option<usize>,
+    queues: Arc<[Arc<Queue>]>,
+    max_push_constants_size: Option<usize>,
+    dispatch_trace: Option<DispatchTrace>,
+    subgroup_sync: bool,
+    memory_budget: Option<u64>,
+    // Device memory of the current run, and the counter of the last run started on any clone.
+    device_bytes: Arc<AtomicU64>,
+    last_run: Arc<Mutex<Arc<AtomicU64>>>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    transfer_queue: Option<Arc<Queue>>,
+    // Keeps the pipelines of the device cached for as long as its handles live.
+    _pipelines: Arc<DevicePipelines>,
 }
 
 impl SubBuffersAllocator {
     pub fn debug(&self) {
         use memory_stats::memory_stats;
         if let Some(usage) = memory_stats() {
-            println!("Current physical memory usage: {}", usage.physical_mem);
-            println!("Current virtual memory usage: {}", usage.virtual_mem);
+            log::debug!("Current physical memory usage: {}", usage.physical_mem);
+            log::debug!("Current virtual memory usage: {}", usage.virtual_mem);
         } else {
-            println!("Couldn't get the current memory usage :(");
+            log::debug!("Couldn't get the current memory usage :(");
         }
-        println!(
+        log::debug!(
             "CPU arena size: {}, GPU arena size: {}",
             self.cpu.arena_size(),
             self.gpu.arena_size()
         );
     }
 
+    /// A device-only buffer, for results that are never read back as is.
+    pub fn gpu_slice<T: BufferContents>(
+        &self,
+        len: u64,
+    ) -> Result<Subbuffer<[T]>, TsDistanceError> {
+        self.reserve(len * std::mem::size_of::<T>() as u64)?;
+        Ok(self.gpu.allocate_slice(len)?)
+    }
+
+    /// A buffer shared by the queue families in `families`, so that a transfer queue can
+    /// fill it for the compute queues without handing its ownership over.
+    fn shared_slice<T: BufferContents>(
+        &self,
+        len: u64,
+        families: [u32; 2],
+        usage: BufferUsage,
+        memory_type_filter: MemoryTypeFilter,
+    ) -> Result<Subbuffer<[T]>, TsDistanceError> {
+        Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage,
+                sharing: Sharing::Concurrent(families.into_iter().collect()),
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter,
+                ..Default::default()
+            },
+            len,
+        )
+        .map_err(|err| TsDistanceError::Allocation(err.to_string()))
+    }
+
+    /// Whether the device has a dedicated transfer queue the uploads can use.
+    pub fn has_transfer_queue(&self) -> bool {
+        self.transfer_queue.is_some()
+    }
+
+    /// Queue the uploads for work on `queue` are submitted to: the dedicated transfer queue
+    /// when there is one and `queue` was created along with it, `queue` otherwise. Only
+    /// uploads chained to the compute queue with a semaphore use it, see
+    /// `standalone::submit_after_transfer`.
+    pub(crate) fn upload_queue(&self, queue: &Arc<Queue>) -> Arc<Queue> {
+        match &self.transfer_queue {
+            Some(transfer_queue) if self.queues.iter().any(|q| Arc::ptr_eq(q, queue)) => {
+                transfer_queue.clone()
+            }
+            _ => queue.clone(),
+        }
+    }
+
+    /// Bounds the device memory a run may allocate, series included, to `bytes`. Batches are
+    /// split into smaller chunks to stay within it, failing with
+    /// `TsDistanceError::MemoryBudgetExceeded` when even a single pair does not fit.
+    pub fn with_memory_budget(mut self, bytes: u64) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    pub fn memory_budget(&self) -> Option<u64> {
+        self.memory_budget
+    }
+
+    /// Bytes of device memory allocated by the last run, from the upload of its series, or
+    /// from the series already resident for a `GpuContext`, to its last chunk.
+    pub fn last_run_memory_bytes(&self) -> u64 {
+        self.last_run.lock().unwrap().load(Ordering::Relaxed)
+    }
+
+    /// The allocator of a new run, `resident` bytes being already in use. It counts on a
+    /// counter of its own, so that runs sharing the handles never mix their memory.
+    pub(crate) fn start_run(&self, resident: u64) -> Self {
+        let device_bytes = Arc::new(AtomicU64::new(resident));
+        *self.last_run.lock().unwrap() = device_bytes.clone();
+        Self {
+            device_bytes,
+            ..self.clone()
+        }
+    }
+
+    /// Bytes the run may still allocate, when it has a budget.
+    pub(crate) fn available_device_bytes(&self) -> Option<u64> {
+        self.memory_budget
+            .map(|budget| budget.saturating_sub(self.device_bytes.load(Ordering::Relaxed)))
+    }
+
+    /// Accounts for `bytes` more of device memory, as long as they fit the budget.
+    fn reserve(&self, bytes: u64) -> Result<(), TsDistanceError> {
+        let required = self.device_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        match self.memory_budget {
+            Some(budget) if required > budget => {
+                self.device_bytes.fetch_sub(bytes, Ordering::Relaxed);
+                Err(TsDistanceError::MemoryBudgetExceeded { required, budget })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Caps the size in bytes of a single storage buffer below the device limit, so that
+    /// batches are split into smaller chunks.
+    pub fn with_max_storage_range(mut self, bytes: usize) -> Self {
+        self.max_storage_range = Some(bytes);
+        self
+    }
+
+    /// Records every dispatch of the diamond kernels into `trace`.
+    pub fn with_dispatch_trace(mut self, trace: DispatchTrace) -> Self {
+        self.dispatch_trace = Some(trace);
+        self
+    }
+
+    /// Hands the record built by `record` to the attached `DispatchTrace`, if any.
+    pub(crate) fn trace_dispatch(&self, record: impl FnOnce() -> DispatchRecord) {
+        if let Some(trace) = &self.dispatch_trace {
+            trace.0.lock().unwrap().push(record());
+        }
+    }
+
+    /// Compute queues of the device the handles were created with, see `get_device`.
+    pub(crate) fn device_queues(&self) -> &[Arc<Queue>] {
+        &self.queues
+    }
+
+    /// Lets the diamonds synchronize with subgroup barriers, cheaper than workgroup ones,
+    /// on devices where every tile lies within a single subgroup, see
+    /// `supports_subgroup_sync`. Only builds with the `subgroup-sync` feature have them, on
+    /// by default there, `false` always uses workgroup barriers.
+    pub fn with_subgroup_sync(mut self, enabled: bool) -> Self {
+        self.subgroup_sync = enabled;
+        self
+    }
+
+    /// Whether tiles of `tile_size` threads synchronize with subgroup barriers on `device`.
+    pub(crate) fn subgroup_sync(&self, device: &Device, tile_size: usize) -> bool {
+        cfg!(feature = "subgroup-sync")
+            && self.subgroup_sync
+            && supports_subgroup_sync(device, tile_size)
+    }
+
+    /// Caps the size in bytes of the push constants below the device limit. Kernels whose
+    /// constants don't fit read them from a uniform buffer instead.
+    pub fn with_max_push_constants_size(mut self, bytes: usize) -> Self {
+        self.max_push_constants_size = Some(bytes);
+        self
+    }
+
+    /// Bytes of push constants a kernel may use on `device`.
+    pub(crate) fn max_push_constants_size(&self, device: &Device) -> usize {
+        let size = device
+            .physical_device()
+            .properties()
+            .max_push_constants_size as usize;
+        self.max_push_constants_size
+            .map_or(size, |limit| limit.min(size))
+    }
+
+    /// A host visible uniform buffer holding `value`, for constants too large to be pushed.
+    pub(crate) fn uniform<T: BufferContents>(
+        &self,
+        value: T,
+    ) -> Result<Subbuffer<T>, TsDistanceError> {
+        let buffer = self.cpu.allocate_sized::<T>()?;
+        *buffer.write()? = value;
+        Ok(buffer)
+    }
+
+    /// Number of floats that fit in a single storage buffer on `device`.
+    pub(crate) fn max_storage_floats(&self, device: &Device) -> usize {
+        let range = device
+            .physical_device()
+            .properties()
+            .max_storage_buffer_range as usize;
+        self.max_storage_range
+            .map_or(range, |limit| limit.min(range))
+            / std::mem::size_of::<Float>()
+    }
+
     pub fn clear(&self) -> () {
         self.gpu.set_arena_size(0);
         self.cpu.set_arena_size(0);
@@ -63,45 +709,228 @@ impl SubBuffersAllocator {
 
 type CachedCore = (
     Arc<Device>,
-    Arc<Queue>,
+    Arc<[Arc<Queue>]>,
+    Option<Arc<Queue>>,
     Arc<StandardCommandBufferAllocator>,
     Arc<StandardDescriptorSetAllocator>,
     Arc<StandardMemoryAllocator>, // memory allocator is Sync
 );
 
-static DEVICE_CORE: LazyLock<CachedCore> = LazyLock::new(|| {
-    let library = VulkanLibrary::new().unwrap();
+/// The limits of a device that decide how a batch is split, see `describe_device`.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub device_type: PhysicalDeviceType,
+    /// Name of the Vulkan driver, when the device reports it.
+    pub driver_name: Option<String>,
+    /// Threads of a subgroup, series are padded to a multiple of it.
+    pub max_subgroup_size: u32,
+    /// Threads of a workgroup along x, the size every kernel is dispatched with.
+    pub max_compute_work_group_size: u32,
+    /// Bytes of a single storage buffer, which bounds the diagonals of a chunk.
+    pub max_storage_buffer_range: u32,
+    /// Bytes of device-local memory, summed over the heaps.
+    pub device_memory: u64,
+    /// Whether the diamonds synchronize with subgroup barriers at the default tile size,
+    /// see `supports_subgroup_sync`. Always `false` without the `subgroup-sync` feature.
+    pub subgroup_sync: bool,
+}
+
+impl DeviceInfo {
+    /// Whether the device is a real GPU rather than a CPU or a software rasterizer such as
+    /// lavapipe or SwiftShader, on which every kernel runs very slowly.
+    pub fn is_hardware_accelerated(&self) -> bool {
+        let software = ["llvmpipe", "lavapipe", "swiftshader", "softpipe"];
+        let is_software = |name: &str| {
+            let name = name.to_lowercase();
+            software.iter().any(|s| name.contains(s))
+        };
+        self.device_type != PhysicalDeviceType::Cpu
+            && !is_software(&self.name)
+            && !self.driver_name.as_deref().is_some_and(is_software)
+    }
+
+    /// Longest series whose diagonal fits in a single storage buffer, beyond which every
+    /// distance fails with `TsDistanceError::SeriesTooLong`.
+    pub fn max_series_len(&self) -> usize {
+        max_series_len(
+            self.max_storage_buffer_range as usize / std::mem::size_of::<Float>(),
+            self.max_subgroup_size as usize,
+        )
+    }
+
+    /// Largest number of pairs of series up to `series_len` values long whose diagonals fit
+    /// in a single storage buffer, i.e. in one chunk of a batch.
+    pub fn recommended_max_pairs(&self, series_len: usize) -> usize {
+        let diag_len = padded_diag_len(series_len, self.max_subgroup_size as usize);
+        self.max_storage_buffer_range as usize / std::mem::size_of::<Float>() / diag_len
+    }
+}
+
+/// Subgroup size assumed when the driver does not report one, as some software
+/// implementations don't.
+pub const FALLBACK_SUBGROUP_SIZE: usize = 32;
+
+static SUBGROUP_SIZE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// `max_subgroup_size` as reported by the device, or else `FALLBACK_SUBGROUP_SIZE` clamped
+/// to the largest power of two within `max_invocations`, the threads a workgroup may hold.
+/// The fallback is logged once.
+pub fn effective_subgroup_size(max_subgroup_size: Option<u32>, max_invocations: u32) -> usize {
+    if let Some(size) = max_subgroup_size {
+        return size as usize;
+    }
+    let size = FALLBACK_SUBGROUP_SIZE.min(1 << max_invocations.max(1).ilog2());
+    if !SUBGROUP_SIZE_WARNED.swap(true, Ordering::Relaxed) {
+        log::warn!(
+            "the device does not report its subgroup size, assuming {}",
+            size
+        );
+    }
+    size
+}
+
+/// Whether a tile of `tile_size` threads always lies within a single subgroup on `device`,
+/// so that a subgroup barrier synchronizes it. Tiles are aligned to their size, so they do
+/// unless the smallest subgroup the device may run compute shaders with is smaller.
+pub fn supports_subgroup_sync(device: &Device, tile_size: usize) -> bool {
+    let properties = device.physical_device().properties();
+    let compute = properties
+        .subgroup_supported_stages
+        .is_some_and(|stages| stages.intersects(ShaderStages::COMPUTE));
+    let basic = properties
+        .subgroup_supported_operations
+        .is_some_and(|operations| operations.intersects(SubgroupFeatures::BASIC));
+    let smallest = properties.min_subgroup_size.or(properties.subgroup_size);
+    compute && basic && smallest.is_some_and(|size| tile_size <= size as usize)
+}
+
+pub fn describe_device(device: &Device) -> DeviceInfo {
+    let physical = device.physical_device();
+    let properties = physical.properties();
+    DeviceInfo {
+        name: properties.device_name.clone(),
+        device_type: properties.device_type,
+        driver_name: properties.driver_name.clone(),
+        max_subgroup_size: effective_subgroup_size(
+            properties.max_subgroup_size,
+            properties.max_compute_work_group_invocations,
+        ) as u32,
+        max_compute_work_group_size: properties.max_compute_work_group_size[0],
+        max_storage_buffer_range: properties.max_storage_buffer_range,
+        device_memory: physical
+            .memory_properties()
+            .memory_heaps
+            .iter()
+            .filter(|heap| heap.flags.intersects(MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum(),
+        subgroup_sync: cfg!(feature = "subgroup-sync")
+            && supports_subgroup_sync(
+                device,
+                effective_subgroup_size(
+                    properties.max_subgroup_size,
+                    properties.max_compute_work_group_invocations,
+                ),
+            ),
+    }
+}
+
+/// Which physical device `get_device_with` should run on.
+#[derive(Clone, Debug)]
+pub enum DevicePreference {
+    /// Position in the order the Vulkan instance enumerates physical devices.
+    Index(usize),
+    /// First device whose name contains the given string.
+    Name(String),
+    /// First device of the given type.
+    Type(PhysicalDeviceType),
+}
+
+/// Most compute queues requested from a device, see `RunOptions::with_max_queues`.
+const MAX_COMPUTE_QUEUES: u32 = 4;
+
+// Only a successfully created device is cached, a failure is reported to the caller and
+// retried on the next call.
+static DEVICE_CORE: OnceLock<CachedCore> = OnceLock::new();
+
+fn create_core(preference: Option<&DevicePreference>) -> Result<CachedCore, TsDistanceError> {
+    let library = VulkanLibrary::new()?;
     let instance = Instance::new(
         library,
         InstanceCreateInfo {
             flags: InstanceCreateFlags::ENUMERATE_PORTABILITY,
             ..Default::default()
         },
-    )
-    .unwrap();
+    )?;
 
     let device_extensions = DeviceExtensions::empty();
 
-    let (physical_device, queue_family_index) = instance
-        .enumerate_physical_devices()
-        .unwrap()
-        .filter(|p| p.supported_extensions().contains(&device_extensions))
-        .filter_map(|p| {
-            p.queue_family_properties()
-                .iter()
-                .position(|q| q.queue_flags.intersects(QueueFlags::COMPUTE))
-                .map(|i| (p, i as u32))
-        })
-        .min_by_key(|(p, _)| match p.properties().device_type {
+    let compute_queue = |p: &Arc<PhysicalDevice>| {
+        p.queue_family_properties()
+            .iter()
+            .position(|q| q.queue_flags.intersects(QueueFlags::COMPUTE))
+            .map(|i| i as u32)
+    };
+
+    let mut candidates = instance
+        .enumerate_physical_devices()?
+        .enumerate()
+        .filter(|(_, p)| p.supported_extensions().contains(&device_extensions))
+        .filter_map(|(index, p)| compute_queue(&p).map(|i| (index, p, i)));
+
+    let selected = match preference {
+        None => candidates.min_by_key(|(_, p, _)| match p.properties().device_type {
             PhysicalDeviceType::DiscreteGpu => 0,
             PhysicalDeviceType::IntegratedGpu => 1,
             PhysicalDeviceType::VirtualGpu => 2,
             PhysicalDeviceType::Cpu => 3,
             PhysicalDeviceType::Other => 4,
             _ => 5,
+        }),
+        Some(DevicePreference::Index(index)) => candidates.find(|(i, _, _)| i == index),
+        Some(DevicePreference::Name(name)) => {
+            candidates.find(|(_, p, _)| p.properties().device_name.contains(name.as_str()))
+        }
+        Some(DevicePreference::Type(device_type)) => {
+            candidates.find(|(_, p, _)| p.properties().device_type == *device_type)
+        }
+    };
+    let (_, physical_device, queue_family_index) = selected.ok_or_else(|| {
+        TsDistanceError::InvalidInput(format!(
+            "no compute capable device matches {:?}",
+            preference
+        ))
+    })?;
+
+    let queue_count = physical_device.queue_family_properties()[queue_family_index as usize]
+        .queue_count
+        .min(MAX_COMPUTE_QUEUES);
+    // A family with transfers alone maps to the device's copy engines, which run alongside
+    // the compute queues.
+    let transfer_family_index = physical_device
+        .queue_family_properties()
+        .iter()
+        .position(|q| {
+            q.queue_flags.intersects(QueueFlags::TRANSFER)
+                && !q
+                    .queue_flags
+                    .intersects(QueueFlags::COMPUTE | QueueFlags::GRAPHICS)
         })
-        .unwrap();
-    let (device, mut queues) = Device::new(
+        .map(|i| i as u32);
+    let mut queue_create_infos = vec![QueueCreateInfo {
+        queue_family_index,
+        queues: vec![0.5; queue_count as usize],
+        ..Default::default()
+    }];
+    if let Some(transfer_family_index) = transfer_family_index {
+        queue_create_infos.push(QueueCreateInfo {
+            queue_family_index: transfer_family_index,
+            queues: vec![0.5],
+            ..Default::default()
+        });
+    }
+    let (device, queues) = Device::new(
         physical_device,
         DeviceCreateInfo {
             enabled_extensions: device_extensions,
@@ -109,16 +938,15 @@ static DEVICE_CORE: LazyLock<CachedCore> = LazyLock::new(|| {
                 let mut features = DeviceFeatures::default();
                 features.shader_int8 = true;
                 features.shader_int64 = true;
+                features.shader_float64 = cfg!(feature = "f64");
                 features
             },
-            queue_create_infos: vec![QueueCreateInfo {
-                queue_family_index,
-                ..Default::default()
-            }],
+            queue_create_infos,
             ..Default::default()
         },
-    )
-    .unwrap();
+    )?;
+    let (queues, transfer_queues): (Vec<_>, Vec<_>) =
+        queues.partition(|queue| queue.queue_family_index() == queue_family_index);
     let command_buffer_allocator = Arc::new(StandardCommandBufferAllocator::new(
         device.clone(),
         Default::default(),
@@ -128,24 +956,54 @@ static DEVICE_CORE: LazyLock<CachedCore> = LazyLock::new(|| {
         Default::default(),
     ));
     let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
-    (
+    Ok((
         device,
-        queues.next().unwrap(),
+        queues.into(),
+        transfer_queues.into_iter().next(),
         command_buffer_allocator,
         descriptor_set_allocator,
         memory_allocator,
-    )
-});
+    ))
+}
 
-pub fn get_device() -> (
+/// The device arguments every distance function takes, as returned by `get_device`.
+pub type DeviceHandles = (
     Arc<Device>,
     Arc<Queue>,
     Arc<StandardCommandBufferAllocator>,
     Arc<StandardDescriptorSetAllocator>,
     SubBuffersAllocator,
-) {
-    let (device, queue, command_buffer_allocator, descriptor_set_allocator, memory_allocator) =
-        DEVICE_CORE.clone();
+);
+
+/// The handles of the default device, created on the first call and shared by every later
+/// one. Fails when there is no Vulkan device with a compute queue, e.g. on a machine without
+/// a GPU, where the `reference` implementations can stand in.
+pub fn get_device() -> Result<DeviceHandles, TsDistanceError> {
+    let core = match DEVICE_CORE.get() {
+        Some(core) => core.clone(),
+        None => {
+            let core = create_core(None)?;
+            DEVICE_CORE.get_or_init(|| core).clone()
+        }
+    };
+    Ok(with_subbuffer_allocators(core))
+}
+
+/// Like `get_device`, on the device matching `preference`. A new logical device is
+/// created on every call instead of sharing the cached default one.
+pub fn get_device_with(preference: DevicePreference) -> Result<DeviceHandles, TsDistanceError> {
+    Ok(with_subbuffer_allocators(create_core(Some(&preference))?))
+}
+
+fn with_subbuffer_allocators(core: CachedCore) -> DeviceHandles {
+    let (
+        device,
+        queues,
+        transfer_queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        memory_allocator,
+    ) = core;
 
     let gpu_buffer_allocator = Arc::new(SubbufferAllocator::new(
         memory_allocator.clone(),
@@ -159,23 +1017,39 @@ pub fn get_device() -> (
     ));
 
     let cpu_buffer_allocator = Arc::new(SubbufferAllocator::new(
-        memory_allocator,
+        memory_allocator.clone(),
         SubbufferAllocatorCreateInfo {
-            buffer_usage: BufferUsage::TRANSFER_DST | BufferUsage::TRANSFER_SRC,
+            buffer_usage: BufferUsage::TRANSFER_DST
+                | BufferUsage::TRANSFER_SRC
+                | BufferUsage::UNIFORM_BUFFER,
             memory_type_filter: MemoryTypeFilter::PREFER_HOST
                 | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
             ..Default::default()
         },
     ));
 
+    let pipelines = device_pipelines(&device);
+    let device_bytes = Arc::new(AtomicU64::new(0));
+
     (
         device,
-        queue,
+        queues[0].clone(),
         command_buffer_allocator,
         descriptor_set_allocator,
         SubBuffersAllocator {
             gpu: gpu_buffer_allocator,
             cpu: cpu_buffer_allocator,
+            max_storage_range: None,
+            queues,
+            max_push_constants_size: None,
+            dispatch_trace: None,
+            subgroup_sync: cfg!(feature = "subgroup-sync"),
+            memory_budget: None,
+            device_bytes: device_bytes.clone(),
+            last_run: Arc::new(Mutex::new(device_bytes)),
+            memory_allocator,
+            transfer_queue,
+            _pipelines: pipelines,
         },
     )
 }
@@ -186,16 +1060,44 @@ pub struct SubBufferPair<T> {
 }
 
 impl<T: BufferContents + Copy> SubBufferPair<T> {
-    pub fn new(subbuffer_allocator: &SubBuffersAllocator, length: u64) -> Self {
-        let cpu = subbuffer_allocator
-            .cpu
-            .allocate_slice(length)
-            .expect("failed to allocate cpu buffer");
-        let gpu = subbuffer_allocator
-            .gpu
-            .allocate_slice(length)
-            .expect("failed to allocate gpu buffer");
-        Self { cpu, gpu }
+    pub fn new(
+        subbuffer_allocator: &SubBuffersAllocator,
+        length: u64,
+    ) -> Result<Self, TsDistanceError> {
+        let cpu = subbuffer_allocator.cpu.allocate_slice(length)?;
+        let gpu = subbuffer_allocator.gpu_slice(length)?;
+        Ok(Self { cpu, gpu })
+    }
+
+    /// Same as `new`, for a device buffer filled on `upload_queue` and read on `queue`, which
+    /// is shared by both families when they differ.
+    pub fn for_upload(
+        subbuffer_allocator: &SubBuffersAllocator,
+        length: u64,
+        queue: &Queue,
+        upload_queue: &Queue,
+    ) -> Result<Self, TsDistanceError> {
+        let families = [
+            queue.queue_family_index(),
+            upload_queue.queue_family_index(),
+        ];
+        if families[0] == families[1] {
+            return Self::new(subbuffer_allocator, length);
+        }
+        let cpu = subbuffer_allocator.shared_slice(
+            length,
+            families,
+            BufferUsage::TRANSFER_DST | BufferUsage::TRANSFER_SRC,
+            MemoryTypeFilter::PREFER_HOST | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+        )?;
+        subbuffer_allocator.reserve(length * std::mem::size_of::<T>() as u64)?;
+        let gpu = subbuffer_allocator.shared_slice(
+            length,
+            families,
+            BufferUsage::TRANSFER_DST | BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+            MemoryTypeFilter::PREFER_DEVICE,
+        )?;
+        Ok(Self { cpu, gpu })
     }
 }
 
@@ -204,20 +1106,542 @@ impl<T: BufferContents + Copy> SubBufferPair<T> {
         &self,
         data: &[T],
         command_buffer: &mut AutoCommandBufferBuilder<L>,
-    ) -> Subbuffer<[T]> {
-        self.cpu.write().unwrap()[0..data.len()].copy_from_slice(&data);
+    ) -> Result<Subbuffer<[T]>, TsDistanceError> {
+        self.cpu.write()?[0..data.len()].copy_from_slice(&data);
 
-        command_buffer
-            .copy_buffer(CopyBufferInfo::buffers(self.cpu.clone(), self.gpu.clone()))
-            .unwrap();
+        command_buffer.copy_buffer(CopyBufferInfo::buffers(self.cpu.clone(), self.gpu.clone()))?;
 
-        self.gpu.clone().slice(0..data.len() as u64)
+        Ok(self.gpu.clone().slice(0..data.len() as u64))
     }
 
-    pub fn move_cpu<L>(&self, command_buffer: &mut AutoCommandBufferBuilder<L>) -> Subbuffer<[T]> {
-        command_buffer
-            .copy_buffer(CopyBufferInfo::buffers(self.gpu.clone(), self.cpu.clone()))
-            .unwrap();
-        self.cpu.clone()
+    pub fn copy_gpu<L>(
+        &self,
+        src: Subbuffer<[T]>,
+        command_buffer: &mut AutoCommandBufferBuilder<L>,
+    ) -> Result<Subbuffer<[T]>, TsDistanceError> {
+        let len = src.len();
+        command_buffer.copy_buffer(CopyBufferInfo::buffers(src, self.gpu.clone()))?;
+        Ok(self.gpu.clone().slice(0..len))
+    }
+
+    pub fn gpu(&self) -> Subbuffer<[T]> {
+        self.gpu.clone()
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.gpu.len()
+    }
+
+    pub fn move_cpu<L>(
+        &self,
+        command_buffer: &mut AutoCommandBufferBuilder<L>,
+    ) -> Result<Subbuffer<[T]>, TsDistanceError> {
+        command_buffer.copy_buffer(CopyBufferInfo::buffers(self.gpu.clone(), self.cpu.clone()))?;
+        Ok(self.cpu.clone())
+    }
+}
+
+/// Rejects empty batches and empty series, which the kernels cannot handle.
+pub(crate) fn check_batch(name: &str, series: &Vec<Vec<Float>>) -> Result<(), TsDistanceError> {
+    if series.is_empty() {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "{} contains no series",
+            name
+        )));
+    }
+    if let Some(i) = series.iter().position(|ts| ts.is_empty()) {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "series {} of {} is empty",
+            i, name
+        )));
+    }
+    Ok(())
+}
+
+/// LCSS and EDR count up to the length of the longer series of a pair, `longest` at most,
+/// which must stay within the integers `Float` holds exactly.
+pub(crate) fn check_exact_counts(metric: &str, longest: usize) -> Result<(), TsDistanceError> {
+    if longest as u64 > MAX_EXACT_COUNT {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "{} counts up to {}, past the {} integers a {} holds exactly",
+            metric,
+            longest,
+            MAX_EXACT_COUNT,
+            std::any::type_name::<Float>()
+        )));
+    }
+    Ok(())
+}
+
+/// Keogh-Pazzani derivative estimate `((x[i] - x[i-1]) + (x[i+1] - x[i-1]) / 2) / 2`.
+/// The first and last points, which lack a neighbour, copy the estimate next to them.
+pub fn derivative(ts: &[Float]) -> Vec<Float> {
+    if ts.len() < 3 {
+        return vec![0.0; ts.len()];
+    }
+    let mut der = vec![0.0; ts.len()];
+    for i in 1..ts.len() - 1 {
+        der[i] = ((ts[i] - ts[i - 1]) + (ts[i + 1] - ts[i - 1]) / 2.0) / 2.0;
+    }
+    der[0] = der[1];
+    der[ts.len() - 1] = der[ts.len() - 2];
+    der
+}
+
+/// Complexity estimate of the CID distance, the length of the series seen as a line,
+/// `sqrt(sum((x[i + 1] - x[i])^2))`.
+pub fn complexity_estimate(ts: &[Float]) -> Float {
+    ts.windows(2)
+        .map(|w| (w[1] - w[0]).powi(2))
+        .sum::<Float>()
+        .sqrt()
+}
+
+/// CID correction factor `max(ce_a, ce_b) / min(ce_a, ce_b)`. Flat series have a zero
+/// estimate, which is clamped to `Float::EPSILON` so that two flat series get a factor of 1.
+pub fn complexity_factor(ce_a: Float, ce_b: Float) -> Float {
+    let (ce_a, ce_b) = (ce_a.max(Float::EPSILON), ce_b.max(Float::EPSILON));
+    ce_a.max(ce_b) / ce_a.min(ce_b)
+}
+
+/// Modified logistic weights of WDTW, `1 / (1 + e^(-g * (i - len / 2)))` for every offset
+/// `i` from the diagonal up to `len`, where `g` sets how fast the penalty grows.
+pub fn logistic_weights(len: usize, g: Float) -> Vec<Float> {
+    let half_len = len as Float / 2.0;
+    (0..len)
+        .map(|i| 1.0 / (1.0 + (-g * (i as Float - half_len)).exp()))
+        .collect()
+}
+
+/// Subtracts the mean and divides by the population standard deviation. Series with a zero
+/// standard deviation are returned unchanged, as on the device.
+pub fn znormalize(ts: &[Float]) -> Vec<Float> {
+    let len = ts.len() as Float;
+    let mean = ts.iter().sum::<Float>() / len;
+    let std = (ts.iter().map(|x| (x - mean).powi(2)).sum::<Float>() / len).sqrt();
+    if std > Float::EPSILON {
+        ts.iter().map(|x| (x - mean) / std).collect()
+    } else {
+        ts.to_vec()
+    }
+}
+
+/// Keogh lower and upper envelopes: the running min and max of `ts` over a window of
+/// `window` points on each side.
+pub fn envelopes(ts: &[Float], window: usize) -> (Vec<Float>, Vec<Float>) {
+    let len = ts.len();
+    (0..len)
+        .map(|i| {
+            let span = &ts[i.saturating_sub(window)..(i + window + 1).min(len)];
+            let lower = span.iter().copied().fold(Float::INFINITY, Float::min);
+            let upper = span.iter().copied().fold(Float::NEG_INFINITY, Float::max);
+            (lower, upper)
+        })
+        .unzip()
+}
+
+/// `envelopes` of every series of `data`, computed on the device.
+pub fn compute_envelopes(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    sba: Arc<StandardCommandBufferAllocator>,
+    dsa: Arc<StandardDescriptorSetAllocator>,
+    sa: SubBuffersAllocator,
+    options: &RunOptions,
+    data: &Vec<Vec<Float>>,
+    window: usize,
+) -> Result<(Vec<Vec<Float>>, Vec<Vec<Float>>), TsDistanceError> {
+    envelopes_gpu(device, queue, sba, dsa, sa, options, data, window)
+}
+
+/// Backtracks the optimal warping path through an accumulated cost matrix, as returned by
+/// `warps::cost_matrix_gpu`, from `(0, 0)` to the last cell. Ties prefer the diagonal move.
+pub fn warping_path(cost: &[Vec<Float>]) -> Vec<(usize, usize)> {
+    let (mut i, mut j) = (cost.len() - 1, cost[0].len() - 1);
+    let mut path = vec![(i, j)];
+    while i > 0 || j > 0 {
+        (i, j) = if i == 0 {
+            (i, j - 1)
+        } else if j == 0 {
+            (i - 1, j)
+        } else {
+            let diag = cost[i - 1][j - 1];
+            let up = cost[i - 1][j];
+            let left = cost[i][j - 1];
+            if diag <= up && diag <= left {
+                (i - 1, j - 1)
+            } else if up <= left {
+                (i - 1, j)
+            } else {
+                (i, j - 1)
+            }
+        };
+        path.push((i, j));
+    }
+    path.reverse();
+    path
+}
+
+/// Xorshift64* generator, enough for reproducible synthetic data without an RNG dependency.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero.
+        Self((seed ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn uniform(&mut self) -> Float {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) as Float
+    }
+
+    /// Standard normal, by the Box-Muller transform.
+    fn normal(&mut self) -> Float {
+        let u = 1.0 - self.uniform();
+        let v = self.uniform();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI as Float * v).cos()
+    }
+}
+
+/// `count` random walks of `len` steps with standard normal increments, the same for the
+/// same `seed`.
+pub fn generate_random_batch(count: usize, len: usize, seed: u64) -> Vec<Vec<Float>> {
+    let mut rng = XorShift::new(seed);
+    (0..count)
+        .map(|_| {
+            let mut value = 0.0;
+            (0..len)
+                .map(|_| {
+                    value += rng.normal();
+                    value
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Length of the Cylinder-Bell-Funnel series.
+pub const CBF_LEN: usize = 128;
+
+/// `count` Cylinder-Bell-Funnel series of `CBF_LEN` values with their labels, 0 for a
+/// cylinder, 1 for a bell and 2 for a funnel, cycling through the classes. Each shape spans
+/// a random `[a, b]` with `a` in `[16, 32]` and `b - a` in `[32, 96]`, its height and the
+/// noise being standard normal, the same for the same `seed`.
+pub fn generate_cbf(count: usize, seed: u64) -> (Vec<Vec<Float>>, Vec<usize>) {
+    let mut rng = XorShift::new(seed);
+    let series = (0..count)
+        .map(|n| {
+            let a = 16.0 + 16.0 * rng.uniform();
+            let b = a + 32.0 + 64.0 * rng.uniform();
+            let height = 6.0 + rng.normal();
+            (0..CBF_LEN)
+                .map(|t| {
+                    let t = t as Float;
+                    let shape = if t < a || t > b {
+                        0.0
+                    } else {
+                        match n % 3 {
+                            0 => 1.0,
+                            1 => (t - a) / (b - a),
+                            _ => (b - t) / (b - a),
+                        }
+                    };
+                    height * shape + rng.normal()
+                })
+                .collect()
+        })
+        .collect();
+    (series, (0..count).map(|n| n % 3).collect())
+}
+
+/// Reads a univariate or multivariate dataset in the sktime `.ts` format of the UCR/UEA
+/// archives, see `read_ts_file_channels`.
+pub fn read_ts_file(path: impl AsRef<Path>) -> io::Result<(Vec<Vec<Float>>, Vec<String>)> {
+    let (series, labels, _) = read_ts_file_channels(path)?;
+    Ok((series, labels))
+}
+
+/// Reads a dataset in the sktime `.ts` format, returning the series, their class labels,
+/// empty when `@classLabel` is false, and the number of channels. The channels of a series,
+/// separated by `:`, are interleaved per timestep as `GpuContext::new_multivariate` expects.
+/// Missing values, written `?`, are read as `NaN`, to be handled through `NanPolicy`.
+pub fn read_ts_file_channels(
+    path: impl AsRef<Path>,
+) -> io::Result<(Vec<Vec<Float>>, Vec<String>, usize)> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut parser = TsParser::default();
+    let mut series = Vec::new();
+    let mut labels = Vec::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        if let Some((ts, label)) = parser.parse_line(number + 1, &line?)? {
+            series.push(ts);
+            labels.extend(label);
+        }
+    }
+
+    parser.finish()?;
+    Ok((series, labels, parser.dim.unwrap_or(1)))
+}
+
+/// Reads a `.ts` dataset as `read_ts_file` does, `chunk_rows` series at a time, so that a
+/// test set too large for the host memory can be scored chunk by chunk, e.g. with
+/// `cpu::dtw_chunked`. Each chunk comes with the labels of its series.
+pub fn read_ts_chunked(path: impl AsRef<Path>, chunk_rows: usize) -> io::Result<TsChunks> {
+    TsChunks::new(
+        path.as_ref(),
+        LineParser::Ts(TsParser::default()),
+        chunk_rows,
+    )
+}
+
+/// Reads a dataset in the CSV layout of the UCR archive, one series per row after its class
+/// label, `chunk_rows` series at a time as `read_ts_chunked` does. Values are separated by
+/// commas, or by tabs in `.tsv` files.
+pub fn read_csv_chunked(path: impl AsRef<Path>, chunk_rows: usize) -> io::Result<TsChunks> {
+    let path = path.as_ref();
+    let delimiter = if path.extension().is_some_and(|ext| ext == "tsv") {
+        '\t'
+    } else {
+        ','
+    };
+    TsChunks::new(path, LineParser::Csv { delimiter }, chunk_rows)
+}
+
+/// Iterator over the chunks of a `.ts` or CSV file, see `read_ts_chunked` and
+/// `read_csv_chunked`. It stops after the first error.
+pub struct TsChunks {
+    lines: std::iter::Enumerate<io::Lines<BufReader<File>>>,
+    parser: LineParser,
+    chunk_rows: usize,
+    done: bool,
+}
+
+impl TsChunks {
+    fn new(path: &Path, parser: LineParser, chunk_rows: usize) -> io::Result<Self> {
+        if chunk_rows == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "chunks must hold at least one series".to_string(),
+            ));
+        }
+        Ok(Self {
+            lines: BufReader::new(File::open(path)?).lines().enumerate(),
+            parser,
+            chunk_rows,
+            done: false,
+        })
+    }
+
+    /// Channels of the series, known once the first one has been read.
+    pub fn dim(&self) -> Option<usize> {
+        match &self.parser {
+            LineParser::Ts(parser) => parser.dim,
+            LineParser::Csv { .. } => Some(1),
+        }
+    }
+}
+
+impl Iterator for TsChunks {
+    type Item = io::Result<(Vec<Vec<Float>>, Vec<String>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut series = Vec::new();
+        let mut labels = Vec::new();
+        while series.len() < self.chunk_rows {
+            let Some((number, line)) = self.lines.next() else {
+                self.done = true;
+                if let Err(err) = self.parser.finish() {
+                    return Some(Err(err));
+                }
+                break;
+            };
+            match line.and_then(|line| self.parser.parse_line(number + 1, &line)) {
+                Ok(Some((ts, label))) => {
+                    series.push(ts);
+                    labels.extend(label);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        (!series.is_empty()).then_some(Ok((series, labels)))
+    }
+}
+
+/// Parser of the lines of the files read by `TsChunks`.
+enum LineParser {
+    Ts(TsParser),
+    Csv { delimiter: char },
+}
+
+impl LineParser {
+    /// The series of a data line and its label, nothing for the lines holding neither.
+    fn parse_line(
+        &mut self,
+        number: usize,
+        line: &str,
+    ) -> io::Result<Option<(Vec<Float>, Option<String>)>> {
+        match self {
+            LineParser::Ts(parser) => parser.parse_line(number, line),
+            LineParser::Csv { delimiter } => parse_csv_line(number, line, *delimiter),
+        }
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        match self {
+            LineParser::Ts(parser) => parser.finish(),
+            LineParser::Csv { .. } => Ok(()),
+        }
+    }
+}
+
+/// The series of a CSV row and its label, the first column, nothing for blank lines.
+fn parse_csv_line(
+    number: usize,
+    line: &str,
+    delimiter: char,
+) -> io::Result<Option<(Vec<Float>, Option<String>)>> {
+    let invalid = |msg: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: {}", number, msg),
+        )
+    };
+
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let mut fields = line.split(delimiter);
+    let label = fields.next().unwrap_or_default().trim().to_string();
+    let series = fields
+        .map(|value| {
+            let value = value.trim();
+            value
+                .parse::<Float>()
+                .map_err(|_| invalid(format!("invalid value {:?}", value)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if series.is_empty() {
+        return Err(invalid("a row holds no values after its label".to_string()));
+    }
+    Ok(Some((series, Some(label))))
+}
+
+/// Line by line state of the `.ts` reader: the headers seen so far and the channels of the
+/// first series, which every other one must match.
+#[derive(Default)]
+struct TsParser {
+    class_label: bool,
+    in_data: bool,
+    dim: Option<usize>,
+}
+
+impl TsParser {
+    /// The series of a data line and its label, nothing for headers, comments and blank
+    /// lines.
+    fn parse_line(
+        &mut self,
+        number: usize,
+        line: &str,
+    ) -> io::Result<Option<(Vec<Float>, Option<String>)>> {
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", number, msg),
+            )
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        if !self.in_data {
+            let Some(header) = line.strip_prefix('@') else {
+                return Err(invalid("expected a header before @data".to_string()));
+            };
+            let (key, value) = header
+                .split_once(char::is_whitespace)
+                .unwrap_or((header, ""));
+            let value = value.trim();
+            match key.to_ascii_lowercase().as_str() {
+                "data" => self.in_data = true,
+                "classlabel" => self.class_label = value.to_ascii_lowercase().starts_with("true"),
+                "timestamps" if value.eq_ignore_ascii_case("true") => {
+                    return Err(invalid("timestamped series are not supported".to_string()));
+                }
+                _ => {}
+            }
+            return Ok(None);
+        }
+
+        let mut fields = line.split(':').collect::<Vec<_>>();
+        let label = if self.class_label {
+            let label = fields.pop().filter(|_| !fields.is_empty());
+            let label = label.ok_or_else(|| invalid("missing class label".to_string()))?;
+            Some(label.trim().to_string())
+        } else {
+            None
+        };
+        let channels = fields
+            .iter()
+            .map(|channel| {
+                channel
+                    .split(',')
+                    .map(|value| match value.trim() {
+                        "?" | "NaN" => Ok(Float::NAN),
+                        value => value
+                            .parse::<Float>()
+                            .map_err(|_| invalid(format!("invalid value {:?}", value))),
+                    })
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let expected = *self.dim.get_or_insert(channels.len());
+        if channels.len() != expected {
+            return Err(invalid(format!(
+                "expected {} channels, got {}",
+                expected,
+                channels.len()
+            )));
+        }
+        let len = channels[0].len();
+        if channels.iter().any(|channel| channel.len() != len) {
+            return Err(invalid(
+                "the channels of a series have different lengths".to_string(),
+            ));
+        }
+        let series = (0..len)
+            .flat_map(|t| channels.iter().map(move |channel| channel[t]))
+            .collect();
+        Ok(Some((series, label)))
+    }
+
+    /// Fails when the file ended without a `@data` section.
+    fn finish(&self) -> io::Result<()> {
+        if !self.in_data {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no @data section".to_string(),
+            ));
+        }
+        Ok(())
     }
 }
@@ -1,125 +1,1966 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::{
-    kernels::kernel_trait::GpuKernelImpl,
-    utils::{SubBufferPair, SubBuffersAllocator},
+    Float,
+    error::TsDistanceError,
+    kernels::{
+        BoundaryCondition, PairLayout, gather_distances::GatherConstants,
+        kernel_trait::GpuKernelImpl, standalone, upper_triangle_pair,
+        znormalize::cpu::znormalize_gpu,
+    },
+    utils::{RunOptions, SubBufferPair, SubBuffersAllocator, check_batch},
 };
+#[cfg(feature = "f16")]
+use crate::{kernels::widen_f16::cpu::widen_f16_gpu, utils::NanPolicy};
 use std::cmp::max;
 use vulkano::{
+    buffer::Subbuffer,
     command_buffer::{
-        AutoCommandBufferBuilder, CommandBufferUsage, allocator::StandardCommandBufferAllocator,
+        AutoCommandBufferBuilder, CommandBufferExecFuture, CommandBufferUsage,
+        PrimaryAutoCommandBuffer, allocator::StandardCommandBufferAllocator,
     },
-    descriptor_set::allocator::StandardDescriptorSetAllocator,
+    descriptor_set::{WriteDescriptorSet, allocator::StandardDescriptorSetAllocator},
     device::{Device, Queue},
-    sync::GpuFuture,
+    sync::{
+        GpuFuture,
+        future::{FenceSignalFuture, NowFuture},
+    },
 };
 
-fn compute_sample_len(a: &Vec<Vec<f32>>) -> usize {
-    a.iter().map(|x| x.len()).sum()
-}
+fn compute_max_len(a: &Vec<Vec<Float>>, dim: usize) -> usize {
+    a.iter().map(|x| x.len() / dim).max().unwrap_or(0)
+}
+
+fn compute_lengths(a: &Vec<Vec<Float>>, dim: usize) -> Vec<u32> {
+    a.iter().map(|x| (x.len() / dim) as u32).collect()
+}
+
+/// Copies every series into its own `new_len` slot, in parallel over the series with the
+/// `rayon` feature. With `reverse` the timesteps of each series are copied last to first,
+/// the channels of a timestep keeping their order.
+fn flatten_and_pad(a: &Vec<Vec<Float>>, pad: usize, dim: usize, reverse: bool) -> Vec<Float> {
+    let new_len = next_multiple_of_n(compute_max_len(a, dim), pad) * dim;
+    let mut padded = vec![0.0; new_len * a.len()];
+    if new_len == 0 {
+        return padded;
+    }
+    let copy = |slot: &mut [Float], row: &Vec<Float>| {
+        if reverse {
+            for (to, from) in slot.chunks_exact_mut(dim).zip(row.chunks_exact(dim).rev()) {
+                to.copy_from_slice(from);
+            }
+        } else {
+            slot[..row.len()].copy_from_slice(row);
+        }
+    };
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        padded
+            .par_chunks_mut(new_len)
+            .zip(a.par_iter())
+            .for_each(|(slot, row)| copy(slot, row));
+    }
+    #[cfg(not(feature = "rayon"))]
+    for (slot, row) in padded.chunks_mut(new_len).zip(a.iter()) {
+        copy(slot, row);
+    }
+    padded
+}
+
+/// A batch of series resident on the device, flattened and zero-padded to a common length
+/// that is a multiple of the subgroup size. Multivariate series store their `dim` channels
+/// interleaved, one timestep after the other.
+#[derive(Clone)]
+pub struct GpuSeries {
+    data: Subbuffer<[Float]>,
+    lengths: Subbuffer<[u32]>,
+    host_lengths: Vec<u32>,
+    padded_len: usize,
+    dim: usize,
+}
+
+impl GpuSeries {
+    pub fn upload(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        subbuffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        series: &Vec<Vec<Float>>,
+    ) -> Result<Self, TsDistanceError> {
+        Self::upload_multivariate(
+            device,
+            queue,
+            command_buffer_allocator,
+            subbuffer_allocator,
+            options,
+            series,
+            1,
+        )
+    }
+
+    pub fn upload_multivariate(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        subbuffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        series: &Vec<Vec<Float>>,
+        dim: usize,
+    ) -> Result<Self, TsDistanceError> {
+        Self::upload_packed(
+            device,
+            queue,
+            command_buffer_allocator,
+            subbuffer_allocator,
+            options,
+            series,
+            dim,
+            false,
+        )
+    }
+
+    /// Uploads the series time-reversed, reversing them while they are packed rather than
+    /// through a reversed copy.
+    pub fn upload_reversed(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        subbuffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        series: &Vec<Vec<Float>>,
+    ) -> Result<Self, TsDistanceError> {
+        Self::upload_packed(
+            device,
+            queue,
+            command_buffer_allocator,
+            subbuffer_allocator,
+            options,
+            series,
+            1,
+            true,
+        )
+    }
+
+    fn upload_packed(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        subbuffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        series: &Vec<Vec<Float>>,
+        dim: usize,
+        reverse: bool,
+    ) -> Result<Self, TsDistanceError> {
+        check_batch("batch", series)?;
+        let series = options.nan_policy().apply("batch", series)?;
+        if dim == 0 || series.iter().any(|ts| ts.len() % dim != 0) {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "series lengths must be a multiple of the number of channels ({})",
+                dim
+            )));
+        }
+
+        let max_subgroup_size = options.subgroup_size(&device);
+
+        let padded = flatten_and_pad(&series, max_subgroup_size, dim, reverse);
+        let host_lengths = compute_lengths(&series, dim);
+        let padded_len = next_multiple_of_n(compute_max_len(&series, dim), max_subgroup_size);
+
+        Self::upload_padded(
+            device,
+            queue,
+            command_buffer_allocator,
+            subbuffer_allocator,
+            options,
+            &padded,
+            host_lengths,
+            padded_len,
+            dim,
+        )
+    }
+
+    /// Uploads `count` univariate series of `series_len` values each, stored back to back in
+    /// `data` and used as they are. `series_len` must be a multiple of the device's subgroup
+    /// size, which spares the copy `upload` makes to pad the series.
+    pub fn upload_flat(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        subbuffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        data: &[Float],
+        count: usize,
+        series_len: usize,
+    ) -> Result<Self, TsDistanceError> {
+        if count == 0 || series_len == 0 {
+            return Err(TsDistanceError::InvalidInput(
+                "flat batch holds no values".to_string(),
+            ));
+        }
+        if data.len() != count * series_len {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "flat batch holds {} values, expected {} series of {}",
+                data.len(),
+                count,
+                series_len
+            )));
+        }
+        let max_subgroup_size = options.subgroup_size(&device);
+        if series_len % max_subgroup_size != 0 {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "flat series of {} values are not padded to a multiple of the subgroup size {}",
+                series_len, max_subgroup_size
+            )));
+        }
+        let data = options.nan_policy().apply_flat("batch", data)?;
+
+        Self::upload_padded(
+            device,
+            queue,
+            command_buffer_allocator,
+            subbuffer_allocator,
+            options,
+            &data,
+            vec![series_len as u32; count],
+            series_len,
+            1,
+        )
+    }
+
+    /// Uploads half precision series, halving the bytes sent from the host to the device.
+    /// They are widened to `Float` on arrival, so every kernel and result stays in full
+    /// precision, and the resident series take as much device memory as with `upload`. The
+    /// packed halves only live on the device until the widening is done.
+    #[cfg(feature = "f16")]
+    pub fn upload_f16(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        subbuffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        series: &Vec<Vec<half::f16>>,
+    ) -> Result<Self, TsDistanceError> {
+        if series.is_empty() || series.iter().any(|ts| ts.is_empty()) {
+            return Err(TsDistanceError::InvalidInput(
+                "half precision batch holds no series or an empty one".to_string(),
+            ));
+        }
+        let max_subgroup_size = options.subgroup_size(&device);
+        let max_len = series.iter().map(|ts| ts.len()).max().unwrap_or(0);
+        let padded_len = next_multiple_of_n(max_len, max_subgroup_size);
+        let count = series.len() * padded_len;
+
+        let policy = options.nan_policy();
+        let mut words = vec![0u32; count.div_ceil(2)];
+        for (i, ts) in series.iter().enumerate() {
+            for (j, &value) in ts.iter().enumerate() {
+                let value = match policy {
+                    NanPolicy::Reject if !value.is_finite() => {
+                        return Err(TsDistanceError::NonFiniteInput(format!(
+                            "series {} of batch holds {} at {}",
+                            i, value, j
+                        )));
+                    }
+                    NanPolicy::Mask if value.is_infinite() => {
+                        return Err(TsDistanceError::NonFiniteInput(format!(
+                            "series {} of batch holds {} at {}",
+                            i, value, j
+                        )));
+                    }
+                    NanPolicy::TreatAsZero if !value.is_finite() => half::f16::ZERO,
+                    _ => value,
+                };
+                let index = i * padded_len + j;
+                words[index / 2] |= (value.to_bits() as u32) << (16 * (index % 2));
+            }
+        }
+        let host_lengths: Vec<u32> = series.iter().map(|ts| ts.len() as u32).collect();
+
+        let upload_queue = subbuffer_allocator.upload_queue(&queue);
+        let words_buffer = SubBufferPair::for_upload(
+            &subbuffer_allocator,
+            words.len() as u64,
+            &queue,
+            &upload_queue,
+        )?;
+        let lengths_buffer = SubBufferPair::for_upload(
+            &subbuffer_allocator,
+            host_lengths.len() as u64,
+            &queue,
+            &upload_queue,
+        )?;
+        let data = subbuffer_allocator.gpu_slice::<Float>(count as u64)?;
+
+        let mut transfer = AutoCommandBufferBuilder::primary(
+            command_buffer_allocator.clone(),
+            upload_queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        let words_gpu = words_buffer.move_gpu(&words, &mut transfer)?;
+        let lengths = lengths_buffer.move_gpu(&host_lengths, &mut transfer)?;
+        let mut builder = AutoCommandBufferBuilder::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        widen_f16_gpu(
+            device.clone(),
+            descriptor_set_allocator,
+            &mut builder,
+            words_gpu,
+            data.clone(),
+            count,
+        )?;
+        standalone::submit_after_transfer(
+            device,
+            upload_queue,
+            transfer,
+            queue,
+            builder,
+            options.timeout(),
+        )?;
+
+        Ok(Self {
+            data,
+            lengths,
+            padded_len,
+            host_lengths,
+            dim: 1,
+        })
+    }
+
+    fn upload_padded(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        subbuffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        padded: &[Float],
+        host_lengths: Vec<u32>,
+        padded_len: usize,
+        dim: usize,
+    ) -> Result<Self, TsDistanceError> {
+        let data_buffer = SubBufferPair::new(&subbuffer_allocator, padded.len() as u64)?;
+        let lengths_buffer = SubBufferPair::new(&subbuffer_allocator, host_lengths.len() as u64)?;
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        let data = data_buffer.move_gpu(padded, &mut builder)?;
+        let lengths = lengths_buffer.move_gpu(&host_lengths, &mut builder)?;
+        let command_buffer = builder.build()?;
+        let future = vulkano::sync::now(device)
+            .then_execute(queue, command_buffer)?
+            .then_signal_fence_and_flush()?;
+        standalone::wait(future, options.timeout())?;
+
+        Ok(Self {
+            data,
+            lengths,
+            padded_len,
+            host_lengths,
+            dim,
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.host_lengths.len()
+    }
+
+    pub fn lengths(&self) -> &[u32] {
+        &self.host_lengths
+    }
+
+    pub fn padded_len(&self) -> usize {
+        self.padded_len
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// Bytes of device memory the series and their lengths take.
+    pub(crate) fn device_bytes(&self) -> u64 {
+        self.data.size() + self.lengths.size()
+    }
+
+    /// Z-normalizes every channel of every series in place on the device.
+    pub fn znormalize(
+        &self,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        timeout: Option<Duration>,
+    ) -> Result<(), TsDistanceError> {
+        znormalize_gpu(
+            device,
+            queue,
+            command_buffer_allocator,
+            descriptor_set_allocator,
+            self.data.clone(),
+            self.lengths.clone(),
+            self.count(),
+            self.padded_len,
+            self.dim,
+            timeout,
+        )
+    }
+}
+
+pub struct DiamondPartitioning<G: GpuKernelImpl> {
+    a_buffer: SubBufferPair<Float>,
+    b_buffer: SubBufferPair<Float>,
+    a_lengths_buffer: SubBufferPair<u32>,
+    b_lengths_buffer: SubBufferPair<u32>,
+    diagonal_buffer: SubBufferPair<Float>,
+    cells_buffer: SubBufferPair<Float>,
+    store_cells: bool,
+    cells: Option<Subbuffer<[Float]>>,
+    kernel_params: Option<G::KernelParams>,
+    /// Diagonal offsets `0..=b_len` seeded with these values instead of the usual corner and
+    /// first row, as the last row of the band above for `diamond_partitioning_row_bands`.
+    top_row: Option<Vec<Float>>,
+}
+
+pub fn diamond_partitioning_gpu<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &Vec<Vec<Float>>,
+    b: &Vec<Vec<Float>>,
+    init_val: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let subbuffer_allocator = subbuffer_allocator.start_run(0);
+    let a_series = GpuSeries::upload(
+        device.clone(),
+        queue.clone(),
+        command_buffer_allocator.clone(),
+        subbuffer_allocator.clone(),
+        options,
+        a,
+    )?;
+    // A self-distance batch is uploaded once and bound as both sides.
+    let b_series = if std::ptr::eq(a, b) {
+        a_series.clone()
+    } else {
+        GpuSeries::upload(
+            device.clone(),
+            queue.clone(),
+            command_buffer_allocator.clone(),
+            subbuffer_allocator.clone(),
+            options,
+            b,
+        )?
+    };
+    diamond_partitioning_resident(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        &a_series,
+        &b_series,
+        init_val,
+    )
+}
+
+/// Same as `diamond_partitioning_gpu`, over series that are already resident on the device.
+pub fn diamond_partitioning_resident<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let (dist_matrix, _, cols) = diamond_partitioning_flat(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        a,
+        b,
+        init_val,
+    )?;
+    Ok(unflatten(dist_matrix, cols))
+}
+
+/// Whether two subbuffers view the very same bytes of the same buffer.
+fn same_range<T: ?Sized>(a: &Subbuffer<T>, b: &Subbuffer<T>) -> bool {
+    Arc::ptr_eq(a.buffer(), b.buffer()) && a.offset() == b.offset() && a.size() == b.size()
+}
+
+/// Splits a row-major matrix into its rows.
+pub fn unflatten(flat: Vec<Float>, cols: usize) -> Vec<Vec<Float>> {
+    if cols == 0 {
+        return Vec::new();
+    }
+    flat.chunks_exact(cols).map(|row| row.to_vec()).collect()
+}
+
+/// Distance matrix over resident series as a single row-major buffer, together with its
+/// number of rows and columns.
+pub fn diamond_partitioning_flat<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+    let (distances, _) = diamond_partitioning_chunks(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        a,
+        b,
+        init_val,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    Ok(distances)
+}
+
+/// Builds the device buffers of `params`' vector parameters, such as the WDTW weights, ahead
+/// of any run, to be shared by several calls of `diamond_partitioning_prepared`.
+pub fn build_kernel_params<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: &G,
+) -> Result<G::KernelParams, TsDistanceError> {
+    let mut builder = AutoCommandBufferBuilder::primary(
+        command_buffer_allocator,
+        queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )?;
+    let kernel_params = params.build_kernel_params(subbuffer_allocator.clone(), &mut builder)?;
+    standalone::submit_and_wait(device, queue, builder, options.timeout())?;
+    Ok(kernel_params)
+}
+
+/// Same as `diamond_partitioning_flat`, with the vector parameters of `params` taken from
+/// `kernel_params`, as built by `build_kernel_params`, instead of being uploaded again.
+pub fn diamond_partitioning_prepared<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    kernel_params: &G::KernelParams,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+    let (distances, _) = diamond_partitioning_chunks(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        a,
+        b,
+        init_val,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(kernel_params),
+    )?;
+    Ok(distances)
+}
+
+/// Same as `diamond_partitioning_flat`, together with the column of the last row each
+/// distance was taken from, the index in `b` where the alignment ends under an open end.
+pub fn diamond_partitioning_ends<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+) -> Result<(Vec<Float>, Vec<usize>, usize, usize), TsDistanceError> {
+    let mut ends = vec![0; a.count() * b.count()];
+    let ((distances, rows, cols), _) = diamond_partitioning_chunks(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        a,
+        b,
+        init_val,
+        None,
+        None,
+        None,
+        None,
+        Some(&mut ends),
+        None,
+    )?;
+    Ok((distances, ends, rows, cols))
+}
+
+/// Same as `diamond_partitioning_flat`, calling `progress` with the fraction of pairs done
+/// after each chunk is read back.
+pub fn diamond_partitioning_with_progress<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+    progress: &dyn Fn(f32),
+) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+    let (distances, _) = diamond_partitioning_chunks(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        a,
+        b,
+        init_val,
+        None,
+        None,
+        Some(progress),
+        None,
+        None,
+        None,
+    )?;
+    Ok(distances)
+}
+
+/// Same as `diamond_partitioning_flat`, on the device buffers of a previous call when they are
+/// large enough for this one. The buffers are handed back to be passed to the next call, which
+/// spares the allocations when distances are recomputed over batches of the same shape.
+pub fn diamond_partitioning_reusing<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+    buffers: Option<DiamondPartitioning<G>>,
+) -> Result<((Vec<Float>, usize, usize), DiamondPartitioning<G>), TsDistanceError> {
+    diamond_partitioning_chunks(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        a,
+        b,
+        init_val,
+        None,
+        buffers,
+        None,
+        None,
+        None,
+        None,
+    )
+}
+
+/// Hands the distance of every pair `(i, j)` of the resident series to `each` as soon as the
+/// chunk holding it is read back, so that the whole matrix never sits in host memory, e.g. to
+/// keep only the nearest neighbours of each row. Pairs arrive chunk by chunk, in no
+/// particular order across chunks.
+pub fn diamond_partitioning_for_each<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+    mut each: impl FnMut(usize, usize, Float),
+) -> Result<(), TsDistanceError> {
+    diamond_partitioning_chunks(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        a,
+        b,
+        init_val,
+        None,
+        None,
+        None,
+        Some(&mut each as &mut dyn FnMut(usize, usize, Float)),
+        None,
+        None,
+    )?;
+    Ok(())
+}
+
+/// Iterator over the distances of every pair `(i, j)` of the resident series, computing a
+/// chunk only once the previous one has been consumed, so that stopping early skips the
+/// remaining chunks. Pairs come row of chunks by row of chunks.
+pub fn diamond_partitioning_iter<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: GpuSeries,
+    b: GpuSeries,
+    init_val: Float,
+) -> Result<DistanceIter<G>, TsDistanceError> {
+    if a.dim != b.dim {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "series have {} and {} channels",
+            a.dim, b.dim
+        )));
+    }
+    let dim = a.dim;
+
+    let subbuffer_allocator = subbuffer_allocator.start_run(resident_bytes(&a, &b));
+    let tile_size = options.tile_size(&device)?;
+
+    let a_len = a.padded_len;
+    let b_len = b.padded_len;
+    let diag_len = padded_diag_len(max(a_len, b_len), tile_size) * params.diagonal_states();
+    let chunk_side = chunk_side(
+        subbuffer_allocator.max_storage_floats(&device) / params.diagonal_states(),
+        max(a_len, b_len),
+        tile_size,
+    )?;
+    let chunk_side = budget_chunk_side(&subbuffer_allocator, chunk_side, |side| {
+        slot_bytes(
+            a.count().min(side),
+            b.count().min(side),
+            a_len * dim,
+            b_len * dim,
+            diag_len,
+            params.kernel_params_bytes(),
+        )
+    })?;
+    let a_chunk = a.count().min(chunk_side).max(1);
+    let b_chunk = b.count().min(chunk_side).max(1);
+
+    let buffers = DiamondPartitioning::new(
+        subbuffer_allocator.clone(),
+        a_chunk as u64,
+        b_chunk as u64,
+        a_len as u64,
+        b_len as u64,
+        dim as u64,
+        diag_len as u64,
+        false,
+    )?;
+
+    Ok(DistanceIter {
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options: options.clone(),
+        params,
+        a,
+        b,
+        init_val,
+        tile_size,
+        a_chunk,
+        b_chunk,
+        next_chunk: 0,
+        buffers,
+        chunk: Vec::new().into_iter(),
+        failed: false,
+    })
+}
+
+/// Distances yielded by `diamond_partitioning_iter` as `(i, j, distance)`, one chunk read
+/// back at a time. A chunk that fails yields its error and ends the iteration.
+pub struct DistanceIter<G: GpuKernelImpl> {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: RunOptions,
+    params: G,
+    a: GpuSeries,
+    b: GpuSeries,
+    init_val: Float,
+    tile_size: usize,
+    a_chunk: usize,
+    b_chunk: usize,
+    next_chunk: usize,
+    buffers: DiamondPartitioning<G>,
+    chunk: std::vec::IntoIter<(usize, usize, Float)>,
+    failed: bool,
+}
+
+impl<G: GpuKernelImpl> DistanceIter<G> {
+    fn chunk_count(&self) -> usize {
+        self.a.count().div_ceil(self.a_chunk) * self.b.count().div_ceil(self.b_chunk)
+    }
+
+    fn read_chunk(&mut self) -> Result<Vec<(usize, usize, Float)>, TsDistanceError> {
+        let b_chunks = self.b.count().div_ceil(self.b_chunk);
+        let a_start = self.next_chunk / b_chunks * self.a_chunk;
+        let b_start = self.next_chunk % b_chunks * self.b_chunk;
+        let a_end = (a_start + self.a_chunk).min(self.a.count());
+        let b_end = (b_start + self.b_chunk).min(self.b.count());
+        self.next_chunk += 1;
+        self.options.check_cancelled()?;
+
+        let (a_len, b_len, dim) = (self.a.padded_len, self.b.padded_len, self.a.dim);
+        let mut chunk = Vec::with_capacity((a_end - a_start) * (b_end - b_start));
+        self.buffers.diamond_partitioning_gpu(
+            self.device.clone(),
+            self.queue.clone(),
+            self.command_buffer_allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            self.subbuffer_allocator.clone(),
+            &self.options,
+            &self.params,
+            self.tile_size,
+            a_len,
+            b_len,
+            self.a
+                .data
+                .clone()
+                .slice((a_start * a_len * dim) as u64..(a_end * a_len * dim) as u64),
+            self.b
+                .data
+                .clone()
+                .slice((b_start * b_len * dim) as u64..(b_end * b_len * dim) as u64),
+            self.a.lengths.clone().slice(a_start as u64..a_end as u64),
+            self.b.lengths.clone().slice(b_start as u64..b_end as u64),
+            a_end - a_start,
+            b_end - b_start,
+            self.init_val,
+            PairLayout::All,
+            corner_offsets(
+                &self.a.host_lengths[a_start..a_end],
+                &self.b.host_lengths[b_start..b_end],
+            ),
+            None,
+            |pair, value| {
+                let (i, j) = (pair / (b_end - b_start), pair % (b_end - b_start));
+                chunk.push((a_start + i, b_start + j, value));
+            },
+        )?;
+        if self.next_chunk == self.chunk_count() {
+            self.subbuffer_allocator.clear();
+        }
+        Ok(chunk)
+    }
+}
+
+impl<G: GpuKernelImpl> Iterator for DistanceIter<G> {
+    type Item = Result<(usize, usize, Float), TsDistanceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(distance) = self.chunk.next() {
+                return Some(Ok(distance));
+            }
+            if self.failed || self.next_chunk == self.chunk_count() {
+                return None;
+            }
+            match self.read_chunk() {
+                Ok(chunk) => self.chunk = chunk.into_iter(),
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl<G: GpuKernelImpl> Drop for DistanceIter<G> {
+    // Stopping early or failing skips the clear after the last chunk.
+    fn drop(&mut self) {
+        self.subbuffer_allocator.clear();
+    }
+}
+
+/// Distance matrix over resident series that stays on the device, `a.count() x b.count()`
+/// row-major, with every distance stored as `offset + scale * distance`, where `scale` is
+/// divided by the shorter length of each pair when `per_pair_min_len` is set. Nothing is read
+/// back, which is what `GpuContext::knn` builds on.
+pub fn diamond_partitioning_device<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+    scale: Float,
+    offset: Float,
+    per_pair_min_len: bool,
+) -> Result<Subbuffer<[Float]>, TsDistanceError> {
+    if a.count() * b.count() > subbuffer_allocator.max_storage_floats(&device) {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "a {} x {} distance matrix does not fit in a single storage buffer",
+            a.count(),
+            b.count()
+        )));
+    }
+
+    let subbuffer_allocator = subbuffer_allocator.start_run(resident_bytes(a, b));
+    let matrix = subbuffer_allocator.gpu_slice((a.count() * b.count()) as u64)?;
+    diamond_partitioning_chunks(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator,
+        options,
+        params,
+        a,
+        b,
+        init_val,
+        Some(DeviceOutput {
+            matrix: matrix.clone(),
+            scale,
+            offset,
+            per_pair_min_len,
+        }),
+        None,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    Ok(matrix)
+}
+
+struct DeviceOutput {
+    matrix: Subbuffer<[Float]>,
+    scale: Float,
+    offset: Float,
+    per_pair_min_len: bool,
+}
+
+/// Where a chunk writes its distances when they stay on the device.
+struct Gather {
+    matrix: Subbuffer<[Float]>,
+    row_offset: usize,
+    col_offset: usize,
+    row_stride: usize,
+    col_stride: usize,
+    scale: Float,
+    offset: Float,
+    per_pair_min_len: bool,
+}
+
+fn diamond_partitioning_chunks<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+    output: Option<DeviceOutput>,
+    buffers: Option<DiamondPartitioning<G>>,
+    progress: Option<&dyn Fn(f32)>,
+    mut each: Option<&mut dyn FnMut(usize, usize, Float)>,
+    mut ends: Option<&mut [usize]>,
+    kernel_params: Option<&G::KernelParams>,
+) -> Result<((Vec<Float>, usize, usize), DiamondPartitioning<G>), TsDistanceError> {
+    if a.dim != b.dim {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "series have {} and {} channels",
+            a.dim, b.dim
+        )));
+    }
+    let dim = a.dim;
+
+    let subbuffer_allocator = subbuffer_allocator
+        .start_run(resident_bytes(a, b) + output.as_ref().map_or(0, |output| output.matrix.size()));
+    let tile_size = options.tile_size(&device)?;
+
+    let a_count = a.count();
+    let a_len = a.padded_len;
+    let b_count = b.count();
+    let b_len = b.padded_len;
+    let len = max(a_len, b_len);
+
+    let diag_len = padded_diag_len(len, tile_size) * params.diagonal_states();
+    // Independent chunks are spread over the device's queues, each with buffers of its own
+    // sized for its chunk, which the storage range bounds separately. Chunks gathered into a
+    // single device matrix stay on one queue.
+    let mut queues = match output {
+        None => chunk_queues(&subbuffer_allocator, options, &queue),
+        Some(_) => vec![queue.clone()],
+    };
+    let chunk_side = chunk_side(
+        subbuffer_allocator.max_storage_floats(&device) / params.diagonal_states(),
+        len,
+        tile_size,
+    )?
+    .min(spread_side(a_count, b_count, queues.len()));
+    let slot_bytes = |side: usize| {
+        slot_bytes(
+            a_count.min(side),
+            b_count.min(side),
+            a_len * dim,
+            b_len * dim,
+            diag_len,
+            params.kernel_params_bytes(),
+        )
+    };
+    let chunk_side = budget_chunk_side(&subbuffer_allocator, chunk_side, slot_bytes)?;
+    if let Some(available) = subbuffer_allocator.available_device_bytes() {
+        queues.truncate((available / slot_bytes(chunk_side)).max(1) as usize);
+    }
+    // to fill the gap in a or b chunk if one is too small
+    let a_chunk = a_count.min(chunk_side);
+    let b_chunk = b_count.min(chunk_side);
+    queues.truncate((a_count.div_ceil(a_chunk.max(1)) * b_count.div_ceil(b_chunk.max(1))).max(1));
+
+    let mut dist_matrix = if output.is_none() && each.is_none() {
+        vec![0.0; a_count * b_count]
+    } else {
+        Vec::new()
+    };
+
+    let mut slots = Vec::with_capacity(queues.len());
+    if let Some(mut buffers) = buffers {
+        if buffers.fits(a_chunk, b_chunk, a_len * dim, b_len * dim, diag_len) {
+            // The kernel parameters belong to the previous call, they are rebuilt unless
+            // prebuilt ones were passed.
+            buffers.kernel_params = kernel_params.cloned();
+            slots.push(buffers);
+        }
+    }
+    while slots.len() < queues.len() {
+        let mut slot = DiamondPartitioning::new(
+            subbuffer_allocator.clone(),
+            a_chunk as u64,
+            b_chunk as u64,
+            a_len as u64,
+            b_len as u64,
+            dim as u64,
+            diag_len as u64,
+            false,
+        )?;
+        slot.kernel_params = kernel_params.cloned();
+        slots.push(slot);
+    }
+
+    let mut done = 0;
+    let mut finish =
+        |(a_start, a_end, b_start, b_end, pending): (usize, usize, usize, usize, PendingChunk)| {
+            let cells = pending.wait_ends(|pair, value, end| {
+                let (i, j) = (pair / (b_end - b_start), pair % (b_end - b_start));
+                match each.as_mut() {
+                    Some(each) => each(a_start + i, b_start + j, value),
+                    None => dist_matrix[(a_start + i) * b_count + b_start + j] = value,
+                }
+                if let Some(ends) = ends.as_mut() {
+                    ends[(a_start + i) * b_count + b_start + j] = end;
+                }
+            })?;
+            if let Some(progress) = progress {
+                done += (a_end - a_start) * (b_end - b_start);
+                progress(done as f32 / (a_count * b_count) as f32);
+            }
+            Ok::<_, TsDistanceError>(cells)
+        };
+
+    let mut in_flight: Vec<_> = queues.iter().map(|_| None).collect();
+    let mut submitted = 0;
+    for a_start in (0..a_count).step_by(a_chunk.max(1)) {
+        let a_end = (a_start + a_chunk).min(a_count);
+
+        for b_start in (0..b_count).step_by(b_chunk.max(1)) {
+            let b_end = (b_start + b_chunk).min(b_count);
+
+            let a_sub = a
+                .data
+                .clone()
+                .slice((a_start * a_len * dim) as u64..(a_end * a_len * dim) as u64);
+            let b_sub = b
+                .data
+                .clone()
+                .slice((b_start * b_len * dim) as u64..(b_end * b_len * dim) as u64);
+
+            let gather = output.as_ref().map(|output| Gather {
+                matrix: output.matrix.clone(),
+                row_offset: a_start,
+                col_offset: b_start,
+                row_stride: b_count,
+                col_stride: 1,
+                scale: output.scale,
+                offset: output.offset,
+                per_pair_min_len: output.per_pair_min_len,
+            });
+
+            // The slot's buffers are reused once its previous chunk has been read back.
+            let slot = submitted % queues.len();
+            if let Some(previous) = in_flight[slot].take() {
+                slots[slot].cells = finish(previous)?;
+            }
+            options.check_cancelled()?;
+            let pending = slots[slot].submit(
+                device.clone(),
+                queues[slot].clone(),
+                command_buffer_allocator.clone(),
+                descriptor_set_allocator.clone(),
+                subbuffer_allocator.clone(),
+                options,
+                &params,
+                tile_size,
+                a_len,
+                b_len,
+                a_sub,
+                b_sub,
+                a.lengths.clone().slice(a_start as u64..a_end as u64),
+                b.lengths.clone().slice(b_start as u64..b_end as u64),
+                a_end - a_start,
+                b_end - b_start,
+                init_val,
+                PairLayout::All,
+                corner_offsets(
+                    &a.host_lengths[a_start..a_end],
+                    &b.host_lengths[b_start..b_end],
+                ),
+                gather,
+            )?;
+            in_flight[slot] = pending.map(|pending| (a_start, a_end, b_start, b_end, pending));
+            submitted += 1;
+        }
+    }
+    // The remaining chunks, in the order they were submitted.
+    for k in 0..queues.len() {
+        let slot = (submitted + k) % queues.len();
+        if let Some(previous) = in_flight[slot].take() {
+            slots[slot].cells = finish(previous)?;
+        }
+    }
+    let dp_buffers = slots.swap_remove(0);
+
+    subbuffer_allocator.clear();
+
+    Ok(((dist_matrix, a_count, b_count), dp_buffers))
+}
+
+/// Distance matrix over resident series, submitted without waiting for the device. Every
+/// chunk gets its own buffers so that all of them can be in flight at once, the matrix is
+/// assembled by `PendingDistances::await_result`.
+pub fn diamond_partitioning_async<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+) -> Result<PendingDistances, TsDistanceError> {
+    if a.dim != b.dim {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "series have {} and {} channels",
+            a.dim, b.dim
+        )));
+    }
+    let dim = a.dim;
+
+    let subbuffer_allocator = subbuffer_allocator.start_run(resident_bytes(a, b));
+    let tile_size = options.tile_size(&device)?;
+
+    let a_count = a.count();
+    let a_len = a.padded_len;
+    let b_count = b.count();
+    let b_len = b.padded_len;
+    let len = max(a_len, b_len);
+
+    let diag_len = padded_diag_len(len, tile_size) * params.diagonal_states();
+    let chunk_side = chunk_side(
+        subbuffer_allocator.max_storage_floats(&device) / params.diagonal_states(),
+        len,
+        tile_size,
+    )?;
+    let a_chunk = a_count.min(chunk_side);
+    let b_chunk = b_count.min(chunk_side);
+
+    // Every chunk has its own buffers, so they are simply dealt out to the device's queues.
+    let queues = chunk_queues(&subbuffer_allocator, options, &queue);
+    let mut chunks = Vec::new();
+    for a_start in (0..a_count).step_by(a_chunk.max(1)) {
+        let a_end = (a_start + a_chunk).min(a_count);
+
+        for b_start in (0..b_count).step_by(b_chunk.max(1)) {
+            let b_end = (b_start + b_chunk).min(b_count);
+
+            let mut dp_buffers = DiamondPartitioning::<G>::new(
+                subbuffer_allocator.clone(),
+                (a_end - a_start) as u64,
+                (b_end - b_start) as u64,
+                a_len as u64,
+                b_len as u64,
+                dim as u64,
+                diag_len as u64,
+                false,
+            )?;
+            let pending = dp_buffers.submit(
+                device.clone(),
+                queues[chunks.len() % queues.len()].clone(),
+                command_buffer_allocator.clone(),
+                descriptor_set_allocator.clone(),
+                subbuffer_allocator.clone(),
+                options,
+                &params,
+                tile_size,
+                a_len,
+                b_len,
+                a.data
+                    .clone()
+                    .slice((a_start * a_len * dim) as u64..(a_end * a_len * dim) as u64),
+                b.data
+                    .clone()
+                    .slice((b_start * b_len * dim) as u64..(b_end * b_len * dim) as u64),
+                a.lengths.clone().slice(a_start as u64..a_end as u64),
+                b.lengths.clone().slice(b_start as u64..b_end as u64),
+                a_end - a_start,
+                b_end - b_start,
+                init_val,
+                PairLayout::All,
+                corner_offsets(
+                    &a.host_lengths[a_start..a_end],
+                    &b.host_lengths[b_start..b_end],
+                ),
+                None,
+            )?;
+            if let Some(pending) = pending {
+                chunks.push((a_start, b_start, b_end - b_start, pending));
+            }
+        }
+    }
+
+    Ok(PendingDistances {
+        chunks,
+        rows: a_count,
+        cols: b_count,
+    })
+}
+
+/// Distance matrix still being computed on the device, see `diamond_partitioning_async`.
+pub struct PendingDistances {
+    chunks: Vec<(usize, usize, usize, PendingChunk)>,
+    rows: usize,
+    cols: usize,
+}
+
+impl PendingDistances {
+    /// Whether the device is done with every chunk, so that `await_result` won't block.
+    pub fn is_ready(&self) -> Result<bool, TsDistanceError> {
+        for (_, _, _, chunk) in &self.chunks {
+            if !chunk.future.is_signaled()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Waits for the device and reads the distances back, as `diamond_partitioning_flat`.
+    pub fn await_flat(self) -> Result<(Vec<Float>, usize, usize), TsDistanceError> {
+        let mut dist_matrix = vec![0.0; self.rows * self.cols];
+        for (a_start, b_start, b_chunk, chunk) in self.chunks {
+            chunk.wait(|pair, value| {
+                let (i, j) = (pair / b_chunk, pair % b_chunk);
+                dist_matrix[(a_start + i) * self.cols + b_start + j] = value;
+            })?;
+        }
+        Ok((dist_matrix, self.rows, self.cols))
+    }
+
+    /// Waits for the device and reads the distances back, as `diamond_partitioning_resident`.
+    pub fn await_result(self) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+        let (dist_matrix, _, cols) = self.await_flat()?;
+        Ok(unflatten(dist_matrix, cols))
+    }
+}
+
+/// Distance matrices of several kernels over the same resident series, recorded into a
+/// single command buffer that is submitted and waited for once. As with
+/// `diamond_partitioning_async`, every chunk of every kernel gets its own buffers.
+pub struct MultiMetricBatch<'a> {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: RunOptions,
+    a: &'a GpuSeries,
+    b: &'a GpuSeries,
+    builder: AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    metrics: Vec<Vec<(usize, usize, usize, RecordedChunk)>>,
+}
+
+impl<'a> MultiMetricBatch<'a> {
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        subbuffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        a: &'a GpuSeries,
+        b: &'a GpuSeries,
+    ) -> Result<Self, TsDistanceError> {
+        if a.dim != b.dim {
+            return Err(TsDistanceError::InvalidInput(format!(
+                "series have {} and {} channels",
+                a.dim, b.dim
+            )));
+        }
+        let builder = AutoCommandBufferBuilder::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        Ok(Self {
+            device,
+            queue,
+            descriptor_set_allocator,
+            subbuffer_allocator,
+            options: options.clone(),
+            a,
+            b,
+            builder,
+            metrics: Vec::new(),
+        })
+    }
+
+    /// Records the distance matrix of `params`, returning its index in the results of
+    /// `submit`.
+    pub fn add<G: GpuKernelImpl>(
+        &mut self,
+        params: G,
+        init_val: Float,
+    ) -> Result<usize, TsDistanceError> {
+        let (a, b) = (self.a, self.b);
+        let dim = a.dim;
 
-fn flatten_and_pad(a: &Vec<Vec<f32>>, pad: usize) -> Vec<f32> {
-    let new_len = next_multiple_of_n(a.first().unwrap().len(), pad);
-    let mut padded = vec![0.0; new_len * a.len()];
-    for (i, row) in a.into_iter().enumerate() {
-        for (j, val) in row.into_iter().enumerate() {
-            padded[i * new_len + j] = *val;
+        let tile_size = self.options.tile_size(&self.device)?;
+
+        let a_count = a.count();
+        let a_len = a.padded_len;
+        let b_count = b.count();
+        let b_len = b.padded_len;
+        let len = max(a_len, b_len);
+
+        let diag_len = padded_diag_len(len, tile_size) * params.diagonal_states();
+        let chunk_side = chunk_side(
+            self.subbuffer_allocator.max_storage_floats(&self.device) / params.diagonal_states(),
+            len,
+            tile_size,
+        )?;
+        let a_chunk = a_count.min(chunk_side);
+        let b_chunk = b_count.min(chunk_side);
+
+        let mut chunks = Vec::new();
+        for a_start in (0..a_count).step_by(a_chunk.max(1)) {
+            let a_end = (a_start + a_chunk).min(a_count);
+
+            for b_start in (0..b_count).step_by(b_chunk.max(1)) {
+                let b_end = (b_start + b_chunk).min(b_count);
+
+                let mut dp_buffers = DiamondPartitioning::<G>::new(
+                    self.subbuffer_allocator.clone(),
+                    (a_end - a_start) as u64,
+                    (b_end - b_start) as u64,
+                    a_len as u64,
+                    b_len as u64,
+                    dim as u64,
+                    diag_len as u64,
+                    false,
+                )?;
+                let recorded = dp_buffers.record(
+                    self.device.clone(),
+                    self.descriptor_set_allocator.clone(),
+                    self.subbuffer_allocator.clone(),
+                    &self.options,
+                    &params,
+                    tile_size,
+                    a_len,
+                    b_len,
+                    a.data
+                        .clone()
+                        .slice((a_start * a_len * dim) as u64..(a_end * a_len * dim) as u64),
+                    b.data
+                        .clone()
+                        .slice((b_start * b_len * dim) as u64..(b_end * b_len * dim) as u64),
+                    a.lengths.clone().slice(a_start as u64..a_end as u64),
+                    b.lengths.clone().slice(b_start as u64..b_end as u64),
+                    a_end - a_start,
+                    b_end - b_start,
+                    init_val,
+                    PairLayout::All,
+                    corner_offsets(
+                        &a.host_lengths[a_start..a_end],
+                        &b.host_lengths[b_start..b_end],
+                    ),
+                    None,
+                    &mut self.builder,
+                )?;
+                if let Some(recorded) = recorded {
+                    chunks.push((a_start, b_start, b_end - b_start, recorded));
+                }
+            }
         }
+
+        self.metrics.push(chunks);
+        Ok(self.metrics.len() - 1)
     }
-    padded
-}
 
-pub struct DiamondPartitioning<G: GpuKernelImpl> {
-    a_buffer: SubBufferPair<f32>,
-    b_buffer: SubBufferPair<f32>,
-    diagonal_buffer: SubBufferPair<f32>,
-    kernel_params: Option<G::KernelParams>,
+    /// Submits every recorded kernel, waits for the device once and reads the distances
+    /// back, as `diamond_partitioning_flat`, in the order they were added.
+    pub fn submit_flat(self) -> Result<Vec<(Vec<Float>, usize, usize)>, TsDistanceError> {
+        let (rows, cols) = (self.a.count(), self.b.count());
+
+        let command_buffer = self.builder.build()?;
+        let future = vulkano::sync::now(self.device)
+            .then_execute(self.queue, command_buffer)?
+            .then_signal_fence_and_flush()?;
+        standalone::wait(future, self.options.timeout())?;
+
+        let mut results = Vec::with_capacity(self.metrics.len());
+        for chunks in self.metrics {
+            let mut dist_matrix = vec![0.0; rows * cols];
+            for (a_start, b_start, b_chunk, chunk) in chunks {
+                chunk.read(|pair, value| {
+                    let (i, j) = (pair / b_chunk, pair % b_chunk);
+                    dist_matrix[(a_start + i) * cols + b_start + j] = value;
+                })?;
+            }
+            results.push((dist_matrix, rows, cols));
+        }
+
+        self.subbuffer_allocator.clear();
+
+        Ok(results)
+    }
+
+    /// Same as `submit_flat`, as `diamond_partitioning_resident`.
+    pub fn submit(self) -> Result<Vec<Vec<Vec<Float>>>, TsDistanceError> {
+        Ok(self
+            .submit_flat()?
+            .into_iter()
+            .map(|(flat, _, cols)| unflatten(flat, cols))
+            .collect())
+    }
 }
 
-pub fn diamond_partitioning_gpu<G: GpuKernelImpl>(
+/// Distances between every pair `i < j` of a single batch, packed row-major as
+/// `[(0, 1), (0, 2), .., (0, n - 1), (1, 2), ..]`. Half of the work of a full `a` vs `a`
+/// matrix is skipped, along with the main diagonal.
+pub fn diamond_partitioning_self<G: GpuKernelImpl>(
     device: Arc<Device>,
     queue: Arc<Queue>,
     command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
     descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
     subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
     params: G,
-    a: &Vec<Vec<f32>>,
-    b: &Vec<Vec<f32>>,
-    init_val: f32,
-) -> Vec<Vec<f32>> {
-    let (a, b) = if compute_sample_len(a) > compute_sample_len(b) {
-        (b, a)
-    } else {
-        (a, b)
-    };
+    a: &GpuSeries,
+    init_val: Float,
+) -> Result<Vec<Float>, TsDistanceError> {
+    let subbuffer_allocator = subbuffer_allocator.start_run(a.device_bytes());
+    let tile_size = options.tile_size(&device)?;
 
-    let properties = device.physical_device().properties();
-    let max_subgroup_size = properties.max_subgroup_size.unwrap() as usize;
-    let max_storage_buffer_size =
-        properties.max_storage_buffer_range as usize / std::mem::size_of::<f32>();
+    let count = a.count();
+    let len = a.padded_len;
+    let dim = a.dim;
 
-    let a_count = a.len();
-    let a_len = next_multiple_of_n(a.first().unwrap().len(), max_subgroup_size);
-    let b_count = b.len();
-    let b_len = next_multiple_of_n(b.first().unwrap().len(), max_subgroup_size);
-    let len = max(a_len, b_len);
+    let diag_len = padded_diag_len(len, tile_size) * params.diagonal_states();
+    let chunk = count.min(budget_chunk_side(
+        &subbuffer_allocator,
+        chunk_side(
+            subbuffer_allocator.max_storage_floats(&device) / params.diagonal_states(),
+            len,
+            tile_size,
+        )?,
+        |side| {
+            let side = count.min(side);
+            slot_bytes(
+                side,
+                side,
+                len * dim,
+                len * dim,
+                diag_len,
+                params.kernel_params_bytes(),
+            )
+        },
+    )?);
+
+    let mut distances = vec![0.0; count * count.saturating_sub(1) / 2];
+    let packed_index = |i: usize, j: usize| i * count - i * (i + 1) / 2 + j - i - 1;
+
+    let mut dp_buffers = DiamondPartitioning::new(
+        subbuffer_allocator.clone(),
+        chunk as u64,
+        chunk as u64,
+        len as u64,
+        len as u64,
+        dim as u64,
+        diag_len as u64,
+        false,
+    )?;
 
-    let a_padded = flatten_and_pad(&a, max_subgroup_size);
-    let b_padded = flatten_and_pad(&b, max_subgroup_size);
+    for a_start in (0..count).step_by(chunk.max(1)) {
+        let a_end = (a_start + chunk).min(count);
 
-    let diag_len = 2 * (next_multiple_of_n(len, max_subgroup_size) + 1).next_power_of_two();
-    let max_pairs = max_storage_buffer_size / diag_len;
+        // Blocks below the main diagonal are the transpose of blocks above it, the block on
+        // it only needs its own upper triangle.
+        for b_start in (a_start..count).step_by(chunk.max(1)) {
+            let b_end = (b_start + chunk).min(count);
+            let pair_layout = if a_start == b_start {
+                PairLayout::UpperTriangle
+            } else {
+                PairLayout::All
+            };
+            options.check_cancelled()?;
 
-    let chunk_side = (max_pairs as f64).sqrt().floor() as usize;
-    // to fill the gap in a or b chunk if one is too small
-    let a_chunk = a_count.min(chunk_side);
-    let b_chunk = b_count.min(chunk_side);
+            dp_buffers.diamond_partitioning_gpu(
+                device.clone(),
+                queue.clone(),
+                command_buffer_allocator.clone(),
+                descriptor_set_allocator.clone(),
+                subbuffer_allocator.clone(),
+                options,
+                &params,
+                tile_size,
+                len,
+                len,
+                a.data
+                    .clone()
+                    .slice((a_start * len * dim) as u64..(a_end * len * dim) as u64),
+                a.data
+                    .clone()
+                    .slice((b_start * len * dim) as u64..(b_end * len * dim) as u64),
+                a.lengths.clone().slice(a_start as u64..a_end as u64),
+                a.lengths.clone().slice(b_start as u64..b_end as u64),
+                a_end - a_start,
+                b_end - b_start,
+                init_val,
+                pair_layout,
+                corner_offsets(
+                    &a.host_lengths[a_start..a_end],
+                    &a.host_lengths[b_start..b_end],
+                ),
+                None,
+                |pair, value| {
+                    let (i, j) = if pair_layout == PairLayout::UpperTriangle {
+                        let (i, j) = upper_triangle_pair(pair as u64, (a_end - a_start) as u64);
+                        (i as usize, j as usize)
+                    } else {
+                        (pair / (b_end - b_start), pair % (b_end - b_start))
+                    };
+                    distances[packed_index(a_start + i, b_start + j)] = value;
+                },
+            )?;
+        }
+    }
 
-    let mut dist_matrix = vec![vec![0f32; b_count]; a_count];
+    subbuffer_allocator.clear();
 
-    let mut dp_buffers = DiamondPartitioning::new(
+    Ok(distances)
+}
+
+/// Distance of every pair `(a[k], b[k])`, for batches holding as many series. All the pairs
+/// of a chunk go in a single submission, which amortizes the dispatch and synchronization
+/// overhead that dominates when small pairs are computed one call at a time.
+pub fn diamond_partitioning_zipped<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &GpuSeries,
+    b: &GpuSeries,
+    init_val: Float,
+) -> Result<Vec<Float>, TsDistanceError> {
+    if a.dim != b.dim {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "series have {} and {} channels",
+            a.dim, b.dim
+        )));
+    }
+    if a.count() != b.count() {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "zipped batches hold {} and {} series",
+            a.count(),
+            b.count()
+        )));
+    }
+    let subbuffer_allocator = subbuffer_allocator.start_run(resident_bytes(a, b));
+    let tile_size = options.tile_size(&device)?;
+
+    let count = a.count();
+    let a_len = a.padded_len;
+    let b_len = b.padded_len;
+    let dim = a.dim;
+
+    let diag_len = padded_diag_len(max(a_len, b_len), tile_size) * params.diagonal_states();
+    let side = chunk_side(
+        subbuffer_allocator.max_storage_floats(&device) / params.diagonal_states(),
+        max(a_len, b_len),
+        tile_size,
+    )?;
+    // Each pair also brings its own two series, which may outgrow the diagonals.
+    let series_values = max(a_len, b_len) * dim;
+    let max_series = subbuffer_allocator.max_storage_floats(&device) / series_values;
+    let chunk = count.min(side * side).min(max_series.max(1));
+
+    let mut distances = vec![0.0; count];
+
+    let mut dp_buffers = DiamondPartitioning::with_pair_capacity(
         subbuffer_allocator.clone(),
-        a_chunk as u64,
-        b_chunk as u64,
+        chunk as u64,
+        chunk as u64,
+        chunk as u64,
         a_len as u64,
         b_len as u64,
+        dim as u64,
         diag_len as u64,
-    );
+        false,
+    )?;
 
-    for a_start in (0..a_count).step_by(a_chunk) {
-        let a_end = (a_start + a_chunk).min(a_count);
+    for start in (0..count).step_by(chunk.max(1)) {
+        let end = (start + chunk).min(count);
+        options.check_cancelled()?;
 
-        for b_start in (0..b_count).step_by(b_chunk) {
-            let b_end = (b_start + b_chunk).min(b_count);
+        dp_buffers.diamond_partitioning_gpu(
+            device.clone(),
+            queue.clone(),
+            command_buffer_allocator.clone(),
+            descriptor_set_allocator.clone(),
+            subbuffer_allocator.clone(),
+            options,
+            &params,
+            tile_size,
+            a_len,
+            b_len,
+            a.data
+                .clone()
+                .slice((start * a_len * dim) as u64..(end * a_len * dim) as u64),
+            b.data
+                .clone()
+                .slice((start * b_len * dim) as u64..(end * b_len * dim) as u64),
+            a.lengths.clone().slice(start as u64..end as u64),
+            b.lengths.clone().slice(start as u64..end as u64),
+            end - start,
+            end - start,
+            init_val,
+            PairLayout::Zipped,
+            corner_offsets(&a.host_lengths[start..end], &b.host_lengths[start..end]),
+            None,
+            |pair, value| distances[start + pair] = value,
+        )?;
+    }
 
-            let a_sub = &a_padded[a_start * a_len..a_end * a_len];
-            let b_sub = &b_padded[b_start * b_len..b_end * b_len];
+    subbuffer_allocator.clear();
 
-            dp_buffers.diamond_partitioning_gpu(
+    Ok(distances)
+}
+
+/// Distance of a single pair computed `band_rows` rows of `a` at a time. Only the last row
+/// of each band is carried over to seed the next one, and the bands are uploaded one at a
+/// time, so the device memory depends on `band_rows` and `b.len()` however long `a` is. The
+/// kernel must only depend on the neighbouring cells, not on where the cell lies in the
+/// whole matrix, as unbanded DTW.
+pub fn diamond_partitioning_row_bands<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &[Float],
+    b: &[Float],
+    band_rows: usize,
+    init_val: Float,
+) -> Result<Float, TsDistanceError> {
+    if band_rows == 0 {
+        return Err(TsDistanceError::InvalidInput(
+            "row bands must hold at least one row".to_string(),
+        ));
+    }
+    if params.diagonal_states() != 1 || params.boundary() != BoundaryCondition::Closed {
+        return Err(TsDistanceError::InvalidInput(
+            "row bands only carry a single state with closed boundaries".to_string(),
+        ));
+    }
+    if a.is_empty() {
+        return Err(TsDistanceError::InvalidInput(
+            "series a holds no values".to_string(),
+        ));
+    }
+    let subbuffer_allocator = subbuffer_allocator.start_run(0);
+    let tile_size = options.tile_size(&device)?;
+    let a = options.nan_policy().apply_flat("a", a)?;
+
+    let b_series = GpuSeries::upload(
+        device.clone(),
+        queue.clone(),
+        command_buffer_allocator.clone(),
+        subbuffer_allocator.clone(),
+        options,
+        &vec![b.to_vec()],
+    )?;
+
+    // Every band is padded to the same length and streamed on its own, so only a single
+    // band of a is ever on the device, and the diagonals span it and b.
+    let a_len = next_multiple_of_n(band_rows.min(a.len()), options.subgroup_size(&device));
+    let b_len = b_series.padded_len;
+    let diag_len = 2 * (max(a_len, b_len) + 1).next_power_of_two();
+    chunk_side(
+        subbuffer_allocator.max_storage_floats(&device),
+        max(a_len, b_len),
+        1,
+    )?;
+
+    let band_buffer = SubBufferPair::new(&subbuffer_allocator, a_len as u64)?;
+    let band_length_buffer = SubBufferPair::new(&subbuffer_allocator, 1)?;
+    let mut dp_buffers = DiamondPartitioning::new(
+        subbuffer_allocator.clone(),
+        1,
+        1,
+        a_len as u64,
+        b_len as u64,
+        1,
+        diag_len as u64,
+        false,
+    )?;
+
+    let mut last_row = Vec::new();
+    let mut padded_band = vec![0.0; a_len];
+    for band in a.chunks(band_rows) {
+        options.check_cancelled()?;
+        padded_band[..band.len()].copy_from_slice(band);
+        padded_band[band.len()..].fill(0.0);
+        let band_length = [band.len() as u32];
+        let mut upload = AutoCommandBufferBuilder::primary(
+            command_buffer_allocator.clone(),
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        let band_gpu = band_buffer.move_gpu(&padded_band, &mut upload)?;
+        let band_length_gpu = band_length_buffer.move_gpu(&band_length, &mut upload)?;
+        standalone::submit_and_wait(device.clone(), queue.clone(), upload, options.timeout())?;
+
+        let pending = dp_buffers
+            .submit(
                 device.clone(),
                 queue.clone(),
                 command_buffer_allocator.clone(),
                 descriptor_set_allocator.clone(),
                 subbuffer_allocator.clone(),
+                options,
                 &params,
-                max_subgroup_size,
+                tile_size,
                 a_len,
                 b_len,
-                a_sub,
-                b_sub,
-                a_end - a_start,
-                b_end - b_start,
+                band_gpu,
+                b_series.data.clone(),
+                band_length_gpu,
+                b_series.lengths.clone(),
+                1,
+                1,
                 init_val,
-                &mut dist_matrix[a_start..a_end],
-                b_start,
-            );
-        }
+                PairLayout::All,
+                corner_offsets(&band_length, &b_series.host_lengths),
+                None,
+            )?
+            .expect("a single pair is always read back");
+        last_row = pending.wait_row(a_len, b_len)?;
+        // Above the next band, the corner lies on the left boundary.
+        let mut top_row = Vec::with_capacity(b_len + 1);
+        top_row.push(init_val);
+        top_row.extend_from_slice(&last_row);
+        dp_buffers.top_row = Some(top_row);
+    }
+
+    subbuffer_allocator.clear();
+
+    let distance = last_row[b.len() - 1];
+    Ok(if options.sqrt_output() && params.squared_cost() {
+        distance.sqrt()
+    } else {
+        distance
+    })
+}
+
+/// Runs the kernel on a single pair and returns the whole `a.len() x b.len()` accumulated
+/// cost matrix, where `matrix[i][j]` is the cost of aligning `a[..=i]` with `b[..=j]`.
+/// Every cell is written back to a dense buffer, so this needs `a.len() * b.len()` extra
+/// floats of device memory on top of the diagonals.
+pub fn cost_matrix_gpu<G: GpuKernelImpl>(
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    subbuffer_allocator: SubBuffersAllocator,
+    options: &RunOptions,
+    params: G,
+    a: &[Float],
+    b: &[Float],
+    init_val: Float,
+) -> Result<Vec<Vec<Float>>, TsDistanceError> {
+    let tile_size = options.tile_size(&device)?;
+
+    let a_series = GpuSeries::upload(
+        device.clone(),
+        queue.clone(),
+        command_buffer_allocator.clone(),
+        subbuffer_allocator.clone(),
+        options,
+        &vec![a.to_vec()],
+    )?;
+    let b_series = GpuSeries::upload(
+        device.clone(),
+        queue.clone(),
+        command_buffer_allocator.clone(),
+        subbuffer_allocator.clone(),
+        options,
+        &vec![b.to_vec()],
+    )?;
+
+    let a_len = a_series.padded_len;
+    let b_len = b_series.padded_len;
+    let max_floats = subbuffer_allocator.max_storage_floats(&device);
+    if a_len * b_len > max_floats {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "the {} x {} cost matrix does not fit a storage buffer of {} values",
+            a_len, b_len, max_floats
+        )));
     }
+    let diag_len = 2 * (max(a_len, b_len) + 1).next_power_of_two() * params.diagonal_states();
+    // The series are already padded to the tile size.
+    chunk_side(
+        subbuffer_allocator.max_storage_floats(&device) / params.diagonal_states(),
+        max(a_len, b_len),
+        1,
+    )?;
+
+    let mut dp_buffers = DiamondPartitioning::new(
+        subbuffer_allocator.clone(),
+        1,
+        1,
+        a_len as u64,
+        b_len as u64,
+        1,
+        diag_len as u64,
+        true,
+    )?;
+
+    dp_buffers.diamond_partitioning_gpu(
+        device,
+        queue,
+        command_buffer_allocator,
+        descriptor_set_allocator,
+        subbuffer_allocator.clone(),
+        options,
+        &params,
+        tile_size,
+        a_len,
+        b_len,
+        a_series.data.clone(),
+        b_series.data.clone(),
+        a_series.lengths.clone(),
+        b_series.lengths.clone(),
+        1,
+        1,
+        init_val,
+        PairLayout::All,
+        corner_offsets(&a_series.host_lengths, &b_series.host_lengths),
+        None,
+        |_, _| {},
+    )?;
+
+    let cells = dp_buffers.cells.take().unwrap();
+    let cells = cells.read()?;
+    let matrix = (0..a.len())
+        .map(|i| cells[i * b_len..i * b_len + b.len()].to_vec())
+        .collect();
 
     subbuffer_allocator.clear();
 
-    // panic!("dist matrix {:?}", &dist_matrix[..5].iter().map(|r| &r[..5]).collect::<Vec<_>>());
-    dist_matrix
+    Ok(matrix)
 }
 
 impl<G: GpuKernelImpl> DiamondPartitioning<G> {
@@ -129,43 +1970,213 @@ impl<G: GpuKernelImpl> DiamondPartitioning<G> {
         b_count: u64,
         a_padded_len: u64,
         b_padded_len: u64,
+        dim: u64,
+        diag_len: u64,
+        store_cells: bool,
+    ) -> Result<Self, TsDistanceError> {
+        Self::with_pair_capacity(
+            subbuffer_allocator,
+            a_count,
+            b_count,
+            a_count * b_count,
+            a_padded_len,
+            b_padded_len,
+            dim,
+            diag_len,
+            store_cells,
+        )
+    }
+
+    /// Same as `new`, with diagonals for only `pair_capacity` pairs instead of every
+    /// `a_count x b_count` one, as `PairLayout::Zipped` needs.
+    pub fn with_pair_capacity(
+        subbuffer_allocator: SubBuffersAllocator,
+        a_count: u64,
+        b_count: u64,
+        pair_capacity: u64,
+        a_padded_len: u64,
+        b_padded_len: u64,
+        dim: u64,
         diag_len: u64,
-    ) -> Self {
-        Self {
-            a_buffer: SubBufferPair::new(&subbuffer_allocator, a_count * a_padded_len),
-            b_buffer: SubBufferPair::new(&subbuffer_allocator, b_count * b_padded_len),
-            diagonal_buffer: SubBufferPair::new(&subbuffer_allocator, a_count * b_count * diag_len),
+        store_cells: bool,
+    ) -> Result<Self, TsDistanceError> {
+        // The kernels always bind a cells buffer, a single element is enough when the
+        // full cost matrix is not requested.
+        let cells_len = if store_cells {
+            pair_capacity * a_padded_len * b_padded_len
+        } else {
+            1
+        };
+        Ok(Self {
+            a_buffer: SubBufferPair::new(&subbuffer_allocator, a_count * a_padded_len * dim)?,
+            b_buffer: SubBufferPair::new(&subbuffer_allocator, b_count * b_padded_len * dim)?,
+            a_lengths_buffer: SubBufferPair::new(&subbuffer_allocator, a_count)?,
+            b_lengths_buffer: SubBufferPair::new(&subbuffer_allocator, b_count)?,
+            diagonal_buffer: SubBufferPair::new(&subbuffer_allocator, pair_capacity * diag_len)?,
+            cells_buffer: SubBufferPair::new(&subbuffer_allocator, cells_len)?,
+            store_cells,
+            cells: None,
             kernel_params: None,
-        }
+            top_row: None,
+        })
+    }
+
+    /// Whether the buffers can hold chunks of `a_count x b_count` pairs, with series of
+    /// `a_values` and `b_values` values each.
+    fn fits(
+        &self,
+        a_count: usize,
+        b_count: usize,
+        a_values: usize,
+        b_values: usize,
+        diag_len: usize,
+    ) -> bool {
+        !self.store_cells
+            && self.a_buffer.capacity() >= (a_count * a_values) as u64
+            && self.b_buffer.capacity() >= (b_count * b_values) as u64
+            && self.a_lengths_buffer.capacity() >= a_count as u64
+            && self.b_lengths_buffer.capacity() >= b_count as u64
+            && self.diagonal_buffer.capacity() >= (a_count * b_count * diag_len) as u64
     }
 
     #[inline(always)]
-    fn diamond_partitioning_gpu(
+    /// Records and submits the chunk without waiting for it, the distances are read back by
+    /// `PendingChunk::wait`. Chunks gathered on the device are waited for here and yield
+    /// nothing to read back.
+    fn submit(
         &mut self,
         device: Arc<Device>,
         queue: Arc<Queue>,
         command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
         descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
         buffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        params: &G,
+        max_subgroup_threads: usize,
+        a_len: usize,
+        b_len: usize,
+        a_padded: Subbuffer<[Float]>,
+        b_padded: Subbuffer<[Float]>,
+        a_lengths: Subbuffer<[u32]>,
+        b_lengths: Subbuffer<[u32]>,
+        a_count: usize,
+        b_count: usize,
+        init_val: Float,
+        pair_layout: PairLayout,
+        corners: (isize, isize),
+        gather: Option<Gather>,
+    ) -> Result<Option<PendingChunk>, TsDistanceError> {
+        if pair_layout.pair_count(a_count, b_count) == 0 {
+            return Ok(None);
+        }
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            command_buffer_allocator,
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )?;
+        let timeout = options.timeout();
+        let gathered = gather.is_some();
+        let recorded = self.record(
+            device.clone(),
+            descriptor_set_allocator,
+            buffer_allocator,
+            options,
+            params,
+            max_subgroup_threads,
+            a_len,
+            b_len,
+            a_padded,
+            b_padded,
+            a_lengths,
+            b_lengths,
+            a_count,
+            b_count,
+            init_val,
+            pair_layout,
+            corners,
+            gather,
+            &mut builder,
+        )?;
+        if gathered {
+            standalone::submit_and_wait(device, queue, builder, timeout)?;
+            return Ok(None);
+        }
+        let Some(chunk) = recorded else {
+            return Ok(None);
+        };
+
+        let command_buffer = builder.build()?;
+        let future = vulkano::sync::now(device)
+            .then_execute(queue, command_buffer)?
+            .then_signal_fence_and_flush()?;
+        Ok(Some(PendingChunk {
+            future,
+            chunk,
+            timeout,
+        }))
+    }
+
+    /// Records the chunk into `builder` without building it. What to read back once the
+    /// builder has been executed is returned, nothing for chunks gathered on the device.
+    fn record(
+        &mut self,
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        buffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
         params: &G,
         max_subgroup_threads: usize,
         a_len: usize,
         b_len: usize,
-        a_padded: &[f32],
-        b_padded: &[f32],
+        a_padded: Subbuffer<[Float]>,
+        b_padded: Subbuffer<[Float]>,
+        a_lengths: Subbuffer<[u32]>,
+        b_lengths: Subbuffer<[u32]>,
         a_count: usize,
         b_count: usize,
-        init_val: f32,
-        dist_matrix: &mut [Vec<f32>],
-        column_offset: usize,
-    ) {
-        let diag_len = 2 * (max(a_len, b_len) + 1).next_power_of_two();
+        init_val: Float,
+        pair_layout: PairLayout,
+        corners: (isize, isize),
+        gather: Option<Gather>,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    ) -> Result<Option<RecordedChunk>, TsDistanceError> {
+        let diag_len = 2 * (max(a_len, b_len) + 1).next_power_of_two() * params.diagonal_states();
+
+        let pair_count = pair_layout.pair_count(a_count, b_count);
+        if pair_count == 0 {
+            return Ok(None);
+        }
+
+        let sqrt = options.sqrt_output() && params.squared_cost();
+        let boundary = params.boundary();
+        // An open end takes the smallest of the last row, the b_len offsets up to the corner.
+        // The padding only repeats cells of the real last row, so it can be included.
+        let end_cells = if boundary.open_end() { b_len } else { 1 };
 
-        let mut diagonal = vec![init_val; a_count * b_count * diag_len];
+        let mut diagonal = vec![init_val; pair_count * diag_len];
 
-        for i in 0..(a_count * b_count) {
-            diagonal[i * diag_len] = 0.0;
+        let top_row = self.top_row.as_deref();
+        let seed = |pair_diagonal: &mut [Float]| {
+            if let Some(top_row) = top_row {
+                pair_diagonal[..top_row.len()].copy_from_slice(top_row);
+                return;
+            }
+            pair_diagonal[0] = 0.0;
+            if boundary.open_begin() {
+                // The cells above the first row lie on the offsets 1..=b_len.
+                pair_diagonal[1..b_len + 1].fill(0.0);
+            }
+        };
+        // The command buffer itself is recorded on this thread, the subbuffer allocator it
+        // draws from is not `Sync`. Only the host side seeding is split.
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            diagonal.par_chunks_mut(diag_len).for_each(seed);
         }
+        #[cfg(not(feature = "rayon"))]
+        diagonal.chunks_mut(diag_len).for_each(seed);
 
         let n_tiles_in_a = a_len.div_ceil(max_subgroup_threads);
         let n_tiles_in_b = b_len.div_ceil(max_subgroup_threads);
@@ -177,48 +2188,96 @@ impl<G: GpuKernelImpl> DiamondPartitioning<G> {
         let mut a_start = 0;
         let mut b_start = 0;
 
-        let mut builder = AutoCommandBufferBuilder::primary(
-            command_buffer_allocator.clone(),
-            queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
-        )
-        .unwrap();
+        // Offsets `j - i` that must be computed: the band, and those between the last real
+        // cell of each pair and the padded corner, which the padding carries it through.
+        // Skipped cells keep `init_val`, except in the cost matrix, which is then computed in
+        // full.
+        // Skipped diamonds would leave an open boundary's seeds in the last row.
+        let band = params
+            .band()
+            .filter(|_| !self.store_cells && boundary == BoundaryCondition::Closed)
+            .map(|band| {
+                let band = band.min((a_len + b_len) as u64) as isize;
+                let padded_corner = b_len as isize - a_len as isize;
+                (
+                    corners.0.min(padded_corner).min(-band),
+                    corners.1.max(padded_corner).max(band),
+                )
+            });
 
         if self.kernel_params.is_none() {
             self.kernel_params =
-                Some(params.build_kernel_params(buffer_allocator.clone(), &mut builder));
+                Some(params.build_kernel_params(buffer_allocator.clone(), builder)?);
         }
 
         let kernel_params = self.kernel_params.as_mut().unwrap();
 
-        let a_gpu = self.a_buffer.move_gpu(&a_padded, &mut builder);
-        let b_gpu = self.b_buffer.move_gpu(&b_padded, &mut builder);
-        let mut diagonal_buffer_gpu = self.diagonal_buffer.move_gpu(&diagonal, &mut builder);
+        // The chunk is copied device-side out of the resident series, only the initial
+        // diagonal comes from the host.
+        // Blocks on the diagonal of a self-distance matrix see the same series on both sides,
+        // which are then copied once.
+        let same_series = same_range(&a_padded, &b_padded) && same_range(&a_lengths, &b_lengths);
+        let a_gpu = self.a_buffer.copy_gpu(a_padded, builder)?;
+        let a_lengths_gpu = self.a_lengths_buffer.copy_gpu(a_lengths, builder)?;
+        let (b_gpu, b_lengths_gpu) = if same_series {
+            (a_gpu.clone(), a_lengths_gpu.clone())
+        } else {
+            (
+                self.b_buffer.copy_gpu(b_padded, builder)?,
+                self.b_lengths_buffer.copy_gpu(b_lengths, builder)?,
+            )
+        };
+        let mut diagonal_buffer_gpu = self.diagonal_buffer.move_gpu(&diagonal, builder)?;
+        let mut cells_gpu = self.cells_buffer.gpu();
 
         // Number of kernel calls
         for i in 0..rows_count {
-            params.dispatch(
-                device.clone(),
-                descriptor_set_allocator.clone(),
-                &mut builder,
-                first_coord as i64,
-                i as u64,
-                diamonds_count as u64,
-                a_start as u64,
-                b_start as u64,
-                a_len as u64,
-                b_len as u64,
-                max_subgroup_threads as u64,
-                &a_gpu,
-                &b_gpu,
-                &mut diagonal_buffer_gpu,
-                &kernel_params,
-            );
-
-            if i < (n_tiles_in_a - 1) {
+            // Diamond d covers the offsets within a tile of b_start - a_start + 2 * d * tile,
+            // so those intersecting the band are contiguous.
+            let tile = max_subgroup_threads as isize;
+            let centre = |d: usize| b_start as isize - a_start as isize + 2 * d as isize * tile;
+            let (mut first, mut last) = (0, diamonds_count);
+            if let Some((low, high)) = band {
+                while first < last && centre(first) + tile < low {
+                    first += 1;
+                }
+                while last > first && centre(last - 1) - tile > high {
+                    last -= 1;
+                }
+            }
+
+            if first < last {
+                params.dispatch(
+                    device.clone(),
+                    descriptor_set_allocator.clone(),
+                    builder,
+                    (first_coord + 2 * first as isize * tile) as i64,
+                    i as u64,
+                    (last - first) as u64,
+                    (a_start - first * max_subgroup_threads) as u64,
+                    (b_start + first * max_subgroup_threads) as u64,
+                    a_len as u64,
+                    b_len as u64,
+                    max_subgroup_threads as u64,
+                    pair_layout,
+                    &a_gpu,
+                    &b_gpu,
+                    &a_lengths_gpu,
+                    &b_lengths_gpu,
+                    &mut diagonal_buffer_gpu,
+                    &mut cells_gpu,
+                    &kernel_params,
+                )?;
+            }
+
+            if i < (n_tiles_in_a - 1) && i < (n_tiles_in_b - 1) {
                 diamonds_count += 1;
                 first_coord -= max_subgroup_threads as isize;
                 a_start += max_subgroup_threads;
+            } else if i < (n_tiles_in_a - 1) {
+                // The datasets are not swapped, so b may be exhausted before a.
+                first_coord -= max_subgroup_threads as isize;
+                a_start += max_subgroup_threads;
             } else if i < (n_tiles_in_b - 1) {
                 first_coord += max_subgroup_threads as isize;
                 b_start += max_subgroup_threads;
@@ -235,23 +2294,381 @@ impl<G: GpuKernelImpl> DiamondPartitioning<G> {
 
         let (_, cx) = index_mat_to_diag(a_len, b_len);
 
-        let diagonal = self.diagonal_buffer.move_cpu(&mut builder);
-        let command_buffer = builder.build().unwrap();
-        let future = vulkano::sync::now(device)
-            .then_execute(queue, command_buffer)
-            .unwrap()
-            .then_signal_fence_and_flush()
-            .unwrap();
-        future.wait(None).unwrap();
-        let diagonal = diagonal.read().unwrap();
-        for i in 0..a_count {
-            for j in 0..b_count {
-                let diag_offset = (i * b_count + j) * diag_len;
-                dist_matrix[i][column_offset + j] =
-                    diagonal[diag_offset + ((cx as usize) & (diag_len - 1))];
-            }
+        if let Some(gather) = gather {
+            let max_threads_x = device
+                .physical_device()
+                .properties()
+                .max_compute_work_group_size[0];
+            standalone::dispatch(
+                device.clone(),
+                descriptor_set_allocator.clone(),
+                builder,
+                "kernels::gather_distances::batch_call",
+                [
+                    WriteDescriptorSet::buffer(0, diagonal_buffer_gpu.clone()),
+                    WriteDescriptorSet::buffer(1, gather.matrix),
+                    WriteDescriptorSet::buffer(2, a_lengths_gpu.clone()),
+                    WriteDescriptorSet::buffer(3, b_lengths_gpu.clone()),
+                ],
+                GatherConstants {
+                    pair_count: pair_count as u64,
+                    b_count: b_count as u64,
+                    diag_len: diag_len as u64,
+                    cell: ((cx as usize) & (diag_len - 1)) as u64,
+                    row_offset: gather.row_offset as u64,
+                    col_offset: gather.col_offset as u64,
+                    row_stride: gather.row_stride as u64,
+                    col_stride: gather.col_stride as u64,
+                    per_pair_min_len: gather.per_pair_min_len as u64,
+                    sqrt: sqrt as u64,
+                    end_cells: end_cells as u64,
+                    scale: gather.scale,
+                    offset: gather.offset,
+                },
+                (pair_count as u32).div_ceil(max_threads_x),
+            )?;
+            return Ok(None);
+        }
+
+        let diagonal = self.diagonal_buffer.move_cpu(builder)?;
+        let cells = if self.store_cells {
+            Some(self.cells_buffer.move_cpu(builder)?)
+        } else {
+            None
+        };
+        Ok(Some(RecordedChunk {
+            diagonal,
+            cells,
+            pair_count,
+            diag_len,
+            cell: (cx as usize) & (diag_len - 1),
+            end_cells,
+            sqrt,
+        }))
+    }
+
+    fn diamond_partitioning_gpu(
+        &mut self,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+        buffer_allocator: SubBuffersAllocator,
+        options: &RunOptions,
+        params: &G,
+        max_subgroup_threads: usize,
+        a_len: usize,
+        b_len: usize,
+        a_padded: Subbuffer<[Float]>,
+        b_padded: Subbuffer<[Float]>,
+        a_lengths: Subbuffer<[u32]>,
+        b_lengths: Subbuffer<[u32]>,
+        a_count: usize,
+        b_count: usize,
+        init_val: Float,
+        pair_layout: PairLayout,
+        corners: (isize, isize),
+        gather: Option<Gather>,
+        store: impl FnMut(usize, Float),
+    ) -> Result<(), TsDistanceError> {
+        if let Some(pending) = self.submit(
+            device,
+            queue,
+            command_buffer_allocator,
+            descriptor_set_allocator,
+            buffer_allocator,
+            options,
+            params,
+            max_subgroup_threads,
+            a_len,
+            b_len,
+            a_padded,
+            b_padded,
+            a_lengths,
+            b_lengths,
+            a_count,
+            b_count,
+            init_val,
+            pair_layout,
+            corners,
+            gather,
+        )? {
+            self.cells = pending.wait(store)?;
+        }
+        Ok(())
+    }
+}
+
+/// A chunk recorded into a command buffer, with the host buffers its distances are read
+/// back from once that command buffer has been executed.
+struct RecordedChunk {
+    diagonal: Subbuffer<[Float]>,
+    cells: Option<Subbuffer<[Float]>>,
+    pair_count: usize,
+    diag_len: usize,
+    cell: usize,
+    end_cells: usize,
+    sqrt: bool,
+}
+
+impl RecordedChunk {
+    /// Hands every pair's distance to `store`, returning the cost matrix cells when they
+    /// were kept. The command buffer must have completed.
+    fn read(
+        self,
+        mut store: impl FnMut(usize, Float),
+    ) -> Result<Option<Subbuffer<[Float]>>, TsDistanceError> {
+        self.read_ends(|pair, value, _| store(pair, value))
+    }
+
+    /// Same as `read`, also handing over the column of the last row the distance was taken
+    /// from, which only varies with an open end. Ties go to the leftmost column, so the
+    /// padding, which repeats the last real column, is never reported.
+    fn read_ends(
+        self,
+        mut store: impl FnMut(usize, Float, usize),
+    ) -> Result<Option<Subbuffer<[Float]>>, TsDistanceError> {
+        let diagonal = self.diagonal.read()?;
+        for pair in 0..self.pair_count {
+            // The column `end_cells - 1 - t` sits `t` offsets before the corner. As with
+            // `Float::min`, a NaN is only kept when every cell is one.
+            let (value, end) = (1..self.end_cells)
+                .map(|t| {
+                    let slot = (self.cell + self.diag_len - t) & (self.diag_len - 1);
+                    (
+                        diagonal[pair * self.diag_len + slot],
+                        self.end_cells - 1 - t,
+                    )
+                })
+                .fold(
+                    (
+                        diagonal[pair * self.diag_len + self.cell],
+                        self.end_cells - 1,
+                    ),
+                    |best, cell| {
+                        if cell.0 <= best.0 || best.0.is_nan() {
+                            cell
+                        } else {
+                            best
+                        }
+                    },
+                );
+            store(pair, if self.sqrt { value.sqrt() } else { value }, end);
+        }
+        Ok(self.cells)
+    }
+}
+
+/// A chunk submitted to the device whose distances have not been read back yet.
+struct PendingChunk {
+    future: FenceSignalFuture<CommandBufferExecFuture<NowFuture>>,
+    chunk: RecordedChunk,
+    timeout: Option<Duration>,
+}
+
+impl PendingChunk {
+    /// Blocks until the chunk is done and hands every pair's distance to `store`, returning
+    /// the cost matrix cells when they were kept.
+    fn wait(
+        self,
+        store: impl FnMut(usize, Float),
+    ) -> Result<Option<Subbuffer<[Float]>>, TsDistanceError> {
+        standalone::wait(self.future, self.timeout)?;
+        self.chunk.read(store)
+    }
+
+    /// Blocks until the single pair of the chunk is done and returns the `b_len` cells of the
+    /// last row of its `a_len x b_len` padded matrix, before any square root.
+    fn wait_row(self, a_len: usize, b_len: usize) -> Result<Vec<Float>, TsDistanceError> {
+        standalone::wait(self.future, self.timeout)?;
+        let chunk = self.chunk;
+        let diagonal = chunk.diagonal.read()?;
+        let mask = chunk.diag_len - 1;
+        // The cell `(a_len - 1, j)` lies on the offset `j - (a_len - 1)`.
+        Ok((0..b_len)
+            .map(|j| diagonal[(j + chunk.diag_len - (a_len - 1)) & mask])
+            .collect())
+    }
+
+    /// Same as `wait`, see `RecordedChunk::read_ends`.
+    fn wait_ends(
+        self,
+        store: impl FnMut(usize, Float, usize),
+    ) -> Result<Option<Subbuffer<[Float]>>, TsDistanceError> {
+        standalone::wait(self.future, self.timeout)?;
+        self.chunk.read_ends(store)
+    }
+}
+
+/// Smallest and largest `m - n` over the pairs of a series of length `n` in `a_lengths` and
+/// one of length `m` in `b_lengths`, the offsets of their last real cells.
+fn corner_offsets(a_lengths: &[u32], b_lengths: &[u32]) -> (isize, isize) {
+    let (a_min, a_max) = (a_lengths.iter().min(), a_lengths.iter().max());
+    let (b_min, b_max) = (b_lengths.iter().min(), b_lengths.iter().max());
+    (
+        *b_min.unwrap_or(&0) as isize - *a_max.unwrap_or(&0) as isize,
+        *b_max.unwrap_or(&0) as isize - *a_min.unwrap_or(&0) as isize,
+    )
+}
+
+/// Bytes of device memory taken by the series of both batches, counted once when they
+/// share their buffers.
+fn resident_bytes(a: &GpuSeries, b: &GpuSeries) -> u64 {
+    if same_range(&a.data, &b.data) {
+        a.device_bytes()
+    } else {
+        a.device_bytes() + b.device_bytes()
+    }
+}
+
+/// Bytes of device memory of the buffers `DiamondPartitioning::new` allocates for chunks of
+/// `a_count x b_count` pairs, with series of `a_values` and `b_values` values each, and of
+/// the kernel's vector parameters.
+fn slot_bytes(
+    a_count: usize,
+    b_count: usize,
+    a_values: usize,
+    b_values: usize,
+    diag_len: usize,
+    kernel_params_bytes: u64,
+) -> u64 {
+    // The cells buffer holds a single value when the cost matrix is not kept.
+    let floats = a_count * a_values + b_count * b_values + a_count * b_count * diag_len + 1;
+    (floats * std::mem::size_of::<Float>() + (a_count + b_count) * std::mem::size_of::<u32>())
+        as u64
+        + kernel_params_bytes
+}
+
+/// Largest chunk side up to `side` whose buffers, of `slot_bytes(side)` bytes, fit in what is
+/// left of the memory budget, `side` itself without a budget.
+fn budget_chunk_side(
+    subbuffer_allocator: &SubBuffersAllocator,
+    side: usize,
+    slot_bytes: impl Fn(usize) -> u64,
+) -> Result<usize, TsDistanceError> {
+    let (Some(budget), Some(available)) = (
+        subbuffer_allocator.memory_budget(),
+        subbuffer_allocator.available_device_bytes(),
+    ) else {
+        return Ok(side);
+    };
+    (1..=side)
+        .rev()
+        .find(|&side| slot_bytes(side) <= available)
+        .ok_or(TsDistanceError::MemoryBudgetExceeded {
+            required: budget - available + slot_bytes(1),
+            budget,
+        })
+}
+
+/// Queues the chunks of a batch are spread over, starting with `queue` and capped by
+/// `RunOptions::with_max_queues`. Only `queue` is used when it was not created by
+/// `get_device`.
+fn chunk_queues(
+    subbuffer_allocator: &SubBuffersAllocator,
+    options: &RunOptions,
+    queue: &Arc<Queue>,
+) -> Vec<Arc<Queue>> {
+    let queues = subbuffer_allocator.device_queues();
+    if !queues.iter().any(|q| Arc::ptr_eq(q, queue)) {
+        return vec![queue.clone()];
+    }
+    std::iter::once(queue.clone())
+        .chain(queues.iter().filter(|q| !Arc::ptr_eq(q, queue)).cloned())
+        .take(options.max_queues().unwrap_or(usize::MAX))
+        .collect()
+}
+
+/// Side of the largest square block that still splits `a_count x b_count` pairs into at
+/// least `parts` chunks, so that each of `parts` queues gets one. The chunks of a single
+/// queue are only bounded by `chunk_side`.
+fn spread_side(a_count: usize, b_count: usize, parts: usize) -> usize {
+    let chunks = |side: usize| a_count.div_ceil(side) * b_count.div_ceil(side);
+    if parts <= 1 {
+        return usize::MAX;
+    }
+    // `chunks` only decreases as the side grows.
+    let (mut lo, mut hi) = (1, a_count.max(b_count).max(1));
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        if chunks(mid) >= parts {
+            lo = mid;
+        } else {
+            hi = mid - 1;
         }
     }
+    lo
+}
+
+/// Side of the largest square block of pairs whose diagonals fit together in `max_floats`
+/// floats, the size of a single storage buffer.
+fn chunk_side(max_floats: usize, len: usize, tile_size: usize) -> Result<usize, TsDistanceError> {
+    let max_pairs = max_floats / padded_diag_len(len, tile_size);
+    if max_pairs == 0 {
+        return Err(TsDistanceError::SeriesTooLong {
+            len,
+            max: max_series_len(max_floats, tile_size),
+        });
+    }
+    Ok((max_pairs as f64).sqrt().floor() as usize)
+}
+
+/// Longest series whose rolling diagonal fits in `max_floats` floats, the inverse of
+/// `padded_diag_len`.
+pub(crate) fn max_series_len(max_floats: usize, tile_size: usize) -> usize {
+    if max_floats < 2 {
+        return 0;
+    }
+    let slots = 1 << (max_floats / 2).ilog2();
+    (slots - 1) / tile_size * tile_size
+}
+
+/// Length of the rolling diagonal of each pair, for series of up to `len` values padded to a
+/// multiple of the tile size.
+pub(crate) fn padded_diag_len(len: usize, tile_size: usize) -> usize {
+    2 * (next_multiple_of_n(len, tile_size) + 1).next_power_of_two()
+}
+
+/// Work the diamond partitioning does for one pair, as `estimate_dispatches` predicts it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DispatchPlan {
+    /// Kernel dispatches, one per row of diamonds.
+    pub rows_count: usize,
+    /// Diamonds over all the rows, each computing a `tile_size` square of cells.
+    pub diamonds: usize,
+    /// Bytes of the rolling diagonal kept on the device for the pair, by a kernel with a
+    /// single state.
+    pub diagonal_bytes: usize,
+}
+
+/// Dispatches and diamonds of a pair of series of `a_len` and `b_len` values computed in
+/// tiles of `tile_size` threads, without a band, and the memory of its rolling diagonal. No
+/// device is needed, the figures only depend on the padded lengths.
+pub fn estimate_dispatches(
+    a_len: usize,
+    b_len: usize,
+    tile_size: usize,
+) -> Result<DispatchPlan, TsDistanceError> {
+    if !tile_size.is_power_of_two() {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "the tile size must be a power of two, got {}",
+            tile_size
+        )));
+    }
+    if a_len == 0 || b_len == 0 {
+        return Err(TsDistanceError::InvalidInput(format!(
+            "series of {} and {} values hold no cell",
+            a_len, b_len
+        )));
+    }
+    let n_tiles_in_a = a_len.div_ceil(tile_size);
+    let n_tiles_in_b = b_len.div_ceil(tile_size);
+    Ok(DispatchPlan {
+        rows_count: n_tiles_in_a + n_tiles_in_b - 1,
+        // Every tile of the padded matrix is one diamond, however the rows split them.
+        diamonds: n_tiles_in_a * n_tiles_in_b,
+        diagonal_bytes: padded_diag_len(max(a_len, b_len), tile_size) * size_of::<Float>(),
+    })
 }
 
 fn next_multiple_of_n(x: usize, n: usize) -> usize {
@@ -1,10 +1,27 @@
 use std::fmt::write;
+use std::time::Duration;
 
 use csv::ReaderBuilder;
+use rspirv::binary::Assemble;
 use tsdistances_gpu::{
-    cpu::{erp, lcss, dtw, wdtw, adtw, msm, twe},
-    utils::get_device,
+    Float, assert_eq_with_tol, assert_matrix_eq_with_tol,
+    context::{GpuContext, Metric},
+    engine::DistanceEngine,
+    cpu::{knn, cid_dtw, erp, erp_multivariate, euclidean, lb_keogh, lcss, dtw, dtw_async, dtw_flat, dtw_gpu_resident, dtw_itakura, dtw_many_singles, dtw_multivariate, dtw_nn_search, dtw_self, dtw_with_cost, dtw_with_path, dtw_with_progress, ddtw, edr, gak, wdtw, wdtw_logistic, adtw, adtw_scaled, msm, msm_c, msm_gen, sbd, twe, wmsm},
+    error::TsDistanceError,
+    kernels::{
+        LocalCost, MSM_C, StepPattern, custom::{CustomKernel, register_custom_kernel}, dtw_distance::cpu::DTWImpl, knn::cpu::knn_select_gpu, msm_distance::cpu::MSMImpl, standalone,
+        twe_distance::cpu::TWEImpl, wdtw_distance::cpu::WDTWImpl,
+    },
+    matrix::DistanceMatrix,
+    reference,
+    utils::{
+        CBF_LEN, DeviceInfo, DevicePreference, FALLBACK_SUBGROUP_SIZE, MAX_EXACT_COUNT, effective_subgroup_size, DispatchTrace, NanPolicy, RunOptions, apply_mask, compare_matrices, describe_device, envelopes, generate_cbf, generate_random_batch,
+        get_device, get_device_with, logistic_weights, read_csv_chunked, read_ts_chunked, read_ts_file, read_ts_file_channels, supports_subgroup_sync, znormalize, CancellationToken,
+    },
+    warps::{DispatchPlan, GpuSeries, estimate_dispatches},
 };
+use vulkano::{buffer::BufferContents, device::physical::PhysicalDeviceType};
 
 fn read_txt<T>(file_path: &str) -> Result<Vec<Vec<T>>, Box<dyn std::error::Error>>
 where
@@ -29,6 +46,19 @@ where
     Ok(records)
 }
 
+fn read_labels(file_path: &str) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(if file_path.ends_with(".tsv") { b'\t' } else { b',' })
+        .from_path(file_path)?;
+
+    let mut labels = Vec::new();
+    for result in reader.records() {
+        labels.push(result?[0].parse::<Float>()? as usize);
+    }
+    Ok(labels)
+}
+
 pub fn write_csv<T>(file_path: &str, data: &[Vec<T>]) -> Result<(), Box<dyn std::error::Error>>
 where
     T: std::fmt::Display,
@@ -41,24 +71,69 @@ where
     Ok(())
 }
 
-const WEIGHT_MAX: f32 = 1.0;
-fn dtw_weights(len: usize, g: f32) -> Vec<f32> {
+/// A file of the temporary directory, removed once dropped. Its name holds the process id and
+/// a counter, so that concurrent tests and test runs never share it.
+struct TempPath(std::path::PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let name = format!("tsdistances_gpu_{}_{}_{}", std::process::id(), n, name);
+        Self(std::env::temp_dir().join(name))
+    }
+
+    fn as_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl AsRef<std::path::Path> for TempPath {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Values of every ACSF1 series.
+const ACSF1_LEN: usize = 1460;
+
+/// The first `a_count` train and `b_count` test series of ACSF1, cut to their first `a_len`
+/// and `b_len` values, with a default engine.
+fn acsf1_fixture(
+    (a_count, a_len): (usize, usize),
+    (b_count, b_len): (usize, usize),
+) -> (DistanceEngine, Vec<Vec<Float>>, Vec<Vec<Float>>) {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..a_count].iter().map(|ts| ts[..a_len].to_vec()).collect();
+    let b = test_data[..b_count].iter().map(|ts| ts[..b_len].to_vec()).collect();
+    (DistanceEngine::new().unwrap(), a, b)
+}
+
+const WEIGHT_MAX: Float = 1.0;
+fn dtw_weights(len: usize, g: Float) -> Vec<Float> {
     let mut weights = vec![0.0; len];
-    let half_len = len as f32 / 2.0;
-    let e = std::f64::consts::E as f32;
+    let half_len = len as Float / 2.0;
+    let e = std::f64::consts::E as Float;
     for i in 0..len {
-        weights[i] = WEIGHT_MAX / (1.0 + e.powf(-g * (i as f32 - half_len)));
+        weights[i] = WEIGHT_MAX / (1.0 + e.powf(-g * (i as Float - half_len)));
     }
     weights
 }
 
 #[test]
 fn test_erp_distance() {
-    let train_data: Vec<Vec<f32>> = read_txt("../../DATA/ucr/UWaveGestureLibraryX/UWaveGestureLibraryX_TRAIN.tsv").unwrap();
-    let test_data: Vec<Vec<f32>> = read_txt("../../DATA/ucr/UWaveGestureLibraryX/UWaveGestureLibraryX_TEST.tsv").unwrap();
+    let train_data: Vec<Vec<Float>> = read_txt("../../DATA/ucr/UWaveGestureLibraryX/UWaveGestureLibraryX_TRAIN.tsv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("../../DATA/ucr/UWaveGestureLibraryX/UWaveGestureLibraryX_TEST.tsv").unwrap();
 
     let start_time = std::time::Instant::now();
-    let (device, queue, sba, sda, ma) = get_device();
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
 
     let result = erp(
         device.clone(),
@@ -66,10 +141,12 @@ fn test_erp_distance() {
         sba.clone(),
         sda.clone(),
         ma.clone(),
+        &RunOptions::default(),
         &train_data,
         &test_data,
         0.0,
-    );
+    )
+    .unwrap();
     let elapsed_time = start_time.elapsed();
     println!("ERP elapsed time: {:?}", elapsed_time);
     write_csv("erp_result.csv", &result).unwrap();
@@ -78,34 +155,165 @@ fn test_erp_distance() {
 
 #[test]
 fn test_lcss_distance() {
-    let train_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
-    let test_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
     let epsilon = 1.0;
 
     let start = std::time::Instant::now();
-    let (device, queue, sba, sda, ma) = get_device();
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
     let result = lcss(
         device.clone(),
         queue.clone(),
         sba.clone(),
         sda.clone(),
         ma.clone(),
+        &RunOptions::default(),
         &train_data,
         &test_data,
         epsilon,
-    );
+        None,
+    )
+    .unwrap();
     let elapsed = start.elapsed();
     println!("LCSS elapsed time: {:?}", elapsed);
     write_csv("lcss_result.csv", &result).unwrap();
 }
 
+#[test]
+fn test_edr() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a: Vec<Vec<Float>> = train_data[..4].iter().map(|ts| ts[..300].to_vec()).collect();
+    let b: Vec<Vec<Float>> = test_data[..5].iter().map(|ts| ts[..250].to_vec()).collect();
+    let epsilon = 0.5;
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = edr(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        epsilon,
+    )
+    .unwrap();
+
+    // Edit counts are integers, so they must match exactly.
+    let expected = reference::edr(&a, &b, epsilon).unwrap();
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn test_lcss_variable_length() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let epsilon = 1.0;
+
+    let a: Vec<Vec<Float>> = [100, 150, 170]
+        .iter()
+        .zip(train_data.iter())
+        .map(|(&len, ts)| ts[..len].to_vec())
+        .collect();
+    let b: Vec<Vec<Float>> = [120, 170, 60, 90]
+        .iter()
+        .zip(test_data.iter())
+        .map(|(&len, ts)| ts[..len].to_vec())
+        .collect();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = lcss(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        epsilon,
+        None,
+    )
+    .unwrap();
+
+    let expected = reference::lcss(&a, &b, epsilon, None).unwrap();
+    for i in 0..a.len() {
+        for j in 0..b.len() {
+            assert_eq_with_tol!(result[i][j], expected[i][j], 1e-4);
+        }
+    }
+}
+
+/// Values worked out by hand on short integer series, independently of the kernels and of
+/// `reference`. With `D[0][0] = 0`, every other boundary cell infinite and `d = a[i] - b[j]`:
+/// - DTW: `D[i][j] = d^2 + min(D[i-1][j-1], D[i-1][j], D[i][j-1])`
+/// - WDTW: `D[i][j] = w[|i - j|] * d^2 + min(..)`, with `w = 1, 1.5, 2, ..`
+/// - ADTW: `D[i][j] = d^2 + min(D[i-1][j-1], D[i-1][j] + 0.5, D[i][j-1] + 0.5)`
+/// - ERP: `min(D[i-1][j-1] + |d|, D[i-1][j] + |a[i]|, D[i][j-1] + |b[j]|)`, gap 0
+/// - LCSS: `1 - s / min(n, m)`, `s` the longest subsequence matching within 1
+/// - MSM: `min(D[i-1][j-1] + |d|, D[i-1][j] + C(a[i], a[i-1], b[j]), D[i][j-1] + C(b[j], b[j-1], a[i]))`,
+///   `C(x, y, z) = 1` if `x` lies between `y` and `z`, `1 + min(|x - y|, |x - z|)` otherwise
+/// - TWE: with `a[-1] = b[-1] = 0`, `nu = 0.5`, `lambda = 1`, deletions cost
+///   `|a[i] - a[i-1]| + nu + lambda` and matches `|d| + |a[i-1] - b[j-1]| + 2 nu |i - j|`
+#[test]
+fn test_golden_values() {
+    let a: Vec<Vec<Float>> = vec![vec![1.0, 2.0, 3.0, 4.0, 5.0], vec![0.0, 3.0, 1.0, 4.0, 2.0, 5.0]];
+    let b: Vec<Vec<Float>> = vec![vec![1.0, 3.0, 2.0, 5.0, 4.0, 6.0], vec![2.0, 2.0, 0.0, 1.0, 3.0, 1.0, 2.0, 4.0]];
+    let weights: Vec<Float> = (0..8).map(|k| 1.0 + 0.5 * k as Float).collect();
+
+    let engine = DistanceEngine::new().unwrap();
+    let cases = [
+        ("dtw", engine.dtw(&a, &b, None).unwrap(), [[4.0, 7.0], [8.0, 9.0]]),
+        ("wdtw", engine.wdtw(&a, &b, &weights).unwrap(), [[4.5, 15.5], [8.0, 11.0]]),
+        ("adtw", engine.adtw(&a, &b, 0.5).unwrap(), [[4.5, 9.5], [8.0, 10.0]]),
+        ("erp", engine.erp(&a, &b, 0.0).unwrap(), [[6.0, 6.0], [6.0, 6.0]]),
+        ("lcss", engine.lcss(&a, &b, 1.0, None).unwrap(), [[0.0, 0.2], [1.0 / 6.0, 1.0 / 6.0]]),
+        ("msm", engine.msm(&a, &b).unwrap(), [[5.0, 10.0], [6.0, 9.0]]),
+        ("twe", engine.twe(&a, &b, 0.5, 1.0).unwrap(), [[9.5, 23.5], [11.0, 20.0]]),
+    ];
+    for (metric, result, expected) in cases {
+        for i in 0..2 {
+            for j in 0..2 {
+                let (value, expected) = (result[i][j], expected[i][j]);
+                assert!((value - expected).abs() <= 1e-5, "{metric}[{i}][{j}]: {value} != {expected}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_lcss_length() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let epsilon = 1.0;
+
+    let a: Vec<Vec<Float>> = train_data[..3].iter().map(|ts| ts[..200].to_vec()).collect();
+    let b: Vec<Vec<Float>> = test_data[..4].iter().map(|ts| ts[..140].to_vec()).collect();
+
+    let engine = DistanceEngine::new().unwrap();
+    let length = engine.lcss_length(&a, &b, epsilon, None).unwrap();
+    let distance = engine.lcss(&a, &b, epsilon, None).unwrap();
+
+    for i in 0..a.len() {
+        for j in 0..b.len() {
+            // Subsequence lengths are whole counts.
+            assert_eq!(length[i][j], length[i][j].round());
+            assert_eq_with_tol!(length[i][j] / 140.0, 1.0 - distance[i][j], 1e-5);
+        }
+    }
+}
+
 #[test]
 fn test_dtw_distance() {
-    let train_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
-    let test_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
 
     let start_time = std::time::Instant::now();
-    let (device, queue, sba, sda, ma) = get_device();
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
 
     let result = dtw(
         device.clone(),
@@ -113,24 +321,28 @@ fn test_dtw_distance() {
         sba.clone(),
         sda.clone(),
         ma.clone(),
+        &RunOptions::default(),
         &train_data,
         &test_data,
-    );
+        None,
+    )
+    .unwrap();
     let elapsed_time = start_time.elapsed();
     println!("DTW elapsed time: {:?}", elapsed_time);
+    // Accumulated squared costs, without the square root.
     write_csv("dtw_result.csv", &result).unwrap();
 }
 
 #[test]
 fn test_wdtw_distance() {
-    let train_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
-    let test_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
 
     let g = 0.05;
     let weights = dtw_weights(train_data[0].len(), g);
 
     let start = std::time::Instant::now();
-    let (device, queue, sba, sda, ma) = get_device();
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
 
     let result = wdtw(
         device.clone(),
@@ -138,24 +350,65 @@ fn test_wdtw_distance() {
         sba.clone(),
         sda.clone(),
         ma.clone(),
+        &RunOptions::default(),
         &train_data,
         &test_data,
         &weights,
-    );
+    )
+    .unwrap();
     let elapsed_time = start.elapsed();
     println!("WDTW elapsed time: {:?}", elapsed_time);
+    // Accumulated squared costs, without the square root.
     write_csv("wdtw_result.csv", &result).unwrap();
 }
 
+#[test]
+fn test_wdtw_logistic() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..5].to_vec();
+    let b = test_data[..5].to_vec();
+    let g = 0.05;
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = wdtw_logistic(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        g,
+    )
+    .unwrap();
+    let expected = wdtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        &dtw_weights(a[0].len(), g),
+    )
+    .unwrap();
+
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+}
+
 #[test]
 fn test_adtw_distance() {
-    let train_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
-    let test_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
     
     let w = 0.1;
 
     let start_time = std::time::Instant::now();
-    let (device, queue, sba, sda, ma) = get_device();
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
 
     let result = adtw(
         device.clone(),
@@ -163,22 +416,154 @@ fn test_adtw_distance() {
         sba.clone(),
         sda.clone(),
         ma.clone(),
+        &RunOptions::default(),
         &train_data,
         &test_data,
         w,
-    );
+    )
+    .unwrap();
     let elapsed_time = start_time.elapsed();
     println!("ADTW elapsed time: {:?}", elapsed_time);
+    // Accumulated squared costs, without the square root.
     write_csv("adtw_result.csv", &result).unwrap();
 }
 
+#[test]
+fn test_sqrt_output() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].iter().map(|ts| znormalize(&ts[..200])).collect::<Vec<_>>();
+    let b = test_data[..4].iter().map(|ts| znormalize(&ts[..200])).collect::<Vec<_>>();
+
+    let engine = DistanceEngine::new().unwrap();
+    let rooted = engine.clone().with_sqrt_output(true);
+    let checks = [
+        (engine.dtw(&a, &b, None).unwrap(), rooted.dtw(&a, &b, None).unwrap()),
+        (engine.adtw(&a, &b, 0.1).unwrap(), rooted.adtw(&a, &b, 0.1).unwrap()),
+    ];
+    for (squared, rooted) in checks {
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                assert_eq_with_tol!(rooted[i][j], squared[i][j].sqrt(), 1e-4 * squared[i][j].sqrt().max(1.0));
+            }
+        }
+    }
+    // Only the kernels summing squared differences are affected.
+    assert_eq!(engine.msm(&a, &b).unwrap(), rooted.msm(&a, &b).unwrap());
+
+    let (squared, _) = engine.dtw_with_path(&a[0], &b[0], None).unwrap();
+    let (distance, _) = rooted.dtw_with_path(&a[0], &b[0], None).unwrap();
+    assert_eq_with_tol!(distance, squared.sqrt(), 1e-4 * squared.sqrt().max(1.0));
+}
+
+#[test]
+fn test_custom_kernel() {
+    // The crate's own ADTW entry point, renamed as if it had been compiled separately.
+    const SHADER: &[u8] = include_bytes!(env!("tsdistances_gpu.spv"));
+    const BUILTIN: &str = "kernels::adtw_distance::batch_call";
+    const ENTRY: &str = "test_custom_kernel::adtw";
+
+    #[derive(BufferContents, Clone, Copy)]
+    #[repr(C)]
+    struct AdtwParams {
+        w: Float,
+        _padding: u64,
+    }
+
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..3].iter().map(|ts| znormalize(&ts[..200])).collect::<Vec<_>>();
+    let b = test_data[..3].iter().map(|ts| znormalize(&ts[..200])).collect::<Vec<_>>();
+
+    let mut module = rspirv::dr::load_bytes(SHADER).unwrap();
+    for inst in module.entry_points.iter_mut() {
+        if inst.operands[2].unwrap_literal_string() == BUILTIN {
+            inst.operands[2] = rspirv::dr::Operand::LiteralString(ENTRY.to_string());
+        }
+    }
+    let renamed = module.assemble();
+    let spirv: &[u8] = bytemuck::cast_slice(&renamed);
+
+    assert!(matches!(
+        register_custom_kernel(spirv, "kernels::no_such_kernel::batch_call"),
+        Err(TsDistanceError::Shader(_))
+    ));
+    // The kernels of the crate can't be replaced.
+    assert!(matches!(
+        register_custom_kernel(SHADER, BUILTIN),
+        Err(TsDistanceError::Shader(_))
+    ));
+    register_custom_kernel(spirv, ENTRY).unwrap();
+
+    let engine = DistanceEngine::new().unwrap();
+    let kernel = CustomKernel {
+        entry: ENTRY,
+        params: AdtwParams { w: 0.1, _padding: 0 },
+        vector: Vec::new(),
+    };
+    let result = engine.context(&a, &b).unwrap().distance(kernel, Float::INFINITY).unwrap();
+    assert_eq!(result, engine.adtw(&a, &b, 0.1).unwrap());
+}
+
+#[test]
+fn test_adtw_scaled() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].iter().map(|ts| znormalize(&ts[..300])).collect::<Vec<_>>();
+    let b = test_data[..4].iter().map(|ts| znormalize(&ts[..300])).collect::<Vec<_>>();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let result = adtw_scaled(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b, 0.1).unwrap();
+    let expected = reference::adtw_scaled(&a, &b, 0.1).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+    // A pair of identical series never warps, whatever the penalty.
+    assert_eq!(reference::adtw_scaled(&a, &a, 0.1).unwrap()[0][0], 0.0);
+}
+
+#[test]
+fn test_gak() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].to_vec();
+    let b = test_data[..4].to_vec();
+    let sigma = 2.0;
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    for triangular in [0, 50] {
+        let result = gak(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &a,
+            &b,
+            sigma,
+            triangular,
+        )
+        .unwrap();
+
+        // Log-space values stay finite where the kernel itself would underflow.
+        let expected = reference::gak(&a, &b, sigma, triangular).unwrap();
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                let expected = expected[i][j];
+                assert!(expected.is_finite());
+                assert_eq_with_tol!(result[i][j], expected, 1e-3 * expected.abs().max(1.0));
+            }
+        }
+    }
+}
+
 #[test]
 fn test_msm_distance() {
-    let train_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
-    let test_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
 
     let start_time = std::time::Instant::now();
-    let (device, queue, sba, sda, ma) = get_device();
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
 
     let result = msm(
         device.clone(),
@@ -186,24 +571,182 @@ fn test_msm_distance() {
         sba.clone(),
         sda.clone(),
         ma.clone(),
+        &RunOptions::default(),
         &train_data,
         &test_data,
-    );
+    )
+    .unwrap();
     let elapsed_time = start_time.elapsed();
     println!("MSM elapsed time: {:?}", elapsed_time);
     write_csv("msm_result.csv", &result).unwrap();
 }
 
+#[test]
+fn test_msm_cost() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].to_vec();
+    let b = test_data[..4].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    // Worked out by hand from the MSM recurrence. [1, 2, 3] against [1, 1, 2] matches 1, 2
+    // and 3 with 1, 1 and 2 for 2 whatever c, every other pair needs splits or merges, whose
+    // cost grows with c.
+    let small_a = vec![vec![1.0, 2.0, 3.0], vec![0.0, 3.0, 1.0, 4.0]];
+    let small_b = vec![vec![2.0, 4.0, 1.0, 3.0], vec![1.0, 1.0, 2.0]];
+    for (c, expected) in [(0.5, [[4.5, 2.0], [4.0, 5.5]]), (2.0, [[6.0, 2.0], [4.0, 7.0]])] {
+        let result = msm_c(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &small_a,
+            &small_b,
+            c,
+        )
+        .unwrap();
+        let expected = expected.map(|row| row.to_vec()).to_vec();
+        assert_matrix_eq_with_tol!(result, expected, 1e-5);
+    }
+
+    let result = msm_c(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        0.5,
+    )
+    .unwrap();
+    let expected = reference::msm_c(&a, &b, 0.5).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+}
+
+#[test]
+fn test_msm_gen() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].to_vec();
+    let b = test_data[..4].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let standard = msm(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+    )
+    .unwrap();
+    let symmetric = msm_gen(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        MSM_C,
+        MSM_C,
+    )
+    .unwrap();
+    assert_eq!(symmetric, standard);
+
+    let result = msm_gen(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        0.5,
+        2.0,
+    )
+    .unwrap();
+    let expected = reference::msm_gen(&a, &b, 0.5, 2.0).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+}
+
+#[test]
+fn test_wmsm() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].iter().map(|ts| ts[..300].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..4].iter().map(|ts| ts[..300].to_vec()).collect::<Vec<_>>();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    // Unit weights leave MSM as it is.
+    let unweighted = wmsm(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        MSM_C,
+        &vec![1.0; 300],
+    )
+    .unwrap();
+    let standard = msm(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+    )
+    .unwrap();
+    assert_eq!(unweighted, standard);
+
+    let weights = logistic_weights(300, 0.05);
+    let result = wmsm(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        MSM_C,
+        &weights,
+    )
+    .unwrap();
+    let expected = reference::wmsm(&a, &b, MSM_C, &weights).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+
+    let too_few = wmsm(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b, MSM_C, &weights[..10]);
+    assert!(matches!(too_few, Err(TsDistanceError::InvalidInput(_))));
+}
+
 #[test]
 fn test_twe_distance() {
-    let train_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
-    let test_data: Vec<Vec<f32>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
 
-    let stiffness = 0.001;
-    let penalty = 1.0;
+    let nu = 0.001;
+    let lambda = 1.0;
 
     let start_time = std::time::Instant::now();
-    let (device, queue, sba, sda, ma) = get_device();
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
 
     let result = twe(
         device.clone(),
@@ -211,12 +754,2875 @@ fn test_twe_distance() {
         sba.clone(),
         sda.clone(),
         ma.clone(),
+        &RunOptions::default(),
         &train_data,
         &test_data,
-        stiffness,
-        penalty,
-    );
+        nu,
+        lambda,
+    )
+    .unwrap();
     let elapsed_time = start_time.elapsed();
     println!("TWE elapsed time: {:?}", elapsed_time);
     write_csv("twe_result.csv", &result).unwrap();
 }
+
+#[test]
+fn test_twe_marteau() {
+    // Values of the recurrence in Marteau (2009), computed by hand with a 0 prepended to both
+    // series at time 0.
+    let a = vec![vec![0.0, 1.0, 2.0], vec![1.0, 2.0, 3.0, 4.0]];
+    let b = vec![vec![1.0, 3.0], vec![2.0, 4.0]];
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let result = twe(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, 0.5, 1.0).unwrap();
+    assert_eq_with_tol!(result[0][0], 5.5, 1e-5);
+
+    let result = twe(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b, 0.25, 0.5).unwrap();
+    assert_eq_with_tol!(result[1][1], 6.0, 1e-5);
+}
+
+#[test]
+fn test_dtw_variable_length() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    let a: Vec<Vec<Float>> = [100, 150, 170]
+        .iter()
+        .zip(train_data.iter())
+        .map(|(&len, ts)| ts[..len].to_vec())
+        .collect();
+    let b: Vec<Vec<Float>> = [120, 170, 100, 90, 160]
+        .iter()
+        .zip(test_data.iter())
+        .map(|(&len, ts)| ts[..len].to_vec())
+        .collect();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let batched = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        None,
+    )
+    .unwrap();
+
+    for (i, a_ts) in a.iter().enumerate() {
+        for (j, b_ts) in b.iter().enumerate() {
+            let single = dtw(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                sda.clone(),
+                ma.clone(),
+                &RunOptions::default(),
+                &vec![a_ts.clone()],
+                &vec![b_ts.clone()],
+                None,
+            )
+            .unwrap();
+            assert_eq_with_tol!(batched[i][j], single[0][0], 1e-3 * single[0][0].max(1.0));
+        }
+    }
+}
+
+#[test]
+fn test_dtw_sakoe_chiba_window() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].to_vec();
+    let b = test_data[..4].to_vec();
+    let full = a[0].len();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    for window in [0, 5, full] {
+        let result = dtw(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &a,
+            &b,
+            Some(window),
+        )
+        .unwrap();
+        let expected = reference::dtw(&a, &b, Some(window)).unwrap();
+
+        assert_matrix_eq_with_tol!(result, expected, 1e-3);
+    }
+}
+
+#[test]
+fn test_query_dtw() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..3].to_vec();
+    let b = test_data[..10].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let ctx = GpuContext::new(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b).unwrap();
+
+    let query = &train_data[20];
+    let result = ctx.query_dtw(query, Some(50)).unwrap();
+    let expected = reference::dtw(&vec![query.clone()], &b, Some(50)).unwrap();
+    assert_eq!(result.len(), b.len());
+    for j in 0..b.len() {
+        assert_eq_with_tol!(result[j], expected[0][j], 1e-3 * expected[0][j].max(1.0));
+    }
+}
+
+#[test]
+fn test_dtw_banded() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..6].to_vec();
+    let b = test_data[..6].to_vec();
+    let full = a[0].len();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    // A band covering the whole matrix skips nothing.
+    let unbanded = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, None).unwrap();
+    let banded = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, Some(full)).unwrap();
+    assert_eq!(banded, unbanded);
+
+    // Narrow bands over series of different lengths, some further apart than the band.
+    let a: Vec<Vec<Float>> = [300, 260, 500]
+        .iter()
+        .zip(train_data.iter())
+        .map(|(&len, ts)| ts[..len].to_vec())
+        .collect();
+    let b: Vec<Vec<Float>> = [290, 500, 120, 310]
+        .iter()
+        .zip(test_data.iter())
+        .map(|(&len, ts)| ts[..len].to_vec())
+        .collect();
+    for window in [1, 20, 45] {
+        let result = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, Some(window)).unwrap();
+        let expected = reference::dtw(&a, &b, Some(window)).unwrap();
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                let expected = expected[i][j];
+                if expected.is_infinite() {
+                    assert!(result[i][j].is_infinite());
+                } else {
+                    assert_eq_with_tol!(result[i][j], expected, 1e-3 * expected.max(1.0));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dtw_step() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    // Different lengths, whose ratio stays within the slopes allowed by P1.
+    let a = train_data[..4].iter().map(|ts| ts[..300].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..3].iter().map(|ts| ts[..240].to_vec()).collect::<Vec<_>>();
+
+    let engine = DistanceEngine::new().unwrap();
+    assert_eq!(
+        engine.dtw_step(&a, &b, None, StepPattern::SymmetricP0).unwrap(),
+        engine.dtw(&a, &b, None).unwrap()
+    );
+
+    for window in [None, Some(80)] {
+        let result = engine.dtw_step(&a, &b, window, StepPattern::SymmetricP1).unwrap();
+        let expected = reference::dtw_step(&a, &b, window, StepPattern::SymmetricP1).unwrap();
+        let unconstrained = reference::dtw(&a, &b, window).unwrap();
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                let expected = expected[i][j];
+                // Every step costs at least as much as under P0.
+                assert!(expected >= unconstrained[i][j]);
+                assert_eq_with_tol!(result[i][j], expected, 1e-3 * expected.max(1.0));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dtw_with_cost() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].iter().map(|ts| ts[..250].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..3].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+
+    let engine = DistanceEngine::new().unwrap();
+    let squared = engine.dtw_with_cost(&a, &b, None, LocalCost::SquaredEuclidean).unwrap();
+    let expected = engine.dtw(&a, &b, None).unwrap();
+    assert_matrix_eq_with_tol!(squared, expected, 1e-4);
+
+    for (cost, window) in [(LocalCost::Absolute, None), (LocalCost::Power(1.5), Some(40)), (LocalCost::Power(2.0), None)] {
+        let result = engine.dtw_with_cost(&a, &b, window, cost).unwrap();
+        let expected = reference::dtw_with_cost(&a, &b, window, cost).unwrap();
+        assert_matrix_eq_with_tol!(result, expected, 1e-3);
+    }
+
+    assert!(matches!(
+        engine.dtw_with_cost(&a, &b, None, LocalCost::Power(-1.0)),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+
+    // Only the squared costs are square rooted.
+    let rooted = engine.clone().with_sqrt_output(true);
+    assert_matrix_eq_with_tol!(rooted.dtw_with_cost(&a, &b, None, LocalCost::SquaredEuclidean).unwrap(), rooted.dtw(&a, &b, None).unwrap(), 1e-4);
+    assert_eq!(
+        rooted.dtw_with_cost(&a, &b, None, LocalCost::Absolute).unwrap(),
+        engine.dtw_with_cost(&a, &b, None, LocalCost::Absolute).unwrap()
+    );
+}
+
+#[test]
+fn test_power_of_two_lengths() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    // Lengths on either side of a power of two, where a rolling diagonal that is not a power
+    // of two long would alias the offsets it masks. P1 doubles the diagonal.
+    let engine = DistanceEngine::new().unwrap();
+    for len in [31, 32, 33, 63, 64, 65, 127, 128, 129, 255, 256, 257] {
+        let a = train_data[..3].iter().map(|ts| ts[..len].to_vec()).collect::<Vec<_>>();
+        let b = vec![test_data[0][..len].to_vec(), test_data[1][..len - 1].to_vec(), test_data[2][..len / 2 + 1].to_vec()];
+
+        let cases = [
+            ("dtw", engine.dtw(&a, &b, None).unwrap(), reference::dtw(&a, &b, None).unwrap()),
+            (
+                "dtw p1",
+                engine.dtw_step(&a, &b, None, StepPattern::SymmetricP1).unwrap(),
+                reference::dtw_step(&a, &b, None, StepPattern::SymmetricP1).unwrap(),
+            ),
+        ];
+        for (metric, result, expected) in cases {
+            for i in 0..a.len() {
+                for j in 0..b.len() {
+                    let expected = expected[i][j];
+                    if expected.is_infinite() {
+                        assert!(result[i][j].is_infinite(), "{metric} {len}");
+                    } else {
+                        assert!((result[i][j] - expected).abs() <= 1e-3 * expected.max(1.0), "{metric} {len}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dtw_itakura() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].to_vec();
+    // Shorter series, so that the parallelogram is not symmetric around the main diagonal.
+    let b = test_data[..4]
+        .iter()
+        .map(|ts| ts[..ts.len() * 4 / 5].to_vec())
+        .collect::<Vec<_>>();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let unconstrained = reference::dtw(&a, &b, None).unwrap();
+    for max_slope in [1.5, 2.0] {
+        let result = dtw_itakura(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &a,
+            &b,
+            max_slope,
+        )
+        .unwrap();
+        let expected = reference::dtw_itakura(&a, &b, max_slope).unwrap();
+
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                let expected = expected[i][j];
+                assert!(expected >= unconstrained[i][j]);
+                assert_eq_with_tol!(result[i][j], expected, 1e-3 * expected.max(1.0));
+            }
+        }
+    }
+
+    let result = dtw_itakura(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        0.5,
+    );
+    assert!(matches!(result, Err(TsDistanceError::InvalidInput(_))));
+    // The reference rejects the same slope, so it can stand in for the device.
+    assert!(matches!(
+        reference::dtw_itakura(&a, &b, 0.5),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_rectangular_orientation() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    // `a` holds more and longer series than `b`, every result must still be `a[i]` vs `b[j]`.
+    let a: Vec<Vec<Float>> = train_data[..10].iter().map(|ts| ts[..200].to_vec()).collect();
+    let b: Vec<Vec<Float>> = test_data[..7].iter().map(|ts| ts[..150].to_vec()).collect();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let ctx = GpuContext::new(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b).unwrap();
+
+    let cases = [
+        ("dtw", ctx.dtw(None).unwrap(), reference::dtw(&a, &b, None).unwrap()),
+        // Split and merge costs differ, so a transposed computation gives other values.
+        (
+            "msm_gen",
+            ctx.msm_gen(0.1, 2.0).unwrap(),
+            reference::msm_gen(&a, &b, 0.1, 2.0).unwrap(),
+        ),
+    ];
+    for (metric, result, expected) in cases {
+        assert_eq!(result.len(), a.len(), "{metric}");
+        for i in 0..a.len() {
+            assert_eq!(result[i].len(), b.len(), "{metric}");
+            for j in 0..b.len() {
+                let expected = expected[i][j];
+                assert_eq_with_tol!(result[i][j], expected, 1e-3 * expected.max(1.0));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dtw_l1() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].to_vec();
+    let b = test_data[..4].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = dtw_with_cost(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        None,
+        LocalCost::Absolute,
+    )
+    .unwrap();
+
+    let expected = reference::dtw_with_cost(&a, &b, None, LocalCost::Absolute).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+}
+
+#[test]
+fn test_cid_dtw() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let mut a = train_data[..4].to_vec();
+    let b = test_data[..4].to_vec();
+    // A flat series has no complexity at all.
+    a.push(vec![1.0; a[0].len()]);
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = cid_dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        Some(20),
+    )
+    .unwrap();
+    let plain = reference::dtw(&a, &b, Some(20)).unwrap();
+    let expected = reference::cid_dtw(&a, &b, Some(20)).unwrap();
+
+    for i in 0..a.len() {
+        for j in 0..b.len() {
+            let expected = expected[i][j];
+            assert!(expected >= plain[i][j]);
+            assert_eq_with_tol!(result[i][j], expected, 1e-3 * expected.max(1.0));
+        }
+    }
+}
+
+#[test]
+fn test_subsequence_dtw() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let b: Vec<Vec<Float>> = train_data[..4]
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| znormalize(&ts[..300 + 40 * i]))
+        .collect();
+    // Patterns cut out of the first reference, and one from elsewhere.
+    let a = vec![
+        b[0][50..110].to_vec(),
+        b[0][200..230].to_vec(),
+        znormalize(&test_data[0][..45]),
+    ];
+
+    let engine = DistanceEngine::new().unwrap();
+    let result = engine.subsequence_dtw(&a, &b).unwrap();
+    let expected = reference::subsequence_dtw(&a, &b).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+    assert_eq!(result[0][0], 0.0);
+    assert_eq!(result[1][0], 0.0);
+    // A subsequence never costs more than the whole series.
+    let full = engine.dtw(&a, &b, None).unwrap();
+    assert!(result.iter().flatten().zip(full.iter().flatten()).all(|(s, f)| s <= f));
+}
+
+#[test]
+fn test_dtw_partial() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    // Long references of different lengths, so that the shorter ones are padded.
+    let b: Vec<Vec<Float>> = train_data[..3]
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| znormalize(&ts[..1000 - 150 * i]))
+        .collect();
+    // A query matching the very beginning of the first reference, and one from elsewhere.
+    let a = vec![b[0][..60].to_vec(), znormalize(&test_data[0][..80])];
+
+    let engine = DistanceEngine::new().unwrap();
+    let result = engine.dtw_partial(&a, &b, None).unwrap();
+    let expected = reference::dtw_partial(&a, &b, None).unwrap();
+    for i in 0..a.len() {
+        for j in 0..b.len() {
+            let ((value, end), (expected_value, expected_end)) = (result[i][j], expected[i][j]);
+            assert_eq_with_tol!(value, expected_value, 1e-3 * expected_value.max(1.0));
+            assert_eq!(end, expected_end);
+        }
+    }
+    assert_eq!(result[0][0], (0.0, 59));
+    // The best prefix never costs more than the whole reference.
+    let full = engine.dtw(&a, &b, None).unwrap();
+    for i in 0..a.len() {
+        for j in 0..b.len() {
+            assert!(result[i][j].0 <= full[i][j]);
+        }
+    }
+}
+
+#[test]
+fn test_pairwise() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a: Vec<Vec<Float>> = train_data[..5]
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| znormalize(&ts[..100 + 20 * i]))
+        .collect();
+    let b: Vec<Vec<Float>> = test_data[..5]
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| znormalize(&ts[..160 - 10 * i]))
+        .collect();
+
+    let engine = DistanceEngine::new().unwrap();
+    let checks = [
+        (Metric::Dtw { window: None }, reference::dtw(&a, &b, None).unwrap()),
+        (Metric::Twe { nu: 0.5, lambda: 1.0 }, reference::twe(&a, &b, 0.5, 1.0).unwrap()),
+        (Metric::Lcss { epsilon: 0.5, delta: None }, engine.lcss(&a, &b, 0.5, None).unwrap()),
+        (Metric::Lcss { epsilon: 0.5, delta: Some(10) }, engine.lcss(&a, &b, 0.5, Some(10)).unwrap()),
+    ];
+    for (metric, expected) in checks {
+        let result = engine.pairwise(&a, &b, metric.clone()).unwrap();
+        assert_eq!(result.len(), a.len());
+        for k in 0..a.len() {
+            assert_eq_with_tol!(result[k], expected[k][k], 1e-3 * expected[k][k].max(1.0));
+        }
+    }
+
+    assert!(matches!(
+        engine.pairwise(&a, &b[..3].to_vec(), Metric::Msm),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_uniform_constants() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..3].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+
+    // No kernel's constants fit in 64 bytes, so all of them go through a uniform buffer.
+    let engine = DistanceEngine::new().unwrap();
+    let uniform = engine.clone().with_max_push_constants_size(64);
+
+    assert_eq!(uniform.dtw(&a, &b, Some(20)).unwrap(), engine.dtw(&a, &b, Some(20)).unwrap());
+    assert_eq!(uniform.msm_gen(&a, &b, 0.5, 2.0).unwrap(), engine.msm_gen(&a, &b, 0.5, 2.0).unwrap());
+    let weights = logistic_weights(200, 0.05);
+    assert_eq!(uniform.wdtw(&a, &b, &weights).unwrap(), engine.wdtw(&a, &b, &weights).unwrap());
+}
+
+#[test]
+fn test_max_queues() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..16].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..11].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+
+    // Enough chunks to go around every queue the device offers.
+    let engine = DistanceEngine::new().unwrap().with_max_storage_range(64 * 1024);
+    let single = engine.clone().with_max_queues(1);
+
+    let expected = single.dtw(&a, &b, None).unwrap();
+    assert_eq!(engine.dtw(&a, &b, None).unwrap(), expected);
+    assert_eq!(engine.dtw_async(&a, &b, None).unwrap().await_result().unwrap(), expected);
+}
+
+#[test]
+fn test_upload_flat() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let subgroup = device.physical_device().properties().max_subgroup_size.unwrap() as usize;
+    let series_len = subgroup * (256 / subgroup).max(1);
+    let a = train_data[..5].iter().map(|ts| ts[..series_len].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..4].iter().map(|ts| ts[..series_len].to_vec()).collect::<Vec<_>>();
+
+    let upload = |batch: &Vec<Vec<Float>>| {
+        GpuSeries::upload_flat(device.clone(), queue.clone(), sba.clone(), ma.clone(), &RunOptions::default(), &batch.concat(), batch.len(), series_len)
+    };
+    let ctx = GpuContext::from_series(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), upload(&a).unwrap(), upload(&b).unwrap());
+    let expected = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, None).unwrap();
+    assert_eq!(ctx.dtw(None).unwrap(), expected);
+
+    let flat = a.concat();
+    let is_invalid = |result: Result<GpuSeries, TsDistanceError>| matches!(result, Err(TsDistanceError::InvalidInput(_)));
+    // Fewer values than announced, then series that are not padded to the subgroup size.
+    assert!(is_invalid(GpuSeries::upload_flat(device.clone(), queue.clone(), sba.clone(), ma.clone(), &RunOptions::default(), &flat, a.len() + 1, series_len)));
+    if subgroup > 1 {
+        assert!(is_invalid(GpuSeries::upload_flat(device.clone(), queue.clone(), sba.clone(), ma.clone(), &RunOptions::default(), &flat[..a.len() * (series_len - 1)], a.len(), series_len - 1)));
+    }
+}
+
+#[cfg(feature = "f16")]
+#[test]
+fn test_upload_f16() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..5].iter().map(|ts| ts[..300].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..4].iter().map(|ts| ts[..250].to_vec()).collect::<Vec<_>>();
+
+    // The halves go through the transfer queue when the device has one.
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    println!("dedicated transfer queue: {}", ma.has_transfer_queue());
+    let upload = |batch: &Vec<Vec<Float>>| {
+        let halves = batch.iter().map(|ts| ts.iter().map(|&x| half::f16::from_f32(x as f32)).collect()).collect();
+        GpuSeries::upload_f16(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &halves).unwrap()
+    };
+    let ctx = GpuContext::from_series(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), upload(&a), upload(&b));
+    let result = ctx.dtw(None).unwrap();
+
+    // Only the inputs are rounded, the accumulation stays in full precision.
+    let expected = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, None).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-2);
+}
+
+#[test]
+fn test_compute_matrices() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..6].iter().map(|ts| ts[..220].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..5].iter().map(|ts| ts[..180].to_vec()).collect::<Vec<_>>();
+
+    let engine = DistanceEngine::new().unwrap();
+    let metrics = [
+        Metric::Dtw { window: Some(30) },
+        Metric::Msm,
+        Metric::Lcss { epsilon: 1.0, delta: None },
+        Metric::Lcss { epsilon: 1.0, delta: Some(20) },
+        Metric::Ddtw,
+        Metric::Msm,
+    ];
+    let matrices = engine.compute_matrices(&a, &b, &metrics).unwrap();
+    assert_eq!(matrices.len(), 5);
+
+    let expected = [
+        (Metric::Dtw { window: Some(30) }, engine.dtw(&a, &b, Some(30)).unwrap()),
+        (Metric::Msm, engine.msm(&a, &b).unwrap()),
+        (Metric::Lcss { epsilon: 1.0, delta: None }, engine.lcss(&a, &b, 1.0, None).unwrap()),
+        (Metric::Lcss { epsilon: 1.0, delta: Some(20) }, engine.lcss(&a, &b, 1.0, Some(20)).unwrap()),
+        (Metric::Ddtw, engine.ddtw(&a, &b).unwrap()),
+    ];
+    for (metric, expected) in expected {
+        let matrix = &matrices[&metric];
+        assert_eq!((matrix.rows, matrix.cols), (a.len(), b.len()));
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                assert_eq_with_tol!(matrix.get(i, j), expected[i][j], 1e-3 * expected[i][j].max(1.0));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_cancellation() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..12].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..9].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+
+    let token = CancellationToken::new();
+    let engine = DistanceEngine::new().unwrap()
+        .with_max_storage_range(64 * 1024)
+        .with_max_queues(1)
+        .with_cancellation(token.clone());
+    let expected = DistanceEngine::new().unwrap().with_max_storage_range(64 * 1024).dtw(&a, &b, None).unwrap();
+    assert_eq!(engine.dtw(&a, &b, None).unwrap(), expected);
+
+    // Cancelled after the first chunk, none of the following ones is submitted.
+    let reports = std::cell::Cell::new(0);
+    let result = engine.dtw_with_progress(&a, &b, None, &|_| {
+        reports.set(reports.get() + 1);
+        token.cancel();
+    });
+    assert!(matches!(result, Err(TsDistanceError::Cancelled)));
+    assert_eq!(reports.get(), 1);
+
+    assert!(matches!(engine.dtw(&a, &b, None), Err(TsDistanceError::Cancelled)));
+}
+
+#[test]
+fn test_memory_budget() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..12].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..9].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+
+    let engine = DistanceEngine::new().unwrap();
+    let expected = engine.dtw(&a, &b, None).unwrap();
+    let unbounded = engine.last_run_memory_bytes();
+    assert!(unbounded > 64 * 1024);
+
+    // The whole batch no longer fits at once, it is split into smaller chunks.
+    let engine = DistanceEngine::new().unwrap().with_memory_budget(64 * 1024);
+    let chunks = std::cell::Cell::new(0);
+    let result = engine
+        .dtw_with_progress(&a, &b, None, &|_| chunks.set(chunks.get() + 1))
+        .unwrap();
+    assert_eq!(result, expected);
+    assert!(chunks.get() > 1);
+    assert!(engine.last_run_memory_bytes() <= 64 * 1024);
+
+    // The weights bound next to the series are planned for as well.
+    let weights = logistic_weights(200, 0.05);
+    let expected = DistanceEngine::new().unwrap().wdtw(&a, &b, &weights).unwrap();
+    assert_eq!(engine.wdtw(&a, &b, &weights).unwrap(), expected);
+    assert!(engine.last_run_memory_bytes() <= 64 * 1024);
+
+    // Not even the series fit.
+    let engine = DistanceEngine::new().unwrap().with_memory_budget(1024);
+    assert!(matches!(
+        engine.dtw(&a, &b, None),
+        Err(TsDistanceError::MemoryBudgetExceeded { budget: 1024, .. })
+    ));
+}
+
+#[test]
+fn test_dtw_iter() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..12].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..9].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+
+    // A small storage range splits the batch into several chunks.
+    let engine = DistanceEngine::new().unwrap().with_max_storage_range(64 * 1024);
+    let expected = engine.dtw(&a, &b, None).unwrap();
+
+    let first = engine.dtw_iter(&a, &b, None).unwrap().take(5);
+    let first = first.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(first.len(), 5);
+    for &(i, j, distance) in &first {
+        assert_eq_with_tol!(distance, expected[i][j], 1e-3);
+    }
+
+    let mut seen = vec![vec![false; b.len()]; a.len()];
+    for distance in engine.dtw_iter(&a, &b, None).unwrap() {
+        let (i, j, distance) = distance.unwrap();
+        assert!(!seen[i][j]);
+        seen[i][j] = true;
+        assert_eq_with_tol!(distance, expected[i][j], 1e-3);
+    }
+    assert!(seen.iter().flatten().all(|&seen| seen));
+
+    // A cancelled run yields its error once, then ends.
+    let token = CancellationToken::new();
+    let engine = engine.with_cancellation(token.clone());
+    let mut distances = engine.dtw_iter(&a, &b, None).unwrap();
+    token.cancel();
+    assert!(matches!(distances.next(), Some(Err(TsDistanceError::Cancelled))));
+    assert!(distances.next().is_none());
+}
+
+#[test]
+fn test_dtw_tiled() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = znormalize(&train_data[0]);
+    let b = znormalize(&test_data[0][..1000]);
+
+    let engine = DistanceEngine::new().unwrap();
+    let expected = engine.dtw(&vec![a.clone()], &vec![b.clone()], None).unwrap()[0][0];
+    // Bands that do not fill the last tile, and a single band spanning the whole of a.
+    for band_rows in [1, 37, 300, a.len()] {
+        let tiled = engine.dtw_tiled(&a, &b, band_rows).unwrap();
+        assert_eq_with_tol!(tiled, expected, 1e-3 * expected.max(1.0));
+    }
+    assert!(engine.dtw_tiled(&a, &b, 0).is_err());
+
+    // A long series against a short one: only a band of it and its diagonals stay resident.
+    let long = train_data[..8]
+        .iter()
+        .flat_map(|ts| znormalize(ts))
+        .collect::<Vec<_>>();
+    let short = b[..200].to_vec();
+    let expected = engine
+        .dtw(&vec![long.clone()], &vec![short.clone()], None)
+        .unwrap()[0][0];
+    let unbanded = engine.last_run_memory_bytes();
+    let tiled = engine.dtw_tiled(&long, &short, 64).unwrap();
+    assert_eq_with_tol!(tiled, expected, 1e-3 * expected.max(1.0));
+    assert!(engine.last_run_memory_bytes() < unbanded / 4);
+}
+
+#[test]
+fn test_cached_kernel_params() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..5].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let weights = dtw_weights(200, 0.1);
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let ctx = GpuContext::new(device, queue, sba, sda, ma, &RunOptions::default().with_max_queues(1), &a, &b).unwrap();
+
+    let first = ctx.wdtw(&weights).unwrap();
+    let cached = ctx.last_run_memory_bytes();
+    let second = ctx.wdtw(&weights).unwrap();
+    assert_eq!(first, second);
+    assert_eq!(ctx.last_run_memory_bytes(), cached);
+
+    // Without the cache the weights are uploaded again by the run itself.
+    let uncached = ctx.distance(WDTWImpl { weights: weights.clone() }, Float::INFINITY).unwrap();
+    assert_eq!(uncached, first);
+    let weight_bytes = (weights.len() * std::mem::size_of::<Float>()) as u64;
+    assert!(ctx.last_run_memory_bytes() >= cached + weight_bytes);
+}
+
+#[test]
+fn test_dtw_for_each() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..12].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..9].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+
+    // A small storage range splits the batch into several chunks.
+    let engine = DistanceEngine::new().unwrap().with_max_storage_range(64 * 1024);
+    let expected = engine.dtw(&a, &b, None).unwrap();
+
+    let mut seen = vec![vec![false; b.len()]; a.len()];
+    let mut nearest = vec![(usize::MAX, Float::INFINITY); a.len()];
+    engine
+        .dtw_for_each(&a, &b, None, |i, j, distance| {
+            assert!(!seen[i][j]);
+            seen[i][j] = true;
+            assert_eq!(distance, expected[i][j]);
+            if distance < nearest[i].1 {
+                nearest[i] = (j, distance);
+            }
+        })
+        .unwrap();
+    assert!(seen.iter().flatten().all(|&s| s));
+    for (i, row) in expected.iter().enumerate() {
+        assert_eq!(nearest[i].1, row.iter().copied().fold(Float::INFINITY, Float::min));
+    }
+}
+
+#[test]
+fn test_dtw_stream() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let template = znormalize(&train_data[0][..150]);
+    let query = znormalize(&test_data[0][..1500]);
+
+    let engine = DistanceEngine::new().unwrap();
+    let mut stream = engine.dtw_stream(&template).unwrap();
+    assert_eq!(stream.distance(), Float::INFINITY);
+
+    let templates = vec![template.clone()];
+    let mut pushed = 0;
+    // Single samples first, then chunks larger than a workgroup.
+    for chunk in [1, 1, 1, 7, 40, 1200, 250] {
+        stream.extend(&query[pushed..pushed + chunk]).unwrap();
+        pushed += chunk;
+        assert_eq!(stream.query_len(), pushed);
+        let expected = reference::dtw(&vec![query[..pushed].to_vec()], &templates, None).unwrap()[0][0];
+        assert_eq_with_tol!(stream.distance(), expected, 1e-3 * expected.max(1.0));
+    }
+
+    assert!(matches!(engine.dtw_stream(&[]), Err(TsDistanceError::InvalidInput(_))));
+}
+
+#[test]
+fn test_dtw_many_singles() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a: Vec<Vec<Float>> = train_data[..6]
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| ts[..100 + 20 * i].to_vec())
+        .collect();
+    let b: Vec<Vec<Float>> = test_data[..6]
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| ts[..180 - 10 * i].to_vec())
+        .collect();
+    let pairs = a
+        .iter()
+        .zip(&b)
+        .map(|(a, b)| (a.as_slice(), b.as_slice()))
+        .collect::<Vec<_>>();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = dtw_many_singles(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &pairs,
+        None,
+    )
+    .unwrap();
+
+    let expected = reference::dtw(&a, &b, None).unwrap();
+    assert_eq!(result.len(), pairs.len());
+    for k in 0..pairs.len() {
+        let expected = expected[k][k];
+        assert_eq_with_tol!(result[k], expected, 1e-3 * expected.max(1.0));
+    }
+}
+
+#[test]
+fn test_dtw_nn_search() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let window = Some(20);
+    // Unequal lengths disable the lower bound, so both the pruned and the plain search run.
+    let ragged = train_data
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| ts[..ts.len() - i % 3].to_vec())
+        .collect::<Vec<_>>();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    for candidates in [&train_data, &ragged] {
+        for query in &test_data[..3] {
+            let (index, distance) = dtw_nn_search(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                sda.clone(),
+                ma.clone(),
+                &RunOptions::default(),
+                query,
+                candidates,
+                window,
+            )
+            .unwrap()
+            .unwrap();
+
+            let all = dtw(
+                device.clone(),
+                queue.clone(),
+                sba.clone(),
+                sda.clone(),
+                ma.clone(),
+                &RunOptions::default(),
+                &vec![query.clone()],
+                candidates,
+                window,
+            )
+            .unwrap();
+            let expected = all[0].iter().copied().fold(Float::INFINITY, Float::min);
+            assert_eq_with_tol!(distance, expected, 1e-3 * expected.max(1.0));
+            assert_eq_with_tol!(all[0][index], expected, 1e-3 * expected.max(1.0));
+        }
+    }
+
+    // A window narrower than the gap between the lengths leaves every candidate out of reach.
+    let query = &test_data[0][..100];
+    let far = dtw_nn_search(device, queue, sba, sda, ma, &RunOptions::default(), query, &train_data[..4].to_vec(), Some(10)).unwrap();
+    assert_eq!(far, None);
+}
+
+#[test]
+fn test_dtw_cutoff() {
+    let a = vec![vec![0.0, 1.0, 2.0]];
+    let b = vec![vec![0.0, 1.0, 2.0], vec![1.0, 2.0, 3.0], vec![4.0, 4.0, 4.0]];
+    // Squared costs 0, 2 (a[0] and a[1] on b[1][0], a[2] on b[1][1] and b[1][2]) and 29.
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let ctx = GpuContext::new(device, queue, sba, sda, ma, &RunOptions::default().with_sqrt_output(false), &a, &b).unwrap();
+    let cut = ctx
+        .distance(DTWImpl { window: u64::MAX, itakura: 0.0, cutoff: 2.5 }, Float::INFINITY)
+        .unwrap();
+    assert_eq_with_tol!(cut[0][0], 0.0, 1e-5);
+    assert_eq_with_tol!(cut[0][1], 2.0, 1e-5);
+    assert_eq!(cut[0][2], Float::INFINITY);
+}
+
+#[test]
+fn test_dispatch_trace() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..3].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..2].iter().map(|ts| ts[..150].to_vec()).collect::<Vec<_>>();
+
+    let trace = DispatchTrace::new();
+    let engine = DistanceEngine::new().unwrap().with_dispatch_trace(trace.clone());
+    let expected = DistanceEngine::new().unwrap().dtw(&a, &b, None).unwrap();
+    assert_eq!(engine.dtw(&a, &b, None).unwrap(), expected);
+
+    // A single chunk, one dispatch per row of diamonds.
+    let tile = engine.device_info().max_subgroup_size as usize;
+    let (a_len, b_len) = (200usize.next_multiple_of(tile), 150usize.next_multiple_of(tile));
+    let rows_count = (a_len + b_len) / tile - 1;
+    let records = trace.records();
+    assert_eq!(records.len(), rows_count);
+    for (row, record) in records.iter().enumerate() {
+        assert_eq!(record.row, row as u64);
+        assert_eq!((record.a_count, record.b_count), (3, 2));
+        assert_eq!((record.a_len, record.b_len), (a_len as u64, b_len as u64));
+    }
+    assert_eq!(records[0].first_coord, -(tile as i64));
+
+    // Untraced engines record nothing.
+    trace.clear();
+    DistanceEngine::new().unwrap().dtw(&a, &b, None).unwrap();
+    assert!(trace.records().is_empty());
+}
+
+#[test]
+fn test_dtw_auto() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].iter().map(|ts| ts[..200].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..3].iter().map(|ts| ts[..150].to_vec()).collect::<Vec<_>>();
+
+    let engine = DistanceEngine::new().unwrap();
+    let expected = engine.dtw(&a, &b, Some(30)).unwrap();
+
+    let single = engine.dtw_auto(&a[1..2], &b[2..3], Some(30)).unwrap();
+    assert_eq!(single.len(), 1);
+    assert_eq!(single[0].len(), 1);
+    assert_eq_with_tol!(single[0][0], expected[1][2], 1e-3 * expected[1][2].max(1.0));
+
+    let batch = engine.dtw_auto(&a, &b, Some(30)).unwrap();
+    assert_matrix_eq_with_tol!(batch, expected, 1e-3);
+    assert_matrix_eq_with_tol!(engine.dtw_auto(&a[..1], &b, None).unwrap(), reference::dtw(&a[..1].to_vec(), &b, None).unwrap(), 1e-3);
+}
+
+#[test]
+fn test_masked_values() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let a = train_data[..3].iter().map(|ts| znormalize(&ts[..200])).collect::<Vec<_>>();
+
+    // The same series with a few outliers, which masked out leave nothing to pay for.
+    let spikes = [10, 75, 76, 150];
+    let b = a
+        .iter()
+        .map(|ts| {
+            let mut ts = ts.clone();
+            for &k in &spikes {
+                ts[k] += 50.0;
+            }
+            ts
+        })
+        .collect::<Vec<_>>();
+    let valid = (0..200).map(|k| !spikes.contains(&k)).collect::<Vec<_>>();
+    let masked = b.iter().map(|ts| apply_mask(ts, &valid).unwrap()).collect::<Vec<_>>();
+
+    let engine = DistanceEngine::new().unwrap();
+    let spiked = engine.dtw(&a, &b, None).unwrap();
+    let dtw_masked = engine.dtw_masked(&a, &masked, None).unwrap();
+    let erp_masked = engine.erp_masked(&a, &masked, 0.0).unwrap();
+    for i in 0..a.len() {
+        assert!(spiked[i][i] > 1.0);
+        assert_eq_with_tol!(dtw_masked[i][i], 0.0, 1e-3);
+        assert_eq_with_tol!(erp_masked[i][i], 0.0, 1e-3);
+    }
+    assert_matrix_eq_with_tol!(dtw_masked, reference::dtw_masked(&a, &masked, None).unwrap(), 1e-3);
+    assert_matrix_eq_with_tol!(erp_masked, reference::erp_masked(&a, &masked, 0.0).unwrap(), 1e-3);
+
+    // Without missing values the masked kernels are the plain ones.
+    assert_matrix_eq_with_tol!(engine.dtw_masked(&a, &b, Some(20)).unwrap(), engine.dtw(&a, &b, Some(20)).unwrap(), 1e-3);
+    assert_matrix_eq_with_tol!(engine.erp_masked(&a, &b, 0.5).unwrap(), engine.erp(&a, &b, 0.5).unwrap(), 1e-3);
+
+    // Missing values are still rejected by the other policies.
+    assert!(matches!(engine.dtw(&a, &masked, None), Err(TsDistanceError::NonFiniteInput(_))));
+    assert!(apply_mask(&a[0], &valid[..10]).is_err());
+}
+
+#[test]
+fn test_dtw_full_matrix() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = &train_data[0][..200];
+    let b = &test_data[0][..150];
+
+    let engine = DistanceEngine::new().unwrap();
+    for window in [None, Some(20)] {
+        let matrix = engine.dtw_full_matrix(a, b, window).unwrap();
+        assert_eq!(matrix.len(), a.len());
+        assert!(matrix.iter().all(|row| row.len() == b.len()));
+        assert_matrix_eq_with_tol!(matrix, reference::dtw_full_matrix(a, b, window).unwrap(), 1e-3);
+    }
+
+    // The last cell is the distance itself.
+    let matrix = engine.dtw_full_matrix(a, b, None).unwrap();
+    let expected = engine.dtw(&vec![a.to_vec()], &vec![b.to_vec()], None).unwrap();
+    assert_eq_with_tol!(matrix[a.len() - 1][b.len() - 1], expected[0][0], 1e-3 * expected[0][0].max(1.0));
+}
+
+#[test]
+fn test_dtw_with_path() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = &train_data[0][..200];
+    let b = &test_data[0][..150];
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let (distance, path) = dtw_with_path(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        a,
+        b,
+        None,
+    )
+    .unwrap();
+    let expected = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &vec![a.to_vec()],
+        &vec![b.to_vec()],
+        None,
+    )
+    .unwrap();
+    assert_eq_with_tol!(distance, expected[0][0], 1e-3 * distance.max(1.0));
+
+    assert_eq!(path.first(), Some(&(0, 0)));
+    assert_eq!(path.last(), Some(&(a.len() - 1, b.len() - 1)));
+    for step in path.windows(2) {
+        let (di, dj) = (step[1].0 - step[0].0, step[1].1 - step[0].1);
+        assert!(di <= 1 && dj <= 1 && di + dj > 0);
+    }
+    let path_cost: Float = path.iter().map(|&(i, j)| (a[i] - b[j]).powi(2)).sum();
+    assert_eq_with_tol!(path_cost, distance, 1e-3 * distance.max(1.0));
+}
+
+#[test]
+fn test_dtw_normalized() {
+    // The same pair of shapes sampled at 100 and at 400 points.
+    let shape = |len: usize, offset: Float| {
+        (0..len)
+            .map(|i| (2.0 * std::f64::consts::PI as Float * i as Float / len as Float).sin() + offset)
+            .collect::<Vec<Float>>()
+    };
+    let a = vec![shape(100, 0.5), shape(400, 0.5)];
+    let b = vec![shape(100, 0.0), shape(400, 0.0)];
+
+    let engine = DistanceEngine::new().unwrap();
+    let raw = engine.dtw(&a, &b, None).unwrap();
+    let normalized = engine.dtw_normalized(&a, &b, None).unwrap();
+
+    let expected = reference::dtw_normalized(&a, &b, None).unwrap();
+    for (row, expected_row) in normalized.iter().zip(&expected) {
+        for (&value, &expected) in row.iter().zip(expected_row) {
+            assert_eq_with_tol!(value, expected, 1e-4);
+        }
+    }
+
+    // The long pair accumulates about four times the cost of the short one, but their
+    // normalized distances are close.
+    assert!(raw[1][1] > 3.0 * raw[0][0]);
+    assert!((normalized[1][1] - normalized[0][0]).abs() < 0.25 * normalized[0][0]);
+
+    for (a, b) in a.iter().zip(&b) {
+        let exact = engine.dtw_normalized_exact(a, b, None).unwrap();
+        let (distance, path) = engine.dtw_with_path(a, b, None).unwrap();
+        assert_eq_with_tol!(exact, distance / path.len() as Float, 1e-6);
+        // The path is never longer than the sum of the lengths.
+        assert!(exact >= distance / (a.len() + b.len()) as Float);
+    }
+}
+
+#[test]
+fn test_ddtw_distance() {
+    let data: Vec<Vec<Float>> = read_txt("tests/data/ts.csv").unwrap();
+    let (a, b) = data.split_at(3);
+    let (a, b) = (a.to_vec(), b.to_vec());
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = ddtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+    )
+    .unwrap();
+
+    // Computed in double precision by an implementation independent of this crate, over
+    // Keogh and Pazzani's derivative estimate.
+    let expected = vec![
+        vec![1.384525, 11.011525, 8.411488],
+        vec![6.404712, 4.643175, 6.139294],
+        vec![18.337563, 14.486687, 8.819606],
+    ];
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+}
+
+#[test]
+fn test_euclidean_distance() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = euclidean(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+    )
+    .unwrap();
+
+    for i in 0..train_data.len() {
+        for j in 0..test_data.len() {
+            let expected = train_data[i]
+                .iter()
+                .zip(test_data[j].iter())
+                .map(|(x, y)| (x - y).powi(2))
+                .sum::<Float>()
+                .sqrt();
+            assert_eq_with_tol!(result[i][j], expected, 1e-3 * expected.max(1.0));
+        }
+    }
+
+    let ragged = vec![train_data[0].clone(), train_data[1][..100].to_vec()];
+    assert!(
+        euclidean(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &ragged,
+            &test_data,
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_bitwise_reproducible() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..10].to_vec();
+    let b = test_data[..10].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let run = || {
+        let euclidean = euclidean(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b).unwrap();
+        let dtw = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, None).unwrap();
+        (euclidean, dtw)
+    };
+
+    let first = run();
+    let second = run();
+    for (x, y) in first.0.iter().flatten().zip(second.0.iter().flatten()) {
+        assert_eq!(x.to_bits(), y.to_bits(), "euclidean");
+    }
+    for (x, y) in first.1.iter().flatten().zip(second.1.iter().flatten()) {
+        assert_eq!(x.to_bits(), y.to_bits(), "dtw");
+    }
+}
+
+#[test]
+fn test_lb_keogh() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let query = &test_data[0];
+    let candidates = train_data[..8].to_vec();
+    let window = 10;
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let bounds = lb_keogh(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        query,
+        &candidates,
+        window,
+    )
+    .unwrap();
+    let distances = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &vec![query.clone()],
+        &candidates,
+        Some(window),
+    )
+    .unwrap();
+
+    assert_eq!(bounds.len(), candidates.len());
+    for (bound, distance) in bounds.iter().zip(distances[0].iter()) {
+        assert!(*bound <= distance * (1.0 + 1e-4) + 1e-4);
+    }
+}
+
+#[test]
+fn test_compute_envelopes() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let data: Vec<Vec<Float>> = train_data[..6]
+        .iter()
+        .enumerate()
+        .map(|(i, ts)| ts[..100 + 30 * i].to_vec())
+        .collect();
+
+    let engine = DistanceEngine::new().unwrap();
+    for window in [0, 5, 40, 1000] {
+        let (lower, upper) = engine.compute_envelopes(&data, window).unwrap();
+        for (i, ts) in data.iter().enumerate() {
+            let (expected_lower, expected_upper) = envelopes(ts, window);
+            assert_eq!(lower[i], expected_lower);
+            assert_eq!(upper[i], expected_upper);
+        }
+    }
+}
+
+#[test]
+fn test_sbd() {
+    let mut a = generate_random_batch(4, 120, 11);
+    let mut b = generate_random_batch(4, 120, 12);
+    // Out of phase sines only differ by a shift, which SBD mostly forgives.
+    a.push((0..120).map(|t| (t as Float / 6.0).sin()).collect());
+    b.push((0..120).map(|t| ((t + 15) as Float / 6.0).sin()).collect());
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let result = sbd(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b).unwrap();
+    let expected = reference::sbd(&a, &b).unwrap();
+    for i in 0..a.len() {
+        for j in 0..b.len() {
+            assert_eq_with_tol!(result[i][j], expected[i][j], 1e-4);
+            assert!((0.0..=2.0 + 1e-4).contains(&result[i][j]));
+        }
+    }
+    assert!(result[4][4] < 0.2);
+
+    let result = sbd(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &a).unwrap();
+    for i in 0..a.len() {
+        assert_eq_with_tol!(result[i][i], 0.0, 1e-4);
+    }
+
+    let uneven = vec![vec![1.0; 10], vec![1.0; 11]];
+    assert!(matches!(
+        sbd(device, queue, sba, sda, ma, &RunOptions::default(), &uneven, &a),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_gpu_context_reuse() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let start_time = std::time::Instant::now();
+    let dtw_free = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+        None,
+    )
+    .unwrap();
+    let msm_free = msm(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+    )
+    .unwrap();
+    let free_time = start_time.elapsed();
+
+    let start_time = std::time::Instant::now();
+    let ctx = GpuContext::new(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+    )
+    .unwrap();
+    let dtw_ctx = ctx.dtw(None).unwrap();
+    let msm_ctx = ctx.msm().unwrap();
+    let ctx_time = start_time.elapsed();
+    println!(
+        "DTW + MSM elapsed time: free functions {:?}, shared context {:?}",
+        free_time, ctx_time
+    );
+
+    for i in 0..train_data.len() {
+        for j in 0..test_data.len() {
+            assert_eq_with_tol!(dtw_ctx[i][j], dtw_free[i][j], 1e-3 * dtw_free[i][j].max(1.0));
+            assert_eq_with_tol!(msm_ctx[i][j], msm_free[i][j], 1e-3 * msm_free[i][j].max(1.0));
+        }
+    }
+}
+
+#[test]
+fn test_reused_buffers() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..6].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let mut buffers = None;
+    for b in [&test_data[..5], &test_data[5..10], &test_data[10..12]] {
+        let b = b.to_vec();
+        let ctx = GpuContext::new(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &a,
+            &b,
+        )
+        .unwrap();
+        let (distances, reused) = ctx
+            .distance_reusing(DTWImpl { window: u64::MAX, itakura: 0.0, cutoff: Float::INFINITY }, Float::INFINITY, buffers)
+            .unwrap();
+        buffers = Some(reused);
+
+        assert_eq!(distances, ctx.dtw_flat(None).unwrap());
+    }
+}
+
+#[test]
+fn test_dtw_flat() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let nested = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+        None,
+    )
+    .unwrap();
+    let (flat, rows, cols) = dtw_flat(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(rows, nested.len());
+    assert_eq!(cols, nested[0].len());
+    assert_eq!(flat.len(), rows * cols);
+    for i in 0..rows {
+        for j in 0..cols {
+            assert_eq!(flat[i * cols + j], nested[i][j]);
+        }
+    }
+}
+
+#[test]
+fn test_distance_matrix_npy() {
+    let a = generate_random_batch(6, 50, 3);
+    let b = generate_random_batch(4, 40, 4);
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let nested = dtw(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b, None).unwrap();
+    let matrix = DistanceMatrix::try_from(nested.clone()).unwrap();
+    assert_eq!((matrix.rows, matrix.cols), (6, 4));
+    assert_eq!(matrix.get(2, 3), nested[2][3]);
+
+    let path = TempPath::new("matrix.npy");
+    matrix.save_npy(&path).unwrap();
+    let loaded = DistanceMatrix::load_npy(&path).unwrap();
+    assert_eq!(loaded, matrix);
+    assert_eq!(Vec::<Vec<Float>>::from(loaded), nested);
+
+    assert!(DistanceMatrix::read_npy(&b"not numpy"[..]).is_err());
+}
+
+#[test]
+fn test_dtw_gpu_resident() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = test_data[..10].to_vec();
+    let b = train_data[..20].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let expected = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, None).unwrap();
+    let (matrix, rows, cols) = dtw_gpu_resident(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &a, &b, None).unwrap();
+    assert_eq!((rows, cols), (a.len(), b.len()));
+    assert_eq!(matrix.len(), (rows * cols) as u64);
+
+    // Consumed on the device by the 1-NN selection, labelling each column by its index.
+    let labels = (0..cols as u32).collect::<Vec<_>>();
+    let nearest = knn_select_gpu(device, queue, sba, sda, ma, &RunOptions::default(), matrix, rows, cols, &labels, 1).unwrap();
+    for i in 0..rows {
+        let best = expected[i][nearest[i] as usize];
+        assert!(expected[i].iter().all(|&d| best <= d));
+    }
+}
+
+#[test]
+fn test_dtw_async() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let expected = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+        Some(10),
+    )
+    .unwrap();
+
+    // Both batches are in flight before either is awaited.
+    let first = dtw_async(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+        Some(10),
+    )
+    .unwrap();
+    let second = dtw_async(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &test_data,
+        &train_data,
+        Some(10),
+    )
+    .unwrap();
+
+    let first = first.await_result().unwrap();
+    let second = second.await_result().unwrap();
+    assert_eq!(first, expected);
+    for i in 0..train_data.len() {
+        for j in 0..test_data.len() {
+            assert_eq_with_tol!(second[j][i], expected[i][j], 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_multi_metric_batch() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let ctx = GpuContext::new(device, queue, sba, sda, ma, &RunOptions::default(), &train_data, &test_data).unwrap();
+
+    // Three kernels recorded into a single submission.
+    let mut batch = ctx.multi_metric().unwrap();
+    let dtw_index = batch.add(DTWImpl { window: 10, itakura: 0.0, cutoff: Float::INFINITY }, Float::INFINITY).unwrap();
+    let msm_index = batch.add(MSMImpl { split_cost: MSM_C, merge_cost: MSM_C, weighted: 0, weights: vec![1.0] }, Float::INFINITY).unwrap();
+    let twe_index = batch.add(TWEImpl { nu: 0.001, lambda: 1.0 }, Float::INFINITY).unwrap();
+    let results = batch.submit().unwrap();
+
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[dtw_index], ctx.dtw(Some(10)).unwrap());
+    assert_eq!(results[msm_index], ctx.msm().unwrap());
+    assert_eq!(results[twe_index], ctx.twe(0.001, 1.0).unwrap());
+
+    assert!(ctx.multi_metric().unwrap().submit().unwrap().is_empty());
+}
+
+#[test]
+fn test_timeout() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    // No device gets through a full matrix within a nanosecond.
+    let result = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default().with_timeout(Duration::from_nanos(1)),
+        &train_data,
+        &test_data,
+        None,
+    );
+    assert!(matches!(result, Err(TsDistanceError::Timeout(_))));
+
+    let result = dtw(
+        device,
+        queue,
+        sba,
+        sda,
+        ma,
+        &RunOptions::default().with_timeout(Duration::from_secs(600)),
+        &train_data[..5].to_vec(),
+        &test_data[..5].to_vec(),
+        None,
+    );
+    assert!(result.is_ok());
+
+    // The timed out submission is kept until the device is done with it.
+    assert_eq!(standalone::release_timed_out(Some(Duration::from_secs(600))), 0);
+}
+
+#[test]
+fn test_dtw_chunked() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap()[..20].to_vec();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap()[..15].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let expected = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+        None,
+    )
+    .unwrap();
+    let expected_self = dtw_self(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        None,
+    )
+    .unwrap();
+
+    // Room for the diagonals of 10 pairs, i.e. 3 x 3 blocks that don't divide either batch.
+    let diag_len = 2 * (train_data[0].len() + 1).next_power_of_two();
+    let limited = ma.clone().with_max_storage_range(10 * diag_len * std::mem::size_of::<Float>());
+
+    let result = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        limited.clone(),
+        &train_data,
+        &test_data,
+        None,
+    )
+    .unwrap();
+    assert_eq!(result, expected);
+
+    let result_self = dtw_self(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        limited.clone(),
+        &train_data,
+        None,
+    )
+    .unwrap();
+    assert_eq!(result_self, expected_self);
+
+    let pending = dtw_async(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        limited.clone(),
+        &train_data,
+        &test_data,
+        None,
+    )
+    .unwrap();
+    assert_eq!(pending.await_result().unwrap(), expected);
+
+    // Not even a single diagonal fits.
+    let result = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone().with_max_storage_range(diag_len),
+        &RunOptions::default(),
+        &train_data,
+        &test_data,
+        None,
+    );
+    assert!(matches!(result, Err(TsDistanceError::SeriesTooLong { .. })));
+}
+
+#[test]
+fn test_series_too_long() {
+    // 64 KiB holds the diagonal of a series a few thousand values long at most.
+    let engine = DistanceEngine::new().unwrap().with_max_storage_range(64 * 1024);
+    let series = |len: usize| vec![(0..len).map(|i| (i as Float).sin()).collect::<Vec<_>>()];
+
+    let long = series(100_000);
+    let max = match engine.dtw(&long, &long, None) {
+        Err(TsDistanceError::SeriesTooLong { len, max }) => {
+            assert!(len >= 100_000);
+            max
+        }
+        other => panic!("expected SeriesTooLong, got {:?}", other.map(|_| ())),
+    };
+    assert!(max > 0 && max < 100_000);
+
+    let fits = series(max);
+    assert!(engine.dtw(&fits, &fits, None).is_ok());
+    let over = series(max + 1);
+    assert!(matches!(
+        engine.dtw(&over, &over, None),
+        Err(TsDistanceError::SeriesTooLong { .. })
+    ));
+}
+
+#[test]
+fn test_tile_size() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a: Vec<Vec<Float>> = train_data[..6].iter().map(|ts| ts[..300].to_vec()).collect();
+    let b: Vec<Vec<Float>> = test_data[..5].iter().map(|ts| ts[..250].to_vec()).collect();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let subgroup_size = describe_device(&device).max_subgroup_size as usize;
+
+    let expected = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        None,
+    )
+    .unwrap();
+    for tile_size in [subgroup_size / 2, subgroup_size / 4] {
+        let result = dtw(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default().with_tile_size(tile_size.max(1)),
+            &a,
+            &b,
+            None,
+        )
+        .unwrap();
+        assert_eq!(result, expected);
+    }
+
+    let invalid = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default().with_tile_size(3),
+        &a,
+        &b,
+        None,
+    );
+    assert!(matches!(invalid, Err(TsDistanceError::InvalidInput(_))));
+}
+
+#[test]
+fn test_dtw_progress() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap()[..10].to_vec();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap()[..7].to_vec();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    // Room for 9 pairs per chunk, so that progress is reported several times.
+    let diag_len = 2 * (train_data[0].len() + 1).next_power_of_two();
+    let limited = ma.clone().with_max_storage_range(9 * diag_len * std::mem::size_of::<Float>());
+
+    let reports = std::cell::RefCell::new(Vec::new());
+    let result = dtw_with_progress(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        limited,
+        &train_data,
+        &test_data,
+        None,
+        &|fraction| reports.borrow_mut().push(fraction),
+    )
+    .unwrap();
+
+    let reports = reports.into_inner();
+    assert!(reports.len() > 1);
+    assert!(reports.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(*reports.last().unwrap(), 1.0);
+
+    let expected = dtw(device, queue, sba, sda, ma, &RunOptions::default(), &train_data, &test_data, None).unwrap();
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn test_dtw_self_distance() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let full = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        &train_data,
+        None,
+    )
+    .unwrap();
+    let packed = dtw_self(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &train_data,
+        None,
+    )
+    .unwrap();
+
+    let n = train_data.len();
+    assert_eq!(packed.len(), n * (n - 1) / 2);
+    let mut k = 0;
+    for i in 0..n {
+        for j in i + 1..n {
+            assert_eq_with_tol!(packed[k], full[i][j], 1e-3 * full[i][j].max(1.0));
+            k += 1;
+        }
+    }
+}
+
+#[test]
+fn test_self_distance_single_upload() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let copy = train_data.clone();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    // The same batch on both sides is uploaded once, a copy goes through two uploads.
+    let shared = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &train_data, &train_data, Some(20)).unwrap();
+    let separate = dtw(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &train_data, &copy, Some(20)).unwrap();
+    assert_eq!(shared, separate);
+
+    let shared = msm(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &train_data, &train_data).unwrap();
+    let separate = msm(device.clone(), queue.clone(), sba.clone(), sda.clone(), ma.clone(), &RunOptions::default(), &train_data, &copy).unwrap();
+    assert_eq!(shared, separate);
+}
+
+#[test]
+fn test_dtw_multivariate() {
+    let dim = 3;
+    let synthetic = |count: usize, len: usize, phase: Float| -> Vec<Vec<Float>> {
+        (0..count)
+            .map(|s| {
+                (0..len)
+                    .flat_map(|t| {
+                        let x = t as Float * 0.1 + s as Float * phase;
+                        [x.sin(), x.cos(), (0.5 * x).sin() * 2.0]
+                    })
+                    .collect()
+            })
+            .collect()
+    };
+    let a = synthetic(10, 150, 0.3);
+    let b = synthetic(12, 130, 0.7);
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = dtw_multivariate(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        dim,
+        None,
+    )
+    .unwrap();
+
+    let expected = reference::dtw_multivariate(&a, &b, dim, None).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+}
+
+#[test]
+fn test_dtw_cosine() {
+    let dim = 3;
+    let synthetic = |count: usize, len: usize, phase: Float| -> Vec<Vec<Float>> {
+        (0..count)
+            .map(|s| {
+                (0..len)
+                    .flat_map(|t| {
+                        let x = t as Float * 0.1 + s as Float * phase;
+                        [x.sin(), x.cos(), (0.5 * x).sin() * 2.0]
+                    })
+                    .collect()
+            })
+            .collect()
+    };
+    let mut a = synthetic(8, 150, 0.3);
+    let mut b = synthetic(9, 130, 0.7);
+    // Zero vectors, against each other and against any other timestep.
+    a[0][..2 * dim].fill(0.0);
+    a[1][30 * dim..31 * dim].fill(0.0);
+    b[0][..dim].fill(0.0);
+
+    let engine = DistanceEngine::new().unwrap();
+    let result = engine.dtw_cosine(&a, &b, dim, None).unwrap();
+    let expected = reference::dtw_cosine(&a, &b, dim, None).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+    assert!(result.iter().flatten().all(|d| d.is_finite() && *d >= -1e-3));
+
+    let windowed = engine.dtw_cosine(&a, &b, dim, Some(25)).unwrap();
+    assert_matrix_eq_with_tol!(windowed, reference::dtw_cosine(&a, &b, dim, Some(25)).unwrap(), 1e-3);
+
+    // Only the direction of every timestep counts.
+    let scaled = b.iter().map(|ts| ts.iter().map(|x| 3.0 * x).collect()).collect::<Vec<_>>();
+    assert_matrix_eq_with_tol!(engine.dtw_cosine(&a, &scaled, dim, None).unwrap(), result, 1e-3);
+
+    // A zero vector matches another zero vector exactly and any other one at distance 1.
+    let zero = vec![vec![0.0; dim]];
+    let unit = vec![vec![1.0, 0.0, 0.0]];
+    let matched = engine.dtw_cosine(&zero, &zero, dim, None).unwrap();
+    assert_eq!(matched[0][0], 0.0);
+    let apart = engine.dtw_cosine(&zero, &unit, dim, None).unwrap();
+    assert_eq_with_tol!(apart[0][0], 1.0, 1e-6);
+}
+
+#[test]
+fn test_erp_multivariate() {
+    let dim = 3;
+    let synthetic = |count: usize, len: usize, phase: Float| -> Vec<Vec<Float>> {
+        (0..count)
+            .map(|s| {
+                (0..len)
+                    .flat_map(|t| {
+                        let x = t as Float * 0.1 + s as Float * phase;
+                        [x.sin(), x.cos() + 1.0, (0.5 * x).sin() * 2.0]
+                    })
+                    .collect()
+            })
+            .collect()
+    };
+    let a = synthetic(8, 140, 0.3);
+    let b = synthetic(6, 120, 0.7);
+    let gap = [0.0, 1.0, -0.5];
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let result = erp_multivariate(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        dim,
+        &gap,
+    )
+    .unwrap();
+
+    let expected = reference::erp_multivariate(&a, &b, dim, &gap).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+}
+
+#[test]
+fn test_knn_dtw() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let train_labels = read_labels("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let distances = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &test_data,
+        &train_data,
+        None,
+    )
+    .unwrap();
+
+    for k in [1, 3] {
+        let predictions = knn(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &train_data,
+            &train_labels,
+            &test_data,
+            k,
+            Metric::Dtw { window: None },
+        )
+        .unwrap();
+
+        assert_eq!(predictions.len(), test_data.len());
+        for (i, row) in distances.iter().enumerate() {
+            let mut neighbours = (0..row.len()).collect::<Vec<_>>();
+            neighbours.sort_by(|&x, &y| row[x].total_cmp(&row[y]));
+            let nearest = neighbours[..k].iter().map(|&n| train_labels[n]).collect::<Vec<_>>();
+            let votes = |label: usize| nearest.iter().filter(|&&l| l == label).count();
+            // Most voted label, ties going to the nearest neighbour.
+            let mut expected = nearest[0];
+            for &label in &nearest {
+                if votes(label) > votes(expected) {
+                    expected = label;
+                }
+            }
+            assert_eq!(predictions[i], expected, "test series {} with k = {}", i, k);
+        }
+    }
+}
+
+#[test]
+fn test_synthetic_data() {
+    let batch = generate_random_batch(5, 200, 7);
+    assert_eq!(batch, generate_random_batch(5, 200, 7));
+    assert_ne!(batch, generate_random_batch(5, 200, 8));
+    assert!(batch.iter().all(|ts| ts.len() == 200));
+
+    let (series, labels) = generate_cbf(30, 7);
+    assert_eq!(labels.len(), 30);
+    assert!(series.iter().all(|ts| ts.len() == CBF_LEN));
+    assert!(series.iter().flatten().all(|x| x.is_finite()));
+
+    // Self-contained data checked against the host reference.
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let result = dtw(device, queue, sba, sda, ma, &RunOptions::default(), &batch, &series, None).unwrap();
+    let expected = reference::dtw(&batch, &series, None).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+}
+
+#[test]
+fn test_read_ts_file() {
+    let path = TempPath::new("univariate.ts");
+    std::fs::write(
+        &path,
+        "# comment\n@problemName Test\n@univariate true\n@classLabel true a b\n@data\n1.0,2.0,3.0:a\n4.0,?,6.5:b\n",
+    )
+    .unwrap();
+    let (series, labels) = read_ts_file(&path).unwrap();
+    assert_eq!(labels, vec!["a", "b"]);
+    assert_eq!(series[0], vec![1.0, 2.0, 3.0]);
+    assert!(series[1][1].is_nan());
+    assert_eq!(series[1][2], 6.5);
+
+    // Channels are interleaved per timestep.
+    std::fs::write(
+        &path,
+        "@problemName Test\n@univariate false\n@dimensions 2\n@classLabel false\n@data\n1,2,3:4,5,6\n7,8:9,10\n",
+    )
+    .unwrap();
+    let (series, labels, dim) = read_ts_file_channels(&path).unwrap();
+    assert!(labels.is_empty());
+    assert_eq!(dim, 2);
+    assert_eq!(series, vec![vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0], vec![7.0, 9.0, 8.0, 10.0]]);
+
+    std::fs::write(&path, "@classLabel true a\n@data\n1,2:3,4:a\n1,2:a\n").unwrap();
+    assert!(read_ts_file(&path).is_err());
+}
+
+#[test]
+fn test_compare_matrices() {
+    let expected = vec![vec![1.0, 200.0, 0.5], vec![Float::INFINITY, 3.0, Float::NAN]];
+    assert!(compare_matrices(&expected, &expected, 0.0).is_ok());
+
+    // Relative to large values, absolute below 1.
+    let mut got = expected.clone();
+    got[0][1] = 200.1;
+    got[0][2] = 0.5005;
+    assert!(compare_matrices(&got, &expected, 1e-3).is_ok());
+
+    got[0][0] = 1.5;
+    got[1][1] = 3.03;
+    got[1][2] = 4.0;
+    let report = compare_matrices(&got, &expected, 1e-3).unwrap_err();
+    assert_eq!(report.mismatch_count, 3);
+    // NaN against a value is the worst, then [0][0], then [1][1].
+    let worst = report.worst.iter().map(|m| (m.i, m.j)).collect::<Vec<_>>();
+    assert_eq!(worst, vec![(1, 2), (0, 0), (1, 1)]);
+    assert_eq_with_tol!(report.worst[1].abs_err, 0.5, 1e-6);
+    assert_eq_with_tol!(report.worst[2].rel_err, 0.01, 1e-4);
+    assert_eq!(report.max_abs_err, Float::INFINITY);
+    assert!(report.to_string().contains("[0][0]: got 1.5, expected 1.0"));
+
+    let report = compare_matrices(&got[..1], &expected, 1.0).unwrap_err();
+    assert_eq!((report.got_shape, report.expected_shape), ((1, 3), (2, 3)));
+    assert_eq!(report.mismatch_count, 0);
+}
+
+#[test]
+fn test_describe_device() {
+    let (device, ..) = get_device().unwrap();
+    let info = describe_device(&device);
+    assert_eq!(info.name, device.physical_device().properties().device_name);
+    assert!(info.max_subgroup_size > 0);
+    assert!(info.device_memory > 0);
+
+    // Longer series need longer diagonals, so fewer of them fit.
+    let short = info.recommended_max_pairs(100);
+    let long = info.recommended_max_pairs(10_000);
+    assert!(long > 0);
+    assert!(short >= long);
+}
+
+#[test]
+fn test_subgroup_sync() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..6].iter().map(|ts| ts[..300].to_vec()).collect::<Vec<_>>();
+    let b = test_data[..5].iter().map(|ts| ts[..300].to_vec()).collect::<Vec<_>>();
+
+    // Only the barriers differ, the cells are computed in the same order.
+    let subgroup = DistanceEngine::new().unwrap().with_subgroup_sync(true);
+    let workgroup = DistanceEngine::new().unwrap().with_subgroup_sync(false);
+    assert_eq!(subgroup.dtw(&a, &b, None).unwrap(), workgroup.dtw(&a, &b, None).unwrap());
+    assert_eq!(subgroup.msm(&a, &b).unwrap(), workgroup.msm(&a, &b).unwrap());
+
+    let info = subgroup.device_info();
+    let (device, ..) = get_device().unwrap();
+    assert_eq!(
+        info.subgroup_sync,
+        cfg!(feature = "subgroup-sync") && supports_subgroup_sync(&device, info.max_subgroup_size as usize)
+    );
+}
+
+#[test]
+fn test_is_hardware_accelerated() {
+    let device = |name: &str, device_type, driver_name: Option<&str>| DeviceInfo {
+        name: name.to_string(),
+        device_type,
+        driver_name: driver_name.map(str::to_string),
+        max_subgroup_size: 32,
+        max_compute_work_group_size: 1024,
+        max_storage_buffer_range: u32::MAX,
+        device_memory: 1 << 33,
+        subgroup_sync: true,
+    };
+    assert!(device("NVIDIA GeForce RTX 3090", PhysicalDeviceType::DiscreteGpu, Some("NVIDIA")).is_hardware_accelerated());
+    assert!(device("Intel(R) UHD Graphics 630", PhysicalDeviceType::IntegratedGpu, None).is_hardware_accelerated());
+    assert!(!device("llvmpipe (LLVM 15.0.7, 256 bits)", PhysicalDeviceType::Cpu, Some("llvmpipe")).is_hardware_accelerated());
+    // Some software implementations do not report themselves as a CPU.
+    assert!(!device("SwiftShader Device (Subzero)", PhysicalDeviceType::Other, None).is_hardware_accelerated());
+    assert!(!device("Emulated GPU", PhysicalDeviceType::VirtualGpu, Some("lavapipe")).is_hardware_accelerated());
+    assert!(!device("Some CPU", PhysicalDeviceType::Cpu, None).is_hardware_accelerated());
+
+    let engine = DistanceEngine::new().unwrap();
+    assert_eq!(engine.is_hardware_accelerated(), engine.device_info().is_hardware_accelerated());
+}
+
+#[test]
+fn test_get_device_with_preference() {
+    let (default_device, ..) = get_device().unwrap();
+    let name = default_device.physical_device().properties().device_name.clone();
+
+    let (device, ..) = get_device_with(DevicePreference::Name(name.clone()).unwrap()).unwrap();
+    assert_eq!(device.physical_device().properties().device_name, name);
+
+    let (device, ..) = get_device_with(DevicePreference::Type(
+        default_device.physical_device().properties().device_type,
+    ).unwrap())
+    .unwrap();
+    assert_eq!(
+        device.physical_device().properties().device_type,
+        default_device.physical_device().properties().device_type
+    );
+
+    assert!(matches!(
+        get_device_with(DevicePreference::Name("no such device".to_string()).unwrap()),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_dtw_on_two_devices() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..5].to_vec();
+    let b = test_data[..5].to_vec();
+
+    // Every call creates its own logical device, each needing its own pipelines.
+    let mut results = Vec::new();
+    for _ in 0..2 {
+        let (device, queue, sba, sda, ma) =
+            get_device_with(DevicePreference::Index(0).unwrap()).unwrap();
+        results.push(dtw(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b, None).unwrap());
+    }
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let expected = dtw(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b, None).unwrap();
+
+    for result in results {
+        assert_eq!(result, expected);
+    }
+}
+
+#[test]
+fn test_dtw_znormalized() {
+    let (mut a, _) = generate_cbf(6, 11);
+    let (b, _) = generate_cbf(5, 12);
+    // A zero-variance series is left unchanged.
+    a.push(vec![2.5; CBF_LEN]);
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let ctx = GpuContext::new(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+    )
+    .unwrap();
+    ctx.znormalize().unwrap();
+    let result = ctx.dtw(None).unwrap();
+
+    let a_norm = a.iter().map(|ts| znormalize(ts)).collect::<Vec<_>>();
+    let b_norm = b.iter().map(|ts| znormalize(ts)).collect::<Vec<_>>();
+    assert_eq!(a_norm[6], a[6]);
+    let expected = reference::dtw(&a_norm, &b_norm, None).unwrap();
+
+    assert_matrix_eq_with_tol!(result, expected, 1e-2);
+}
+
+#[test]
+fn test_nan_policy() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let mut a = train_data[..3].to_vec();
+    let b = test_data[..3].to_vec();
+    a[1][42] = Float::NAN;
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let rejected = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        None,
+    );
+    assert!(matches!(rejected, Err(TsDistanceError::NonFiniteInput(_))));
+
+    let zeroed = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default().with_nan_policy(NanPolicy::TreatAsZero),
+        &a,
+        &b,
+        None,
+    )
+    .unwrap();
+    a[1][42] = 0.0;
+    let expected = reference::dtw(&a, &b, None).unwrap();
+    assert_matrix_eq_with_tol!(zeroed, expected, 1e-3);
+
+    a[1][42] = Float::INFINITY;
+    let propagated = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default().with_nan_policy(NanPolicy::Propagate),
+        &a,
+        &b,
+        None,
+    );
+    assert!(propagated.is_ok());
+}
+
+#[test]
+fn test_reference_agreement() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..4].iter().map(|ts| znormalize(ts)).collect::<Vec<_>>();
+    let b = test_data[..5].iter().map(|ts| znormalize(ts)).collect::<Vec<_>>();
+    let weights = dtw_weights(a[0].len(), 0.05);
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let ctx = GpuContext::new(device, queue, sba, sda, ma, &RunOptions::default(), &a, &b).unwrap();
+
+    let cases = [
+        ("erp", ctx.erp(0.0).unwrap(), reference::erp(&a, &b, 0.0).unwrap()),
+        ("lcss", ctx.lcss(0.1, None).unwrap(), reference::lcss(&a, &b, 0.1, None).unwrap()),
+        ("dtw", ctx.dtw(Some(20)).unwrap(), reference::dtw(&a, &b, Some(20)).unwrap()),
+        ("wdtw", ctx.wdtw(&weights).unwrap(), reference::wdtw(&a, &b, &weights).unwrap()),
+        ("msm", ctx.msm().unwrap(), reference::msm(&a, &b).unwrap()),
+        ("twe", ctx.twe(0.001, 1.0).unwrap(), reference::twe(&a, &b, 0.001, 1.0).unwrap()),
+        ("adtw", ctx.adtw(0.1).unwrap(), reference::adtw(&a, &b, 0.1).unwrap()),
+    ];
+    for (metric, result, expected) in cases {
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                let tol = 1e-3 * expected[i][j].abs().max(1.0);
+                if (result[i][j] - expected[i][j]).abs() > tol {
+                    panic!(
+                        "{} ({}, {}): gpu {} vs reference {}",
+                        metric, i, j, result[i][j], expected[i][j]
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_invalid_input() {
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let series: Vec<Vec<Float>> = vec![vec![1.0, 2.0, 3.0], vec![0.5, 1.5, 2.5]];
+    let empty_batch: Vec<Vec<Float>> = Vec::new();
+    let empty_series: Vec<Vec<Float>> = vec![vec![1.0, 2.0, 3.0], Vec::new()];
+    let is_invalid = |result: Result<Vec<Vec<Float>>, TsDistanceError>| {
+        matches!(result, Err(TsDistanceError::InvalidInput(_)))
+    };
+
+    for (a, b) in [
+        (&empty_batch, &series),
+        (&series, &empty_batch),
+        (&empty_series, &series),
+        (&series, &empty_series),
+    ] {
+        assert!(is_invalid(dtw(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            a,
+            b,
+            None,
+        )));
+        assert!(is_invalid(euclidean(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            a,
+            b,
+        )));
+    }
+
+    // Length mismatches where the measure requires equal lengths.
+    let longer = vec![vec![1.0, 2.0, 3.0, 4.0]];
+    assert!(is_invalid(euclidean(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &series,
+        &longer,
+    )));
+    assert!(matches!(
+        lb_keogh(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &series[0],
+            &longer,
+            1,
+        ),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+    assert!(matches!(
+        lb_keogh(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &[],
+            &series,
+            1,
+        ),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+    assert!(is_invalid(wdtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &series,
+        &longer,
+        &[1.0, 1.0, 1.0],
+    )));
+    assert!(is_invalid(dtw_multivariate(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &series,
+        &longer,
+        2,
+        None,
+    )));
+    assert!(matches!(
+        dtw_with_path(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &[],
+            &series[0],
+            None,
+        ),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+    assert!(matches!(
+        dtw_self(
+            device.clone(),
+            queue.clone(),
+            sba.clone(),
+            sda.clone(),
+            ma.clone(),
+            &RunOptions::default(),
+            &empty_series,
+            None,
+        ),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_distance_engine() {
+    let train_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TRAIN.csv").unwrap();
+    let test_data: Vec<Vec<Float>> = read_txt("tests/ACSF1/ACSF1_TEST.csv").unwrap();
+    let a = train_data[..6].to_vec();
+    let b = test_data[..5].to_vec();
+
+    let engine = DistanceEngine::new().unwrap();
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+
+    let expected = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        Some(10),
+    )
+    .unwrap();
+    assert_eq!(engine.dtw(&a, &b, Some(10)).unwrap(), expected);
+
+    let expected = twe(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &a,
+        &b,
+        0.001,
+        1.0,
+    )
+    .unwrap();
+    assert_eq!(engine.twe(&a, &b, 0.001, 1.0).unwrap(), expected);
+
+    let ctx = engine.context(&a, &b).unwrap();
+    assert_eq!(ctx.twe(0.001, 1.0).unwrap(), expected);
+}
+
+#[test]
+fn test_role_order() {
+    let (engine, a, b) = acsf1_fixture((3, 300), (4, 200));
+
+    let result = engine.msm_gen(&a, &b, 0.5, 2.0).unwrap();
+    assert_matrix_eq_with_tol!(result, reference::msm_gen(&a, &b, 0.5, 2.0).unwrap(), 1e-3);
+
+    // Splitting a value of `a` is merging into `b` once the roles are exchanged.
+    let exchanged = engine.msm_gen(&b, &a, 2.0, 0.5).unwrap();
+    let transposed = (0..a.len())
+        .map(|i| exchanged.iter().map(|row| row[i]).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_matrix_eq_with_tol!(result, transposed, 1e-3);
+
+    let swapped = engine.msm_gen(&b, &a, 0.5, 2.0).unwrap();
+    assert!((0..a.len()).any(|i| (0..b.len()).any(|j| (result[i][j] - swapped[j][i]).abs() > 1e-3)));
+
+    // The 2 of a merges into the 0 before it for 0.5 + 2, the same 2 in b splits off the 0 of
+    // a for 2 + 2.
+    let (a, b) = (vec![vec![0.0, 2.0]], vec![vec![0.0]]);
+    assert_eq_with_tol!(engine.msm_gen(&a, &b, 2.0, 0.5).unwrap()[0][0], 2.5, 1e-6);
+    assert_eq_with_tol!(engine.msm_gen(&b, &a, 2.0, 0.5).unwrap()[0][0], 4.0, 1e-6);
+}
+
+#[test]
+fn test_condensed_matrix() {
+    let data = generate_random_batch(7, 60, 5);
+
+    let (device, queue, sba, sda, ma) = get_device().unwrap();
+    let full = dtw(
+        device.clone(),
+        queue.clone(),
+        sba.clone(),
+        sda.clone(),
+        ma.clone(),
+        &RunOptions::default(),
+        &data,
+        &data,
+        None,
+    )
+    .unwrap();
+    let packed = dtw_self(device, queue, sba, sda, ma, &RunOptions::default(), &data, None).unwrap();
+
+    let condensed = DistanceMatrix::try_from(full.clone()).unwrap().to_condensed().unwrap();
+    assert_eq!(condensed.len(), 7 * 6 / 2);
+    for (value, expected) in condensed.iter().zip(&packed) {
+        assert_eq_with_tol!(value, expected, 1e-3);
+    }
+
+    let matrix = DistanceMatrix::from_condensed(&condensed).unwrap();
+    assert_eq!((matrix.rows, matrix.cols), (7, 7));
+    assert_eq!(matrix.to_condensed().unwrap(), condensed);
+    for i in 0..7 {
+        assert_eq!(matrix.get(i, i), 0.0);
+        for j in 0..7 {
+            assert_eq!(matrix.get(i, j), matrix.get(j, i));
+        }
+    }
+
+    assert!(DistanceMatrix::try_from(vec![vec![0.0; 3]; 2]).unwrap().to_condensed().is_err());
+    assert!(matches!(
+        DistanceMatrix::try_from(vec![vec![0.0; 3], vec![0.0; 2]]),
+        Err(TsDistanceError::InvalidInput(_))
+    ));
+    assert!(DistanceMatrix::from_condensed(&[1.0, 2.0]).is_err());
+}
+
+#[test]
+fn test_compensated_sum() {
+    let (engine, a, b) = acsf1_fixture((3, ACSF1_LEN), (3, ACSF1_LEN));
+    let result = engine.twe_compensated(&a, &b, 0.001, 1.0).unwrap();
+    assert_matrix_eq_with_tol!(result, reference::twe(&a, &b, 0.001, 1.0).unwrap(), 1e-2);
+    let result = engine.msm_compensated(&a, &b, MSM_C).unwrap();
+    assert_matrix_eq_with_tol!(result, reference::msm(&a, &b).unwrap(), 1e-2);
+
+    // Every step of the diagonal path adds the same cost, which a plain f32 running sum
+    // rounds a little further off at each of the 5000 steps.
+    let len = 5000;
+    let a = vec![vec![0.0; len]];
+    let b = vec![vec![0.1; len]];
+    let step = (0.1 as Float + 0.1) as f64;
+    let expected = 0.1 as Float as f64 + (len - 1) as f64 * step;
+    let relative_error = |value: Float| (value as f64 - expected).abs() / expected;
+
+    let naive = engine.twe(&a, &b, 0.001, 1.0).unwrap();
+    let compensated = engine.twe_compensated(&a, &b, 0.001, 1.0).unwrap();
+    if std::mem::size_of::<Float>() == 4 {
+        assert!(relative_error(naive[0][0]) > 1e-5);
+    }
+    assert!(relative_error(compensated[0][0]) < 1e-6);
+
+    let expected = len as f64 * (0.1 as Float as f64);
+    let naive = engine.msm_c(&a, &b, MSM_C).unwrap();
+    let compensated = engine.msm_compensated(&a, &b, MSM_C).unwrap();
+    let relative_error = |value: Float| (value as f64 - expected).abs() / expected;
+    assert!(relative_error(compensated[0][0]) < 1e-6);
+    assert!(relative_error(compensated[0][0]) <= relative_error(naive[0][0]));
+}
+
+#[test]
+fn test_estimate_dispatches() {
+    let float = std::mem::size_of::<Float>();
+    // 2 x 2 tiles: rows of 1, 2 and 1 diamonds, over a diagonal of 2 * 128 offsets.
+    assert_eq!(
+        estimate_dispatches(64, 64, 32).unwrap(),
+        DispatchPlan {
+            rows_count: 3,
+            diamonds: 4,
+            diagonal_bytes: 256 * float,
+        }
+    );
+    // Padded to 128 x 64, 4 x 2 tiles.
+    assert_eq!(
+        estimate_dispatches(100, 40, 32).unwrap(),
+        DispatchPlan {
+            rows_count: 5,
+            diamonds: 8,
+            diagonal_bytes: 512 * float,
+        }
+    );
+    assert_eq!(
+        estimate_dispatches(1, 1, 16).unwrap(),
+        DispatchPlan {
+            rows_count: 1,
+            diamonds: 1,
+            diagonal_bytes: 64 * float,
+        }
+    );
+    assert!(estimate_dispatches(64, 64, 24).is_err());
+    assert!(estimate_dispatches(0, 64, 32).is_err());
+
+    let a = generate_random_batch(1, 300, 1);
+    let b = generate_random_batch(1, 200, 2);
+    let trace = DispatchTrace::new();
+    let engine = DistanceEngine::new().unwrap().with_tile_size(4).with_dispatch_trace(trace.clone());
+    engine.dtw(&a, &b, None).unwrap();
+    assert_eq!(trace.records().len(), estimate_dispatches(300, 200, 4).unwrap().rows_count);
+}
+
+#[test]
+fn test_ensemble() {
+    let (engine, a, b) = acsf1_fixture((3, 300), (4, 300));
+
+    // DTW and MSM both align 0 with 1 at a cost of 1 and the second values for free.
+    let metrics = [(Metric::Dtw { window: None }, 0.5), (Metric::Msm, 2.0)];
+    let result = engine
+        .ensemble(&vec![vec![0.0, 1.0]], &vec![vec![1.0, 1.0]], &metrics)
+        .unwrap();
+    assert_eq_with_tol!(result[0][0], 2.5, 1e-6);
+
+    // A metric listed twice counts with both weights.
+    let metrics = [
+        (Metric::Dtw { window: Some(10) }, 0.25),
+        (Metric::Twe { nu: 0.001, lambda: 1.0 }, 1.5),
+        (Metric::Dtw { window: Some(10) }, 0.25),
+    ];
+    let result = engine.ensemble(&a, &b, &metrics).unwrap();
+    let dtw = reference::dtw(&a, &b, Some(10)).unwrap();
+    let twe = reference::twe(&a, &b, 0.001, 1.0).unwrap();
+    let expected = (0..a.len())
+        .map(|i| (0..b.len()).map(|j| 0.5 * dtw[i][j] + 1.5 * twe[i][j]).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_matrix_eq_with_tol!(result, expected, 1e-2);
+
+    assert!(engine.ensemble(&a, &b, &[]).is_err());
+}
+
+#[test]
+fn test_read_ts_chunked() {
+    let train = generate_random_batch(5, 120, 21);
+    let test = generate_random_batch(1000, 120, 22);
+    let path = TempPath::new("chunked.ts");
+    let mut contents = "@problemName Test\n@classLabel true 0 1\n@data\n".to_string();
+    for (n, ts) in test.iter().enumerate() {
+        let values = ts.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        contents.push_str(&format!("{}:{}\n", values.join(","), n % 2));
+    }
+    std::fs::write(&path, contents).unwrap();
+
+    let chunks = read_ts_chunked(&path, 300)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        chunks.iter().map(|(series, _)| series.len()).collect::<Vec<_>>(),
+        vec![300, 300, 300, 100]
+    );
+    assert_eq!(chunks[3].1[1], "1");
+    let (series, labels) = read_ts_file(&path).unwrap();
+    assert_eq!(chunks.iter().flat_map(|(series, _)| series.clone()).collect::<Vec<_>>(), series);
+    assert_eq!(chunks.iter().flat_map(|(_, labels)| labels.clone()).collect::<Vec<_>>(), labels);
+
+    let engine = DistanceEngine::new().unwrap();
+    let chunks = read_ts_chunked(&path, 300).unwrap().map(|chunk| chunk.map(|(series, _)| series));
+    let result = engine.dtw_chunked(&train, chunks, Some(10)).unwrap();
+    let expected = engine.dtw(&series, &train, Some(10)).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+
+    assert!(read_ts_chunked(&path, 0).is_err());
+    std::fs::write(&path, "@classLabel false\n1,2,3\n").unwrap();
+    assert!(read_ts_chunked(&path, 10).unwrap().next().unwrap().is_err());
+}
+
+#[test]
+fn test_read_csv_chunked() {
+    let train = generate_random_batch(5, 120, 23);
+    let test = generate_random_batch(1000, 120, 24);
+    let path = TempPath::new("chunked.csv");
+    let rows = test
+        .iter()
+        .enumerate()
+        .map(|(n, ts)| std::iter::once((n % 3) as Float).chain(ts.iter().copied()).collect())
+        .collect::<Vec<Vec<Float>>>();
+    write_csv(path.as_str(), &rows).unwrap();
+
+    // The rows are parsed as `read_txt` does, the label column apart.
+    let chunks = read_csv_chunked(&path, 300)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        chunks.iter().map(|(series, _)| series.len()).collect::<Vec<_>>(),
+        vec![300, 300, 300, 100]
+    );
+    let series = read_txt::<Float>(path.as_str()).unwrap();
+    let labels = read_labels(path.as_str()).unwrap();
+    assert_eq!(chunks.iter().flat_map(|(series, _)| series.clone()).collect::<Vec<_>>(), series);
+    let chunk_labels = chunks.iter().flat_map(|(_, labels)| labels.clone());
+    assert_eq!(chunk_labels.map(|label| label.parse::<usize>().unwrap()).collect::<Vec<_>>(), labels);
+
+    let engine = DistanceEngine::new().unwrap();
+    let chunks = read_csv_chunked(&path, 300).unwrap().map(|chunk| chunk.map(|(series, _)| series));
+    let result = engine.dtw_chunked(&train, chunks, Some(10)).unwrap();
+    let expected = engine.dtw(&series, &train, Some(10)).unwrap();
+    assert_matrix_eq_with_tol!(result, expected, 1e-3);
+
+    assert!(read_csv_chunked(&path, 0).is_err());
+    std::fs::write(&path, "1,2,x\n").unwrap();
+    assert!(read_csv_chunked(&path, 10).unwrap().next().unwrap().is_err());
+}
+
+#[test]
+fn test_dtw_reversed() {
+    let (engine, a, b) = acsf1_fixture((3, 300), (4, 250));
+
+    // 3, 2, 1 read backwards matches 1, 2, 3 exactly.
+    let result = engine.dtw_reversed(&vec![vec![1.0, 2.0, 3.0]], &vec![vec![3.0, 2.0, 1.0]], None);
+    assert_eq!(result.unwrap()[0][0], 0.0);
+
+    // A series reading the same both ways is its own reversal.
+    let palindromes = b
+        .iter()
+        .map(|ts| ts.iter().chain(ts.iter().rev()).copied().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let result = engine.dtw_reversed(&a, &palindromes, Some(20)).unwrap();
+    assert_matrix_eq_with_tol!(result, reference::dtw(&a, &palindromes, Some(20)).unwrap(), 1e-3);
+
+    let reversed = b.iter().map(|ts| ts.iter().rev().copied().collect::<Vec<_>>()).collect::<Vec<_>>();
+    let result = engine.dtw_reversed(&a, &b, Some(20)).unwrap();
+    assert_matrix_eq_with_tol!(result, reference::dtw(&a, &reversed, Some(20)).unwrap(), 1e-3);
+}
+
+#[test]
+fn test_integer_counts() {
+    let (engine, a, b) = acsf1_fixture((3, ACSF1_LEN), (4, ACSF1_LEN));
+
+    // 1, 3 and 4 are common, 2 is substituted by 5.
+    let (x, y) = (vec![vec![1.0, 2.0, 3.0, 4.0]], vec![vec![1.0, 5.0, 3.0, 4.0]]);
+    assert_eq!(engine.lcss_count(&x, &y, 0.1, None).unwrap(), vec![vec![3]]);
+    assert_eq!(engine.edr_count(&x, &y, 0.1).unwrap(), vec![vec![1]]);
+
+    // The reference LCSS distance is normalized by the common length of the series.
+    let counts = engine.lcss_count(&a, &b, 0.1, None).unwrap();
+    let distances = reference::lcss(&a, &b, 0.1, None).unwrap();
+    for (counts, distances) in counts.iter().zip(&distances) {
+        for (&count, &distance) in counts.iter().zip(distances) {
+            assert_eq!(count, ((1.0 - distance) * ACSF1_LEN as Float).round() as u32);
+        }
+    }
+    let counts = engine.edr_count(&a, &b, 0.1).unwrap();
+    let edits = reference::edr(&a, &b, 0.1).unwrap();
+    for (counts, edits) in counts.iter().zip(&edits) {
+        for (&count, &edit) in counts.iter().zip(edits) {
+            assert_eq!(count as Float, edit);
+        }
+    }
+
+    // Past MAX_EXACT_COUNT a count may round to its neighbour, so longer series are refused
+    // instead of returning it, before being uploaded.
+    if std::mem::size_of::<Float>() != 4 {
+        return;
+    }
+    let long = vec![vec![0.0; MAX_EXACT_COUNT as usize + 1]];
+    let err = engine.edr_count(&long, &b[..1].to_vec(), 0.1).unwrap_err();
+    assert!(matches!(err, TsDistanceError::InvalidInput(_)));
+    let err = engine.lcss_count(&b[..1].to_vec(), &long, 0.1, None).unwrap_err();
+    assert!(matches!(err, TsDistanceError::InvalidInput(_)));
+}
+
+#[test]
+fn test_subgroup_size_fallback() {
+    assert_eq!(effective_subgroup_size(Some(64), 1024), 64);
+    assert_eq!(effective_subgroup_size(None, 1024), FALLBACK_SUBGROUP_SIZE);
+    assert_eq!(effective_subgroup_size(None, 24), 16);
+    assert_eq!(effective_subgroup_size(None, 0), 1);
+
+    let (engine, a, b) = acsf1_fixture((3, ACSF1_LEN), (4, ACSF1_LEN));
+    let result = engine
+        .with_subgroup_size_query(false)
+        .dtw(&a, &b, Some(10))
+        .unwrap();
+    assert_matrix_eq_with_tol!(result, reference::dtw(&a, &b, Some(10)).unwrap(), 1e-3);
+}
+
+#[test]
+fn test_lcss_delta() {
+    let (engine, a, b) = acsf1_fixture((3, 300), (4, 250));
+
+    // The common subsequence 1, 2, 3, 4 lies two steps later in y.
+    let x = vec![vec![1.0, 2.0, 3.0, 4.0, 0.0, 0.0]];
+    let y = vec![vec![0.0, 0.0, 1.0, 2.0, 3.0, 4.0]];
+    for (delta, expected) in [(None, 4), (Some(2), 4), (Some(1), 0), (Some(0), 0)] {
+        assert_eq!(engine.lcss_count(&x, &y, 0.1, delta).unwrap()[0][0], expected);
+    }
+
+    let unbounded = engine.lcss(&a, &b, 0.5, None).unwrap();
+    let windowed = engine.lcss(&a, &b, 0.5, Some(5)).unwrap();
+    assert_matrix_eq_with_tol!(windowed, reference::lcss(&a, &b, 0.5, Some(5)).unwrap(), 1e-4);
+    // Fewer matches can only lengthen the distance.
+    for (unbounded, windowed) in unbounded.iter().zip(&windowed) {
+        for (&unbounded, &windowed) in unbounded.iter().zip(windowed) {
+            assert!(windowed >= unbounded - 1e-6);
+        }
+    }
+}